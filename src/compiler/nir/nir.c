@@ -3101,6 +3101,7 @@ nir_tex_instr_result_size(const nir_tex_instr *instr)
 
    case nir_texop_hdr_dim_nv:
    case nir_texop_tex_type_nv:
+   case nir_texop_sample_footprint_nv:
       return 4;
 
    default:
@@ -3208,6 +3209,7 @@ nir_tex_instr_src_type(const nir_tex_instr *instr, unsigned src)
    case nir_tex_src_sampler_offset:
    case nir_tex_src_texture_handle:
    case nir_tex_src_sampler_handle:
+   case nir_tex_src_footprint_granularity_nv:
       return nir_type_uint;
 
    case nir_num_tex_src_types:
@@ -2248,6 +2248,9 @@ typedef enum nir_tex_src_type {
    /** Plane index for multi-plane YCbCr textures */
    nir_tex_src_plane,
 
+   /** Footprint granularity for nir_texop_sample_footprint_nv */
+   nir_tex_src_footprint_granularity_nv,
+
    /**
     * Backend-specific vec4 tex src argument.
     *
@@ -2321,6 +2324,13 @@ typedef enum nir_texop {
    nir_texop_hdr_dim_nv,
    /** Maps to TXQ.TEXTURE_TYPE */
    nir_texop_tex_type_nv,
+   /**
+    * Texture footprint query for NV_shader_texture_footprint.
+    *
+    * Returns the four raw dwords written by the hardware.  The component
+    * field is 1 for a coarse footprint and 0 for a fine one.
+    */
+   nir_texop_sample_footprint_nv,
 } nir_texop;
 
 /** Represents a texture instruction */
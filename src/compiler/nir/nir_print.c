@@ -1712,6 +1712,9 @@ print_tex_instr(nir_tex_instr *instr, print_state *state)
    case nir_texop_tex_type_nv:
       fprintf(fp, "tex_type_nv ");
       break;
+   case nir_texop_sample_footprint_nv:
+      fprintf(fp, "sample_footprint_nv ");
+      break;
    default:
       unreachable("Invalid texture operation");
       break;
@@ -1789,6 +1792,9 @@ print_tex_instr(nir_tex_instr *instr, print_state *state)
       case nir_tex_src_plane:
          fprintf(fp, "(plane)");
          break;
+      case nir_tex_src_footprint_granularity_nv:
+         fprintf(fp, "(footprint_granularity_nv)");
+         break;
 
       default:
          unreachable("Invalid texture source type");
@@ -874,7 +874,9 @@ validate_tex_instr(nir_tex_instr *instr, validate_state *state)
    else
       validate_assert(state, instr->op != nir_texop_txf_ms);
 
-   if (instr->op != nir_texop_tg4)
+   if (instr->op == nir_texop_sample_footprint_nv)
+      validate_assert(state, instr->component <= 1);
+   else if (instr->op != nir_texop_tg4)
       validate_assert(state, instr->component == 0);
 
    if (nir_tex_instr_has_explicit_tg4_offsets(instr)) {
@@ -26,9 +26,38 @@ void nak_compiler_destroy(struct nak_compiler *nak);
 
 uint64_t nak_debug_flags(const struct nak_compiler *nak);
 
+/** Returns the NIR options for this compiler's SM
+ *
+ * These are the source of truth for what NAK expects NIR to look like.
+ * Anything these options don't lower away has to be handled by NAK, so
+ * drivers should use them as-is rather than keeping their own lists.
+ */
 const struct nir_shader_compiler_options *
 nak_nir_options(const struct nak_compiler *nak);
 
+/** Returns true if NAK can translate the given intrinsic on this SM
+ *
+ * Any intrinsic for which this returns false has to be lowered away before
+ * the shader is handed to nak_compile_shader().
+ */
+bool nak_supports_intrinsic(const struct nak_compiler *nak,
+                            nir_intrinsic_op intrin);
+
+/** Fills a BITSET_WORDS(nir_num_intrinsics) bitset with every intrinsic
+ * for which nak_supports_intrinsic() returns true
+ */
+static inline void
+nak_supported_intrinsics(const struct nak_compiler *nak,
+                         BITSET_WORD *supported)
+{
+   for (unsigned i = 0; i < nir_num_intrinsics; i++) {
+      if (nak_supports_intrinsic(nak, (nir_intrinsic_op)i))
+         BITSET_SET(supported, i);
+      else
+         BITSET_CLEAR(supported, i);
+   }
+}
+
 void nak_optimize_nir(nir_shader *nir, const struct nak_compiler *nak);
 void nak_preprocess_nir(nir_shader *nir, const struct nak_compiler *nak);
 
@@ -40,6 +69,12 @@ struct nak_fs_key {
     */
    bool force_sample_shading;
 
+   /** True if written depth is allowed outside of [0, 1], as with
+    * VK_EXT_depth_range_unrestricted.  Otherwise, gl_FragDepth is clamped
+    * to [0, 1] before it's exported.
+    */
+   bool depth_unrestricted;
+
    /**
     * The constant buffer index and offset at which the sample locations table lives.
     * Each sample location is two 4-bit unorm values packed into an 8-bit value
@@ -47,6 +82,16 @@ struct nak_fs_key {
    */
    uint8_t sample_locations_cb;
    uint32_t sample_locations_offset;
+
+   /**
+    * Color components which will never be written, 4 bits per render target
+    *
+    * This is the inverse of the color write mask so that a zeroed key
+    * writes everything.  Components set here are not exported and the code
+    * computing them is thrown away.  The driver must not set anything which
+    * is still consumed elsewhere, such as alpha for alpha-to-coverage.
+    */
+   uint32_t color_write_disable;
 };
 
 void nak_postprocess_nir(nir_shader *nir, const struct nak_compiler *nak,
@@ -101,7 +146,10 @@ struct nak_shader_info {
    /** Number of barriers used */
    uint8_t num_barriers;
 
-   uint16_t _pad0;
+   /** Theoretical maximum number of warps per SM given the GPR usage */
+   uint8_t max_warps_per_sm;
+
+   uint8_t _pad0;
 
    /** Size of shader local (scratch) memory */
    uint32_t slm_size;
@@ -119,12 +167,25 @@ struct nak_shader_info {
 
       struct {
          bool writes_depth;
+
+         /** True if the shader may write a depth less than the incoming
+          * depth.  This is false for FRAG_DEPTH_LAYOUT_GREATER and
+          * FRAG_DEPTH_LAYOUT_UNCHANGED.
+          */
+         bool writes_depth_less;
+
+         /** True if the shader may write a depth greater than the incoming
+          * depth.  This is false for FRAG_DEPTH_LAYOUT_LESS and
+          * FRAG_DEPTH_LAYOUT_UNCHANGED.
+          */
+         bool writes_depth_greater;
+
          bool reads_sample_mask;
          bool post_depth_coverage;
          bool uses_sample_shading;
          bool early_fragment_tests;
 
-         uint8_t _pad[7];
+         uint8_t _pad[5];
       } fs;
 
       struct {
@@ -140,6 +201,12 @@ struct nak_shader_info {
    };
 
    struct {
+      /** True if gl_Layer is written
+       *
+       * The driver needs this to tell the hardware to take the layer from
+       * the shader.  gl_ViewportIndex only needs its bit in the SPH output
+       * map so there's no equivalent for it.
+       */
       bool writes_layer;
       uint8_t clip_enable;
       uint8_t cull_enable;
@@ -154,22 +221,63 @@ struct nak_shader_info {
 };
 #pragma GCC diagnostic pop
 
+/** Statistics about a compiled shader */
+struct nak_shader_stats {
+   /** Number of instructions, not counting scheduling words */
+   uint32_t instr_count;
+
+   /** Sum of the static delays of all instructions
+    *
+    * This is a rough estimate of how many cycles it takes to issue every
+    * instruction in the shader once.
+    */
+   uint32_t static_cycle_count;
+};
+
+/** A human-readable form of the shader at some point during compilation */
+struct nak_shader_ir {
+   const char *name;
+   const char *description;
+   const char *text;
+};
+
 struct nak_shader_bin {
    struct nak_shader_info info;
 
+   struct nak_shader_stats stats;
+
    uint32_t code_size;
    const void *code;
 
    const char *asm_str;
+
+   /** Textual forms of the shader, only filled out if dump_asm is set */
+   uint32_t ir_count;
+   const struct nak_shader_ir *irs;
+
+   /** Newline-separated compiler warnings or NULL if there are none */
+   const char *warnings_str;
 };
 
 void nak_shader_bin_destroy(struct nak_shader_bin *bin);
 
+/** Compiles a shader
+ *
+ * If max_gprs is non-zero, the register allocator will spill as needed to
+ * keep the shader within max_gprs GPRs, as reported in nak_shader_info.  This
+ * can be used to target a particular occupancy.  Values which are too small
+ * for the compiler to work with are rounded up.
+ *
+ * If align_loops is set, loop headers are padded out to the start of an
+ * instruction cache line.  This only pays off for shaders which spend most of
+ * their time in short, hot loops.  It is only supported on SM70+.
+ */
 struct nak_shader_bin *
 nak_compile_shader(nir_shader *nir, bool dump_asm,
                    const struct nak_compiler *nak,
                    nir_variable_mode robust2_modes,
-                   const struct nak_fs_key *fs_key);
+                   const struct nak_fs_key *fs_key,
+                   uint8_t max_gprs, bool align_loops);
 
 #ifdef __cplusplus
 }
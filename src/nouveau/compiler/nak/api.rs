@@ -2,12 +2,12 @@
 // SPDX-License-Identifier: MIT
 
 use crate::from_nir::*;
-use crate::ir::{ShaderIoInfo, ShaderStageInfo};
+use crate::golden::{check_golden, golden_enabled, GoldenInput};
+use crate::ir::{Shader, ShaderIoInfo, ShaderStageInfo, XfbInfo};
 use crate::sph;
 
 use nak_bindings::*;
 
-use std::cmp::max;
 use std::env;
 use std::ffi::{CStr, CString};
 use std::fmt::Write;
@@ -19,6 +19,7 @@ enum DebugFlags {
     Print,
     Serial,
     Spill,
+    Validate,
 }
 
 pub struct Debug {
@@ -41,6 +42,7 @@ impl Debug {
                 "print" => flags |= 1 << DebugFlags::Print as u8,
                 "serial" => flags |= 1 << DebugFlags::Serial as u8,
                 "spill" => flags |= 1 << DebugFlags::Spill as u8,
+                "validate" => flags |= 1 << DebugFlags::Validate as u8,
                 unk => eprintln!("Unknown NAK_DEBUG flag \"{}\"", unk),
             }
         }
@@ -62,6 +64,11 @@ pub trait GetDebugFlags {
     fn spill(&self) -> bool {
         self.debug_flags() & (1 << DebugFlags::Spill as u8) != 0
     }
+
+    fn validate(&self) -> bool {
+        cfg!(debug_assertions)
+            || self.debug_flags() & (1 << DebugFlags::Validate as u8) != 0
+    }
 }
 
 pub static DEBUG: OnceLock<Debug> = OnceLock::new();
@@ -185,19 +192,70 @@ pub extern "C" fn nak_nir_options(
     &nak.nir_options
 }
 
+#[no_mangle]
+pub extern "C" fn nak_supports_intrinsic(
+    nak: *const nak_compiler,
+    intrin: nir_intrinsic_op,
+) -> bool {
+    assert!(!nak.is_null());
+    let nak = unsafe { &*nak };
+    intrinsic_is_supported(nak.sm, intrin)
+}
+
+/// A textual form of the shader to hand back to the driver
+struct ShaderIr {
+    name: &'static str,
+    description: &'static str,
+    text: String,
+}
+
 #[repr(C)]
 struct ShaderBin {
     bin: nak_shader_bin,
     code: Vec<u32>,
     asm: CString,
+    ir_strs: Vec<CString>,
+    irs: Vec<nak_shader_ir>,
+    warnings: CString,
 }
 
 impl ShaderBin {
-    pub fn new(info: nak_shader_info, code: Vec<u32>, asm: &str) -> ShaderBin {
+    pub fn new(
+        info: nak_shader_info,
+        stats: nak_shader_stats,
+        code: Vec<u32>,
+        asm: &str,
+        irs: Vec<ShaderIr>,
+        warnings: &[String],
+    ) -> ShaderBin {
         let asm = CString::new(asm)
             .expect("NAK assembly has unexpected null characters");
+
+        // The CStrings own the actual bytes so we can point at them from
+        // the nak_shader_ir structs.  Moving them doesn't move the bytes.
+        let mut ir_strs = Vec::new();
+        for ir in irs {
+            ir_strs.push(CString::new(ir.name).unwrap());
+            ir_strs.push(CString::new(ir.description).unwrap());
+            ir_strs.push(
+                CString::new(ir.text)
+                    .expect("NAK IR has unexpected null characters"),
+            );
+        }
+        let irs: Vec<nak_shader_ir> = ir_strs
+            .chunks(3)
+            .map(|strs| nak_shader_ir {
+                name: strs[0].as_ptr(),
+                description: strs[1].as_ptr(),
+                text: strs[2].as_ptr(),
+            })
+            .collect();
+
+        let warnings = CString::new(warnings.join("\n"))
+            .expect("NAK warnings have unexpected null characters");
         let bin = nak_shader_bin {
             info: info,
+            stats: stats,
             code_size: (code.len() * 4).try_into().unwrap(),
             code: code.as_ptr() as *const c_void,
             asm_str: if asm.is_empty() {
@@ -205,11 +263,21 @@ impl ShaderBin {
             } else {
                 asm.as_ptr()
             },
+            ir_count: irs.len().try_into().unwrap(),
+            irs: irs.as_ptr(),
+            warnings_str: if warnings.is_empty() {
+                std::ptr::null()
+            } else {
+                warnings.as_ptr()
+            },
         };
         ShaderBin {
             bin: bin,
             code: code,
             asm: asm,
+            ir_strs: ir_strs,
+            irs: irs,
+            warnings: warnings,
         }
     }
 }
@@ -233,6 +301,32 @@ fn eprint_hex(label: &str, data: &[u32]) {
     eprintln!("");
 }
 
+/// Adds up the static delays of every instruction in the shader
+fn static_cycle_count(s: &Shader) -> u32 {
+    let mut cycles = 0_u32;
+    s.for_each_instr(&mut |instr| {
+        cycles += u32::from(instr.deps.delay);
+    });
+    cycles
+}
+
+fn xfb_to_nak(xfb: &Option<Box<XfbInfo>>) -> nak_xfb_info {
+    let mut nak_xfb: nak_xfb_info = unsafe { std::mem::zeroed() };
+    let Some(xfb) = xfb else {
+        return nak_xfb;
+    };
+
+    nak_xfb.stride = xfb.stride;
+    nak_xfb.stream = xfb.stream;
+    for b in 0..4 {
+        let attr_index = &xfb.attr_index[b];
+        nak_xfb.attr_count[b] = attr_index.len().try_into().unwrap();
+        nak_xfb.attr_index[b].fill(0xff); // = skip
+        nak_xfb.attr_index[b][..attr_index.len()].copy_from_slice(attr_index);
+    }
+    nak_xfb
+}
+
 #[no_mangle]
 pub extern "C" fn nak_compile_shader(
     nir: *mut nir_shader,
@@ -240,7 +334,20 @@ pub extern "C" fn nak_compile_shader(
     nak: *const nak_compiler,
     robust2_modes: nir_variable_mode,
     fs_key: *const nak_fs_key,
+    max_gprs: u8,
+    align_loops: bool,
 ) -> *mut nak_shader_bin {
+    let golden_input = golden_enabled().then(|| {
+        GoldenInput::new(
+            unsafe { &*nak },
+            unsafe { &*nir },
+            robust2_modes,
+            unsafe { fs_key.as_ref() },
+            max_gprs,
+            align_loops,
+        )
+    });
+
     unsafe { nak_postprocess_nir(nir, nak, robust2_modes, fs_key) };
     let nak = unsafe { &*nak };
     let nir = unsafe { &*nir };
@@ -250,52 +357,172 @@ pub extern "C" fn nak_compile_shader(
         Some(unsafe { &*fs_key })
     };
 
-    let mut s = nak_shader_from_nir(nir, nak.sm);
+    assert!(
+        !align_loops || nak.sm >= 70,
+        "Loop alignment is only supported on SM70+"
+    );
+
+    let mut s = nak_shader_from_nir(nir, nak.sm, fs_key);
+    s.info.align_loops = align_loops;
+    if max_gprs > 0 {
+        // The limit the driver gives us is in terms of the number of GPRs the
+        // hardware allocates, which includes the two extra GPRs on Volta+.
+        let max_gprs = u32::from(max_gprs);
+        s.set_max_gprs(if nak.sm >= 70 {
+            max_gprs.saturating_sub(2)
+        } else {
+            max_gprs
+        });
+    }
+
+    let golden_input_ir = golden_input.as_ref().map(|_| s.to_string());
 
     if DEBUG.print() {
         eprintln!("NAK IR:\n{}", &s);
     }
+    if DEBUG.validate() {
+        s.validate();
+    }
 
     s.opt_bar_prop();
     if DEBUG.print() {
         eprintln!("NAK IR after opt_bar_prop:\n{}", &s);
     }
+    if DEBUG.validate() {
+        s.validate();
+    }
 
     s.opt_copy_prop();
     if DEBUG.print() {
         eprintln!("NAK IR after opt_copy_prop:\n{}", &s);
     }
+    if DEBUG.validate() {
+        s.validate();
+    }
+
+    s.opt_const_fold();
+    if DEBUG.print() {
+        eprintln!("NAK IR after opt_const_fold:\n{}", &s);
+    }
+    if DEBUG.validate() {
+        s.validate();
+    }
+
+    // Propagate the results of constant folding
+    s.opt_copy_prop();
+    if DEBUG.print() {
+        eprintln!("NAK IR after opt_copy_prop:\n{}", &s);
+    }
+    if DEBUG.validate() {
+        s.validate();
+    }
+
+    s.opt_fold_srcs();
+    if DEBUG.print() {
+        eprintln!("NAK IR after opt_fold_srcs:\n{}", &s);
+    }
+    if DEBUG.validate() {
+        s.validate();
+    }
 
     s.opt_lop();
     if DEBUG.print() {
         eprintln!("NAK IR after opt_lop:\n{}", &s);
     }
+    if DEBUG.validate() {
+        s.validate();
+    }
+
+    s.opt_canonicalize();
+    if DEBUG.print() {
+        eprintln!("NAK IR after opt_canonicalize:\n{}", &s);
+    }
+    if DEBUG.validate() {
+        s.validate();
+    }
+
+    s.opt_cse();
+    if DEBUG.print() {
+        eprintln!("NAK IR after opt_cse:\n{}", &s);
+    }
+    if DEBUG.validate() {
+        s.validate();
+    }
+
+    s.opt_loop_prefetch();
+    if DEBUG.print() {
+        eprintln!("NAK IR after opt_loop_prefetch:\n{}", &s);
+    }
+    if DEBUG.validate() {
+        s.validate();
+    }
+
+    s.opt_uniform_regs();
+    if DEBUG.print() {
+        eprintln!("NAK IR after opt_uniform_regs:\n{}", &s);
+    }
+    if DEBUG.validate() {
+        s.validate();
+    }
 
     s.opt_dce();
     if DEBUG.print() {
         eprintln!("NAK IR after dce:\n{}", &s);
     }
+    if DEBUG.validate() {
+        s.validate();
+    }
 
     s.opt_out();
     if DEBUG.print() {
         eprintln!("NAK IR after opt_out:\n{}", &s);
     }
+    if DEBUG.validate() {
+        s.validate();
+    }
 
     s.legalize();
     if DEBUG.print() {
         eprintln!("NAK IR after legalize:\n{}", &s);
     }
+    if DEBUG.validate() {
+        s.validate();
+    }
+
+    s.opt_instr_sched();
+    if DEBUG.print() {
+        eprintln!("NAK IR after opt_instr_sched:\n{}", &s);
+    }
+    if DEBUG.validate() {
+        s.validate();
+    }
+
+    let mut irs = Vec::new();
+    if dump_asm {
+        irs.push(ShaderIr {
+            name: "NAK IR",
+            description: "NAK IR before register allocation",
+            text: s.to_string(),
+        });
+    }
 
     s.assign_regs();
     if DEBUG.print() {
         eprintln!("NAK IR after assign_regs:\n{}", &s);
     }
+    if DEBUG.validate() {
+        s.validate();
+    }
 
     s.lower_ineg();
     s.lower_par_copies();
     s.lower_copy_swap();
     s.opt_jump_thread();
     s.calc_instr_deps();
+    s.assign_reuse();
+    if DEBUG.validate() {
+        s.verify_instr_deps();
+    }
 
     if DEBUG.print() {
         eprintln!("NAK IR:\n{}", &s);
@@ -305,12 +532,13 @@ pub extern "C" fn nak_compile_shader(
 
     let info = nak_shader_info {
         stage: nir.info.stage(),
-        num_gprs: if s.info.sm >= 70 {
-            max(4, s.info.num_gprs + 2)
-        } else {
-            max(4, s.info.num_gprs)
-        },
+        num_gprs: s.info.num_hw_gprs(),
         num_barriers: s.info.num_barriers,
+        max_warps_per_sm: s
+            .info
+            .max_warps_per_sm(nak.warps_per_sm.into())
+            .try_into()
+            .unwrap(),
         _pad0: Default::default(),
         slm_size: s.info.slm_size,
         __bindgen_anon_1: match &s.info.stage {
@@ -337,6 +565,8 @@ pub extern "C" fn nak_compile_shader(
                 nak_shader_info__bindgen_ty_1 {
                     fs: nak_shader_info__bindgen_ty_1__bindgen_ty_2 {
                         writes_depth: fs_info.writes_depth,
+                        writes_depth_less: fs_info.writes_depth_less,
+                        writes_depth_greater: fs_info.writes_depth_greater,
                         reads_sample_mask: fs_info.reads_sample_mask,
                         post_depth_coverage: nir_fs_info.post_depth_coverage(),
                         uses_sample_shading: nir_fs_info.uses_sample_shading(),
@@ -403,7 +633,10 @@ pub extern "C" fn nak_compile_shader(
                     clip_enable: clip_enable.try_into().unwrap(),
                     cull_enable: cull_enable.try_into().unwrap(),
                     _pad: Default::default(),
-                    xfb: unsafe { nak_xfb_from_nir(nir.xfb_info) },
+                    xfb: match &s.info.io {
+                        ShaderIoInfo::Vtg(io) => xfb_to_nak(&io.xfb),
+                        _ => panic!("Expected VTG I/O info"),
+                    },
                 }
             }
             _ => unsafe { std::mem::zeroed() },
@@ -414,14 +647,36 @@ pub extern "C" fn nak_compile_shader(
     let mut asm = String::new();
     if dump_asm {
         write!(asm, "{}", s).expect("Failed to dump assembly");
+        irs.push(ShaderIr {
+            name: "NAK assembly",
+            description: "NAK IR after register allocation and scheduling",
+            text: asm.clone(),
+        });
     }
 
-    let code = if nak.sm >= 70 {
-        s.encode_sm70()
-    } else if nak.sm >= 50 {
-        s.encode_sm50()
-    } else {
-        panic!("Unsupported shader model");
+    let encoder = s.encoder();
+    let code = encoder.encode_shader(&s);
+    if DEBUG.validate() {
+        assert!(encoder.encoded_size(&s) == code.len() * 4);
+    }
+
+    if let (Some(input), Some(input_ir)) = (&golden_input, &golden_input_ir) {
+        check_golden(s.info.sm, input, input_ir, &s.to_string(), &code);
+    }
+
+    if dump_asm {
+        if let Some(disasm) = encoder.disassemble(&code) {
+            irs.push(ShaderIr {
+                name: "Disassembly",
+                description: "Disassembly of the final shader binary",
+                text: disasm,
+            });
+        }
+    }
+
+    let stats = nak_shader_stats {
+        instr_count: encoder.instr_count(&code).try_into().unwrap(),
+        static_cycle_count: static_cycle_count(&s),
     };
 
     if DEBUG.print() {
@@ -429,17 +684,12 @@ pub extern "C" fn nak_compile_shader(
             let c_name = _mesa_shader_stage_to_string(info.stage as u32);
             CStr::from_ptr(c_name).to_str().expect("Invalid UTF-8")
         };
-        let instruction_count = if nak.sm >= 70 {
-            code.len() / 4
-        } else if nak.sm >= 50 {
-            (code.len() / 8) * 3
-        } else {
-            unreachable!()
-        };
 
         eprintln!("Stage: {}", stage_name);
-        eprintln!("Instruction count: {}", instruction_count);
+        eprintln!("Instruction count: {}", stats.instr_count);
+        eprintln!("Static cycle count: {}", stats.static_cycle_count);
         eprintln!("Num GPRs: {}", info.num_gprs);
+        eprintln!("Max warps per SM: {}", info.max_warps_per_sm);
         eprintln!("SLM size: {}", info.slm_size);
 
         if info.stage != MESA_SHADER_COMPUTE {
@@ -447,8 +697,17 @@ pub extern "C" fn nak_compile_shader(
         }
 
         eprint_hex("Encoded shader", &code);
+
+        if let Some(disasm) = encoder.disassemble(&code) {
+            eprintln!("Disassembly:\n{}", disasm);
+        }
+
+        for warning in &s.warnings {
+            eprintln!("Warning: {}", warning);
+        }
     }
 
-    let bin = Box::new(ShaderBin::new(info, code, &asm));
+    let bin =
+        Box::new(ShaderBin::new(info, stats, code, &asm, irs, &s.warnings));
     Box::into_raw(bin) as *mut nak_shader_bin
 }
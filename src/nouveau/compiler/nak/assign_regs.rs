@@ -46,8 +46,18 @@ enum SSAUse {
     Vec(SSARef),
 }
 
+/// Number of GPR banks on SM50 and SM60.  A register's bank is its index
+/// modulo this.
+const SM50_GPR_BANKS: u32 = 4;
+
 struct SSAUseMap {
     ssa_map: HashMap<SSAValue, Vec<(usize, SSAUse)>>,
+
+    /// For each scalar GPR value, the instructions which read it together
+    /// with other scalar GPR values and what those other values are.  This
+    /// is only populated on SM50 and SM60 where reading two sources from the
+    /// same register bank stalls the operand collector.
+    bank_map: HashMap<SSAValue, Vec<(usize, Vec<SSAValue>)>>,
 }
 
 impl SSAUseMap {
@@ -81,7 +91,68 @@ impl SSAUseMap {
         }
     }
 
-    pub fn add_block(&mut self, b: &BasicBlock) {
+    fn find_bank_use_after(
+        &self,
+        ssa: SSAValue,
+        ip: usize,
+    ) -> Option<&Vec<SSAValue>> {
+        let v = self.bank_map.get(&ssa)?;
+        let p = v.partition_point(|(uip, _)| *uip <= ip);
+        v.get(p).map(|(_, others)| others)
+    }
+
+    fn add_bank_uses(
+        &mut self,
+        ip: usize,
+        instr: &Instr,
+        prev: Option<&Instr>,
+    ) {
+        let scalar_gpr_ssa = |src: &Src| match src.src_ref {
+            SrcRef::SSA(ssa)
+                if ssa.comps() == 1 && ssa.file() == RegFile::GPR =>
+            {
+                Some(ssa[0])
+            }
+            _ => None,
+        };
+
+        let mut srcs = Vec::new();
+        for (i, src) in instr.srcs().iter().enumerate() {
+            let Some(ssa) = scalar_gpr_ssa(src) else {
+                continue;
+            };
+
+            // If the previous instruction read the same value in the same
+            // source slot, assign_reuse() will most likely let us read it
+            // from the operand reuse cache, in which case it doesn't need a
+            // register bank read at all.
+            let reused = prev.map_or(false, |prev| {
+                prev.srcs().get(i).and_then(scalar_gpr_ssa) == Some(ssa)
+            });
+            if !reused && !srcs.contains(&ssa) {
+                srcs.push(ssa);
+            }
+        }
+
+        if srcs.len() < 2 {
+            return;
+        }
+
+        for ssa in &srcs {
+            let others = srcs.iter().filter(|o| *o != ssa).copied().collect();
+            let v = self.bank_map.entry(*ssa).or_insert_with(|| Vec::new());
+            v.push((ip, others));
+        }
+    }
+
+    pub fn add_block(&mut self, b: &BasicBlock, sm: u8) {
+        if sm < 70 {
+            for (ip, instr) in b.instrs.iter().enumerate() {
+                let prev = ip.checked_sub(1).map(|p| &*b.instrs[p]);
+                self.add_bank_uses(ip, instr, prev);
+            }
+        }
+
         for (ip, instr) in b.instrs.iter().enumerate() {
             match &instr.op {
                 Op::FSOut(op) => {
@@ -105,11 +176,12 @@ impl SSAUseMap {
         }
     }
 
-    pub fn for_block(b: &BasicBlock) -> SSAUseMap {
+    pub fn for_block(b: &BasicBlock, sm: u8) -> SSAUseMap {
         let mut am = SSAUseMap {
             ssa_map: HashMap::new(),
+            bank_map: HashMap::new(),
         };
-        am.add_block(b);
+        am.add_block(b, sm);
         am
     }
 }
@@ -337,12 +409,47 @@ impl RegAllocator {
             }
         }
 
+        if let Some(reg) = self.try_find_bank_free_reg(ip, sum, ssa) {
+            self.assign_reg(ssa, reg);
+            return reg;
+        }
+
         let reg = self
             .try_find_unused_reg_range(0, 1, 1)
             .expect("Failed to find free register");
         self.assign_reg(ssa, reg);
         reg
     }
+
+    /// Tries to find a free register for `ssa` which doesn't share a bank
+    /// with any of the already allocated values it's next read with.
+    fn try_find_bank_free_reg(
+        &self,
+        ip: usize,
+        sum: &SSAUseMap,
+        ssa: SSAValue,
+    ) -> Option<u32> {
+        let others = sum.find_bank_use_after(ssa, ip)?;
+
+        let mut used_banks = 0_u32;
+        for other in others {
+            if let Some(reg) = self.try_get_reg(*other) {
+                used_banks |= 1 << (reg % SM50_GPR_BANKS);
+            }
+        }
+        if used_banks == 0 || used_banks == (1 << SM50_GPR_BANKS) - 1 {
+            return None;
+        }
+
+        let mut next_reg = 0;
+        loop {
+            let reg = self.try_find_unused_reg_range(next_reg, 1, 1)?;
+            if used_banks & (1 << (reg % SM50_GPR_BANKS)) == 0 {
+                return Some(reg);
+            }
+            next_reg = reg + 1;
+        }
+    }
 }
 
 struct PinnedRegAllocator<'a> {
@@ -806,6 +913,7 @@ impl PerRegFile<RegAllocator> {
 }
 
 struct AssignRegsBlock {
+    sm: u8,
     ra: PerRegFile<RegAllocator>,
     pcopy_tmp_gprs: u8,
     live_in: Vec<LiveValue>,
@@ -813,8 +921,13 @@ struct AssignRegsBlock {
 }
 
 impl AssignRegsBlock {
-    fn new(num_regs: &PerRegFile<u32>, pcopy_tmp_gprs: u8) -> AssignRegsBlock {
+    fn new(
+        sm: u8,
+        num_regs: &PerRegFile<u32>,
+        pcopy_tmp_gprs: u8,
+    ) -> AssignRegsBlock {
         AssignRegsBlock {
+            sm: sm,
             ra: PerRegFile::new_with(|file| {
                 RegAllocator::new(file, num_regs[file])
             }),
@@ -1095,7 +1208,7 @@ impl AssignRegsBlock {
             }
         }
 
-        let sum = SSAUseMap::for_block(b);
+        let sum = SSAUseMap::for_block(b, self.sm);
 
         let mut instrs = Vec::new();
         let mut srcs_killed = KillSet::new();
@@ -1220,7 +1333,8 @@ impl Shader {
         let mut gpr_limit = max(max_live[RegFile::GPR], 16);
         let mut total_gprs = gpr_limit + u32::from(tmp_gprs);
 
-        let max_gprs = RegFile::GPR.num_regs(self.info.sm);
+        let max_gprs = self.info.max_gprs;
+        let mut spilled_gprs = None;
         if total_gprs > max_gprs {
             // If we're spilling GPRs, we need to reserve 2 GPRs for OpParCopy
             // lowering because it needs to be able lower Mem copies which
@@ -1233,6 +1347,8 @@ impl Shader {
 
             // Re-calculate liveness one last time
             live = SimpleLiveness::for_function(f);
+
+            spilled_gprs = Some(max_live[RegFile::GPR]);
         }
 
         self.info.num_gprs = total_gprs.try_into().unwrap();
@@ -1264,7 +1380,7 @@ impl Shader {
 
             let bl = live.block_live(b_idx);
 
-            let mut arb = AssignRegsBlock::new(&limit, tmp_gprs);
+            let mut arb = AssignRegsBlock::new(self.info.sm, &limit, tmp_gprs);
             arb.first_pass(&mut f.blocks[b_idx], bl, pred_ra);
 
             assert!(blocks.len() == b_idx);
@@ -1277,5 +1393,13 @@ impl Shader {
                 arb.second_pass(&blocks[sb_idx], &mut f.blocks[b_idx]);
             }
         }
+
+        if let Some(max_live) = spilled_gprs {
+            self.warn(format!(
+                "Spilled GPRs to local memory: {} live values but only {} \
+                 GPRs available",
+                max_live, gpr_limit
+            ));
+        }
     }
 }
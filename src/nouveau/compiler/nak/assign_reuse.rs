@@ -0,0 +1,103 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+//! Operand reuse cache flags for SM50
+//!
+//! Maxwell and Pascal have a small operand cache per source slot.  If an
+//! instruction sets the reuse bit for a slot, the register it reads there is
+//! kept around and the next instruction can read it from the cache instead of
+//! the register file if it reads the same register in the same slot.  This
+//! saves register bank bandwidth and avoids bank conflict stalls.
+
+use crate::api::{GetDebugFlags, DEBUG};
+use crate::ir::*;
+
+/// Returns the register read by each hardware source slot or None if we
+/// don't know how `instr` maps its sources to slots.
+///
+/// The SM50 encoder puts sources 0, 1, and 2 in the A, B, and C slots for
+/// these ops as long as every source is a register.  Immediate and cbuf
+/// forms shuffle things around so we don't bother with them.
+fn reg_src_slots(instr: &Instr) -> Option<[Option<RegRef>; 3]> {
+    match &instr.op {
+        Op::FAdd(_)
+        | Op::FFma(_)
+        | Op::FMul(_)
+        | Op::FSetP(_)
+        | Op::IAdd2(_)
+        | Op::ISetP(_) => (),
+        _ => return None,
+    }
+
+    let mut slots = [None; 3];
+    for (i, src) in instr.srcs().iter().enumerate() {
+        match src.src_ref {
+            SrcRef::Zero | SrcRef::True | SrcRef::False => (),
+            SrcRef::Reg(reg) => {
+                if reg.file() == RegFile::GPR && i < slots.len() {
+                    slots[i] = Some(reg);
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(slots)
+}
+
+fn writes_reg(instr: &Instr, reg: RegRef) -> bool {
+    instr.dsts().iter().any(|dst| match dst {
+        Dst::Reg(dst) => {
+            dst.file() == reg.file()
+                && dst.idx_range().start < reg.idx_range().end
+                && reg.idx_range().start < dst.idx_range().end
+        }
+        _ => false,
+    })
+}
+
+fn assign_reuse_block(b: &mut BasicBlock) {
+    for ip in 1..b.instrs.len() {
+        let (prev, next) = b.instrs.split_at_mut(ip);
+        let prev = prev.last_mut().unwrap();
+        let next = &next[0];
+
+        // Only do this for straight-line code.  If the warp yields or the
+        // first instruction may not execute, there's no guarantee the value
+        // is actually in the cache.
+        if !prev.pred.is_true() || prev.deps.yld {
+            continue;
+        }
+
+        let Some(prev_slots) = reg_src_slots(prev) else {
+            continue;
+        };
+        let Some(next_slots) = reg_src_slots(next) else {
+            continue;
+        };
+
+        for (slot, (p, n)) in prev_slots.iter().zip(&next_slots).enumerate() {
+            let (Some(p), Some(n)) = (p, n) else {
+                continue;
+            };
+            if p == n && !writes_reg(prev, *p) {
+                prev.deps.add_reuse(slot.try_into().unwrap());
+            }
+        }
+    }
+}
+
+impl Shader {
+    /// Sets the operand reuse flags on SM50.  This has to run after RA and
+    /// after anything which adds, removes, or re-orders instructions.
+    pub fn assign_reuse(&mut self) {
+        if self.info.sm >= 70 || DEBUG.serial() {
+            return;
+        }
+
+        for f in &mut self.functions {
+            for b in &mut f.blocks {
+                assign_reuse_block(b);
+            }
+        }
+    }
+}
@@ -3,6 +3,8 @@
 
 use crate::ir::*;
 
+use std::collections::HashMap;
+
 pub trait Builder {
     fn push_instr(&mut self, instr: Box<Instr>) -> &mut Instr;
 
@@ -549,6 +551,22 @@ pub trait SSABuilder: Builder {
         dst
     }
 
+    /// Shuffles a 32-bit value between the lanes of each quad.  With
+    /// ShflOp::Bfly, each lane reads from its quad lane index XOR `lane` and,
+    /// with ShflOp::Idx, every lane reads from quad lane `lane`.
+    fn shfl_quad(&mut self, op: ShflOp, x: Src, lane: Src) -> SSARef {
+        let dst = self.alloc_ssa(RegFile::GPR, 1);
+        self.push_op(OpShfl {
+            dst: dst.into(),
+            in_bounds: Dst::None,
+            src: x,
+            lane: lane,
+            c: (0x3_u32 | 0x1c_u32 << 8).into(),
+            op: op,
+        });
+        dst
+    }
+
     fn bmov_to_bar(&mut self, src: Src) -> SSARef {
         assert!(src.src_ref.as_ssa().unwrap().file() == RegFile::GPR);
         let dst = self.alloc_ssa(RegFile::Bar, 1);
@@ -612,6 +630,10 @@ impl Builder for InstrBuilder {
 pub struct SSAInstrBuilder<'a> {
     b: InstrBuilder,
     alloc: &'a mut SSAValueAllocator,
+    /// Immediates already materialized by copy().  Everything we build goes
+    /// into the same block, in order, so an earlier copy dominates any later
+    /// use and can be shared.
+    imm_copies: HashMap<SrcRef, SSAValue>,
 }
 
 impl<'a> SSAInstrBuilder<'a> {
@@ -619,6 +641,7 @@ impl<'a> SSAInstrBuilder<'a> {
         Self {
             b: InstrBuilder::new(sm),
             alloc: alloc,
+            imm_copies: HashMap::new(),
         }
     }
 
@@ -646,6 +669,31 @@ impl<'a> SSABuilder for SSAInstrBuilder<'a> {
     fn alloc_ssa(&mut self, file: RegFile, comps: u8) -> SSARef {
         self.alloc.alloc_vec(file, comps)
     }
+
+    fn copy(&mut self, src: Src) -> SSARef {
+        let is_imm = src.src_mod.is_none()
+            && matches!(
+                src.src_ref,
+                SrcRef::Zero | SrcRef::True | SrcRef::False | SrcRef::Imm32(_)
+            );
+        if is_imm {
+            if let Some(ssa) = self.imm_copies.get(&src.src_ref) {
+                return (*ssa).into();
+            }
+        }
+
+        let file = if src.is_predicate() {
+            RegFile::Pred
+        } else {
+            RegFile::GPR
+        };
+        let dst = self.alloc_ssa(file, 1);
+        self.copy_to(dst.into(), src);
+        if is_imm {
+            self.imm_copies.insert(src.src_ref, dst[0]);
+        }
+        dst
+    }
 }
 
 pub struct PredicatedBuilder<'a, T: Builder> {
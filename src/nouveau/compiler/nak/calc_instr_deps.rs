@@ -49,6 +49,14 @@ impl<T> RegTracker<T> {
         }
     }
 
+    pub fn for_each_mut(&mut self, mut f: impl FnMut(&mut T)) {
+        self.reg.iter_mut().for_each(&mut f);
+        self.ureg.iter_mut().for_each(&mut f);
+        self.pred.iter_mut().for_each(&mut f);
+        self.upred.iter_mut().for_each(&mut f);
+        self.carry.iter_mut().for_each(&mut f);
+    }
+
     pub fn for_each_instr_pred_mut(
         &mut self,
         instr: &Instr,
@@ -286,6 +294,10 @@ impl DepGraph {
         }
     }
 
+    pub fn dep_first_wait(&self, dep: usize) -> Option<(usize, usize)> {
+        self.deps[dep].first_wait
+    }
+
     pub fn get_instr_deps(
         &self,
         block_idx: usize,
@@ -305,30 +317,36 @@ impl DepGraph {
 
 struct BarAlloc {
     num_bars: u8,
-    bar_dep: [usize; 6],
+    /// The deps tracked by each barrier.  Barriers are counters so more than
+    /// one instruction can signal the same barrier and waiting on it waits
+    /// for all of them.
+    bar_deps: [Vec<usize>; 6],
 }
 
 impl BarAlloc {
     pub fn new() -> BarAlloc {
         BarAlloc {
             num_bars: 6,
-            bar_dep: [usize::MAX; 6],
+            bar_deps: Default::default(),
         }
     }
 
     pub fn bar_is_free(&self, bar: u8) -> bool {
         debug_assert!(bar < self.num_bars);
-        self.bar_dep[usize::from(bar)] == usize::MAX
+        self.bar_deps[usize::from(bar)].is_empty()
     }
 
-    pub fn set_bar_dep(&mut self, bar: u8, dep: usize) {
-        debug_assert!(self.bar_is_free(bar));
-        self.bar_dep[usize::from(bar)] = dep;
+    pub fn add_bar_dep(&mut self, bar: u8, dep: usize) {
+        self.bar_deps[usize::from(bar)].push(dep);
     }
 
     pub fn free_bar(&mut self, bar: u8) {
         debug_assert!(!self.bar_is_free(bar));
-        self.bar_dep[usize::from(bar)] = usize::MAX;
+        self.bar_deps[usize::from(bar)].clear();
+    }
+
+    fn bar_oldest_dep(&self, bar: u8) -> usize {
+        *self.bar_deps[usize::from(bar)].iter().min().unwrap()
     }
 
     pub fn try_find_free_bar(&self) -> Option<u8> {
@@ -340,11 +358,46 @@ impl BarAlloc {
         None
     }
 
+    /// Finds a barrier which `dep` can share without making anything wait
+    /// any earlier than it already does.  That's any barrier whose deps are
+    /// all first waited on no earlier than `dep` is, because then the wait
+    /// for `dep` is the first wait on the barrier.  Of those, we take the one
+    /// with the oldest dep as it's the most likely to be done by then.
+    pub fn try_find_shared_bar(
+        &self,
+        deps: &DepGraph,
+        dep: usize,
+        exclude: Option<u8>,
+    ) -> Option<u8> {
+        let dep_wait = deps.dep_first_wait(dep)?;
+
+        let mut best: Option<u8> = None;
+        for bar in 0..self.num_bars {
+            if self.bar_is_free(bar) || Some(bar) == exclude {
+                continue;
+            }
+
+            let waited_later = self.bar_deps[usize::from(bar)]
+                .iter()
+                .all(|d| deps.dep_first_wait(*d) >= Some(dep_wait));
+            if !waited_later {
+                continue;
+            }
+
+            if best.map_or(true, |b| {
+                self.bar_oldest_dep(bar) < self.bar_oldest_dep(b)
+            }) {
+                best = Some(bar);
+            }
+        }
+        best
+    }
+
     pub fn free_some_bar(&mut self) -> u8 {
         // Get the oldest by looking for the one with the smallest dep
         let mut bar = 0;
         for b in 1..self.num_bars {
-            if self.bar_dep[usize::from(b)] < self.bar_dep[usize::from(bar)] {
+            if self.bar_oldest_dep(b) < self.bar_oldest_dep(bar) {
                 bar = b;
             }
         }
@@ -354,7 +407,7 @@ impl BarAlloc {
 
     pub fn get_bar_for_dep(&self, dep: usize) -> Option<u8> {
         for bar in 0..self.num_bars {
-            if self.bar_dep[usize::from(bar)] == dep {
+            if self.bar_deps[usize::from(bar)].contains(&dep) {
                 return Some(bar);
             }
         }
@@ -441,37 +494,106 @@ fn assign_barriers(f: &mut Function, sm: u8) {
                 continue;
             }
 
+            // If we run out of barriers, we first try to share one with deps
+            // which get waited on no earlier than ours.  Only if that fails
+            // do we stall here to free up the oldest one.
             let (rd_dep, wr_dep) = deps.get_instr_deps(bi, ip);
             if deps.dep_is_waited_after(rd_dep, bi, ip) {
-                let rd_bar = bars.try_find_free_bar().unwrap_or_else(|| {
-                    let bar = bars.free_some_bar();
-                    instr.deps.add_wt_bar(bar);
-                    bar
-                });
-                bars.set_bar_dep(rd_bar, rd_dep);
+                let rd_bar = bars
+                    .try_find_free_bar()
+                    .or_else(|| bars.try_find_shared_bar(&deps, rd_dep, None))
+                    .unwrap_or_else(|| {
+                        let bar = bars.free_some_bar();
+                        instr.deps.add_wt_bar(bar);
+                        bar
+                    });
+                bars.add_bar_dep(rd_bar, rd_dep);
                 instr.deps.set_rd_bar(rd_bar);
             }
             if deps.dep_is_waited_after(wr_dep, bi, ip) {
-                let wr_bar = bars.try_find_free_bar().unwrap_or_else(|| {
-                    let bar = bars.free_some_bar();
-                    instr.deps.add_wt_bar(bar);
-                    bar
-                });
-                bars.set_bar_dep(wr_bar, wr_dep);
+                // Keep the read and write barriers of an instruction separate
+                let rd_bar = instr.deps.rd_bar();
+                let wr_bar = bars
+                    .try_find_free_bar()
+                    .or_else(|| bars.try_find_shared_bar(&deps, wr_dep, rd_bar))
+                    .unwrap_or_else(|| {
+                        let bar = bars.free_some_bar();
+                        instr.deps.add_wt_bar(bar);
+                        bar
+                    });
+                bars.add_bar_dep(wr_bar, wr_dep);
                 instr.deps.set_wr_bar(wr_bar);
             }
         }
     }
 }
 
+fn is_dual_issue_alu(instr: &Instr, sm: u8) -> bool {
+    match &instr.op {
+        Op::FAdd(_)
+        | Op::FFma(_)
+        | Op::FMul(_)
+        | Op::FSetP(_)
+        | Op::IAdd2(_)
+        | Op::ISetP(_)
+        | Op::Lop2(_)
+        | Op::Mov(_)
+        | Op::Sel(_) => {
+            debug_assert!(instr.has_fixed_latency(sm));
+            debug_assert!(instr.get_exec_latency(sm) == 1);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn is_dual_issue_mem(instr: &Instr) -> bool {
+    matches!(&instr.op, Op::Ld(_) | Op::Ldc(_) | Op::St(_))
+}
+
+/// Returns true if `instr` and the instruction right after it, `next`, can
+/// be issued in the same cycle.  SM50 can dual-issue an ALU instruction with
+/// a memory instruction as long as neither has to wait on anything.  Register
+/// dependencies are handled by the caller.
+fn can_dual_issue(instr: &Instr, next: &Instr, sm: u8) -> bool {
+    if sm >= 70 {
+        return false;
+    }
+
+    // Each instruction can only be paired once
+    if next.deps.delay == 0 {
+        return false;
+    }
+
+    if instr.deps.yld || next.deps.yld || next.deps.wt_bar_mask != 0 {
+        return false;
+    }
+
+    (is_dual_issue_alu(instr, sm) && is_dual_issue_mem(next))
+        || (is_dual_issue_mem(instr) && is_dual_issue_alu(next, sm))
+}
+
 fn calc_delays(f: &mut Function, sm: u8) {
     for b in f.blocks.iter_mut().rev() {
         let mut cycle = 0_u32;
+        // We don't know what the successors read so treat every register as
+        // read at the end of the block.  This ensures that all fixed-latency
+        // writes have landed by the time we leave the block, regardless of
+        // which edge we take.
         let mut ready = RegTracker::new(0_u32);
         let mut bars_ready = [0_u32; 6];
-        for instr in b.instrs.iter_mut().rev() {
-            // TODO: co-issue
-            let mut min_start = cycle + instr.get_exec_latency(sm);
+        for ip in (0..b.instrs.len()).rev() {
+            let dual_issue = ip + 1 < b.instrs.len()
+                && can_dual_issue(&b.instrs[ip], &b.instrs[ip + 1], sm);
+            let instr = &mut b.instrs[ip];
+
+            // If we can dual-issue with the next instruction, the only thing
+            // which can hold it back is a dependency, which we check below.
+            let mut min_start = if dual_issue {
+                cycle
+            } else {
+                cycle + instr.get_exec_latency(sm)
+            };
             if let Some(bar) = instr.deps.rd_bar() {
                 min_start = max(min_start, bars_ready[usize::from(bar)] + 2);
             }
@@ -490,10 +612,14 @@ fn calc_delays(f: &mut Function, sm: u8) {
             }
 
             let delay = min_start - cycle;
-            let delay = delay
-                .clamp(MIN_INSTR_DELAY.into(), MAX_INSTR_DELAY.into())
-                .try_into()
-                .unwrap();
+            let delay = if dual_issue && delay == 0 {
+                0
+            } else {
+                delay
+                    .clamp(MIN_INSTR_DELAY.into(), MAX_INSTR_DELAY.into())
+                    .try_into()
+                    .unwrap()
+            };
             instr.deps.set_delay(delay);
 
             ready.for_each_instr_pred_mut(instr, |c| *c = min_start);
@@ -523,34 +649,182 @@ fn calc_delays(f: &mut Function, sm: u8) {
     });
 }
 
+/// Bar mask for a variable-latency access which no barrier tracks.  No wait
+/// can ever retire it so any later conflicting access is an error.
+const UNTRACKED_BAR: u8 = 1 << 7;
+
+/// What the dependency verifier knows about a single register
+#[derive(Clone)]
+struct RegDepState {
+    /// Cycle at which the last fixed-latency write lands
+    ready: u32,
+    /// Barriers, a wait on any one of which retires the last variable-latency
+    /// write or zero if there is none in flight
+    wr_bars: u8,
+    /// Same as wr_bars but for each variable-latency read in flight
+    rd_bars: Vec<u8>,
+}
+
+impl RegDepState {
+    fn new() -> Self {
+        RegDepState {
+            ready: 0,
+            wr_bars: 0,
+            rd_bars: Vec::new(),
+        }
+    }
+
+    fn wait(&mut self, wt_bar_mask: u8) {
+        if self.wr_bars & wt_bar_mask != 0 {
+            self.wr_bars = 0;
+        }
+        self.rd_bars.retain(|bars| bars & wt_bar_mask == 0);
+    }
+}
+
+/// Walks the final instruction stream, modeling the delays and scoreboards in
+/// InstrDeps, and asserts that nothing touches a register before whatever it
+/// depends on has retired.
+///
+/// Like assign_barriers(), this assumes blocks execute in order and, because
+/// branches wait on every outstanding barrier, any other control-flow edge is
+/// no worse than falling through.  Fixed-latency writes must land before the
+/// end of their block so that the cycle count can simply carry across blocks
+/// no matter which edge is taken.
+fn verify_deps(f: &Function, sm: u8) {
+    let mut regs = RegTracker::new_with(&RegDepState::new);
+    let mut cycle = 0_u32;
+
+    for b in &f.blocks {
+        let mut bar_set = [None; 6];
+
+        for instr in &b.instrs {
+            let delay = instr.deps.delay;
+            let wt_bar_mask = instr.deps.wt_bar_mask;
+
+            assert!(delay > 0 || sm < 70, "Invalid zero delay: {instr}");
+            let exec_latency = instr.get_exec_latency(sm);
+            assert!(
+                delay == 0
+                    || u32::from(delay)
+                        >= exec_latency.min(MAX_INSTR_DELAY.into()),
+                "Delay is shorter than the exec latency: {instr}"
+            );
+
+            for (bar, set) in bar_set.iter().enumerate() {
+                if wt_bar_mask & (1 << bar) == 0 {
+                    continue;
+                }
+                if let Some(set) = set {
+                    assert!(
+                        cycle >= set + 2,
+                        "Barrier {bar} waited too soon after being set: {instr}"
+                    );
+                }
+            }
+            regs.for_each_mut(|r| r.wait(wt_bar_mask));
+
+            let check_read = |r: &mut RegDepState| {
+                assert!(
+                    r.ready <= cycle,
+                    "Register read before its write landed: {instr}"
+                );
+                assert!(
+                    r.wr_bars == 0,
+                    "Register read before its write retired: {instr}"
+                );
+            };
+            regs.for_each_instr_pred_mut(instr, check_read);
+            regs.for_each_instr_src_mut(instr, check_read);
+            regs.for_each_instr_dst_mut(instr, |r| {
+                assert!(
+                    r.wr_bars == 0,
+                    "Register written before its last write retired: {instr}"
+                );
+                assert!(
+                    r.rd_bars.is_empty(),
+                    "Register written before its last read retired: {instr}"
+                );
+            });
+
+            if instr.has_fixed_latency(sm) {
+                for (idx, dst) in instr.dsts().iter().enumerate() {
+                    if let Dst::Reg(reg) = dst {
+                        let ready = cycle + instr.get_dst_latency(sm, idx);
+                        for r in &mut regs[*reg] {
+                            r.ready = ready;
+                        }
+                    }
+                }
+            } else {
+                // Waiting on the write barrier also waits on the reads
+                let wr_bars =
+                    instr.deps.wr_bar().map_or(UNTRACKED_BAR, |bar| 1 << bar);
+                let rd_bars =
+                    wr_bars | instr.deps.rd_bar().map_or(0, |bar| 1 << bar);
+                regs.for_each_instr_src_mut(instr, |r| {
+                    r.rd_bars.push(rd_bars);
+                });
+                regs.for_each_instr_dst_mut(instr, |r| {
+                    r.wr_bars = wr_bars;
+                });
+            }
+
+            if let Some(bar) = instr.deps.rd_bar() {
+                bar_set[usize::from(bar)] = Some(cycle);
+            }
+            if let Some(bar) = instr.deps.wr_bar() {
+                bar_set[usize::from(bar)] = Some(cycle);
+            }
+
+            cycle += u32::from(delay);
+        }
+
+        regs.for_each_mut(|r| {
+            assert!(
+                r.ready <= cycle,
+                "Fixed-latency write still in flight at the end of block {}",
+                b.label
+            );
+        });
+    }
+}
+
 impl Shader {
+    /// Makes every instruction wait for the one before it to completely
+    /// retire.  This is terribly slow but it means the shader no longer
+    /// depends on our latency tables or barrier tracking being correct, which
+    /// makes it a handy way to tell scheduling bugs apart from codegen bugs.
     pub fn assign_deps_serial(&mut self) {
         for f in &mut self.functions {
-            for b in &mut f.blocks.iter_mut().rev() {
-                let mut wt = 0_u8;
+            for b in &mut f.blocks {
                 for instr in &mut b.instrs {
+                    instr.deps.set_delay(MAX_INSTR_DELAY);
+                    instr.deps.add_wt_bar_mask(0x3f);
                     if instr.is_barrier() {
                         instr.deps.set_yield(true);
-                    } else if instr.is_branch() {
-                        instr.deps.add_wt_bar_mask(0x3f);
-                    } else {
-                        instr.deps.add_wt_bar_mask(wt);
-                        if instr.dsts().len() > 0 {
-                            instr.deps.set_wr_bar(0);
-                            wt |= 1 << 0;
-                        }
-                        if !instr.pred.pred_ref.is_none()
-                            || instr.srcs().len() > 0
-                        {
-                            instr.deps.set_rd_bar(1);
-                            wt |= 1 << 1;
-                        }
+                    }
+                    if instr.dsts().len() > 0 {
+                        instr.deps.set_wr_bar(0);
+                    }
+                    if !instr.pred.pred_ref.is_none() || instr.srcs().len() > 0
+                    {
+                        instr.deps.set_rd_bar(1);
                     }
                 }
             }
         }
     }
 
+    /// Checks the delays and barriers assigned by calc_instr_deps() against
+    /// a model of the hardware scoreboard and panics if any instruction may
+    /// touch a register before its producer or consumer has retired.
+    pub fn verify_instr_deps(&self) {
+        for f in &self.functions {
+            verify_deps(f, self.info.sm);
+        }
+    }
+
     pub fn calc_instr_deps(&mut self) {
         if DEBUG.serial() {
             self.assign_deps_serial();
@@ -562,3 +836,149 @@ impl Shader {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir_parse::parse_function;
+
+    /// Builds a DepGraph with one write dep per entry in `waits`, each of
+    /// which is first waited on at the given instruction in block 0
+    fn deps_waited_at(waits: &[Option<usize>]) -> (DepGraph, Vec<usize>) {
+        let mut deps = DepGraph::new();
+        let mut wr_deps = Vec::new();
+        for ip in 0..waits.len() {
+            let (_, wr) = deps.add_instr(0, ip);
+            deps.add_signal(wr);
+            wr_deps.push(wr);
+        }
+        for (dep, wait) in wr_deps.iter().zip(waits) {
+            if let Some(wait) = wait {
+                deps.add_waits(0, *wait, vec![*dep]);
+            }
+        }
+        (deps, wr_deps)
+    }
+
+    #[test]
+    fn shared_bar_needs_a_wait() {
+        let (deps, d) = deps_waited_at(&[Some(10), None]);
+        let mut bars = BarAlloc::new();
+        bars.add_bar_dep(0, d[0]);
+
+        // Nothing waits on d[1] so there's nothing to share
+        assert_eq!(bars.try_find_shared_bar(&deps, d[1], None), None);
+    }
+
+    #[test]
+    fn shared_bar_waited_no_earlier() {
+        let (deps, d) = deps_waited_at(&[Some(10), Some(11), Some(12)]);
+        let mut bars = BarAlloc::new();
+        bars.add_bar_dep(0, d[0]);
+        bars.add_bar_dep(1, d[2]);
+
+        // Sharing bar 0 would make the wait at 10 also wait on d[1]
+        assert_eq!(bars.try_find_shared_bar(&deps, d[1], None), Some(1));
+
+        // Waiting at the same instruction is fine
+        assert_eq!(bars.try_find_shared_bar(&deps, d[2], None), Some(1));
+
+        // Everything is waited on later than d[0]
+        assert_eq!(bars.try_find_shared_bar(&deps, d[0], None), Some(0));
+    }
+
+    #[test]
+    fn shared_bar_exclude() {
+        let (deps, d) = deps_waited_at(&[Some(10), Some(11)]);
+        let mut bars = BarAlloc::new();
+        bars.add_bar_dep(2, d[1]);
+
+        assert_eq!(bars.try_find_shared_bar(&deps, d[0], None), Some(2));
+        assert_eq!(bars.try_find_shared_bar(&deps, d[0], Some(2)), None);
+    }
+
+    #[test]
+    fn shared_bar_prefers_oldest() {
+        let (deps, d) =
+            deps_waited_at(&[Some(10), Some(20), Some(21), Some(22)]);
+        let mut bars = BarAlloc::new();
+        bars.add_bar_dep(3, d[3]);
+        bars.add_bar_dep(4, d[1]);
+        bars.add_bar_dep(5, d[2]);
+
+        assert_eq!(bars.try_find_shared_bar(&deps, d[0], None), Some(4));
+    }
+
+    fn calc_and_verify_deps(text: &str, sm: u8) {
+        let mut f = parse_function(text).unwrap();
+        assign_barriers(&mut f, sm);
+        calc_delays(&mut f, sm);
+        verify_deps(&f, sm);
+    }
+
+    #[test]
+    fn verify_variable_latency_across_blocks() {
+        calc_and_verify_deps(
+            "block 0 L0 [] -> {
+                r0 = s2r sr[0x21]
+            } -> [1]
+            block 1 L1 [0] -> {
+                r3 = iadd3 r0 r0 rZ
+                r0 = iadd3 r1 r1 rZ
+                exit
+            } -> []",
+            70,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Register read before its write landed")]
+    fn verify_short_delay() {
+        let mut f = parse_function(
+            "block 0 L0 [] -> {
+                r0 = iadd3 r1 r2 rZ
+                r3 = iadd3 r0 r0 rZ
+                exit
+            } -> []",
+        )
+        .unwrap();
+        for instr in &mut f.blocks[0].instrs {
+            instr.deps.set_delay(1);
+        }
+        verify_deps(&f, 70);
+    }
+
+    #[test]
+    #[should_panic(expected = "still in flight at the end of block")]
+    fn verify_write_in_flight_at_block_end() {
+        let mut f = parse_function(
+            "block 0 L0 [] -> {
+                r0 = iadd3 r1 r2 rZ
+            } -> [1]
+            block 1 L1 [0] -> {
+                exit
+            } -> []",
+        )
+        .unwrap();
+        f.blocks[0].instrs[0].deps.set_delay(1);
+        f.blocks[1].instrs[0].deps.set_delay(1);
+        verify_deps(&f, 70);
+    }
+
+    #[test]
+    #[should_panic(expected = "Register read before its write retired")]
+    fn verify_missing_barrier() {
+        let mut f = parse_function(
+            "block 0 L0 [] -> {
+                r0 = s2r sr[0x21]
+                r3 = iadd3 r0 r0 rZ
+                exit
+            } -> []",
+        )
+        .unwrap();
+        for instr in &mut f.blocks[0].instrs {
+            instr.deps.set_delay(MAX_INSTR_DELAY);
+        }
+        verify_deps(&f, 70);
+    }
+}
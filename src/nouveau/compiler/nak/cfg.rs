@@ -177,6 +177,15 @@ fn calc_dominance<N>(nodes: &mut Vec<CFGNode<N>>) {
     debug_assert!(count == nodes.len() * 2);
 }
 
+fn node_dominates<N>(
+    nodes: &Vec<CFGNode<N>>,
+    parent: usize,
+    child: usize,
+) -> bool {
+    nodes[child].dom_pre_idx >= nodes[parent].dom_pre_idx
+        && nodes[child].dom_post_idx <= nodes[parent].dom_post_idx
+}
+
 fn loop_detect_dfs<N>(
     nodes: &Vec<CFGNode<N>>,
     id: usize,
@@ -206,18 +215,40 @@ fn detect_loops<N>(nodes: &mut Vec<CFGNode<N>>) -> bool {
     let mut loops = BitSet::new();
     loop_detect_dfs(nodes, 0, &mut dfs_pre, &mut dfs_post, &mut loops);
 
+    for n in nodes.iter_mut() {
+        n.lph = usize::MAX;
+    }
+
+    // A block is in a loop if it can reach one of the back-edges without
+    // going through the header.  Being dominated by the header isn't enough:
+    // the block after a loop is only reachable through breaks so it's
+    // dominated by a block inside the loop.
+    //
+    // Headers come before any loop nested inside them so walking them in
+    // order lets inner loops claim their blocks after outer loops do.
     let mut has_loop = false;
-    nodes[0].lph = usize::MAX;
-    for i in 1..nodes.len() {
-        if loops.get(i) {
-            // This is a loop header
-            nodes[i].lph = i;
-            has_loop = true;
-        } else {
-            // Otherwise, we have the same loop header as our dominator
-            let dom = nodes[i].dom;
-            let dom_lph = nodes[dom].lph;
-            nodes[i].lph = dom_lph;
+    for h in 1..nodes.len() {
+        if !loops.get(h) {
+            continue;
+        }
+        has_loop = true;
+
+        let mut in_loop = BitSet::new();
+        in_loop.insert(h);
+        nodes[h].lph = h;
+
+        let mut stack: Vec<usize> = nodes[h]
+            .pred
+            .iter()
+            .cloned()
+            .filter(|p| node_dominates(nodes, h, *p))
+            .collect();
+        while let Some(b) = stack.pop() {
+            if !in_loop.insert(b) {
+                continue;
+            }
+            nodes[b].lph = h;
+            stack.extend(nodes[b].pred.iter().cloned());
         }
     }
 
@@ -299,8 +330,7 @@ impl<N> CFG<N> {
         // If a block is unreachable, then dom_pre_idx == usize::MAX and
         // dom_post_idx == 0.  This allows us to trivially handle unreachable
         // blocks here with zero extra work.
-        self.dom_dfs_pre_index(child) >= self.dom_dfs_pre_index(parent)
-            && self.dom_dfs_post_index(child) <= self.dom_dfs_post_index(parent)
+        node_dominates(&self.nodes, parent, child)
     }
 
     pub fn has_loop(&self) -> bool {
@@ -408,3 +438,114 @@ impl<K, N> Default for CFGBuilder<K, N> {
         CFGBuilder::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a CFG the same way from_nir does, with the NIR block index as
+    /// both the key and the node, and returns it along with a map from NIR
+    /// block index to CFG index.
+    fn build_cfg(
+        num_blocks: usize,
+        edges: &[(usize, usize)],
+    ) -> (CFG<usize>, Vec<usize>) {
+        let mut builder = CFGBuilder::new();
+        for b in 0..num_blocks {
+            builder.add_node(b, b);
+        }
+        for (p, s) in edges {
+            builder.add_edge(*p, *s);
+        }
+        let cfg = builder.as_cfg();
+
+        let mut map = vec![usize::MAX; num_blocks];
+        for i in 0..cfg.len() {
+            map[cfg[i]] = i;
+        }
+        (cfg, map)
+    }
+
+    fn loop_header(cfg: &CFG<usize>, idx: usize) -> Option<usize> {
+        cfg.loop_header_index(idx).map(|h| cfg[h])
+    }
+
+    #[test]
+    fn single_loop() {
+        // loop {
+        //     if (c) { break; }
+        //     x++;
+        // }
+        let (cfg, map) =
+            build_cfg(5, &[(0, 1), (1, 2), (1, 3), (2, 4), (3, 1)]);
+
+        assert!(cfg.has_loop());
+        assert!(cfg.is_loop_header(map[1]));
+        assert!(cfg.pred_indices(map[1]).contains(&map[3]));
+
+        assert_eq!(loop_header(&cfg, map[0]), None);
+        assert_eq!(loop_header(&cfg, map[1]), Some(1));
+        assert_eq!(loop_header(&cfg, map[3]), Some(1));
+
+        // Neither the break nor the block after the loop run more than once
+        assert_eq!(loop_header(&cfg, map[2]), None);
+        assert_eq!(loop_header(&cfg, map[4]), None);
+    }
+
+    #[test]
+    fn nested_loops() {
+        // loop {
+        //     loop {
+        //         if (c0) { break; }
+        //         x++;
+        //     }
+        //     if (c1) { continue; } else { break; }
+        // }
+        let (cfg, map) = build_cfg(
+            10,
+            &[
+                (0, 1),
+                (1, 2),
+                (2, 3),
+                (2, 4),
+                (3, 6),
+                (4, 5),
+                (5, 2),
+                (6, 7),
+                (6, 8),
+                (7, 1),
+                (8, 9),
+            ],
+        );
+
+        assert!(cfg.has_loop());
+        let headers: Vec<usize> = (0..cfg.len())
+            .filter(|i| cfg.is_loop_header(*i))
+            .map(|i| cfg[i])
+            .collect();
+        assert_eq!(headers, [1, 2]);
+
+        // The continue and the end of the inner loop body are back-edges
+        assert!(cfg.pred_indices(map[1]).contains(&map[7]));
+        assert!(cfg.pred_indices(map[2]).contains(&map[5]));
+        assert!(cfg.dominates(map[1], map[2]));
+
+        let expected = [
+            None,
+            Some(1),
+            Some(2),
+            // Breaking out of the inner loop lands in the outer loop
+            Some(1),
+            Some(2),
+            Some(2),
+            Some(1),
+            Some(1),
+            // Breaking out of the outer loop leaves every loop
+            None,
+            None,
+        ];
+        for (b, lph) in expected.iter().enumerate() {
+            assert_eq!(loop_header(&cfg, map[b]), *lph, "block {b}");
+        }
+    }
+}
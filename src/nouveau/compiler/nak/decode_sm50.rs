@@ -0,0 +1,1217 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+//! A disassembler for SM50 (Maxwell and Pascal)
+//!
+//! This only knows about the instructions NAK emits and only decodes the
+//! operands shared by most of them: destination, sources, predicates, and
+//! scheduling information.  Anything else is printed as raw bits.  It's good
+//! enough to sanity-check the encoder without reaching for nvdisasm.
+
+use bitview::*;
+
+use std::fmt::Write;
+use std::ops::Range;
+
+#[derive(Clone, Copy)]
+enum SrcB {
+    Reg,
+    Imm20I,
+    Imm20F,
+    Imm32,
+    CBuf,
+}
+
+#[derive(Clone, Copy)]
+enum Form {
+    /// dst, srcs[1]
+    Alu1(SrcB),
+    /// dst, srcs[0], srcs[1]
+    Alu2(SrcB),
+    /// dst, srcs[0], srcs[1], srcs[2]
+    Alu3(SrcB),
+    /// pred dst, pred dst1, srcs[0], srcs[1], accum
+    SetP(SrcB),
+    /// dst, srcs[0], srcs[1], cond
+    Sel(SrcB),
+    /// dst, srcs[0]
+    Unary,
+    /// dst or data, [addr + offset]
+    Mem,
+    S2R,
+    Bra,
+    NoOperands,
+    Other,
+}
+
+struct OpDesc {
+    name: &'static str,
+    opcode: u16,
+    mask: u16,
+    form: Form,
+}
+
+/// Opcodes live in bits 48..64 but many instructions stash modifiers in the
+/// low bits of that range and, for 20-bit immediates, the sign in bit 56.  The
+/// mask says which bits are actually opcode.
+const SM50_OPS: &[OpDesc] = &[
+    OpDesc {
+        name: "fadd",
+        opcode: 0x5c58,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::Reg),
+    },
+    OpDesc {
+        name: "fadd",
+        opcode: 0x3858,
+        mask: 0xfef8,
+        form: Form::Alu2(SrcB::Imm20F),
+    },
+    OpDesc {
+        name: "fadd",
+        opcode: 0x4c58,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "fmnmx",
+        opcode: 0x5c60,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::Reg),
+    },
+    OpDesc {
+        name: "fmnmx",
+        opcode: 0x3860,
+        mask: 0xfef8,
+        form: Form::Alu2(SrcB::Imm20F),
+    },
+    OpDesc {
+        name: "fmnmx",
+        opcode: 0x4c60,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "fmul",
+        opcode: 0x5c68,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::Reg),
+    },
+    OpDesc {
+        name: "fmul",
+        opcode: 0x3868,
+        mask: 0xfef8,
+        form: Form::Alu2(SrcB::Imm20F),
+    },
+    OpDesc {
+        name: "fmul",
+        opcode: 0x4c68,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "ffma",
+        opcode: 0x5980,
+        mask: 0xff80,
+        form: Form::Alu3(SrcB::Reg),
+    },
+    OpDesc {
+        name: "ffma",
+        opcode: 0x3280,
+        mask: 0xfe80,
+        form: Form::Alu3(SrcB::Imm20F),
+    },
+    OpDesc {
+        name: "ffma",
+        opcode: 0x4980,
+        mask: 0xff80,
+        form: Form::Alu3(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "fset",
+        opcode: 0x5800,
+        mask: 0xff00,
+        form: Form::Alu2(SrcB::Reg),
+    },
+    OpDesc {
+        name: "fset",
+        opcode: 0x3000,
+        mask: 0xfe00,
+        form: Form::Alu2(SrcB::Imm20F),
+    },
+    OpDesc {
+        name: "fset",
+        opcode: 0x4800,
+        mask: 0xff00,
+        form: Form::Alu2(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "fsetp",
+        opcode: 0x5bb0,
+        mask: 0xfff0,
+        form: Form::SetP(SrcB::Reg),
+    },
+    OpDesc {
+        name: "fsetp",
+        opcode: 0x36b0,
+        mask: 0xfef0,
+        form: Form::SetP(SrcB::Imm20F),
+    },
+    OpDesc {
+        name: "fsetp",
+        opcode: 0x4bb0,
+        mask: 0xfff0,
+        form: Form::SetP(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "dadd",
+        opcode: 0x5c70,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::Reg),
+    },
+    OpDesc {
+        name: "dadd",
+        opcode: 0x3870,
+        mask: 0xfef8,
+        form: Form::Alu2(SrcB::Imm20F),
+    },
+    OpDesc {
+        name: "dadd",
+        opcode: 0x4c70,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "dfma",
+        opcode: 0x5b70,
+        mask: 0xfff0,
+        form: Form::Alu3(SrcB::Reg),
+    },
+    OpDesc {
+        name: "dfma",
+        opcode: 0x3670,
+        mask: 0xfef0,
+        form: Form::Alu3(SrcB::Imm20F),
+    },
+    OpDesc {
+        name: "dfma",
+        opcode: 0x4b70,
+        mask: 0xfff0,
+        form: Form::Alu3(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "dmnmx",
+        opcode: 0x5c50,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::Reg),
+    },
+    OpDesc {
+        name: "dmnmx",
+        opcode: 0x3850,
+        mask: 0xfef8,
+        form: Form::Alu2(SrcB::Imm20F),
+    },
+    OpDesc {
+        name: "dmnmx",
+        opcode: 0x4c50,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "dmul",
+        opcode: 0x5c80,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::Reg),
+    },
+    OpDesc {
+        name: "dmul",
+        opcode: 0x3880,
+        mask: 0xfef8,
+        form: Form::Alu2(SrcB::Imm20F),
+    },
+    OpDesc {
+        name: "dmul",
+        opcode: 0x4c80,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "dsetp",
+        opcode: 0x5b80,
+        mask: 0xfff0,
+        form: Form::SetP(SrcB::Reg),
+    },
+    OpDesc {
+        name: "dsetp",
+        opcode: 0x3680,
+        mask: 0xfef0,
+        form: Form::SetP(SrcB::Imm20F),
+    },
+    OpDesc {
+        name: "dsetp",
+        opcode: 0x4b80,
+        mask: 0xfff0,
+        form: Form::SetP(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "f2f",
+        opcode: 0x5ca8,
+        mask: 0xfff8,
+        form: Form::Alu1(SrcB::Reg),
+    },
+    OpDesc {
+        name: "f2f",
+        opcode: 0x38a8,
+        mask: 0xfef8,
+        form: Form::Alu1(SrcB::Imm20I),
+    },
+    OpDesc {
+        name: "f2f",
+        opcode: 0x4ca8,
+        mask: 0xfff8,
+        form: Form::Alu1(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "f2i",
+        opcode: 0x5cb0,
+        mask: 0xfff8,
+        form: Form::Alu1(SrcB::Reg),
+    },
+    OpDesc {
+        name: "f2i",
+        opcode: 0x38b0,
+        mask: 0xfef8,
+        form: Form::Alu1(SrcB::Imm20F),
+    },
+    OpDesc {
+        name: "f2i",
+        opcode: 0x4cb0,
+        mask: 0xfff8,
+        form: Form::Alu1(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "i2f",
+        opcode: 0x5cb8,
+        mask: 0xfff8,
+        form: Form::Alu1(SrcB::Reg),
+    },
+    OpDesc {
+        name: "i2f",
+        opcode: 0x38b8,
+        mask: 0xfef8,
+        form: Form::Alu1(SrcB::Imm20I),
+    },
+    OpDesc {
+        name: "i2f",
+        opcode: 0x4cb8,
+        mask: 0xfff8,
+        form: Form::Alu1(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "i2i",
+        opcode: 0x5ce0,
+        mask: 0xfff8,
+        form: Form::Alu1(SrcB::Reg),
+    },
+    OpDesc {
+        name: "i2i",
+        opcode: 0x38e0,
+        mask: 0xfef8,
+        form: Form::Alu1(SrcB::Imm20I),
+    },
+    OpDesc {
+        name: "i2i",
+        opcode: 0x4ce0,
+        mask: 0xfff8,
+        form: Form::Alu1(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "flo",
+        opcode: 0x5c30,
+        mask: 0xfff8,
+        form: Form::Alu1(SrcB::Reg),
+    },
+    OpDesc {
+        name: "flo",
+        opcode: 0x3830,
+        mask: 0xfef8,
+        form: Form::Alu1(SrcB::Imm20I),
+    },
+    OpDesc {
+        name: "flo",
+        opcode: 0x4c30,
+        mask: 0xfff8,
+        form: Form::Alu1(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "iadd",
+        opcode: 0x5c10,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::Reg),
+    },
+    OpDesc {
+        name: "iadd",
+        opcode: 0x3810,
+        mask: 0xfef8,
+        form: Form::Alu2(SrcB::Imm20I),
+    },
+    OpDesc {
+        name: "iadd",
+        opcode: 0x4c10,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "imad",
+        opcode: 0x5a00,
+        mask: 0xff00,
+        form: Form::Alu3(SrcB::Reg),
+    },
+    OpDesc {
+        name: "imad",
+        opcode: 0x3400,
+        mask: 0xfe00,
+        form: Form::Alu3(SrcB::Imm20I),
+    },
+    OpDesc {
+        name: "imad",
+        opcode: 0x4a00,
+        mask: 0xff00,
+        form: Form::Alu3(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "imnmx",
+        opcode: 0x5c20,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::Reg),
+    },
+    OpDesc {
+        name: "imnmx",
+        opcode: 0x3820,
+        mask: 0xfef8,
+        form: Form::Alu2(SrcB::Imm20I),
+    },
+    OpDesc {
+        name: "imnmx",
+        opcode: 0x4c20,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "imul",
+        opcode: 0x5c38,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::Reg),
+    },
+    OpDesc {
+        name: "imul",
+        opcode: 0x3838,
+        mask: 0xfef8,
+        form: Form::Alu2(SrcB::Imm20I),
+    },
+    OpDesc {
+        name: "imul",
+        opcode: 0x4c38,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "isetp",
+        opcode: 0x5b60,
+        mask: 0xfff0,
+        form: Form::SetP(SrcB::Reg),
+    },
+    OpDesc {
+        name: "isetp",
+        opcode: 0x3660,
+        mask: 0xfef0,
+        form: Form::SetP(SrcB::Imm20I),
+    },
+    OpDesc {
+        name: "isetp",
+        opcode: 0x4b60,
+        mask: 0xfff0,
+        form: Form::SetP(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "lop",
+        opcode: 0x5c40,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::Reg),
+    },
+    OpDesc {
+        name: "lop",
+        opcode: 0x3840,
+        mask: 0xfef8,
+        form: Form::Alu2(SrcB::Imm20I),
+    },
+    OpDesc {
+        name: "lop",
+        opcode: 0x4c40,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "mov",
+        opcode: 0x5c98,
+        mask: 0xfff8,
+        form: Form::Alu1(SrcB::Reg),
+    },
+    OpDesc {
+        name: "mov",
+        opcode: 0x4c98,
+        mask: 0xfff8,
+        form: Form::Alu1(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "popc",
+        opcode: 0x5c08,
+        mask: 0xfff8,
+        form: Form::Alu1(SrcB::Reg),
+    },
+    OpDesc {
+        name: "popc",
+        opcode: 0x3808,
+        mask: 0xfef8,
+        form: Form::Alu1(SrcB::Imm20I),
+    },
+    OpDesc {
+        name: "popc",
+        opcode: 0x4c08,
+        mask: 0xfff8,
+        form: Form::Alu1(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "prmt",
+        opcode: 0x5bc0,
+        mask: 0xfff0,
+        form: Form::Alu3(SrcB::Reg),
+    },
+    OpDesc {
+        name: "prmt",
+        opcode: 0x36c0,
+        mask: 0xfef0,
+        form: Form::Alu3(SrcB::Imm20I),
+    },
+    OpDesc {
+        name: "prmt",
+        opcode: 0x4bc0,
+        mask: 0xfff0,
+        form: Form::Alu3(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "sel",
+        opcode: 0x5ca0,
+        mask: 0xfff8,
+        form: Form::Sel(SrcB::Reg),
+    },
+    OpDesc {
+        name: "sel",
+        opcode: 0x38a0,
+        mask: 0xfef8,
+        form: Form::Sel(SrcB::Imm20I),
+    },
+    OpDesc {
+        name: "sel",
+        opcode: 0x4ca0,
+        mask: 0xfff8,
+        form: Form::Sel(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "shf.r",
+        opcode: 0x5cf8,
+        mask: 0xfff8,
+        form: Form::Alu3(SrcB::Reg),
+    },
+    OpDesc {
+        name: "shf.r",
+        opcode: 0x38f8,
+        mask: 0xfef8,
+        form: Form::Alu3(SrcB::Imm20I),
+    },
+    OpDesc {
+        name: "shl",
+        opcode: 0x5c48,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::Reg),
+    },
+    OpDesc {
+        name: "shl",
+        opcode: 0x3848,
+        mask: 0xfef8,
+        form: Form::Alu2(SrcB::Imm20I),
+    },
+    OpDesc {
+        name: "shl",
+        opcode: 0x4c48,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "shr",
+        opcode: 0x5c28,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::Reg),
+    },
+    OpDesc {
+        name: "shr",
+        opcode: 0x3828,
+        mask: 0xfef8,
+        form: Form::Alu2(SrcB::Imm20I),
+    },
+    OpDesc {
+        name: "shr",
+        opcode: 0x4c28,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::CBuf),
+    },
+    OpDesc {
+        name: "mov32i",
+        opcode: 0x0100,
+        mask: 0xfff0,
+        form: Form::Alu1(SrcB::Imm32),
+    },
+    OpDesc {
+        name: "lop32i",
+        opcode: 0x0400,
+        mask: 0xfc00,
+        form: Form::Alu2(SrcB::Imm32),
+    },
+    OpDesc {
+        name: "fadd32i",
+        opcode: 0x0800,
+        mask: 0xfe00,
+        form: Form::Alu2(SrcB::Imm32),
+    },
+    OpDesc {
+        name: "iadd32i",
+        opcode: 0x1c00,
+        mask: 0xfe00,
+        form: Form::Alu2(SrcB::Imm32),
+    },
+    OpDesc {
+        name: "fmul32i",
+        opcode: 0x1e00,
+        mask: 0xff00,
+        form: Form::Alu2(SrcB::Imm32),
+    },
+    OpDesc {
+        name: "imul32i",
+        opcode: 0x1f00,
+        mask: 0xff00,
+        form: Form::Alu2(SrcB::Imm32),
+    },
+    OpDesc {
+        name: "mufu",
+        opcode: 0x5080,
+        mask: 0xfff8,
+        form: Form::Unary,
+    },
+    OpDesc {
+        name: "psetp",
+        opcode: 0x5090,
+        mask: 0xfff8,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "nop",
+        opcode: 0x50b0,
+        mask: 0xfff8,
+        form: Form::NoOperands,
+    },
+    OpDesc {
+        name: "vote",
+        opcode: 0x50d8,
+        mask: 0xfff8,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "fswzadd",
+        opcode: 0x50f8,
+        mask: 0xfff8,
+        form: Form::Alu2(SrcB::Reg),
+    },
+    OpDesc {
+        name: "shfl",
+        opcode: 0xef10,
+        mask: 0xfff8,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "ldg",
+        opcode: 0xeed0,
+        mask: 0xfff8,
+        form: Form::Mem,
+    },
+    OpDesc {
+        name: "stg",
+        opcode: 0xeed8,
+        mask: 0xfff8,
+        form: Form::Mem,
+    },
+    OpDesc {
+        name: "ldl",
+        opcode: 0xef40,
+        mask: 0xfff8,
+        form: Form::Mem,
+    },
+    OpDesc {
+        name: "lds",
+        opcode: 0xef48,
+        mask: 0xfff8,
+        form: Form::Mem,
+    },
+    OpDesc {
+        name: "stl",
+        opcode: 0xef50,
+        mask: 0xfff8,
+        form: Form::Mem,
+    },
+    OpDesc {
+        name: "sts",
+        opcode: 0xef58,
+        mask: 0xfff8,
+        form: Form::Mem,
+    },
+    OpDesc {
+        name: "cctl",
+        opcode: 0xef60,
+        mask: 0xffe0,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "cctll",
+        opcode: 0xef80,
+        mask: 0xfff8,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "ldc",
+        opcode: 0xef90,
+        mask: 0xfff8,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "membar",
+        opcode: 0xef98,
+        mask: 0xfff8,
+        form: Form::NoOperands,
+    },
+    OpDesc {
+        name: "ald",
+        opcode: 0xefd8,
+        mask: 0xfff8,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "ast",
+        opcode: 0xeff0,
+        mask: 0xfff8,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "atom",
+        opcode: 0xed00,
+        mask: 0xff00,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "atoms",
+        opcode: 0xec00,
+        mask: 0xff00,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "suatom",
+        opcode: 0xea60,
+        mask: 0xffe0,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "suatom.cas",
+        opcode: 0xeac0,
+        mask: 0xffe0,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "suld",
+        opcode: 0xeb00,
+        mask: 0xffe0,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "sust",
+        opcode: 0xeb20,
+        mask: 0xffe0,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "tex",
+        opcode: 0xdeb8,
+        mask: 0xfff8,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "tld",
+        opcode: 0xdd38,
+        mask: 0xfff8,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "tld4",
+        opcode: 0xdef8,
+        mask: 0xfff8,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "tmml",
+        opcode: 0xdf60,
+        mask: 0xfff8,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "txd",
+        opcode: 0xde78,
+        mask: 0xfff8,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "txq",
+        opcode: 0xdf50,
+        mask: 0xfff8,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "ipa",
+        opcode: 0xe000,
+        mask: 0xff00,
+        form: Form::Other,
+    },
+    OpDesc {
+        name: "bra",
+        opcode: 0xe240,
+        mask: 0xfff0,
+        form: Form::Bra,
+    },
+    OpDesc {
+        name: "ssy",
+        opcode: 0xe290,
+        mask: 0xfff0,
+        form: Form::Bra,
+    },
+    OpDesc {
+        name: "sync",
+        opcode: 0xf0f8,
+        mask: 0xfff8,
+        form: Form::NoOperands,
+    },
+    OpDesc {
+        name: "pbk",
+        opcode: 0xe2a0,
+        mask: 0xfff0,
+        form: Form::Bra,
+    },
+    OpDesc {
+        name: "brk",
+        opcode: 0xe340,
+        mask: 0xfff0,
+        form: Form::NoOperands,
+    },
+    OpDesc {
+        name: "pcnt",
+        opcode: 0xe2b0,
+        mask: 0xfff0,
+        form: Form::Bra,
+    },
+    OpDesc {
+        name: "cont",
+        opcode: 0xe350,
+        mask: 0xfff0,
+        form: Form::NoOperands,
+    },
+    OpDesc {
+        name: "exit",
+        opcode: 0xe300,
+        mask: 0xfff0,
+        form: Form::NoOperands,
+    },
+    OpDesc {
+        name: "bar",
+        opcode: 0xf0a8,
+        mask: 0xfff8,
+        form: Form::NoOperands,
+    },
+    OpDesc {
+        name: "s2r",
+        opcode: 0xf0c8,
+        mask: 0xfff8,
+        form: Form::S2R,
+    },
+];
+
+fn find_op(opcode: u16) -> Option<&'static OpDesc> {
+    SM50_OPS.iter().find(|op| (opcode & op.mask) == op.opcode)
+}
+
+struct SM50Word {
+    inst: [u32; 2],
+}
+
+impl SM50Word {
+    fn get(&self, range: Range<usize>) -> u32 {
+        self.inst.get_bit_range_u64(range).try_into().unwrap()
+    }
+
+    fn get_bit(&self, bit: usize) -> bool {
+        self.get(bit..bit + 1) != 0
+    }
+
+    fn reg(&self, range: Range<usize>) -> String {
+        match self.get(range) {
+            255 => "rZ".to_string(),
+            idx => format!("r{idx}"),
+        }
+    }
+
+    fn pred(&self, range: Range<usize>) -> String {
+        match self.get(range) {
+            7 => "pT".to_string(),
+            idx => format!("p{idx}"),
+        }
+    }
+
+    fn pred_src(&self, range: Range<usize>, not_bit: usize) -> String {
+        let not = if self.get_bit(not_bit) { "!" } else { "" };
+        format!("{not}{}", self.pred(range))
+    }
+
+    fn src_b(&self, src_b: SrcB) -> String {
+        match src_b {
+            SrcB::Reg => self.reg(20..28),
+            SrcB::Imm20I => {
+                let mut i = self.get(20..39);
+                if self.get_bit(56) {
+                    i |= 0xfff80000;
+                }
+                format!("{:#x}", i)
+            }
+            SrcB::Imm20F => {
+                let mut f = self.get(20..39) << 12;
+                if self.get_bit(56) {
+                    f |= 0x80000000;
+                }
+                format!("{:?}", f32::from_bits(f))
+            }
+            SrcB::Imm32 => format!("{:#x}", self.get(20..52)),
+            SrcB::CBuf => {
+                let offset = self.get(20..34) << 2;
+                let idx = self.get(34..39);
+                format!("c[{:#x}][{:#x}]", idx, offset)
+            }
+        }
+    }
+
+    fn signed_field(&self, range: Range<usize>) -> i32 {
+        let bits = range.len();
+        let shift = 32 - bits;
+        ((self.get(range) << shift) as i32) >> shift
+    }
+
+    fn disassemble(&self, ip: usize) -> String {
+        let mut s = String::new();
+
+        let pred_idx = self.get(16..19);
+        let pred_inv = self.get_bit(19);
+        if pred_idx != 7 || pred_inv {
+            let not = if pred_inv { "!" } else { "" };
+            write!(s, "@{not}p{pred_idx} ").unwrap();
+        }
+
+        let opcode = u16::try_from(self.get(48..64)).unwrap();
+        let Some(op) = find_op(opcode) else {
+            write!(s, "??? {:#018x}", self.inst.get_bit_range_u64(0..64))
+                .unwrap();
+            return s;
+        };
+
+        s.push_str(op.name);
+        let operands = match op.form {
+            Form::Alu1(src_b) => vec![self.reg(0..8), self.src_b(src_b)],
+            Form::Alu2(src_b) => {
+                vec![self.reg(0..8), self.reg(8..16), self.src_b(src_b)]
+            }
+            Form::Alu3(src_b) => vec![
+                self.reg(0..8),
+                self.reg(8..16),
+                self.src_b(src_b),
+                self.reg(39..47),
+            ],
+            Form::SetP(src_b) => vec![
+                self.pred(3..6),
+                self.pred(0..3),
+                self.reg(8..16),
+                self.src_b(src_b),
+                self.pred_src(39..42, 42),
+            ],
+            Form::Sel(src_b) => vec![
+                self.reg(0..8),
+                self.reg(8..16),
+                self.src_b(src_b),
+                self.pred_src(39..42, 42),
+            ],
+            Form::Unary => vec![self.reg(0..8), self.reg(8..16)],
+            Form::Mem => {
+                let offset = self.signed_field(20..44);
+                let addr = if offset < 0 {
+                    format!("[{}-{:#x}]", self.reg(8..16), -offset)
+                } else {
+                    format!("[{}+{:#x}]", self.reg(8..16), offset)
+                };
+                vec![self.reg(0..8), addr]
+            }
+            Form::S2R => {
+                vec![self.reg(0..8), format!("sr[{:#x}]", self.get(20..28))]
+            }
+            Form::Bra => {
+                let offset = self.signed_field(20..44);
+                let target = (ip as i64) + 8 + i64::from(offset);
+                vec![format!("{:#x}", target)]
+            }
+            Form::NoOperands => Vec::new(),
+            Form::Other => {
+                let raw = self.inst.get_bit_range_u64(0..64);
+                vec![format!("/* {:#018x} */", raw)]
+            }
+        };
+
+        for (i, operand) in operands.iter().enumerate() {
+            let sep = if i == 0 { " " } else { ", " };
+            write!(s, "{sep}{operand}").unwrap();
+        }
+
+        s
+    }
+}
+
+fn sched_to_string(sched: u32) -> String {
+    let mut s = String::new();
+    let delay = sched.get_bit_range_u64(0..4);
+    if delay > 0 {
+        write!(s, " delay={}", delay).unwrap();
+    }
+    let wt_bar_mask = sched.get_bit_range_u64(11..17);
+    if wt_bar_mask != 0 {
+        write!(s, " wt={:06b}", wt_bar_mask).unwrap();
+    }
+    let rd_bar = sched.get_bit_range_u64(8..11);
+    if rd_bar != 7 {
+        write!(s, " rd:{}", rd_bar).unwrap();
+    }
+    let wr_bar = sched.get_bit_range_u64(5..8);
+    if wr_bar != 7 {
+        write!(s, " wr:{}", wr_bar).unwrap();
+    }
+    let reuse_mask = sched.get_bit_range_u64(17..21);
+    if reuse_mask != 0 {
+        write!(s, " reuse={:04b}", reuse_mask).unwrap();
+    }
+    if sched.get_bit_range_u64(4..5) != 0 {
+        write!(s, " yld").unwrap();
+    }
+    s
+}
+
+/// Disassembles SM50 shader code, as produced by Shader::encode_sm50()
+///
+/// Code is made of groups of four 64-bit words: one scheduling word which
+/// holds 21 bits of scheduling information for each of the three
+/// instructions which follow it.
+pub fn disassemble_sm50(code: &[u32]) -> String {
+    assert!(code.len() % 8 == 0);
+
+    let mut s = String::new();
+    for (g, group) in code.chunks(8).enumerate() {
+        let sched_word = [group[0], group[1]];
+        for i in 0..3 {
+            let ip = (g * 4 + 1 + i) * 8;
+            let word = SM50Word {
+                inst: [group[2 + i * 2], group[3 + i * 2]],
+            };
+            let sched: u32 = sched_word
+                .get_bit_range_u64(i * 21..(i + 1) * 21)
+                .try_into()
+                .unwrap();
+            writeln!(
+                s,
+                "/*{:04x}*/ {};{}",
+                ip,
+                word.disassemble(ip),
+                sched_to_string(sched)
+            )
+            .unwrap();
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode_sm50::encode_sm50_function;
+    use crate::ir::*;
+    use crate::ir_parse::parse_function;
+
+    /// Encodes a register allocated function and disassembles it again,
+    /// returning one (ip, instruction, scheduling) tuple per instruction.
+    fn round_trip(func: &Function) -> Vec<(usize, String, String)> {
+        let code = encode_sm50_function(func, 50);
+        disassemble_sm50(&code)
+            .lines()
+            .map(|line| {
+                let (ip, rest) = line.split_once("*/ ").unwrap();
+                let ip = ip.strip_prefix("/*").unwrap();
+                let (instr, sched) = rest.split_once(';').unwrap();
+                (
+                    usize::from_str_radix(ip, 16).unwrap(),
+                    instr.to_string(),
+                    sched.to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip_alu() {
+        let mut f = parse_function(
+            "block 0 L0 [] -> {
+                r0 = fadd r1 r2
+                r3 = fadd.ftz r0 0x3f800000
+                r4 = ffma r0 c[0x1][0x10] r3
+                r5 = s2r sr[0x21]
+                p0 = isetp.lt.u32 r4 r1
+                r6 = sel p0 r1 0x10
+                exit
+            } -> []",
+        )
+        .unwrap();
+        f.blocks[0].instrs[0].deps.set_delay(6);
+        f.blocks[0].instrs[3].deps.set_yield(true);
+
+        let dis = round_trip(&f);
+        let instrs: Vec<&str> = dis.iter().map(|d| d.1.as_str()).collect();
+        assert_eq!(
+            instrs,
+            [
+                "fadd r0, r1, r2",
+                "fadd r3, r0, 1.0",
+                "ffma r4, r0, c[0x1][0x10], r3",
+                "s2r r5, sr[0x21]",
+                "isetp p0, pT, r4, r1, pT",
+                "sel r6, r1, 0x10, p0",
+                "exit",
+                "nop",
+                "nop",
+            ]
+        );
+
+        // Each group of three instructions is preceded by a scheduling word
+        let ips: Vec<usize> = dis.iter().map(|d| d.0).collect();
+        assert_eq!(ips, [0x8, 0x10, 0x18, 0x28, 0x30, 0x38, 0x48, 0x50, 0x58]);
+
+        assert!(dis[0].2.contains("delay=6"), "{}", dis[0].2);
+        assert!(dis[3].2.contains("yld"), "{}", dis[3].2);
+    }
+
+    #[test]
+    fn round_trip_branch() {
+        let f = parse_function(
+            "block 0 L0 [] -> {
+                p0 = isetp.lt.u32 r0 r1
+                @!p0 bra L2
+            } -> [1, 2]
+            block 1 L1 [0] -> {
+                r2 = s2r sr[0x21]
+            } -> [2]
+            block 2 L2 [0, 1] -> {
+                exit
+            } -> []",
+        )
+        .unwrap();
+
+        // The bra jumps over block 1 to the exit
+        let dis = round_trip(&f);
+        let instrs: Vec<(usize, &str)> =
+            dis.iter().map(|d| (d.0, d.1.as_str())).collect();
+        assert_eq!(
+            instrs,
+            [
+                (0x08, "isetp p0, pT, r0, r1, pT"),
+                (0x10, "@!p0 bra 0x48"),
+                (0x18, "nop"),
+                (0x28, "s2r r2, sr[0x21]"),
+                (0x30, "nop"),
+                (0x38, "nop"),
+                (0x48, "exit"),
+                (0x50, "nop"),
+                (0x58, "nop"),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trip_loop() {
+        // What from_nir emits for a divergent loop with a continue:
+        //
+        // loop {
+        //     if (c) { continue; } else { break; }
+        // }
+        let f = parse_function(
+            "block 0 L0 [] -> {
+                pbk L4
+                bra L1
+            } -> [1]
+            block 1 L1 [0, 2] -> {
+                pcnt L1
+                p0 = isetp.lt.u32 r0 r1
+                @!p0 bra L3
+            } -> [2, 3]
+            block 2 L2 [1] -> {
+                cont L1
+            } -> [1]
+            block 3 L3 [1] -> {
+                brk L4
+            } -> [4]
+            block 4 L4 [3] -> {
+                exit
+            } -> []",
+        )
+        .unwrap();
+        assert!(f.blocks.is_loop_header(1));
+
+        // The break token has to point past the loop and the continue token
+        // back at the header, which is where the PCNT itself lives.
+        let dis = round_trip(&f);
+        let instrs: Vec<(usize, &str)> =
+            dis.iter().map(|d| (d.0, d.1.as_str())).collect();
+        assert_eq!(
+            instrs,
+            [
+                (0x08, "pbk 0x88"),
+                (0x10, "bra 0x28"),
+                (0x18, "nop"),
+                (0x28, "pcnt 0x28"),
+                (0x30, "isetp p0, pT, r0, r1, pT"),
+                (0x38, "@!p0 bra 0x68"),
+                (0x48, "cont"),
+                (0x50, "nop"),
+                (0x58, "nop"),
+                (0x68, "brk"),
+                (0x70, "nop"),
+                (0x78, "nop"),
+                (0x88, "exit"),
+                (0x90, "nop"),
+                (0x98, "nop"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+use crate::ir::*;
+
+use nak_bindings::*;
+
+use std::collections::HashSet;
+
+fn sysval_is_warp_uniform(idx: u8) -> bool {
+    let ctaid_x = u8::try_from(NAK_SV_CTAID_X).unwrap();
+    let ctaid_z = u8::try_from(NAK_SV_CTAID_Z).unwrap();
+    idx >= ctaid_x && idx <= ctaid_z
+}
+
+/// Returns true if the destinations of the instruction have the same value in
+/// every lane of the warp whenever all of its sources do.
+fn op_preserves_warp_uniform(op: &Op) -> bool {
+    match op {
+        Op::Copy(_)
+        | Op::Mov(_)
+        | Op::IAdd2(_)
+        | Op::IAdd3(_)
+        | Op::IAdd3X(_)
+        | Op::IMad(_)
+        | Op::IMul(_)
+        | Op::Lop2(_)
+        | Op::Lop3(_)
+        | Op::Shf(_)
+        | Op::Shl(_)
+        | Op::Shr(_)
+        | Op::Prmt(_)
+        | Op::Sel(_)
+        | Op::ISetP(_)
+        | Op::PLop3(_)
+        | Op::Ldc(_) => true,
+        Op::S2R(op) => sysval_is_warp_uniform(op.idx),
+        _ => false,
+    }
+}
+
+/// Collects the set of SSA values which are known to have the same value in
+/// all active lanes of a warp.  This is very conservative: a value is only
+/// warp-uniform if it's computed entirely from immediates, constant buffers
+/// and a few system values by simple ALU ops.  Phis are never warp-uniform.
+pub fn find_warp_uniform_values(f: &Function) -> HashSet<SSAValue> {
+    let mut uniform = HashSet::new();
+    for b in &f.blocks {
+        for instr in &b.instrs {
+            if !instr.pred.is_true() || !op_preserves_warp_uniform(&instr.op) {
+                continue;
+            }
+
+            let mut srcs_uniform = true;
+            instr.for_each_ssa_use(|ssa| {
+                srcs_uniform &= uniform.contains(ssa);
+            });
+
+            if srcs_uniform {
+                instr.for_each_ssa_def(|ssa| {
+                    uniform.insert(*ssa);
+                });
+            }
+        }
+    }
+    uniform
+}
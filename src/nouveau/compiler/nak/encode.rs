@@ -0,0 +1,154 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+//! Code shared between the per-generation encoders
+
+use crate::encode_sm50::SM50Encoder;
+use crate::encode_sm70::SM70Encoder;
+use crate::ir::*;
+use bitview::*;
+
+use std::ops::Range;
+
+/// A backend which turns a fully lowered shader into machine code
+pub trait Encoder {
+    fn encode_shader(&self, s: &Shader) -> Vec<u32>;
+
+    /// Returns the exact size in bytes of the code encode_shader() would
+    /// produce for `s`, without encoding anything.
+    ///
+    /// This is computed from the same layout encode_shader() uses and is
+    /// only used to check the encoders against themselves when validation
+    /// is enabled.  It is not part of the C API.
+    fn encoded_size(&self, s: &Shader) -> usize;
+
+    /// Returns the number of instructions in the encoded shader, not counting
+    /// any scheduling words
+    fn instr_count(&self, code: &[u32]) -> usize;
+
+    /// Returns a textual disassembly of the encoded shader if the backend
+    /// knows how to decode its own output
+    fn disassemble(&self, _code: &[u32]) -> Option<String> {
+        None
+    }
+}
+
+/// Helpers for operands which are packed the same way on every generation.
+/// Only the bit positions differ.
+pub trait EncodeFields: SetFieldU64 + Sized {
+    fn set_reg(&mut self, range: Range<usize>, reg: RegRef) {
+        assert!(range.len() == 8);
+        assert!(reg.file() == RegFile::GPR);
+        self.set_field(range, reg.base_idx());
+    }
+
+    fn set_reg_src(&mut self, range: Range<usize>, src: Src) {
+        assert!(src.src_mod.is_none());
+        match src.src_ref {
+            SrcRef::Zero => self.set_reg(range, RegRef::zero(RegFile::GPR, 1)),
+            SrcRef::Reg(reg) => self.set_reg(range, reg),
+            _ => panic!("Not a register"),
+        }
+    }
+
+    fn set_pred_reg(&mut self, range: Range<usize>, reg: RegRef) {
+        assert!(range.len() == 3);
+        assert!(reg.file() == RegFile::Pred);
+        assert!(reg.base_idx() <= 7);
+        assert!(reg.comps() == 1);
+        self.set_field(range, reg.base_idx());
+    }
+
+    fn set_pred_dst(&mut self, range: Range<usize>, dst: Dst) {
+        match dst {
+            Dst::None => {
+                self.set_pred_reg(range, RegRef::zero(RegFile::Pred, 1));
+            }
+            Dst::Reg(reg) => self.set_pred_reg(range, reg),
+            _ => panic!("Not a register"),
+        }
+    }
+
+    fn set_pred_src(&mut self, range: Range<usize>, not_bit: usize, src: Src) {
+        assert!(src.src_mod.is_none() || src.src_mod.is_bnot());
+
+        // The default for predicates is true
+        let true_reg = RegRef::new(RegFile::Pred, 7, 1);
+
+        let (not, reg) = match src.src_ref {
+            SrcRef::True => (false, true_reg),
+            SrcRef::False => (true, true_reg),
+            SrcRef::Reg(reg) => (false, reg),
+            _ => panic!("Not a register"),
+        };
+        self.set_pred_reg(range, reg);
+        self.set_bit(not_bit, not ^ src.src_mod.is_bnot());
+    }
+
+    /// Sets the predicate which guards the whole instruction
+    fn set_instr_pred(
+        &mut self,
+        range: Range<usize>,
+        inv_bit: usize,
+        pred: &Pred,
+    ) {
+        assert!(!pred.is_false());
+        self.set_pred_reg(
+            range,
+            match pred.pred_ref {
+                PredRef::None => RegRef::zero(RegFile::Pred, 1),
+                PredRef::Reg(reg) => reg,
+                PredRef::SSA(_) => panic!("SSA values must be lowered"),
+            },
+        );
+        self.set_bit(inv_bit, pred.pred_inv);
+    }
+}
+
+impl<T: SetFieldU64> EncodeFields for T {}
+
+/// Packs the scheduling information for an instruction into the 21-bit
+/// control field used by both SM50 and SM70+.
+pub fn encode_instr_deps(deps: &InstrDeps) -> u32 {
+    let mut sched = 0_u32;
+    let mut v = BitMutView::new(&mut sched);
+
+    v.set_field(0..4, deps.delay);
+    v.set_bit(4, deps.yld);
+    v.set_field(5..8, deps.wr_bar().unwrap_or(7));
+    v.set_field(8..11, deps.rd_bar().unwrap_or(7));
+    v.set_field(11..17, deps.wt_bar_mask);
+    v.set_field(17..21, deps.reuse_mask);
+
+    sched
+}
+
+/// Instruction cache lines are 128 bytes on everything we support
+pub const ICACHE_LINE_SIZE: usize = 128;
+
+impl Shader {
+    /// Returns the alignment in bytes for loop headers or 1 if they don't
+    /// need to be aligned.
+    ///
+    /// Starting a loop on a fresh instruction cache line means a short loop
+    /// body touches as few lines as possible on every iteration.  This costs
+    /// NOPs on the way into the loop so it's up to the driver to ask for
+    /// it.  Only the SM70+ encoder supports it.
+    pub fn loop_header_align(&self) -> usize {
+        if self.info.align_loops {
+            ICACHE_LINE_SIZE
+        } else {
+            1
+        }
+    }
+
+    pub fn encoder(&self) -> &'static dyn Encoder {
+        if self.info.sm >= 70 {
+            &SM70Encoder
+        } else if self.info.sm >= 50 {
+            &SM50Encoder
+        } else {
+            panic!("Unsupported shader model");
+        }
+    }
+}
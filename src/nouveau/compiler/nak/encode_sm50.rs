@@ -1,6 +1,8 @@
 // Copyright © 2023 Collabora, Ltd.
 // SPDX-License-Identifier: MIT
 
+use crate::decode_sm50::disassemble_sm50;
+use crate::encode::*;
 use crate::ir::*;
 use bitview::*;
 
@@ -71,6 +73,25 @@ impl SM50Instr {
         res
     }
 
+    /// An unconditional branch, used for trampolines
+    fn bra(sm: u8, ip: usize, target_ip: usize) -> Self {
+        let mut res = Self::new(sm);
+
+        res.set_opcode(0xe240);
+        res.set_rel_offset(20..44, ip, target_ip);
+        res.set_field(0..5, 0xF_u8);
+        res.set_pred(&Pred {
+            pred_ref: PredRef::None,
+            pred_inv: false,
+        });
+
+        let mut deps = InstrDeps::new();
+        deps.set_delay(MIN_INSTR_DELAY);
+        res.set_instr_deps(&deps);
+
+        res
+    }
+
     fn set_bit(&mut self, bit: usize, val: bool) {
         BitMutView::new(&mut self.inst).set_bit(bit, val);
     }
@@ -79,42 +100,12 @@ impl SM50Instr {
         self.set_field(48..64, opcode);
     }
 
-    fn set_pred_reg(&mut self, range: Range<usize>, reg: RegRef) {
-        assert!(range.len() == 3);
-        assert!(reg.file() == RegFile::Pred);
-        assert!(reg.base_idx() <= 7);
-        assert!(reg.comps() == 1);
-        self.set_field(range, reg.base_idx());
-    }
-
     fn set_pred(&mut self, pred: &Pred) {
-        assert!(!pred.is_false());
-        self.set_pred_reg(
-            16..19,
-            match pred.pred_ref {
-                PredRef::None => RegRef::zero(RegFile::Pred, 1),
-                PredRef::Reg(reg) => reg,
-                PredRef::SSA(_) => panic!("SSA values must be lowered"),
-            },
-        );
-        self.set_bit(19, pred.pred_inv);
+        self.set_instr_pred(16..19, 19, pred);
     }
 
     fn set_instr_deps(&mut self, deps: &InstrDeps) {
-        let mut sched = BitMutView::new(&mut self.sched);
-
-        sched.set_field(0..4, deps.delay);
-        sched.set_bit(4, deps.yld);
-        sched.set_field(5..8, deps.wr_bar().unwrap_or(7));
-        sched.set_field(8..11, deps.rd_bar().unwrap_or(7));
-        sched.set_field(11..17, deps.wt_bar_mask);
-        sched.set_field(17..21, deps.reuse_mask);
-    }
-
-    fn set_reg(&mut self, range: Range<usize>, reg: RegRef) {
-        assert!(range.len() == 8);
-        assert!(reg.file() == RegFile::GPR);
-        self.set_field(range, reg.base_idx());
+        self.sched = encode_instr_deps(deps);
     }
 
     fn set_reg_src_ref(&mut self, range: Range<usize>, src_ref: SrcRef) {
@@ -125,11 +116,6 @@ impl SM50Instr {
         }
     }
 
-    fn set_reg_src(&mut self, range: Range<usize>, src: Src) {
-        assert!(src.src_mod.is_none());
-        self.set_reg_src_ref(range, src.src_ref);
-    }
-
     fn set_reg_fmod_src(
         &mut self,
         range: Range<usize>,
@@ -152,30 +138,6 @@ impl SM50Instr {
         self.set_bit(neg_bit, src.src_mod.is_ineg());
     }
 
-    fn set_pred_dst(&mut self, range: Range<usize>, dst: Dst) {
-        match dst {
-            Dst::None => {
-                self.set_pred_reg(range, RegRef::zero(RegFile::Pred, 1));
-            }
-            Dst::Reg(reg) => self.set_pred_reg(range, reg),
-            _ => panic!("Not a register"),
-        }
-    }
-
-    fn set_pred_src(&mut self, range: Range<usize>, not_bit: usize, src: Src) {
-        // The default for predicates is true
-        let true_reg = RegRef::new(RegFile::Pred, 7, 1);
-
-        let (not, reg) = match src.src_ref {
-            SrcRef::True => (false, true_reg),
-            SrcRef::False => (true, true_reg),
-            SrcRef::Reg(reg) => (false, reg),
-            _ => panic!("Not a register"),
-        };
-        self.set_pred_reg(range, reg);
-        self.set_bit(not_bit, not ^ src.src_mod.is_bnot());
-    }
-
     fn set_dst(&mut self, dst: Dst) {
         let reg = match dst {
             Dst::None => RegRef::zero(RegFile::GPR, 1),
@@ -438,6 +400,31 @@ impl SM50Instr {
         );
     }
 
+    fn set_ldg_cache_op(&mut self, range: Range<usize>, access: &MemAccess) {
+        assert!(range.len() == 2);
+        // L1 isn't coherent so anything stronger than CTA scope has to go
+        // straight to L2.
+        let cache_op = match access.order {
+            MemOrder::Strong(MemScope::GPU | MemScope::System) => 1_u8, // CG
+            _ => match access.eviction_priority {
+                MemEvictionPriority::First => 2_u8,     // CS
+                MemEvictionPriority::Unchanged => 2_u8, // CS
+                _ => 0_u8,                              // CA
+            },
+        };
+        self.set_field(range, cache_op);
+    }
+
+    fn set_stg_cache_op(&mut self, range: Range<usize>, access: &MemAccess) {
+        assert!(range.len() == 2);
+        let cache_op = match access.eviction_priority {
+            MemEvictionPriority::First => 2_u8,     // CS
+            MemEvictionPriority::Unchanged => 2_u8, // CS
+            _ => 0_u8,                              // WB
+        };
+        self.set_field(range, cache_op);
+    }
+
     fn encode_ldg(&mut self, op: &OpLd) {
         self.set_opcode(0xeed0);
 
@@ -446,6 +433,7 @@ impl SM50Instr {
         self.set_field(20..44, op.offset);
 
         self.set_mem_access(&op.access);
+        self.set_ldg_cache_op(46..48, &op.access);
     }
 
     fn encode_ldl(&mut self, op: &OpLd) {
@@ -482,7 +470,7 @@ impl SM50Instr {
             panic!("Not a CBuf source");
         };
         let CBuf::Binding(cb_idx) = cb.buf else {
-            panic!("Must be a bound constant buffer");
+            panic!("SM50 has no bindless constant buffers");
         };
 
         self.set_opcode(0xef90);
@@ -502,6 +490,7 @@ impl SM50Instr {
         self.set_reg_src(8..16, op.addr);
         self.set_field(20..44, op.offset);
         self.set_mem_access(&op.access);
+        self.set_stg_cache_op(46..48, &op.access);
     }
 
     fn encode_stl(&mut self, op: &OpSt) {
@@ -974,8 +963,11 @@ impl SM50Instr {
         self.set_image_dim(33..36, op.image_dim);
         self.set_mem_order(&op.mem_order);
 
-        assert!(op.mask == 0x1 || op.mask == 0x3 || op.mask == 0xf);
-        self.set_field(20..24, op.mask);
+        let ImageAccess::Formatted(mask) = op.image_access else {
+            panic!("SUST.B is not supported prior to SM70");
+        };
+        assert!(mask == 0x1 || mask == 0x3 || mask == 0xf);
+        self.set_field(20..24, mask);
     }
 
     fn set_atom_op(&mut self, range: Range<usize>, atom_op: AtomOp) {
@@ -992,18 +984,17 @@ impl SM50Instr {
                 AtomOp::Or => 6_u8,
                 AtomOp::Xor => 7_u8,
                 AtomOp::Exch => 8_u8,
-                AtomOp::CmpExch => panic!("CmpXchg not yet supported"),
+                AtomOp::CmpExch => panic!("CAS has its own opcode"),
             },
         );
     }
 
-    fn encode_atomg(&mut self, op: &OpAtom) {
-        self.set_opcode(0xed00);
+    fn encode_red(&mut self, op: &OpAtom) {
+        self.set_opcode(0xebf8);
         self.set_mem_order(&op.mem_order);
 
-        self.set_dst(op.dst);
+        self.set_reg_src(0..8, op.data);
         self.set_reg_src(8..16, op.addr);
-        self.set_reg_src(20..28, op.data);
         self.set_field(28..48, op.addr_offset);
         self.set_field(
             48..49,
@@ -1012,8 +1003,9 @@ impl SM50Instr {
                 MemAddrType::A64 => 1_u8,
             },
         );
+
         self.set_field(
-            49..52,
+            20..23,
             match op.atom_type {
                 AtomType::U32 => 0_u8,
                 AtomType::I32 => 1_u8,
@@ -1021,34 +1013,120 @@ impl SM50Instr {
                 AtomType::F32 => 3_u8,
                 // NOTE: U128 => 4_u8,
                 AtomType::I64 => 5_u8,
-                // TODO: do something about ATOMG.F64
-                other => panic!("ATOMG.{other} not supported on SM50"),
+                other => panic!("RED.{other} not supported on SM50"),
+            },
+        );
+        self.set_field(
+            23..26,
+            match op.atom_op {
+                AtomOp::Add => 0_u8,
+                AtomOp::Min => 1_u8,
+                AtomOp::Max => 2_u8,
+                AtomOp::Inc => 3_u8,
+                AtomOp::Dec => 4_u8,
+                AtomOp::And => 5_u8,
+                AtomOp::Or => 6_u8,
+                AtomOp::Xor => 7_u8,
+                other => panic!("RED.{other} does not exist"),
             },
         );
-        self.set_atom_op(52..56, op.atom_op);
     }
 
-    fn encode_atoms(&mut self, op: &OpAtom) {
-        self.set_opcode(0xec00);
+    fn encode_atomg(&mut self, op: &OpAtom) {
+        if op.dst.is_none() && op.can_be_red() {
+            self.encode_red(op);
+            return;
+        }
+
+        if op.atom_op == AtomOp::CmpExch {
+            self.set_opcode(0xee00);
+        } else {
+            self.set_opcode(0xed00);
+        }
         self.set_mem_order(&op.mem_order);
 
         self.set_dst(op.dst);
         self.set_reg_src(8..16, op.addr);
         self.set_reg_src(20..28, op.data);
+        self.set_field(28..48, op.addr_offset);
         self.set_field(
-            28..30,
-            match op.atom_type {
-                AtomType::U32 => 0_u8,
-                AtomType::I32 => 1_u8,
-                AtomType::U64 => 2_u8,
-                AtomType::I64 => 3_u8,
-                // TODO: do something about ATOMS.F{32,64}
-                other => panic!("ATOMS.{other} not supported on SM50"),
+            48..49,
+            match op.mem_space.addr_type() {
+                MemAddrType::A32 => 0_u8,
+                MemAddrType::A64 => 1_u8,
             },
         );
+
+        if op.atom_op == AtomOp::CmpExch {
+            // The comparison value and the new value both live in data
+            assert!(op.cmpr.is_zero());
+            self.set_field(
+                49..52,
+                match op.atom_type {
+                    AtomType::U32 => 0_u8,
+                    AtomType::U64 => 1_u8,
+                    other => panic!("ATOMG.CAS.{other} not supported on SM50"),
+                },
+            );
+            self.set_field(52..56, 0xf_u8);
+        } else {
+            self.set_field(
+                49..52,
+                match op.atom_type {
+                    AtomType::U32 => 0_u8,
+                    AtomType::I32 => 1_u8,
+                    AtomType::U64 => 2_u8,
+                    AtomType::F32 => 3_u8,
+                    // NOTE: U128 => 4_u8,
+                    AtomType::I64 => 5_u8,
+                    // TODO: do something about ATOMG.F64
+                    other => panic!("ATOMG.{other} not supported on SM50"),
+                },
+            );
+            self.set_atom_op(52..56, op.atom_op);
+        }
+    }
+
+    fn encode_atoms(&mut self, op: &OpAtom) {
+        if op.atom_op == AtomOp::CmpExch {
+            self.set_opcode(0xee00);
+        } else {
+            self.set_opcode(0xec00);
+        }
+        self.set_mem_order(&op.mem_order);
+
+        self.set_dst(op.dst);
+        self.set_reg_src(8..16, op.addr);
+        self.set_reg_src(20..28, op.data);
         assert_eq!(op.addr_offset % 4, 0);
         self.set_field(30..52, op.addr_offset / 4);
-        self.set_atom_op(52..56, op.atom_op);
+
+        if op.atom_op == AtomOp::CmpExch {
+            // The comparison value and the new value both live in data.  The
+            // low bit of the sub-op selects the 64-bit form.
+            assert!(op.cmpr.is_zero());
+            self.set_field(
+                52..56,
+                match op.atom_type {
+                    AtomType::U32 => 4_u8,
+                    AtomType::U64 => 5_u8,
+                    other => panic!("ATOMS.CAS.{other} not supported on SM50"),
+                },
+            );
+        } else {
+            self.set_field(
+                28..30,
+                match op.atom_type {
+                    AtomType::U32 => 0_u8,
+                    AtomType::I32 => 1_u8,
+                    AtomType::U64 => 2_u8,
+                    AtomType::I64 => 3_u8,
+                    // TODO: do something about ATOMS.F{32,64}
+                    other => panic!("ATOMS.{other} not supported on SM50"),
+                },
+            );
+            self.set_atom_op(52..56, op.atom_op);
+        }
     }
 
     fn encode_atom(&mut self, op: &OpAtom) {
@@ -1084,7 +1162,10 @@ impl SM50Instr {
                 TexLodMode::Zero => 1_u8,
                 TexLodMode::Bias => 2_u8,
                 TexLodMode::Lod => 3_u8,
-                _ => panic!("Unknown LOD mode"),
+                // nak_nir_lower_sm50_min_lod() turns these into Lod
+                TexLodMode::Clamp | TexLodMode::BiasClamp => {
+                    panic!("SM50 has no LOD clamp")
+                }
             },
         );
     }
@@ -1266,6 +1347,74 @@ impl SM50Instr {
         self.set_field(47..49, op.access.comps - 1);
     }
 
+    fn encode_out(&mut self, op: &OpOut) {
+        match &op.stream.src_ref {
+            SrcRef::Imm32(imm32) => {
+                self.set_opcode(0xf6e0);
+                self.set_src_imm_i20(20..39, 56, *imm32);
+            }
+            SrcRef::Zero | SrcRef::Reg(_) => {
+                self.set_opcode(0xfbe0);
+                self.set_reg_src(20..28, op.stream);
+            }
+            SrcRef::CBuf(cbuf) => {
+                self.set_opcode(0xebe0);
+                self.set_src_cb(20..39, cbuf);
+            }
+            src => panic!("Unsupported src type for OUT: {src}"),
+        }
+
+        self.set_dst(op.dst);
+        self.set_reg_src(8..16, op.handle);
+        self.set_field(
+            39..41,
+            match op.out_type {
+                OutType::Emit => 1_u8,
+                OutType::Cut => 2_u8,
+                OutType::EmitThenCut => 3_u8,
+            },
+        );
+    }
+
+    fn encode_cctl(&mut self, op: &OpCCtl) {
+        match op.mem_space {
+            MemSpace::Global(addr_type) => {
+                self.set_opcode(0xef60);
+                assert!(op.addr_offset % 4 == 0);
+                self.set_field(22..52, op.addr_offset / 4);
+                self.set_field(
+                    52..53,
+                    match addr_type {
+                        MemAddrType::A32 => 0_u8,
+                        MemAddrType::A64 => 1_u8,
+                    },
+                );
+            }
+            MemSpace::Local => {
+                self.set_opcode(0xef80);
+                assert!(op.addr_offset % 4 == 0);
+                self.set_field(22..44, op.addr_offset / 4);
+            }
+            MemSpace::Shared => panic!("Shared memory isn't cached"),
+        }
+
+        self.set_reg_src(8..16, op.addr);
+        self.set_field(
+            0..4,
+            match op.op {
+                CCtlOp::PF1 => 1_u8,
+                CCtlOp::PF2 => 3_u8,
+                CCtlOp::WB => 4_u8,
+                CCtlOp::IV => 5_u8,
+                CCtlOp::IVAll => 6_u8,
+                CCtlOp::RS => 7_u8,
+                CCtlOp::IVAllP | CCtlOp::WBAll | CCtlOp::WBAllP => {
+                    panic!("CCTL.{} requires SM70+", op.op)
+                }
+            },
+        );
+    }
+
     fn encode_membar(&mut self, op: &OpMemBar) {
         self.set_opcode(0xef98);
 
@@ -1282,14 +1431,12 @@ impl SM50Instr {
     fn set_rel_offset(
         &mut self,
         range: Range<usize>,
-        label: &Label,
         ip: usize,
-        labels: &HashMap<Label, usize>,
+        target_ip: usize,
     ) {
         let ip = u32::try_from(ip).unwrap();
         let ip = i32::try_from(ip).unwrap();
 
-        let target_ip = *labels.get(label).unwrap();
         let target_ip = u32::try_from(target_ip).unwrap();
         let target_ip = i32::try_from(target_ip).unwrap();
 
@@ -1298,15 +1445,58 @@ impl SM50Instr {
         self.set_field(range, rel_offset);
     }
 
-    fn encode_bra(
+    fn encode_bra(&mut self, ip: usize, target_ip: usize) {
+        self.set_opcode(0xe240);
+        self.set_rel_offset(20..44, ip, target_ip);
+        self.set_field(0..5, 0xF_u8); // TODO: Pred?
+    }
+
+    fn encode_ssy(
         &mut self,
-        op: &OpBra,
+        op: &OpSSy,
         ip: usize,
         labels: &HashMap<Label, usize>,
     ) {
-        self.set_opcode(0xe240);
-        self.set_rel_offset(20..44, &op.target, ip, labels);
-        self.set_field(0..5, 0xF_u8); // TODO: Pred?
+        self.set_opcode(0xe290);
+        let target_ip = *labels.get(&op.target).unwrap();
+        self.set_rel_offset(20..44, ip, target_ip);
+    }
+
+    fn encode_sync(&mut self, _op: &OpSync) {
+        self.set_opcode(0xf0f8);
+        self.set_field(0..5, 0xF_u8); // CC.T
+    }
+
+    fn encode_pbk(
+        &mut self,
+        op: &OpPBk,
+        ip: usize,
+        labels: &HashMap<Label, usize>,
+    ) {
+        self.set_opcode(0xe2a0);
+        let target_ip = *labels.get(&op.target).unwrap();
+        self.set_rel_offset(20..44, ip, target_ip);
+    }
+
+    fn encode_brk(&mut self, _op: &OpBrk) {
+        self.set_opcode(0xe340);
+        self.set_field(0..5, 0xF_u8); // CC.T
+    }
+
+    fn encode_pcnt(
+        &mut self,
+        op: &OpPCnt,
+        ip: usize,
+        labels: &HashMap<Label, usize>,
+    ) {
+        self.set_opcode(0xe2b0);
+        let target_ip = *labels.get(&op.target).unwrap();
+        self.set_rel_offset(20..44, ip, target_ip);
+    }
+
+    fn encode_cont(&mut self, _op: &OpCont) {
+        self.set_opcode(0xe350);
+        self.set_field(0..5, 0xF_u8); // CC.T
     }
 
     fn encode_exit(&mut self, _op: &OpExit) {
@@ -1354,6 +1544,25 @@ impl SM50Instr {
         self.set_field(8..12, 0xf_u8); // CC.T
     }
 
+    fn encode_pixld(&mut self, op: &OpPixLd) {
+        self.set_opcode(0xefe8);
+        self.set_dst(op.dst);
+        self.set_reg_src(8..16, SrcRef::Zero.into());
+        self.set_field(
+            31..34,
+            match op.val {
+                PixVal::MsCount => 0_u8,
+                PixVal::CovMask => 1_u8,
+                PixVal::CentroidOffset => 4_u8,
+                PixVal::MyIndex => 5_u8,
+                PixVal::InnerCoverage => {
+                    panic!("PIXLD.INNER_COVERAGE not supported on SM50")
+                }
+            },
+        );
+        self.set_pred_dst(45..48, Dst::None);
+    }
+
     fn encode_s2r(&mut self, op: &OpS2R) {
         self.set_opcode(0xf0c8);
         self.set_dst(op.dst);
@@ -1996,11 +2205,14 @@ impl SM50Instr {
         self.set_reg_src(39..47, op.handle);
     }
 
+    /// Encodes a single instruction.  If `bra_target_ip` is given, a BRA
+    /// jumps there instead of to its label.
     pub fn encode(
         instr: &Instr,
         sm: u8,
         ip: usize,
         labels: &HashMap<Label, usize>,
+        bra_target_ip: Option<usize>,
     ) -> Self {
         assert!(sm >= 50);
 
@@ -2029,6 +2241,7 @@ impl SM50Instr {
             Op::PSetP(op) => si.encode_psetp(&op),
             Op::SuSt(op) => si.encode_sust(&op),
             Op::S2R(op) => si.encode_s2r(&op),
+            Op::PixLd(op) => si.encode_pixld(&op),
             Op::PopC(op) => si.encode_popc(&op),
             Op::Prmt(op) => si.encode_prmt(&op),
             Op::Ld(op) => si.encode_ld(&op),
@@ -2055,10 +2268,22 @@ impl SM50Instr {
             Op::Ipa(op) => si.encode_ipa(&op),
             Op::ALd(op) => si.encode_ald(&op),
             Op::ASt(op) => si.encode_ast(&op),
+            Op::Out(op) => si.encode_out(&op),
+            Op::CCtl(op) => si.encode_cctl(&op),
             Op::MemBar(op) => si.encode_membar(&op),
             Op::Atom(op) => si.encode_atom(&op),
-            Op::Bra(op) => si.encode_bra(&op, ip, labels),
+            Op::Bra(op) => {
+                let target_ip = bra_target_ip
+                    .unwrap_or_else(|| *labels.get(&op.target).unwrap());
+                si.encode_bra(ip, target_ip)
+            }
             Op::Exit(op) => si.encode_exit(&op),
+            Op::SSy(op) => si.encode_ssy(&op, ip, labels),
+            Op::Sync(op) => si.encode_sync(&op),
+            Op::PBk(op) => si.encode_pbk(&op, ip, labels),
+            Op::Brk(op) => si.encode_brk(&op),
+            Op::PCnt(op) => si.encode_pcnt(&op, ip, labels),
+            Op::Cont(op) => si.encode_cont(&op),
             Op::Bar(op) => si.encode_bar(&op),
             Op::SuLd(op) => si.encode_suld(&op),
             Op::SuAtom(op) => si.encode_suatom(&op),
@@ -2072,90 +2297,321 @@ impl SM50Instr {
     }
 }
 
-fn encode_instr(
-    instr_index: usize,
-    instr: Option<&Box<Instr>>,
-    sm: u8,
-    labels: &HashMap<Label, usize>,
-    ip: &mut usize,
-    sched_instr: &mut [u32; 2],
-) -> [u32; 2] {
-    let res = instr
-        .map(|x| SM50Instr::encode(x, sm, *ip, labels))
-        .unwrap_or_else(|| SM50Instr::nop(sm));
+/// Returns the byte offset of the given instruction within a block, taking
+/// into account the scheduling word in front of every group of three.
+fn instr_offset(idx: usize) -> usize {
+    (idx + idx / 3) * 8
+}
 
-    *ip += 8;
+/// Returns the size in bytes of a run of instructions once it's been padded
+/// out to whole groups of three plus their scheduling words.
+fn instrs_size(num_instrs: usize) -> usize {
+    let num_instrs = align_up(num_instrs, 3);
+    (num_instrs + num_instrs / 3) * 8
+}
 
-    BitMutView::new(sched_instr)
-        .set_field(21 * instr_index..21 * (instr_index + 1), res.sched);
+/// BRA takes a signed 24-bit byte offset relative to the next instruction
+fn rel_offset_fits(ip: usize, target_ip: usize) -> bool {
+    let rel_offset =
+        i64::try_from(target_ip).unwrap() - i64::try_from(ip).unwrap() - 8;
+    rel_offset >= -(1 << 23) && rel_offset < (1 << 23)
+}
 
-    res.inst
+#[derive(Clone, Copy)]
+enum BraTarget {
+    Label(Label),
+    Trampoline(usize),
 }
 
-impl Shader {
-    pub fn encode_sm50(&self) -> Vec<u32> {
-        assert!(self.functions.len() == 1);
-        let func = &self.functions[0];
+/// An unconditional branch placed between two blocks which lets a branch
+/// reach a target which is too far away to jump to directly.
+///
+/// Trampolines should only be reachable by branching to them.  If the block
+/// they go after falls through, the island starts with a branch to the next
+/// block which skips over them.
+struct Trampoline {
+    after_block: usize,
+    target: BraTarget,
+}
 
-        let mut num_instrs = 0_usize;
+struct SM50Layout {
+    labels: HashMap<Label, usize>,
+    block_ip: Vec<usize>,
+    /// The address of the group following each block, where its trampolines
+    /// go
+    island_ip: Vec<usize>,
+    /// Whether each island starts with a branch over its trampolines
+    island_skip: Vec<bool>,
+    tramp_ip: Vec<usize>,
+    /// Size of the whole shader in bytes
+    size: usize,
+}
+
+impl SM50Layout {
+    fn new(func: &Function, tramps: &[Trampoline]) -> SM50Layout {
+        let mut ip = 0_usize;
         let mut labels = HashMap::new();
-        for b in &func.blocks {
-            // We ensure blocks will have groups of 3 instructions with a
-            // schedule instruction before each groups.  As we should never jump
-            // to a schedule instruction, we account for that here.
-            labels.insert(b.label, num_instrs + 8);
+        let mut block_ip = Vec::new();
+        let mut island_ip = Vec::new();
+        let mut island_skip = Vec::new();
+        let mut tramp_ip = vec![0; tramps.len()];
+        for (i, b) in func.blocks.iter().enumerate() {
+            // Blocks always start a new group of 3 instructions with a
+            // scheduling word in front.  As we should never jump to a
+            // scheduling word, we account for that here.
+            labels.insert(b.label, ip + 8);
+            block_ip.push(ip + 8);
+            ip += instrs_size(b.instrs.len());
+
+            island_ip.push(ip);
+            let skip = b.falls_through()
+                && tramps.iter().any(|tramp| tramp.after_block == i);
+            island_skip.push(skip);
+            let mut num_instrs = usize::from(skip);
+            for (t, tramp) in tramps.iter().enumerate() {
+                if tramp.after_block == i {
+                    tramp_ip[t] = ip + 8 + instr_offset(num_instrs);
+                    num_instrs += 1;
+                }
+            }
+            ip += instrs_size(num_instrs);
+        }
 
-            let block_num_instrs = align_up(b.instrs.len(), 3);
+        SM50Layout {
+            labels: labels,
+            block_ip: block_ip,
+            island_ip: island_ip,
+            island_skip: island_skip,
+            tramp_ip: tramp_ip,
+            size: ip,
+        }
+    }
 
-            // Every 3 instructions, we have a new schedule instruction so we
-            // need to account for that.
-            num_instrs += (block_num_instrs + (block_num_instrs / 3)) * 8;
+    fn target_ip(&self, target: BraTarget) -> usize {
+        match target {
+            BraTarget::Label(label) => *self.labels.get(&label).unwrap(),
+            BraTarget::Trampoline(t) => self.tramp_ip[t],
         }
+    }
+}
 
-        let mut encoded = Vec::new();
-        for b in &func.blocks {
-            // A block is composed of groups of 3 instructions.
-            let block_num_instrs = align_up(b.instrs.len(), 3);
+/// Finds a spot for a trampoline which gets a branch at `ip` closer to
+/// `target_ip` and returns the index of the block to put it after.
+///
+/// We prefer blocks which don't fall through because a trampoline after one
+/// that does also needs a branch to skip over it.
+fn find_trampoline_block(
+    func: &Function,
+    layout: &SM50Layout,
+    ip: usize,
+    target_ip: usize,
+) -> usize {
+    let mut best: Option<((bool, usize), usize)> = None;
+    for (k, b) in func.blocks.iter().enumerate() {
+        // There's nothing to skip to after the last block
+        if b.falls_through() && k + 1 == func.blocks.len() {
+            continue;
+        }
 
-            let mut instrs_iter = b.instrs.iter();
+        // Leave some room for the island to grow
+        let island_ip = layout.island_ip[k] + 8;
+        let between = if target_ip > ip {
+            island_ip > ip && island_ip < target_ip
+        } else {
+            island_ip < ip && island_ip > target_ip
+        };
+        if !between || !rel_offset_fits(ip, island_ip + 64) {
+            continue;
+        }
 
-            for _ in 0..(block_num_instrs / 3) {
-                let mut ip = ((encoded.len() / 2) + 1) * 8;
+        let key = (b.falls_through(), island_ip.abs_diff(target_ip));
+        if best.map_or(true, |(best_key, _)| key < best_key) {
+            best = Some((key, k));
+        }
+    }
 
-                let mut sched_instr = [0x0; 2];
+    // Every block gets an island so this only fails if a single block is
+    // bigger than the branch range.
+    let (_, k) =
+        best.expect("No room for a trampoline to reach the branch target");
+    k
+}
 
-                let instr0 = encode_instr(
-                    0,
-                    instrs_iter.next(),
-                    self.info.sm,
-                    &labels,
-                    &mut ip,
-                    &mut sched_instr,
-                );
-                let instr1 = encode_instr(
-                    1,
-                    instrs_iter.next(),
-                    self.info.sm,
-                    &labels,
-                    &mut ip,
-                    &mut sched_instr,
-                );
-                let instr2 = encode_instr(
-                    2,
-                    instrs_iter.next(),
-                    self.info.sm,
-                    &labels,
-                    &mut ip,
-                    &mut sched_instr,
-                );
+/// Adds trampolines until every branch can reach its target.  Returns the
+/// trampolines and the new target of every block branch which needs one.
+fn relax_branches(
+    func: &Function,
+) -> (Vec<Trampoline>, HashMap<usize, BraTarget>) {
+    let mut tramps: Vec<Trampoline> = Vec::new();
+    let mut redirect: HashMap<usize, BraTarget> = HashMap::new();
+
+    loop {
+        let layout = SM50Layout::new(func, &tramps);
+
+        // Because every trampoline moves everything after it, we only fix
+        // one branch at a time and re-compute the layout.
+        let mut fixed = false;
+        for (i, b) in func.blocks.iter().enumerate() {
+            let Some(Op::Bra(bra)) = b.branch().map(|instr| &instr.op) else {
+                continue;
+            };
+
+            let ip = layout.block_ip[i] + instr_offset(b.instrs.len() - 1);
+            let target =
+                *redirect.get(&i).unwrap_or(&BraTarget::Label(bra.target));
+            let target_ip = layout.target_ip(target);
+            if rel_offset_fits(ip, target_ip) {
+                continue;
+            }
+
+            let k = find_trampoline_block(func, &layout, ip, target_ip);
+            redirect.insert(i, BraTarget::Trampoline(tramps.len()));
+            tramps.push(Trampoline {
+                after_block: k,
+                target: target,
+            });
+            fixed = true;
+            break;
+        }
+
+        if !fixed {
+            let too_far = (0..tramps.len()).find(|&t| {
+                let target_ip = layout.target_ip(tramps[t].target);
+                !rel_offset_fits(layout.tramp_ip[t], target_ip)
+            });
+
+            if let Some(t) = too_far {
+                // Chain to another trampoline further along
+                let ip = layout.tramp_ip[t];
+                let target_ip = layout.target_ip(tramps[t].target);
+                let k = find_trampoline_block(func, &layout, ip, target_ip);
+                let target = tramps[t].target;
+                tramps[t].target = BraTarget::Trampoline(tramps.len());
+                tramps.push(Trampoline {
+                    after_block: k,
+                    target: target,
+                });
+                fixed = true;
+            }
+        }
+
+        if !fixed {
+            return (tramps, redirect);
+        }
+    }
+}
 
-                encoded.extend_from_slice(&sched_instr[..]);
-                encoded.extend_from_slice(&instr0[..]);
-                encoded.extend_from_slice(&instr1[..]);
-                encoded.extend_from_slice(&instr2[..]);
+/// Encodes a run of instructions as groups of 3 with a scheduling word in
+/// front of each group, padding the last group with NOPs.
+fn push_instr_groups(encoded: &mut Vec<u32>, instrs: Vec<SM50Instr>, sm: u8) {
+    let mut instrs = instrs.into_iter();
+    loop {
+        let Some(first) = instrs.next() else {
+            break;
+        };
+        let mut group = [
+            first,
+            instrs.next().unwrap_or_else(|| SM50Instr::nop(sm)),
+            instrs.next().unwrap_or_else(|| SM50Instr::nop(sm)),
+        ];
+
+        // A delay of 0 dual-issues an instruction with the one after it but
+        // both have to be in the same group.  If the pair got split, issue
+        // them one after the other instead.
+        let last = &mut group[2].sched;
+        if *last & 0xf == 0 {
+            *last |= u32::from(MIN_INSTR_DELAY);
+        }
+
+        let mut sched_instr = [0x0; 2];
+        for (i, si) in group.iter().enumerate() {
+            BitMutView::new(&mut sched_instr)
+                .set_field(21 * i..21 * (i + 1), si.sched);
+        }
+
+        encoded.extend_from_slice(&sched_instr[..]);
+        for si in &group {
+            encoded.extend_from_slice(&si.inst[..]);
+        }
+    }
+}
+
+/// Encodes a single function, which must already be register allocated and
+/// have its scheduling information filled out.
+pub fn encode_sm50_function(func: &Function, sm: u8) -> Vec<u32> {
+    let (tramps, redirect) = relax_branches(func);
+    let layout = SM50Layout::new(func, &tramps);
+
+    let mut encoded = Vec::with_capacity(layout.size / 4);
+    for (i, b) in func.blocks.iter().enumerate() {
+        let mut instrs = Vec::new();
+        for (j, instr) in b.instrs.iter().enumerate() {
+            let ip = layout.block_ip[i] + instr_offset(j);
+
+            // A branch whose target is out of range goes through a
+            // trampoline instead.  This has to be resolved before
+            // encoding as the original offset doesn't fit.
+            let bra_target_ip = if j == b.instrs.len() - 1 {
+                redirect.get(&i).map(|t| layout.target_ip(*t))
+            } else {
+                None
+            };
+            let si =
+                SM50Instr::encode(instr, sm, ip, &layout.labels, bra_target_ip);
+            instrs.push(si);
+        }
+        push_instr_groups(&mut encoded, instrs, sm);
+        debug_assert!(encoded.len() * 4 == layout.island_ip[i]);
+
+        let mut island = Vec::new();
+        if layout.island_skip[i] {
+            let ip = layout.island_ip[i] + 8;
+            let next_ip = layout.block_ip[i + 1];
+            island.push(SM50Instr::bra(sm, ip, next_ip));
+        }
+        for (t, tramp) in tramps.iter().enumerate() {
+            if tramp.after_block == i {
+                let ip = layout.tramp_ip[t];
+                let target_ip = layout.target_ip(tramp.target);
+                island.push(SM50Instr::bra(sm, ip, target_ip));
             }
         }
+        push_instr_groups(&mut encoded, island, sm);
+    }
+    debug_assert!(encoded.len() * 4 == layout.size);
+
+    encoded
+}
+
+impl Shader {
+    pub fn encode_sm50(&self) -> Vec<u32> {
+        assert!(self.functions.len() == 1);
+        encode_sm50_function(&self.functions[0], self.info.sm)
+    }
+}
+
+pub struct SM50Encoder;
+
+impl Encoder for SM50Encoder {
+    fn encode_shader(&self, s: &Shader) -> Vec<u32> {
+        assert!(s.loop_header_align() == 1, "SM50 can't align loop headers");
+        s.encode_sm50()
+    }
+
+    fn encoded_size(&self, s: &Shader) -> usize {
+        assert!(s.functions.len() == 1);
+        let func = &s.functions[0];
+
+        let (tramps, _) = relax_branches(func);
+        SM50Layout::new(func, &tramps).size
+    }
+
+    fn instr_count(&self, code: &[u32]) -> usize {
+        // Every group of three instructions has a scheduling word
+        (code.len() / 8) * 3
+    }
 
-        encoded
+    fn disassemble(&self, code: &[u32]) -> Option<String> {
+        Some(disassemble_sm50(code))
     }
 }
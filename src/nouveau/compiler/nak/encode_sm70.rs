@@ -1,9 +1,11 @@
 // Copyright © 2022 Collabora, Ltd.
 // SPDX-License-Identifier: MIT
 
+use crate::encode::*;
 use crate::ir::*;
 use bitview::*;
 
+use std::cmp::max;
 use std::collections::HashMap;
 use std::ops::Range;
 
@@ -41,14 +43,6 @@ fn src_mod_has_neg(src_mod: SrcMod) -> bool {
     }
 }
 
-fn src_mod_is_bnot(src_mod: SrcMod) -> bool {
-    match src_mod {
-        SrcMod::None => false,
-        SrcMod::BNot => true,
-        _ => panic!("Not an predicate source modifier"),
-    }
-}
-
 fn dst_is_bar(dst: Dst) -> bool {
     match dst {
         Dst::None => false,
@@ -57,6 +51,14 @@ fn dst_is_bar(dst: Dst) -> bool {
     }
 }
 
+fn dst_is_uniform(dst: Dst) -> bool {
+    match dst {
+        Dst::None => false,
+        Dst::SSA(ssa) => ssa.file() == RegFile::UGPR,
+        Dst::Reg(reg) => reg.file() == RegFile::UGPR,
+    }
+}
+
 impl ALUSrc {
     fn from_src_file(src: &Src, file: RegFile) -> ALUSrc {
         match src.src_ref {
@@ -96,10 +98,16 @@ impl ALUSrc {
     }
 
     pub fn from_src(src: &Src) -> ALUSrc {
-        ALUSrc::from_src_file(src, RegFile::GPR)
+        match src.src_ref {
+            // Vector ALU instructions can read one UGPR in place of src1 or
+            // src2, such as when copying a uniform value back to a GPR.
+            SrcRef::Reg(reg) if reg.file() == RegFile::UGPR => {
+                ALUSrc::from_src_file(src, RegFile::UGPR)
+            }
+            _ => ALUSrc::from_src_file(src, RegFile::GPR),
+        }
     }
 
-    #[allow(dead_code)]
     pub fn from_usrc(src: &Src) -> ALUSrc {
         assert!(src.is_uniform());
         ALUSrc::from_src_file(src, RegFile::UGPR)
@@ -143,12 +151,6 @@ impl SM70Instr {
         self.set_field(range, *u);
     }
 
-    fn set_reg(&mut self, range: Range<usize>, reg: RegRef) {
-        assert!(range.len() == 8);
-        assert!(reg.file() == RegFile::GPR);
-        self.set_field(range, reg.base_idx());
-    }
-
     fn set_ureg(&mut self, range: Range<usize>, reg: RegRef) {
         assert!(self.sm >= 75);
         assert!(range.len() == 8);
@@ -157,54 +159,14 @@ impl SM70Instr {
         self.set_field(range, reg.base_idx());
     }
 
-    fn set_pred_reg(&mut self, range: Range<usize>, reg: RegRef) {
-        assert!(range.len() == 3);
-        assert!(reg.file() == RegFile::Pred);
-        assert!(reg.base_idx() <= 7);
-        assert!(reg.comps() == 1);
-        self.set_field(range, reg.base_idx());
-    }
-
-    fn set_reg_src(&mut self, range: Range<usize>, src: Src) {
-        assert!(src.src_mod.is_none());
-        match src.src_ref {
-            SrcRef::Zero => self.set_reg(range, RegRef::zero(RegFile::GPR, 1)),
-            SrcRef::Reg(reg) => self.set_reg(range, reg),
-            _ => panic!("Not a register"),
-        }
-    }
-
-    fn set_pred_dst(&mut self, range: Range<usize>, dst: Dst) {
-        match dst {
-            Dst::None => {
-                self.set_pred_reg(range, RegRef::zero(RegFile::Pred, 1));
-            }
-            Dst::Reg(reg) => self.set_pred_reg(range, reg),
-            _ => panic!("Not a register"),
-        }
-    }
-
-    fn set_pred_src(&mut self, range: Range<usize>, not_bit: usize, src: Src) {
-        // The default for predicates is true
-        let true_reg = RegRef::new(RegFile::Pred, 7, 1);
-
-        let (not, reg) = match src.src_ref {
-            SrcRef::True => (false, true_reg),
-            SrcRef::False => (true, true_reg),
-            SrcRef::Reg(reg) => (false, reg),
-            _ => panic!("Not a register"),
-        };
-        self.set_pred_reg(range, reg);
-        self.set_bit(not_bit, not ^ src_mod_is_bnot(src.src_mod));
-    }
-
     fn set_src_cb(&mut self, range: Range<usize>, cb: &CBufRef) {
         let mut v = BitMutView::new_subset(self, range);
         v.set_field(0..16, cb.offset);
         if let CBuf::Binding(idx) = cb.buf {
             v.set_field(16..21, idx);
         } else {
-            panic!("Must be a bound constant buffer");
+            // Bindless cbufs are only supported by LDC for now
+            panic!("ALU ops can't read bindless constant buffers");
         }
     }
 
@@ -229,16 +191,7 @@ impl SM70Instr {
     }
 
     fn set_pred(&mut self, pred: &Pred) {
-        assert!(!pred.is_false());
-        self.set_pred_reg(
-            12..15,
-            match pred.pred_ref {
-                PredRef::None => RegRef::zero(RegFile::Pred, 1),
-                PredRef::Reg(reg) => reg,
-                PredRef::SSA(_) => panic!("SSA values must be lowered"),
-            },
-        );
-        self.set_bit(15, pred.pred_inv);
+        self.set_instr_pred(12..15, 15, pred);
     }
 
     fn set_dst(&mut self, dst: Dst) {
@@ -249,6 +202,14 @@ impl SM70Instr {
         }
     }
 
+    fn set_udst(&mut self, dst: Dst) {
+        match dst {
+            Dst::None => self.set_ureg(16..24, RegRef::zero(RegFile::UGPR, 1)),
+            Dst::Reg(reg) => self.set_ureg(16..24, reg),
+            _ => panic!("Not a register"),
+        }
+    }
+
     fn set_bar_reg(&mut self, range: Range<usize>, reg: RegRef) {
         assert!(range.len() == 4);
         assert!(reg.file() == RegFile::Bar);
@@ -315,6 +276,22 @@ impl SM70Instr {
         }
     }
 
+    fn set_alu_ureg_src(
+        &mut self,
+        range: Range<usize>,
+        abs_bit: usize,
+        neg_bit: usize,
+        src: &ALUSrc,
+    ) {
+        match src {
+            ALUSrc::None => (),
+            ALUSrc::UReg(reg) => {
+                self.set_alu_ureg(range, abs_bit, neg_bit, reg)
+            }
+            _ => panic!("Invalid uniform ALU src"),
+        }
+    }
+
     fn encode_alu(
         &mut self,
         opcode: u16,
@@ -374,13 +351,47 @@ impl SM70Instr {
         self.set_field(9..12, form);
     }
 
+    /// Encodes an instruction for the uniform datapath.  These use the same
+    /// layout as encode_alu() but every register is a UGPR and only src1 can
+    /// be an immediate or a constant buffer.
+    fn encode_ualu(
+        &mut self,
+        opcode: u16,
+        dst: Option<Dst>,
+        src0: ALUSrc,
+        src1: ALUSrc,
+        src2: ALUSrc,
+    ) {
+        if let Some(dst) = dst {
+            self.set_udst(dst);
+        }
+
+        self.set_alu_ureg_src(24..32, 73, 72, &src0);
+        self.set_alu_ureg_src(64..72, 74, 75, &src2);
+
+        let form = match &src1 {
+            ALUSrc::None => 1_u8, // form
+            ALUSrc::UReg(reg1) => {
+                self.set_alu_ureg(32..40, 62, 63, reg1);
+                1_u8 // form
+            }
+            ALUSrc::Imm32(imm) => {
+                self.set_src_imm(32..64, &imm);
+                4_u8 // form
+            }
+            ALUSrc::CBuf(cb) => {
+                self.set_alu_cb(38..59, 62, 63, cb);
+                5_u8 // form
+            }
+            ALUSrc::Reg(_) => panic!("Invalid uniform ALU src1"),
+        };
+
+        self.set_field(0..9, opcode);
+        self.set_field(9..12, form);
+    }
+
     fn set_instr_deps(&mut self, deps: &InstrDeps) {
-        self.set_field(105..109, deps.delay);
-        self.set_bit(109, deps.yld);
-        self.set_field(110..113, deps.wr_bar().unwrap_or(7));
-        self.set_field(113..116, deps.rd_bar().unwrap_or(7));
-        self.set_field(116..122, deps.wt_bar_mask);
-        self.set_field(122..126, deps.reuse_mask);
+        self.set_field(105..126, encode_instr_deps(deps));
     }
 
     fn set_rnd_mode(&mut self, range: Range<usize>, rnd_mode: FRndMode) {
@@ -554,6 +565,132 @@ impl SM70Instr {
         self.set_bit(80, op.ftz);
     }
 
+    fn encode_fp16_alu(
+        &mut self,
+        opcode: u16,
+        dst: Option<Dst>,
+        src0: ALUSrc,
+        src1: ALUSrc,
+        src2: ALUSrc,
+    ) {
+        if let Some(dst) = dst {
+            self.set_dst(dst);
+        }
+
+        // Unlike the 32-bit float ops, the source modifiers for the third
+        // source live up at bits 84 and 85 because bits 74..76 are used for
+        // the src0 swizzle.
+        self.set_alu_reg_src(24..32, 73, 72, &src0);
+        self.set_field(74..76, 0_u8); // src0 swizzle: H1_H0
+
+        let form = match &src2 {
+            ALUSrc::None | ALUSrc::Reg(_) => {
+                self.set_alu_reg_src(64..72, 84, 85, &src2);
+                self.set_field(81..83, 0_u8); // src2 swizzle: H1_H0
+                match &src1 {
+                    ALUSrc::None => 1_u8, // form
+                    ALUSrc::Reg(reg1) => {
+                        self.set_alu_reg(32..40, 62, 63, reg1);
+                        self.set_field(60..62, 0_u8); // src1 swizzle: H1_H0
+                        1_u8 // form
+                    }
+                    ALUSrc::Imm32(imm) => {
+                        self.set_src_imm(32..64, &imm);
+                        4_u8 // form
+                    }
+                    ALUSrc::CBuf(cb) => {
+                        self.set_alu_cb(38..59, 62, 63, cb);
+                        5_u8 // form
+                    }
+                    _ => panic!("Invalid fp16 ALU src1"),
+                }
+            }
+            ALUSrc::Imm32(imm) => {
+                self.set_src_imm(32..64, &imm);
+                self.set_alu_reg_src(64..72, 84, 85, &src1);
+                2_u8 // form
+            }
+            ALUSrc::CBuf(cb) => {
+                self.set_alu_cb(38..59, 62, 63, cb);
+                self.set_alu_reg_src(64..72, 84, 85, &src1);
+                3_u8 // form
+            }
+            _ => panic!("Invalid fp16 ALU src2"),
+        };
+
+        self.set_field(0..9, opcode);
+        self.set_field(9..12, form);
+    }
+
+    // HADD2 and HMUL2 follow FADD and FMUL: HADD2 is really an FMA with an
+    // implicit 1.0 so a non-register addend goes in the src2 slot while
+    // HMUL2 takes a non-register multiplicand in the src1 slot.
+    fn encode_hadd2(&mut self, op: &OpHAdd2) {
+        if op.srcs[1].src_ref.as_reg().is_some() {
+            self.encode_fp16_alu(
+                0x030,
+                Some(op.dst),
+                ALUSrc::from_src(&op.srcs[0]),
+                ALUSrc::from_src(&op.srcs[1]),
+                ALUSrc::None,
+            );
+        } else {
+            self.encode_fp16_alu(
+                0x030,
+                Some(op.dst),
+                ALUSrc::from_src(&op.srcs[0]),
+                ALUSrc::from_src(&Src::new_zero()),
+                ALUSrc::from_src(&op.srcs[1]),
+            );
+        }
+        self.set_bit(77, op.saturate);
+        self.set_bit(78, op.f32);
+        self.set_bit(80, op.ftz);
+    }
+
+    fn encode_hfma2(&mut self, op: &OpHFma2) {
+        self.encode_fp16_alu(
+            0x031,
+            Some(op.dst),
+            ALUSrc::from_src(&op.srcs[0]),
+            ALUSrc::from_src(&op.srcs[1]),
+            ALUSrc::from_src(&op.srcs[2]),
+        );
+        self.set_bit(76, op.dnz);
+        self.set_bit(77, op.saturate);
+        self.set_bit(78, op.f32);
+        self.set_bit(80, op.ftz);
+    }
+
+    fn encode_hmul2(&mut self, op: &OpHMul2) {
+        self.encode_fp16_alu(
+            0x032,
+            Some(op.dst),
+            ALUSrc::from_src(&op.srcs[0]),
+            ALUSrc::from_src(&op.srcs[1]),
+            ALUSrc::None,
+        );
+        self.set_bit(76, op.dnz);
+        self.set_bit(77, op.saturate);
+        self.set_bit(78, false); // .F32
+        self.set_bit(80, op.ftz);
+    }
+
+    fn encode_hset2(&mut self, op: &OpHSet2) {
+        self.encode_fp16_alu(
+            0x033,
+            Some(op.dst),
+            ALUSrc::from_src(&op.srcs[0]),
+            ALUSrc::from_src(&op.srcs[1]),
+            ALUSrc::None,
+        );
+        self.set_bit(65, false); // .BF
+        self.set_pred_set_op(69..71, op.set_op);
+        self.set_float_cmp_op(76..80, op.cmp_op);
+        self.set_bit(80, op.ftz);
+        self.set_pred_src(87..90, 90, op.accum);
+    }
+
     fn encode_mufu(&mut self, op: &OpMuFu) {
         self.encode_alu(
             0x108,
@@ -694,13 +831,23 @@ impl SM70Instr {
         // Hardware requires at least one of these be unmodified
         assert!(op.srcs[0].src_mod.is_none() || op.srcs[1].src_mod.is_none());
 
-        self.encode_alu(
-            0x010,
-            Some(op.dst),
-            ALUSrc::from_src(&op.srcs[0]),
-            ALUSrc::from_src(&op.srcs[1]),
-            ALUSrc::from_src(&op.srcs[2]),
-        );
+        if dst_is_uniform(op.dst) {
+            self.encode_ualu(
+                0x090,
+                Some(op.dst),
+                ALUSrc::from_usrc(&op.srcs[0]),
+                ALUSrc::from_usrc(&op.srcs[1]),
+                ALUSrc::from_usrc(&op.srcs[2]),
+            );
+        } else {
+            self.encode_alu(
+                0x010,
+                Some(op.dst),
+                ALUSrc::from_src(&op.srcs[0]),
+                ALUSrc::from_src(&op.srcs[1]),
+                ALUSrc::from_src(&op.srcs[2]),
+            );
+        }
 
         self.set_pred_dst(81..84, op.overflow[0]);
         self.set_pred_dst(84..87, op.overflow[1]);
@@ -755,13 +902,23 @@ impl SM70Instr {
     }
 
     fn encode_imad(&mut self, op: &OpIMad) {
-        self.encode_alu(
-            0x024,
-            Some(op.dst),
-            ALUSrc::from_src(&op.srcs[0]),
-            ALUSrc::from_src(&op.srcs[1]),
-            ALUSrc::from_src(&op.srcs[2]),
-        );
+        if dst_is_uniform(op.dst) {
+            self.encode_ualu(
+                0x0a4,
+                Some(op.dst),
+                ALUSrc::from_usrc(&op.srcs[0]),
+                ALUSrc::from_usrc(&op.srcs[1]),
+                ALUSrc::from_usrc(&op.srcs[2]),
+            );
+        } else {
+            self.encode_alu(
+                0x024,
+                Some(op.dst),
+                ALUSrc::from_src(&op.srcs[0]),
+                ALUSrc::from_src(&op.srcs[1]),
+                ALUSrc::from_src(&op.srcs[2]),
+            );
+        }
         self.set_pred_dst(81..84, Dst::None);
         self.set_bit(73, op.signed);
     }
@@ -840,13 +997,23 @@ impl SM70Instr {
     }
 
     fn encode_lop3(&mut self, op: &OpLop3) {
-        self.encode_alu(
-            0x012,
-            Some(op.dst),
-            ALUSrc::from_src(&op.srcs[0].into()),
-            ALUSrc::from_src(&op.srcs[1].into()),
-            ALUSrc::from_src(&op.srcs[2].into()),
-        );
+        if dst_is_uniform(op.dst) {
+            self.encode_ualu(
+                0x092,
+                Some(op.dst),
+                ALUSrc::from_usrc(&op.srcs[0].into()),
+                ALUSrc::from_usrc(&op.srcs[1].into()),
+                ALUSrc::from_usrc(&op.srcs[2].into()),
+            );
+        } else {
+            self.encode_alu(
+                0x012,
+                Some(op.dst),
+                ALUSrc::from_src(&op.srcs[0].into()),
+                ALUSrc::from_src(&op.srcs[1].into()),
+                ALUSrc::from_src(&op.srcs[2].into()),
+            );
+        }
 
         self.set_field(72..80, op.op.lut);
         self.set_bit(80, false); // .PAND
@@ -868,13 +1035,23 @@ impl SM70Instr {
     }
 
     fn encode_shf(&mut self, op: &OpShf) {
-        self.encode_alu(
-            0x019,
-            Some(op.dst),
-            ALUSrc::from_src(&op.low),
-            ALUSrc::from_src(&op.shift),
-            ALUSrc::from_src(&op.high),
-        );
+        if dst_is_uniform(op.dst) {
+            self.encode_ualu(
+                0x099,
+                Some(op.dst),
+                ALUSrc::from_usrc(&op.low),
+                ALUSrc::from_usrc(&op.shift),
+                ALUSrc::from_usrc(&op.high),
+            );
+        } else {
+            self.encode_alu(
+                0x019,
+                Some(op.dst),
+                ALUSrc::from_src(&op.low),
+                ALUSrc::from_src(&op.shift),
+                ALUSrc::from_src(&op.high),
+            );
+        }
 
         self.set_field(
             73..75,
@@ -1000,14 +1177,24 @@ impl SM70Instr {
     }
 
     fn encode_mov(&mut self, op: &OpMov) {
-        self.encode_alu(
-            0x002,
-            Some(op.dst),
-            ALUSrc::None,
-            ALUSrc::from_src(&op.src.into()),
-            ALUSrc::None,
-        );
-        self.set_field(72..76, op.quad_lanes);
+        if dst_is_uniform(op.dst) {
+            self.encode_ualu(
+                0x082,
+                Some(op.dst),
+                ALUSrc::None,
+                ALUSrc::from_usrc(&op.src.into()),
+                ALUSrc::None,
+            );
+        } else {
+            self.encode_alu(
+                0x002,
+                Some(op.dst),
+                ALUSrc::None,
+                ALUSrc::from_src(&op.src.into()),
+                ALUSrc::None,
+            );
+            self.set_field(72..76, op.quad_lanes);
+        }
     }
 
     fn encode_prmt(&mut self, op: &OpPrmt) {
@@ -1266,6 +1453,34 @@ impl SM70Instr {
         self.set_bit(90, false); // TODO: .NODEP
     }
 
+    fn encode_footprint(&mut self, op: &OpFootprint) {
+        assert!(self.sm >= 75, "Footprint queries require SM75+");
+
+        self.set_opcode(0x37d);
+        self.set_bit(59, true); // .B
+
+        self.set_dst(op.dsts[0]);
+        if let Dst::Reg(reg) = op.dsts[1] {
+            self.set_reg(64..72, reg);
+        } else {
+            self.set_field(64..72, 255_u8);
+        }
+
+        self.set_reg_src(24..32, op.srcs[0]);
+        self.set_reg_src(32..40, op.srcs[1]);
+
+        self.set_tex_dim(61..64, op.dim);
+        self.set_field(72..76, op.mask);
+        // With an explicit LOD, there are no derivatives to compute
+        self.set_bit(
+            77,
+            matches!(op.lod_mode, TexLodMode::Zero | TexLodMode::Lod),
+        ); // .NDV
+        self.set_bit(78, op.coarse);
+        self.set_tex_lod_mode(87..90, op.lod_mode);
+        self.set_bit(90, false); // TODO: .NODEP
+    }
+
     fn encode_txq(&mut self, op: &OpTxq) {
         self.set_opcode(0x370);
         self.set_bit(59, true); // .B
@@ -1372,7 +1587,17 @@ impl SM70Instr {
     }
 
     fn encode_sust(&mut self, op: &OpSuSt) {
-        self.set_opcode(0x99c);
+        match op.image_access {
+            ImageAccess::Binary(mem_type) => {
+                self.set_opcode(0x99e);
+                self.set_mem_type(73..76, mem_type);
+            }
+            ImageAccess::Formatted(mask) => {
+                self.set_opcode(0x99c);
+                assert!(mask == 0x1 || mask == 0x3 || mask == 0xf);
+                self.set_field(72..76, mask);
+            }
+        }
 
         self.set_reg_src(24..32, op.coord);
         self.set_reg_src(32..40, op.data);
@@ -1381,9 +1606,6 @@ impl SM70Instr {
         self.set_image_dim(61..64, op.image_dim);
         self.set_mem_order(&op.mem_order);
         self.set_eviction_priority(&op.mem_eviction_priority);
-
-        assert!(op.mask == 0x1 || op.mask == 0x3 || op.mask == 0xf);
-        self.set_field(72..76, op.mask);
     }
 
     fn encode_suatom(&mut self, op: &OpSuAtom) {
@@ -1483,13 +1705,40 @@ impl SM70Instr {
     }
 
     fn encode_ldc(&mut self, op: &OpLdc) {
-        self.encode_alu(
-            0x182,
-            Some(op.dst),
-            ALUSrc::from_src(&op.offset),
-            ALUSrc::from_src(&op.cb),
-            ALUSrc::None,
-        );
+        let SrcRef::CBuf(cb) = &op.cb.src_ref else {
+            panic!("Not a CBuf source");
+        };
+
+        if let CBuf::BindlessGPR(handle) = cb.buf {
+            // Bindless cbufs take the handle from a UGPR and have no uniform
+            // form.  Unlike the bound form, the offset is in bytes and there
+            // is no index.
+            assert!(self.sm >= 75, "Bindless LDC requires SM75+");
+            assert!(!dst_is_uniform(op.dst), "Bindless LDC has no ULDC form");
+            assert!(op.cb.src_mod.is_none());
+
+            self.set_opcode(0x582);
+            self.set_dst(op.dst);
+            self.set_reg_src(24..32, op.offset);
+            self.set_field(38..54, cb.offset);
+            self.set_ureg(64..72, handle);
+        } else if dst_is_uniform(op.dst) {
+            self.encode_ualu(
+                0x0b9,
+                Some(op.dst),
+                ALUSrc::from_usrc(&op.offset),
+                ALUSrc::from_usrc(&op.cb),
+                ALUSrc::None,
+            );
+        } else {
+            self.encode_alu(
+                0x182,
+                Some(op.dst),
+                ALUSrc::from_src(&op.offset),
+                ALUSrc::from_src(&op.cb),
+                ALUSrc::None,
+            );
+        }
 
         self.set_mem_type(73..76, op.mem_type);
         self.set_field(78..80, 0_u8); // subop
@@ -1573,7 +1822,13 @@ impl SM70Instr {
     }
 
     fn encode_atomg(&mut self, op: &OpAtom) {
-        if op.atom_op == AtomOp::CmpExch {
+        if op.dst.is_none() && op.can_be_red() {
+            self.set_opcode(0x98e);
+
+            self.set_reg_src(32..40, op.data);
+
+            self.set_atom_op(87..91, op.atom_op);
+        } else if op.atom_op == AtomOp::CmpExch {
             self.set_opcode(0x38b);
 
             self.set_reg_src(32..40, op.cmpr);
@@ -1930,8 +2185,13 @@ impl SM70Instr {
     }
 
     fn encode_s2r(&mut self, op: &OpS2R) {
-        self.set_opcode(0x919);
-        self.set_dst(op.dst);
+        if dst_is_uniform(op.dst) {
+            self.set_opcode(0x9c3);
+            self.set_udst(op.dst);
+        } else {
+            self.set_opcode(0x919);
+            self.set_dst(op.dst);
+        }
         self.set_field(72..80, op.idx);
     }
 
@@ -2002,6 +2262,10 @@ impl SM70Instr {
             Op::FSet(op) => si.encode_fset(&op),
             Op::FSetP(op) => si.encode_fsetp(&op),
             Op::FSwzAdd(op) => si.encode_fswzadd(&op),
+            Op::HAdd2(op) => si.encode_hadd2(&op),
+            Op::HFma2(op) => si.encode_hfma2(&op),
+            Op::HMul2(op) => si.encode_hmul2(&op),
+            Op::HSet2(op) => si.encode_hset2(&op),
             Op::DAdd(op) => si.encode_dadd(&op),
             Op::DFma(op) => si.encode_dfma(&op),
             Op::DMul(op) => si.encode_dmul(&op),
@@ -2036,6 +2300,7 @@ impl SM70Instr {
             Op::Tmml(op) => si.encode_tmml(&op),
             Op::Txd(op) => si.encode_txd(&op),
             Op::Txq(op) => si.encode_txq(&op),
+            Op::Footprint(op) => si.encode_footprint(&op),
             Op::SuLd(op) => si.encode_suld(&op),
             Op::SuSt(op) => si.encode_sust(&op),
             Op::SuAtom(op) => si.encode_suatom(&op),
@@ -2078,14 +2343,35 @@ impl SM70Instr {
     }
 }
 
-impl Shader {
-    pub fn encode_sm70(&self) -> Vec<u32> {
-        assert!(self.functions.len() == 1);
-        let func = &self.functions[0];
+/// Where everything goes in the encoded shader.  Instruction addresses here
+/// are in dwords.
+struct SM70Layout {
+    labels: HashMap<Label, usize>,
+    /// Number of NOPs to insert in front of each block for alignment
+    padding: Vec<usize>,
+    /// Size of the whole shader in dwords
+    size: usize,
+}
+
+impl SM70Layout {
+    fn new(s: &Shader) -> SM70Layout {
+        assert!(s.functions.len() == 1);
+        let func = &s.functions[0];
+
+        let align = max(s.loop_header_align() / 4, 1);
+        let mut padding = Vec::new();
 
         let mut ip = 0_usize;
         let mut labels = HashMap::new();
-        for b in &func.blocks {
+        for (i, b) in func.blocks.iter().enumerate() {
+            let pad = if func.blocks.is_loop_header(i) {
+                (ip.next_multiple_of(align) - ip) / 4
+            } else {
+                0
+            };
+            padding.push(pad);
+            ip += pad * 4;
+
             labels.insert(b.label, ip);
             for instr in &b.instrs {
                 match &instr.op {
@@ -2100,18 +2386,288 @@ impl Shader {
             }
         }
 
-        let mut encoded = Vec::new();
-        for b in &func.blocks {
+        SM70Layout {
+            labels: labels,
+            padding: padding,
+            size: ip,
+        }
+    }
+}
+
+impl Shader {
+    pub fn encode_sm70(&self) -> Vec<u32> {
+        assert!(self.functions.len() == 1);
+        let func = &self.functions[0];
+
+        let layout = SM70Layout::new(self);
+        let labels = &layout.labels;
+
+        let mut nop = Instr::new(OpNop { label: None });
+        nop.deps.set_delay(MIN_INSTR_DELAY);
+
+        let mut encoded = Vec::with_capacity(layout.size);
+        for (i, b) in func.blocks.iter().enumerate() {
+            for _ in 0..layout.padding[i] {
+                let e = SM70Instr::encode(
+                    &nop,
+                    self.info.sm,
+                    encoded.len(),
+                    labels,
+                );
+                encoded.extend_from_slice(&e[..]);
+            }
+
             for instr in &b.instrs {
                 let e = SM70Instr::encode(
                     instr,
                     self.info.sm,
                     encoded.len(),
-                    &labels,
+                    labels,
                 );
                 encoded.extend_from_slice(&e[..]);
             }
         }
+
+        debug_assert!(encoded.len() == layout.size);
+
         encoded
     }
 }
+
+pub struct SM70Encoder;
+
+impl Encoder for SM70Encoder {
+    fn encode_shader(&self, s: &Shader) -> Vec<u32> {
+        s.encode_sm70()
+    }
+
+    fn encoded_size(&self, s: &Shader) -> usize {
+        SM70Layout::new(s).size * 4
+    }
+
+    fn instr_count(&self, code: &[u32]) -> usize {
+        code.len() / 4
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_op(op: impl Into<Op>, sm: u8) -> [u32; 4] {
+        let instr = Instr::new(op);
+        SM70Instr::encode(&instr, sm, 0, &HashMap::new())
+    }
+
+    fn f16x2_atom(dst: Dst, mem_space: MemSpace) -> OpAtom {
+        OpAtom {
+            dst: dst,
+            addr: RegRef::new(RegFile::GPR, 2, 2).into(),
+            cmpr: 0.into(),
+            data: RegRef::new(RegFile::GPR, 4, 1).into(),
+            atom_op: AtomOp::Add,
+            atom_type: AtomType::F16x2,
+            addr_offset: 0,
+            mem_space: mem_space,
+            mem_order: MemOrder::Strong(MemScope::CTA),
+            mem_eviction_priority: MemEvictionPriority::Normal,
+        }
+    }
+
+    #[test]
+    fn atomg_f16x2() {
+        let dst = RegRef::new(RegFile::GPR, 0, 1).into();
+        let atom = f16x2_atom(dst, MemSpace::Global(MemAddrType::A64));
+        let e = encode_op(atom, 70);
+        assert_eq!(e.get_bit_range_u64(0..12), 0x38a);
+        assert_eq!(e.get_bit_range_u64(73..76), 4);
+        assert_eq!(e.get_bit_range_u64(87..91), 0);
+    }
+
+    #[test]
+    fn atomg_f16x2_unused_dst() {
+        // There's no f16x2 RED so this has to stay an ATOMG
+        let atom = f16x2_atom(Dst::None, MemSpace::Global(MemAddrType::A64));
+        assert!(!atom.can_be_red());
+        let e = encode_op(atom, 70);
+        assert_eq!(e.get_bit_range_u64(0..12), 0x38a);
+        assert_eq!(e.get_bit_range_u64(16..24), 0xff);
+    }
+
+    #[test]
+    fn redg_u32() {
+        let mut atom =
+            f16x2_atom(Dst::None, MemSpace::Global(MemAddrType::A64));
+        atom.atom_type = AtomType::U32;
+        assert!(atom.can_be_red());
+        let e = encode_op(atom, 70);
+        assert_eq!(e.get_bit_range_u64(0..12), 0x98e);
+        assert_eq!(e.get_bit_range_u64(73..76), 0);
+    }
+
+    #[test]
+    fn atoms_f16x2() {
+        let dst = RegRef::new(RegFile::GPR, 0, 1).into();
+        let e = encode_op(f16x2_atom(dst, MemSpace::Shared), 70);
+        assert_eq!(e.get_bit_range_u64(0..12), 0x38c);
+        assert_eq!(e.get_bit_range_u64(73..76), 4);
+    }
+
+    fn gpr(idx: u32) -> RegRef {
+        RegRef::new(RegFile::GPR, idx, 1)
+    }
+
+    #[test]
+    fn hadd2_forms() {
+        let hadd2 = |src1: Src| OpHAdd2 {
+            dst: gpr(0).into(),
+            srcs: [gpr(1).into(), src1],
+            saturate: false,
+            ftz: false,
+            f32: true,
+        };
+
+        let e = encode_op(hadd2(gpr(2).into()), 70);
+        assert_eq!(e.get_bit_range_u64(0..12), 0x230);
+        assert_eq!(e.get_bit_range_u64(24..32), 1);
+        assert_eq!(e.get_bit_range_u64(32..40), 2);
+        assert_eq!(e.get_bit_range_u64(78..79), 1);
+
+        // A non-register addend goes in the src2 slot
+        let e = encode_op(hadd2(0x3c003c00.into()), 70);
+        assert_eq!(e.get_bit_range_u64(0..12), 0x430);
+        assert_eq!(e.get_bit_range_u64(32..64), 0x3c003c00);
+        assert_eq!(e.get_bit_range_u64(64..72), 0xff);
+    }
+
+    #[test]
+    fn hmul2_forms() {
+        let hmul2 = |src1: Src| OpHMul2 {
+            dst: gpr(0).into(),
+            srcs: [gpr(1).into(), src1],
+            saturate: false,
+            ftz: true,
+            dnz: true,
+        };
+
+        let e = encode_op(hmul2(gpr(2).into()), 70);
+        assert_eq!(e.get_bit_range_u64(0..12), 0x232);
+        assert_eq!(e.get_bit_range_u64(32..40), 2);
+        assert_eq!(e.get_bit_range_u64(76..77), 1);
+        assert_eq!(e.get_bit_range_u64(78..79), 0);
+        assert_eq!(e.get_bit_range_u64(80..81), 1);
+
+        // A non-register multiplicand stays in the src1 slot
+        let e = encode_op(hmul2(0x3c003c00.into()), 70);
+        assert_eq!(e.get_bit_range_u64(0..12), 0x832);
+        assert_eq!(e.get_bit_range_u64(32..64), 0x3c003c00);
+    }
+
+    #[test]
+    fn hset2_accum() {
+        let pred = RegRef::new(RegFile::Pred, 2, 1);
+        let hset2 = OpHSet2 {
+            dst: gpr(0).into(),
+            set_op: PredSetOp::Xor,
+            cmp_op: FloatCmpOp::OrdLt,
+            srcs: [gpr(1).into(), gpr(2).into()],
+            accum: Src::from(pred).bnot(),
+            ftz: false,
+        };
+        let e = encode_op(hset2, 70);
+        assert_eq!(e.get_bit_range_u64(0..12), 0x233);
+        assert_eq!(e.get_bit_range_u64(69..71), 2);
+        assert_eq!(e.get_bit_range_u64(76..80), 1);
+        assert_eq!(e.get_bit_range_u64(87..90), 2);
+        assert_eq!(e.get_bit_range_u64(90..91), 1);
+
+        let hset2 = OpHSet2 {
+            dst: gpr(0).into(),
+            set_op: PredSetOp::And,
+            cmp_op: FloatCmpOp::OrdLt,
+            srcs: [gpr(1).into(), gpr(2).into()],
+            accum: SrcRef::True.into(),
+            ftz: false,
+        };
+        let e = encode_op(hset2, 70);
+        assert_eq!(e.get_bit_range_u64(87..90), 7);
+        assert_eq!(e.get_bit_range_u64(90..91), 0);
+    }
+
+    fn footprint(lod_mode: TexLodMode) -> OpFootprint {
+        OpFootprint {
+            dsts: [gpr(0).into(), gpr(2).into()],
+            srcs: [gpr(4).into(), gpr(6).into()],
+            dim: TexDim::Array2D,
+            lod_mode: lod_mode,
+            coarse: true,
+            mask: 0xf,
+        }
+    }
+
+    #[test]
+    fn footprint_fields() {
+        let e = encode_op(footprint(TexLodMode::Lod), 75);
+        assert_eq!(e.get_bit_range_u64(0..12), 0x37d);
+        assert_eq!(e.get_bit_range_u64(59..60), 1);
+        assert_eq!(e.get_bit_range_u64(16..24), 0);
+        assert_eq!(e.get_bit_range_u64(64..72), 2);
+        assert_eq!(e.get_bit_range_u64(24..32), 4);
+        assert_eq!(e.get_bit_range_u64(32..40), 6);
+        assert_eq!(e.get_bit_range_u64(61..64), 5);
+        assert_eq!(e.get_bit_range_u64(72..76), 0xf);
+        assert_eq!(e.get_bit_range_u64(77..78), 1);
+        assert_eq!(e.get_bit_range_u64(78..79), 1);
+        assert_eq!(e.get_bit_range_u64(87..90), 3);
+    }
+
+    #[test]
+    fn footprint_ndv() {
+        for (lod_mode, ndv) in [
+            (TexLodMode::Auto, 0),
+            (TexLodMode::Bias, 0),
+            (TexLodMode::Zero, 1),
+            (TexLodMode::Lod, 1),
+        ] {
+            let e = encode_op(footprint(lod_mode), 75);
+            assert_eq!(e.get_bit_range_u64(77..78), ndv);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Footprint queries require SM75+")]
+    fn footprint_sm70() {
+        encode_op(footprint(TexLodMode::Auto), 70);
+    }
+
+    fn bindless_ldc() -> OpLdc {
+        let handle = RegRef::new(RegFile::UGPR, 4, 2);
+        OpLdc {
+            dst: RegRef::new(RegFile::GPR, 0, 1).into(),
+            cb: SrcRef::from(CBufRef {
+                buf: CBuf::BindlessGPR(handle),
+                offset: 0x30,
+            })
+            .into(),
+            offset: RegRef::new(RegFile::GPR, 2, 1).into(),
+            mem_type: MemType::B32,
+        }
+    }
+
+    #[test]
+    fn ldc_bindless() {
+        let e = encode_op(bindless_ldc(), 75);
+        assert_eq!(e.get_bit_range_u64(0..12), 0x582);
+        assert_eq!(e.get_bit_range_u64(16..24), 0);
+        assert_eq!(e.get_bit_range_u64(24..32), 2);
+        assert_eq!(e.get_bit_range_u64(38..54), 0x30);
+        assert_eq!(e.get_bit_range_u64(64..72), 4);
+        assert_eq!(e.get_bit_range_u64(73..76), 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "Bindless LDC requires SM75+")]
+    fn ldc_bindless_sm70() {
+        encode_op(bindless_ldc(), 70);
+    }
+}
@@ -6,18 +6,48 @@
 use crate::cfg::CFGBuilder;
 use crate::ir::*;
 use crate::nir::*;
+use crate::prologue::PrologueBuilder;
 use crate::sph::{OutputTopology, PixelImap};
 
 use nak_bindings::*;
 
-use std::cmp::max;
 use std::collections::{HashMap, HashSet};
 use std::ops::Index;
 
+/// Returns the number of per-patch attribute dwords a tessellation control
+/// shader writes, given a mask of the patch slots it writes relative to
+/// VARYING_SLOT_PATCH0.
+fn tcs_per_patch_attribute_count(patch_outputs_written: u32) -> u8 {
+    // The first six per-patch attributes are the outer and inner
+    // tessellation levels which the tessellator always reads.  Generic patch
+    // slots come after them, starting at NAK_ATTR_PATCH_START, and take up a
+    // vec4 each.
+    let count = if patch_outputs_written == 0 {
+        6
+    } else {
+        let num_slots = 32 - patch_outputs_written.leading_zeros();
+        u32::from(NAK_ATTR_PATCH_START) / 4 + num_slots * 4
+    };
+    count.try_into().unwrap()
+}
+
+/// Maps a NIR memory scope onto the scope of a memory barrier or a strong
+/// memory access.  Workgroup scope stays at GPU, as it always has been for
+/// barriers, to be conservative about global memory.
+fn mem_scope_from_nir(scope: mesa_scope) -> MemScope {
+    match scope {
+        SCOPE_INVOCATION | SCOPE_SUBGROUP => MemScope::CTA,
+        SCOPE_WORKGROUP | SCOPE_QUEUE_FAMILY | SCOPE_DEVICE => MemScope::GPU,
+        _ => panic!("Unhandled memory scope"),
+    }
+}
+
 fn init_info_from_nir(nir: &nir_shader, sm: u8) -> ShaderInfo {
     ShaderInfo {
         sm: sm,
         num_gprs: 0,
+        max_gprs: RegFile::GPR.num_regs(sm),
+        align_loops: false,
         num_barriers: 0,
         slm_size: nir.scratch_size,
         uses_global_mem: false,
@@ -60,8 +90,15 @@ fn init_info_from_nir(nir: &nir_shader, sm: u8) -> ShaderInfo {
             }
             MESA_SHADER_TESS_CTRL => {
                 let info_tess = unsafe { &nir.info.__bindgen_anon_1.tess };
+                assert!(
+                    info_tess.tcs_vertices_out > 0
+                        && info_tess.tcs_vertices_out <= 32,
+                    "Patches are limited to 32 invocations"
+                );
                 ShaderStageInfo::TessellationInit(TessellationInitShaderInfo {
-                    per_patch_attribute_count: 6,
+                    per_patch_attribute_count: tcs_per_patch_attribute_count(
+                        nir.info.patch_outputs_written,
+                    ),
                     threads_per_patch: info_tess.tcs_vertices_out,
                 })
             }
@@ -84,6 +121,8 @@ fn init_info_from_nir(nir: &nir_shader, sm: u8) -> ShaderInfo {
                 writes_color: 0,
                 writes_sample_mask: false,
                 writes_depth: false,
+                writes_depth_less: false,
+                writes_depth_greater: false,
                 // TODO: Should be set if interlocks are in use. (VK_EXT_fragment_shader_interlock)
                 does_interlock: false,
             }),
@@ -101,12 +140,148 @@ fn init_info_from_nir(nir: &nir_shader, sm: u8) -> ShaderInfo {
                 // TODO: figure out how to fill this.
                 store_req_start: u8::MAX,
                 store_req_end: 0,
+                xfb: if nir.info.stage() == MESA_SHADER_TESS_CTRL {
+                    None
+                } else {
+                    xfb_info_from_nir(nir)
+                },
             }),
             _ => panic!("Unknown shader stage"),
         },
     }
 }
 
+fn xfb_info_from_nir(nir: &nir_shader) -> Option<Box<XfbInfo>> {
+    let nir_xfb = unsafe { nir.xfb_info.as_ref() }?;
+
+    let mut xfb = XfbInfo {
+        stride: [0; 4],
+        stream: [0; 4],
+        attr_index: Default::default(),
+    };
+
+    for b in 0..4 {
+        if nir_xfb.buffers_written & (1 << b) != 0 {
+            xfb.stride[b] = nir_xfb.buffers[b].stride.into();
+            xfb.stream[b] = nir_xfb.buffer_to_stream[b];
+        }
+    }
+
+    let outputs =
+        unsafe { nir_xfb.outputs.as_slice(nir_xfb.output_count.into()) };
+    for out in outputs {
+        let b = usize::from(out.buffer);
+        assert!(nir_xfb.buffers_written & (1 << b) != 0);
+
+        let attr_addr = unsafe { nak_varying_attr_addr(out.location.into()) };
+        assert!(attr_addr % 4 == 0);
+        let attr_idx = attr_addr / 4;
+
+        assert!(out.offset % 4 == 0);
+        let mut out_idx = usize::from(out.offset / 4);
+
+        let attr_index = &mut xfb.attr_index[b];
+        for c in 0..4 {
+            if out.component_mask & (1 << c) == 0 {
+                continue;
+            }
+            if attr_index.len() <= out_idx {
+                // Anything we don't write is skipped
+                attr_index.resize(out_idx + 1, 0xff);
+            }
+            attr_index[out_idx] = (attr_idx + c).try_into().unwrap();
+            out_idx += 1;
+        }
+    }
+
+    Some(Box::new(xfb))
+}
+
+/// Returns the memory type for a bit-exact store of a whole texel if the
+/// texels of `format` are exactly the 32-bit channels of the store data
+fn image_binary_mem_type(format: pipe_format) -> Option<MemType> {
+    match format {
+        PIPE_FORMAT_R32_UINT | PIPE_FORMAT_R32_SINT | PIPE_FORMAT_R32_FLOAT => {
+            Some(MemType::B32)
+        }
+        PIPE_FORMAT_R32G32_UINT
+        | PIPE_FORMAT_R32G32_SINT
+        | PIPE_FORMAT_R32G32_FLOAT => Some(MemType::B64),
+        PIPE_FORMAT_R32G32B32A32_UINT
+        | PIPE_FORMAT_R32G32B32A32_SINT
+        | PIPE_FORMAT_R32G32B32A32_FLOAT => Some(MemType::B128),
+        _ => None,
+    }
+}
+
+/// Returns true if parse_intrinsic() handles the given intrinsic on this SM
+///
+/// This has to be kept in sync with the match in parse_intrinsic(), which
+/// asserts that every intrinsic it sees is in this list.
+pub fn intrinsic_is_supported(_sm: u8, intrin: nir_intrinsic_op) -> bool {
+    matches!(
+        intrin,
+        nir_intrinsic_al2p_nv
+            | nir_intrinsic_ald_nv
+            | nir_intrinsic_ast_nv
+            | nir_intrinsic_ballot
+            | nir_intrinsic_bar_break_nv
+            | nir_intrinsic_bar_set_nv
+            | nir_intrinsic_bar_sync_nv
+            | nir_intrinsic_barrier
+            | nir_intrinsic_bindless_image_atomic
+            | nir_intrinsic_bindless_image_atomic_swap
+            | nir_intrinsic_bindless_image_load
+            | nir_intrinsic_bindless_image_store
+            | nir_intrinsic_demote
+            | nir_intrinsic_demote_if
+            | nir_intrinsic_discard
+            | nir_intrinsic_discard_if
+            | nir_intrinsic_emit_vertex_nv
+            | nir_intrinsic_end_primitive_nv
+            | nir_intrinsic_final_primitive_nv
+            | nir_intrinsic_global_atomic
+            | nir_intrinsic_global_atomic_swap
+            | nir_intrinsic_ipa_nv
+            | nir_intrinsic_isberd_nv
+            | nir_intrinsic_ldtram_nv
+            | nir_intrinsic_load_barycentric_at_offset_nv
+            | nir_intrinsic_load_barycentric_centroid
+            | nir_intrinsic_load_barycentric_pixel
+            | nir_intrinsic_load_barycentric_sample
+            | nir_intrinsic_load_fully_covered
+            | nir_intrinsic_load_global
+            | nir_intrinsic_load_global_constant
+            | nir_intrinsic_load_sample_id
+            | nir_intrinsic_load_sample_mask_in
+            | nir_intrinsic_load_scratch
+            | nir_intrinsic_load_shared
+            | nir_intrinsic_load_sysval_nv
+            | nir_intrinsic_load_tess_coord_xy
+            | nir_intrinsic_load_ubo
+            | nir_intrinsic_quad_broadcast
+            | nir_intrinsic_quad_swap_diagonal
+            | nir_intrinsic_quad_swap_horizontal
+            | nir_intrinsic_quad_swap_vertical
+            | nir_intrinsic_read_invocation
+            | nir_intrinsic_shared_atomic
+            | nir_intrinsic_shared_atomic_swap
+            | nir_intrinsic_shuffle
+            | nir_intrinsic_shuffle_down
+            | nir_intrinsic_shuffle_up
+            | nir_intrinsic_shuffle_xor
+            | nir_intrinsic_store_global
+            | nir_intrinsic_store_output
+            | nir_intrinsic_store_scratch
+            | nir_intrinsic_store_shared
+            | nir_intrinsic_terminate
+            | nir_intrinsic_terminate_if
+            | nir_intrinsic_vote_all
+            | nir_intrinsic_vote_any
+            | nir_intrinsic_vote_ieq
+    )
+}
+
 fn alloc_ssa_for_nir(b: &mut impl SSABuilder, ssa: &nir_def) -> Vec<SSAValue> {
     let (file, comps) = if ssa.bit_size == 1 {
         (RegFile::Pred, ssa.num_components)
@@ -143,6 +318,56 @@ impl<'a> PhiAllocMap<'a> {
     }
 }
 
+fn block_ends_in_jump(nb: &nir_block) -> bool {
+    match nb.iter_instr_list().last() {
+        Some(ni) => ni.type_ == nir_instr_type_jump,
+        None => false,
+    }
+}
+
+/// Returns true if the list contains a continue for the loop it's in
+fn cf_list_has_continue(list: ExecListIter<nir_cf_node>) -> bool {
+    for node in list {
+        match node.type_ {
+            nir_cf_node_block => {
+                let nb = node.as_block().unwrap();
+                if let Some(ni) = nb.iter_instr_list().last() {
+                    if let Some(jump) = ni.as_jump() {
+                        if jump.type_ == nir_jump_continue {
+                            return true;
+                        }
+                    }
+                }
+            }
+            nir_cf_node_if => {
+                let ni = node.as_if().unwrap();
+                if cf_list_has_continue(ni.iter_then_list())
+                    || cf_list_has_continue(ni.iter_else_list())
+                {
+                    return true;
+                }
+            }
+            // Continues in an inner loop are for that loop
+            nir_cf_node_loop => (),
+            _ => panic!("Invalid inner CF node type"),
+        }
+    }
+    false
+}
+
+/// How a loop uses the SM50 control stack
+///
+/// Prior to Volta, divergent threads reconverge through a stack of tokens
+/// pushed by SSY, PBK, and PCNT and popped by SYNC, BRK, and CONT.
+struct LoopSync {
+    header: u32,
+    exit: u32,
+    /// Breaks are BRK and the loop is preceded by a PBK
+    brk: bool,
+    /// Back-edges are CONT and the header starts with a PCNT
+    cont: bool,
+}
+
 struct PerSizeFloatControls {
     pub ftz: bool,
     pub rnd_mode: FRndMode,
@@ -229,12 +454,16 @@ impl Index<FloatType> for ShaderFloatControls {
 
 struct ShaderFromNir<'a> {
     nir: &'a nir_shader,
+    fs_key: Option<&'a nak_fs_key>,
     info: ShaderInfo,
     float_ctl: ShaderFloatControls,
     cfg: CFGBuilder<u32, BasicBlock>,
     label_alloc: LabelAllocator,
     block_label: HashMap<u32, Label>,
     bar_label: HashMap<u32, Label>,
+    ssy_merge: HashSet<u32>,
+    loop_sync: Vec<LoopSync>,
+    loop_hints: HashMap<u32, LoopHints>,
     fs_out_regs: [SSAValue; 34],
     end_block_id: u32,
     ssa_map: HashMap<u32, Vec<SSAValue>>,
@@ -242,15 +471,23 @@ struct ShaderFromNir<'a> {
 }
 
 impl<'a> ShaderFromNir<'a> {
-    fn new(nir: &'a nir_shader, sm: u8) -> Self {
+    fn new(
+        nir: &'a nir_shader,
+        sm: u8,
+        fs_key: Option<&'a nak_fs_key>,
+    ) -> Self {
         Self {
             nir: nir,
+            fs_key: fs_key,
             info: init_info_from_nir(nir, sm),
             float_ctl: ShaderFloatControls::from_nir(nir),
             cfg: CFGBuilder::new(),
             label_alloc: LabelAllocator::new(),
             block_label: HashMap::new(),
             bar_label: HashMap::new(),
+            ssy_merge: HashSet::new(),
+            loop_sync: Vec::new(),
+            loop_hints: HashMap::new(),
             fs_out_regs: [SSAValue::NONE; 34],
             end_block_id: 0,
             ssa_map: HashMap::new(),
@@ -265,6 +502,29 @@ impl<'a> ShaderFromNir<'a> {
             .or_insert_with(|| self.label_alloc.alloc())
     }
 
+    /// Returns true if the two sides of the if need to be reconverged with
+    /// SSY and SYNC.  If either side ends in a jump, there's no merge and
+    /// the BRK or CONT of the loop takes care of it.
+    fn if_needs_ssy(&self, ni: &nir_if) -> bool {
+        let then_end = ni.iter_then_list().last().unwrap();
+        let else_end = ni.iter_else_list().last().unwrap();
+        self.info.sm < 70
+            && ni.condition.as_def().divergent
+            && !block_ends_in_jump(then_end.as_block().unwrap())
+            && !block_ends_in_jump(else_end.as_block().unwrap())
+    }
+
+    fn get_loop_sync(&self, nl: &nir_loop) -> LoopSync {
+        let cont = self.info.sm < 70 && cf_list_has_continue(nl.iter_body());
+        LoopSync {
+            header: nl.first_block().index,
+            exit: nl.following_block().index,
+            // Breaks also have to pop the PCNT token
+            brk: self.info.sm < 70 && (nl.divergent || cont),
+            cont: cont,
+        }
+    }
+
     fn get_ssa(&mut self, ssa: &nir_def) -> &[SSAValue] {
         self.ssa_map.get(&ssa.index).unwrap()
     }
@@ -330,6 +590,26 @@ impl<'a> ShaderFromNir<'a> {
         }
     }
 
+    /// Like get_io_addr_offset() but also folds in the base of a shared
+    /// memory intrinsic.  If the combined offset doesn't fit in the
+    /// instruction, the base is added into the address instead.
+    fn get_shared_addr_offset(
+        &mut self,
+        b: &mut impl SSABuilder,
+        addr: &nir_src,
+        base: i32,
+    ) -> (Src, i32) {
+        let (addr, offset) = self.get_io_addr_offset(addr, 24);
+        if let Some(folded) = offset.checked_add(base) {
+            if MemSpace::Shared.imm_offset_fits(self.info.sm, folded) {
+                return (addr, folded);
+            }
+        }
+
+        let addr = b.iadd(addr, (base as u32).into());
+        (addr.into(), offset)
+    }
+
     fn set_dst(&mut self, def: &nir_def, ssa: SSARef) {
         self.set_ssa(def, (*ssa).into());
     }
@@ -673,6 +953,16 @@ impl<'a> ShaderFromNir<'a> {
                         rnd_mode: self.float_ctl[ftype].rnd_mode,
                         ftz: self.float_ctl[ftype].ftz,
                     });
+                } else if alu.def.bit_size() == 16 {
+                    assert!(self.info.sm >= 70);
+                    dst = b.alloc_ssa(RegFile::GPR, 1);
+                    b.push_op(OpHAdd2 {
+                        dst: dst.into(),
+                        srcs: [x, y],
+                        saturate: self.try_saturate_alu_dst(&alu.def),
+                        ftz: self.float_ctl[ftype].ftz,
+                        f32: false,
+                    });
                 } else {
                     panic!("Unsupported float type: f{}", alu.def.bit_size());
                 }
@@ -748,6 +1038,29 @@ impl<'a> ShaderFromNir<'a> {
                         accum: SrcRef::True.into(),
                         ftz: self.float_ctl[src_type].ftz,
                     });
+                } else if alu.get_src(0).bit_size() == 16 {
+                    assert!(self.info.sm >= 70);
+                    let set = b.alloc_ssa(RegFile::GPR, 1);
+                    b.push_op(OpHSet2 {
+                        dst: set.into(),
+                        set_op: PredSetOp::And,
+                        cmp_op: cmp_op,
+                        srcs: [srcs[0], srcs[1]],
+                        accum: SrcRef::True.into(),
+                        ftz: self.float_ctl[src_type].ftz,
+                    });
+                    // Only the low half holds a real comparison result
+                    let lo = b.lop2(LogicOp2::And, set.into(), 0xffff.into());
+                    b.push_op(OpISetP {
+                        dst: dst.into(),
+                        set_op: PredSetOp::And,
+                        cmp_op: IntCmpOp::Ne,
+                        cmp_type: IntCmpType::U32,
+                        ex: false,
+                        srcs: [lo.into(), 0.into()],
+                        accum: SrcRef::True.into(),
+                        low_cmp: SrcRef::True.into(),
+                    });
                 } else {
                     panic!(
                         "Unsupported float type: f{}",
@@ -780,6 +1093,17 @@ impl<'a> ShaderFromNir<'a> {
                         ftz: self.float_ctl[ftype].ftz,
                         dnz: false,
                     });
+                } else if alu.def.bit_size() == 16 {
+                    assert!(self.info.sm >= 70);
+                    dst = b.alloc_ssa(RegFile::GPR, 1);
+                    b.push_op(OpHFma2 {
+                        dst: dst.into(),
+                        srcs: [srcs[0], srcs[1], srcs[2]],
+                        saturate: self.try_saturate_alu_dst(&alu.def),
+                        ftz: self.float_ctl[ftype].ftz,
+                        dnz: false,
+                        f32: false,
+                    });
                 } else {
                     panic!("Unsupported float type: f{}", alu.def.bit_size());
                 }
@@ -849,6 +1173,16 @@ impl<'a> ShaderFromNir<'a> {
                         ftz: self.float_ctl[ftype].ftz,
                         dnz: false,
                     });
+                } else if alu.def.bit_size() == 16 {
+                    assert!(self.info.sm >= 70);
+                    dst = b.alloc_ssa(RegFile::GPR, 1);
+                    b.push_op(OpHMul2 {
+                        dst: dst.into(),
+                        srcs: [srcs[0], srcs[1]],
+                        saturate: self.try_saturate_alu_dst(&alu.def),
+                        ftz: self.float_ctl[ftype].ftz,
+                        dnz: false,
+                    });
                 } else {
                     panic!("Unsupported float type: f{}", alu.def.bit_size());
                 }
@@ -1403,20 +1737,9 @@ impl<'a> ShaderFromNir<'a> {
                 }
             }
             nir_op_fddx | nir_op_fddx_coarse | nir_op_fddx_fine => {
-                // TODO: Real coarse derivatives
-
                 assert!(alu.def.bit_size() == 32);
                 let ftype = FloatType::F32;
-                let scratch = b.alloc_ssa(RegFile::GPR, 1);
-
-                b.push_op(OpShfl {
-                    dst: scratch[0].into(),
-                    in_bounds: Dst::None,
-                    src: srcs[0],
-                    lane: 1_u32.into(),
-                    c: (0x3_u32 | 0x1c_u32 << 8).into(),
-                    op: ShflOp::Bfly,
-                });
+                let scratch = b.shfl_quad(ShflOp::Bfly, srcs[0], 1_u32.into());
 
                 let dst = b.alloc_ssa(RegFile::GPR, 1);
 
@@ -1433,23 +1756,18 @@ impl<'a> ShaderFromNir<'a> {
                     ftz: self.float_ctl[ftype].ftz,
                 });
 
-                dst
+                if alu.op == nir_op_fddx_coarse {
+                    // Every lane in the quad gets the same derivative as
+                    // quad lane 0
+                    b.shfl_quad(ShflOp::Idx, dst.into(), 0_u32.into())
+                } else {
+                    dst
+                }
             }
             nir_op_fddy | nir_op_fddy_coarse | nir_op_fddy_fine => {
-                // TODO: Real coarse derivatives
-
                 assert!(alu.def.bit_size() == 32);
                 let ftype = FloatType::F32;
-                let scratch = b.alloc_ssa(RegFile::GPR, 1);
-
-                b.push_op(OpShfl {
-                    dst: scratch[0].into(),
-                    in_bounds: Dst::None,
-                    src: srcs[0],
-                    lane: 2_u32.into(),
-                    c: (0x3_u32 | 0x1c_u32 << 8).into(),
-                    op: ShflOp::Bfly,
-                });
+                let scratch = b.shfl_quad(ShflOp::Bfly, srcs[0], 2_u32.into());
 
                 let dst = b.alloc_ssa(RegFile::GPR, 1);
 
@@ -1466,15 +1784,33 @@ impl<'a> ShaderFromNir<'a> {
                     ftz: self.float_ctl[ftype].ftz,
                 });
 
-                dst
+                if alu.op == nir_op_fddy_coarse {
+                    // Every lane in the quad gets the same derivative as
+                    // quad lane 0
+                    b.shfl_quad(ShflOp::Idx, dst.into(), 0_u32.into())
+                } else {
+                    dst
+                }
             }
+            // Any op not handled above has to be lowered away before we get
+            // here, either by a lower_* flag set in nir_options() in api.rs
+            // or by nak_nir_lower_algebraic_late.  Those options are the list
+            // of what we don't support, so keep them in sync with this match.
             _ => panic!("Unsupported ALU instruction: {}", alu.info().name()),
         };
         self.set_dst(&alu.def, dst);
     }
 
-    fn parse_jump(&mut self, _b: &mut impl SSABuilder, _jump: &nir_jump_instr) {
-        // Nothing to do
+    fn parse_jump(&mut self, _b: &mut impl SSABuilder, jump: &nir_jump_instr) {
+        // The branch is emitted by parse_block() from the successor of the
+        // block.  For a break, that's the block after the loop and, for a
+        // continue, it's the loop header so the back-edge ends up in the CFG
+        // like any other.  Halt and return go to the end block and exit.
+        match jump.type_ {
+            nir_jump_break | nir_jump_continue | nir_jump_halt
+            | nir_jump_return => (),
+            _ => panic!("Unstructured control flow is not supported"),
+        }
     }
 
     fn parse_tex(&mut self, b: &mut impl SSABuilder, tex: &nir_tex_instr) {
@@ -1525,7 +1861,21 @@ impl<'a> ShaderFromNir<'a> {
         let flags: nak_nir_tex_flags =
             unsafe { std::mem::transmute_copy(&tex.backend_flags) };
 
-        let mask = tex.def.components_read();
+        // For sparse ops, the last component is the residency code which
+        // comes from the resident predicate rather than the texture unit.
+        let mut tex_comps = tex.def.num_components();
+        let mut mask = tex.def.components_read();
+        let resident = if tex.is_sparse {
+            tex_comps -= 1;
+            mask &= (1 << tex_comps) - 1;
+            // The op still has to write something
+            if mask == 0 {
+                mask = 1;
+            }
+            b.alloc_ssa(RegFile::Pred, 1).into()
+        } else {
+            Dst::None
+        };
         let mask = u8::try_from(mask).unwrap();
 
         let dst_comps = u8::try_from(mask.count_ones()).unwrap();
@@ -1576,13 +1926,24 @@ impl<'a> ShaderFromNir<'a> {
 
             let srcs = [self.get_src(&srcs[0].src), self.get_src(&srcs[1].src)];
 
-            if tex.op == nir_texop_txd {
+            if tex.op == nir_texop_sample_footprint_nv {
+                assert!(offset_mode == Tld4OffsetMode::None);
+                assert!(!flags.has_z_cmpr());
+                b.push_op(OpFootprint {
+                    dsts: dsts,
+                    srcs: srcs,
+                    dim: dim,
+                    lod_mode: lod_mode,
+                    coarse: tex.component() != 0,
+                    mask: mask,
+                });
+            } else if tex.op == nir_texop_txd {
                 assert!(lod_mode == TexLodMode::Auto);
                 assert!(offset_mode != Tld4OffsetMode::PerPx);
                 assert!(!flags.has_z_cmpr());
                 b.push_op(OpTxd {
                     dsts: dsts,
-                    resident: Dst::None,
+                    resident: resident,
                     srcs: srcs,
                     dim: dim,
                     offset: offset_mode == Tld4OffsetMode::AddOffI,
@@ -1597,10 +1958,12 @@ impl<'a> ShaderFromNir<'a> {
                     mask: mask,
                 });
             } else if tex.op == nir_texop_txf || tex.op == nir_texop_txf_ms {
+                // Per-pixel offsets only exist for TLD4.  nak_nir_lower_tex
+                // only ever uses them for tg4.
                 assert!(offset_mode != Tld4OffsetMode::PerPx);
                 b.push_op(OpTld {
                     dsts: dsts,
-                    resident: Dst::None,
+                    resident: resident,
                     srcs: srcs,
                     dim: dim,
                     lod_mode: lod_mode,
@@ -1611,7 +1974,7 @@ impl<'a> ShaderFromNir<'a> {
             } else if tex.op == nir_texop_tg4 {
                 b.push_op(OpTld4 {
                     dsts: dsts,
-                    resident: Dst::None,
+                    resident: resident,
                     srcs: srcs,
                     dim: dim,
                     comp: tex.component().try_into().unwrap(),
@@ -1623,7 +1986,7 @@ impl<'a> ShaderFromNir<'a> {
                 assert!(offset_mode != Tld4OffsetMode::PerPx);
                 b.push_op(OpTex {
                     dsts: dsts,
-                    resident: Dst::None,
+                    resident: resident,
                     srcs: srcs,
                     dim: dim,
                     lod_mode: lod_mode,
@@ -1634,16 +1997,27 @@ impl<'a> ShaderFromNir<'a> {
             }
         }
 
+        // The texture op only writes the components which are actually read
+        // so unread components never take up a register.  They still need
+        // an SSA value, though, so they all share a single zero which
+        // opt_dce() throws away since nothing uses it.
+        let mut unread = None;
         let mut di = 0_usize;
         let mut nir_dst = Vec::new();
-        for i in 0..tex.def.num_components() {
+        for i in 0..tex_comps {
             if mask & (1 << i) == 0 {
-                nir_dst.push(b.copy(0.into())[0]);
+                let zero = *unread.get_or_insert_with(|| b.copy(0.into())[0]);
+                nir_dst.push(zero);
             } else {
                 nir_dst.push(dst[di].into());
                 di += 1;
             }
         }
+        if let Dst::SSA(resident) = resident {
+            // See lower_sparse_residency() in nak_nir_lower_tex.c
+            let code = b.sel(resident.into(), 0.into(), 1.into());
+            nir_dst.push(code[0]);
+        }
         self.set_ssa(&tex.def.as_def(), nir_dst);
     }
 
@@ -1659,10 +2033,17 @@ impl<'a> ShaderFromNir<'a> {
             nir_atomic_op_ior => AtomType::U(bit_size),
             nir_atomic_op_ixor => AtomType::U(bit_size),
             nir_atomic_op_xchg => AtomType::U(bit_size),
+            nir_atomic_op_fadd | nir_atomic_op_fmin | nir_atomic_op_fmax
+                if bit_size == 16 =>
+            {
+                panic!("16-bit float atomics are not supported")
+            }
             nir_atomic_op_fadd => AtomType::F(bit_size),
             nir_atomic_op_fmin => AtomType::F(bit_size),
             nir_atomic_op_fmax => AtomType::F(bit_size),
             nir_atomic_op_cmpxchg => AtomType::U(bit_size),
+            nir_atomic_op_inc_wrap => AtomType::U(bit_size),
+            nir_atomic_op_dec_wrap => AtomType::U(bit_size),
             _ => panic!("Unsupported NIR atomic op"),
         }
     }
@@ -1682,21 +2063,62 @@ impl<'a> ShaderFromNir<'a> {
             nir_atomic_op_fmin => AtomOp::Min,
             nir_atomic_op_fmax => AtomOp::Max,
             nir_atomic_op_cmpxchg => AtomOp::CmpExch,
+            nir_atomic_op_inc_wrap => AtomOp::Inc,
+            nir_atomic_op_dec_wrap => AtomOp::Dec,
             _ => panic!("Unsupported NIR atomic op"),
         }
     }
 
+    /// Returns the cmpr and data sources for a compare-and-swap atomic.
+    ///
+    /// SM50 has no separate comparison source.  Instead, it takes the
+    /// comparison value and the new value as a single vector in consecutive
+    /// registers, comparison value first.
+    fn get_cas_srcs(&mut self, cmpr: &nir_src, data: &nir_src) -> (Src, Src) {
+        if self.info.sm >= 70 {
+            (self.get_src(cmpr), self.get_src(data))
+        } else {
+            let mut vec = Vec::new();
+            vec.extend_from_slice(self.get_ssa(cmpr.as_def()));
+            vec.extend_from_slice(self.get_ssa(data.as_def()));
+            (0.into(), SSARef::try_from(vec).unwrap().into())
+        }
+    }
+
     fn get_eviction_priority(
         &mut self,
         access: gl_access_qualifier,
     ) -> MemEvictionPriority {
-        if self.info.sm >= 70 && access & ACCESS_NON_TEMPORAL != 0 {
+        if access & ACCESS_NON_TEMPORAL != 0 {
             MemEvictionPriority::First
         } else {
             MemEvictionPriority::Normal
         }
     }
 
+    /// Returns the memory ordering for a global memory load or store.
+    ///
+    /// Per the Vulkan memory model, only coherent and volatile accesses
+    /// have to be strong.  Everything else can be weak and is made
+    /// available and visible by the barriers around it.  SPIR-V's Coherent
+    /// only promises coherence with the rest of the queue family so those
+    /// get GPU scope.  Volatile accesses may be observed by the host so
+    /// they have to go all the way to system scope.
+    ///
+    /// ACCESS_CAN_REORDER is deliberately not treated as MemOrder::Constant.
+    /// It only says that NIR may move the load around, not that nothing
+    /// writes the memory while the shader runs, and .CONSTANT loads go
+    /// through a cache which isn't kept coherent with stores.
+    fn get_global_mem_order(&self, access: gl_access_qualifier) -> MemOrder {
+        if (access & ACCESS_VOLATILE) != 0 {
+            MemOrder::Strong(MemScope::System)
+        } else if (access & ACCESS_COHERENT) != 0 {
+            MemOrder::Strong(mem_scope_from_nir(SCOPE_QUEUE_FAMILY))
+        } else {
+            MemOrder::Weak
+        }
+    }
+
     fn get_image_dim(&mut self, intrin: &nir_intrinsic_instr) -> ImageDim {
         let is_array = intrin.image_array();
         let image_dim = intrin.image_dim();
@@ -1744,6 +2166,7 @@ impl<'a> ShaderFromNir<'a> {
         b: &mut impl SSABuilder,
         intrin: &nir_intrinsic_instr,
     ) {
+        debug_assert!(intrinsic_is_supported(self.info.sm, intrin.intrinsic));
         let srcs = intrin.srcs_as_slice();
         match intrin.intrinsic {
             nir_intrinsic_al2p_nv => {
@@ -1786,9 +2209,12 @@ impl<'a> ShaderFromNir<'a> {
                         match &mut self.info.stage {
                             ShaderStageInfo::TessellationInit(stage) => {
                                 assert!(flags.output());
-                                stage.per_patch_attribute_count = max(
-                                    stage.per_patch_attribute_count,
-                                    (range.end / 4).try_into().unwrap(),
+                                assert!(
+                                    range.end / 4
+                                        <= stage
+                                            .per_patch_attribute_count
+                                            .into(),
+                                    "Patch output not in patch_outputs_written"
                                 );
                             }
                             ShaderStageInfo::Tessellation => (),
@@ -1863,12 +2289,13 @@ impl<'a> ShaderFromNir<'a> {
             nir_intrinsic_bar_break_nv => {
                 let src = self.get_src(&srcs[0]);
                 let bar_in = b.bmov_to_bar(src);
+                let cond = self.get_src(&srcs[1]);
 
                 let bar_out = b.alloc_ssa(RegFile::Bar, 1);
                 b.push_op(OpBreak {
                     bar_out: bar_out.into(),
                     bar_in: bar_in.into(),
-                    cond: SrcRef::True.into(),
+                    cond: cond,
                 });
 
                 self.set_dst(&intrin.def, b.bmov_to_gpr(bar_out.into()));
@@ -1967,10 +2394,17 @@ impl<'a> ShaderFromNir<'a> {
                 let coord = self.get_image_coord(intrin, dim);
                 // let sample = self.get_src(&srcs[2]);
 
+                // The encoders only take R, RG, and RGBA masks.  NIR always
+                // gives us a vec4 and nak_nir_lower_tex() only ever shrinks
+                // it to a power of two, padding the result back out itself.
                 let comps = u8::try_from(intrin.num_components).unwrap();
                 assert!(intrin.def.bit_size() == 32);
                 assert!(comps == 1 || comps == 2 || comps == 4);
 
+                // Every GPU we support has typed SULD and SUST, which convert
+                // according to the format in the image descriptor.  Loads
+                // and stores without a format therefore need no lowering and
+                // the format never has to be known at compile time.
                 let dst = b.alloc_ssa(RegFile::GPR, comps);
 
                 b.push_op(OpSuLd {
@@ -1997,12 +2431,25 @@ impl<'a> ShaderFromNir<'a> {
                 assert!(srcs[3].bit_size() == 32);
                 assert!(comps == 1 || comps == 2 || comps == 4);
 
+                // When the format is known and there's nothing to convert,
+                // store the data raw.  Stores without a format go through
+                // the formatted path which uses the format from the
+                // descriptor.
+                let data_type = MemType::from_size(comps * 4, false);
+                let image_access = match image_binary_mem_type(intrin.format())
+                {
+                    Some(mem_type) if b.sm() >= 70 && mem_type == data_type => {
+                        ImageAccess::Binary(mem_type)
+                    }
+                    _ => ImageAccess::Formatted((1 << comps) - 1),
+                };
+
                 b.push_op(OpSuSt {
+                    image_access: image_access,
                     image_dim: dim,
                     mem_order: MemOrder::Strong(MemScope::System),
                     mem_eviction_priority: self
                         .get_eviction_priority(intrin.access()),
-                    mask: (1 << comps) - 1,
                     handle: handle,
                     coord: coord,
                     data: data,
@@ -2033,7 +2480,16 @@ impl<'a> ShaderFromNir<'a> {
                 let cond = self.get_ssa(&srcs[0].as_def())[0];
                 b.predicate(cond.into()).push_op(OpKill {});
 
-                if intrin.intrinsic == nir_intrinsic_terminate_if {
+                // If the condition is uniform, either every active lane is
+                // killed or none are.  Exiting right away lets the warp skip
+                // the rest of the shader instead of running it with nothing
+                // left alive.  Demoted lanes have to stick around as helpers
+                // for lanes which may be inactive right now so demote_if is
+                // left to nak_nir_exit_demoted_warps().
+                if intrin.intrinsic == nir_intrinsic_terminate_if
+                    || (intrin.intrinsic == nir_intrinsic_discard_if
+                        && !srcs[0].as_def().divergent)
+                {
                     b.predicate(cond.into()).push_op(OpExit {});
                 }
             }
@@ -2065,8 +2521,7 @@ impl<'a> ShaderFromNir<'a> {
                 assert!(intrin.atomic_op() == nir_atomic_op_cmpxchg);
                 let bit_size = intrin.def.bit_size();
                 let (addr, offset) = self.get_io_addr_offset(&srcs[0], 24);
-                let cmpr = self.get_src(&srcs[1]);
-                let data = self.get_src(&srcs[2]);
+                let (cmpr, data) = self.get_cas_srcs(&srcs[1], &srcs[2]);
                 let atom_type = AtomType::U(bit_size);
 
                 assert!(intrin.def.num_components() == 1);
@@ -2156,6 +2611,23 @@ impl<'a> ShaderFromNir<'a> {
             nir_intrinsic_load_barycentric_centroid => (),
             nir_intrinsic_load_barycentric_pixel => (),
             nir_intrinsic_load_barycentric_sample => (),
+            nir_intrinsic_load_fully_covered => {
+                // The inner coverage is only non-zero when the primitive
+                // covers the whole pixel, which is what underestimate
+                // conservative rasterization asks for.
+                let cov = b.alloc_ssa(RegFile::GPR, 1);
+                b.push_op(OpPixLd {
+                    dst: cov.into(),
+                    val: PixVal::InnerCoverage,
+                });
+                let dst = b.isetp(
+                    IntCmpType::U32,
+                    IntCmpOp::Ne,
+                    cov.into(),
+                    0.into(),
+                );
+                self.set_dst(&intrin.def, dst);
+            }
             nir_intrinsic_load_global | nir_intrinsic_load_global_constant => {
                 let size_B =
                     (intrin.def.bit_size() / 8) * intrin.def.num_components();
@@ -2164,7 +2636,7 @@ impl<'a> ShaderFromNir<'a> {
                     if intrin.intrinsic == nir_intrinsic_load_global_constant {
                         MemOrder::Constant
                     } else {
-                        MemOrder::Strong(MemScope::System)
+                        self.get_global_mem_order(intrin.access())
                     };
                 let access = MemAccess {
                     mem_type: MemType::from_size(size_B, false),
@@ -2173,9 +2645,27 @@ impl<'a> ShaderFromNir<'a> {
                     eviction_priority: self
                         .get_eviction_priority(intrin.access()),
                 };
-                let (addr, offset) = self.get_io_addr_offset(&srcs[0], 32);
+                let (addr, offset) = self.get_io_addr_offset(
+                    &srcs[0],
+                    access.space.imm_offset_bits(self.info.sm),
+                );
                 let dst = b.alloc_ssa(RegFile::GPR, size_B.div_ceil(4));
 
+                // It's tempting to turn constant loads through a pointer
+                // which itself lives in a cbuf into bindless cbuf loads.
+                // However, a bindless cbuf is bounds-checked against a size
+                // which is part of the handle and a global pointer doesn't
+                // come with one.  Bindless LDC also needs SM75+ and RA
+                // doesn't assign CBuf::BindlessSSA yet.  Until the driver
+                // hands us cbuf handles rather than raw addresses, LDG with
+                // MemOrder::Constant is the best we can do.
+                //
+                // We also don't bother scalarizing loads with a uniform
+                // address into a single lane plus a broadcast.  The LSU
+                // already merges requests from lanes which hit the same
+                // address so all that would buy us is an extra branch and
+                // SHFL.  The uniform datapath on SM75+ has no global loads,
+                // only LDC, so there's nothing to gain there either.
                 b.push_op(OpLd {
                     dst: dst.into(),
                     addr: addr,
@@ -2306,8 +2796,8 @@ impl<'a> ShaderFromNir<'a> {
                     order: MemOrder::Strong(MemScope::CTA),
                     eviction_priority: MemEvictionPriority::Normal,
                 };
-                let (addr, offset) = self.get_io_addr_offset(&srcs[0], 24);
-                let offset = offset + intrin.base();
+                let (addr, offset) =
+                    self.get_shared_addr_offset(b, &srcs[0], intrin.base());
                 let dst = b.alloc_ssa(RegFile::GPR, size_B.div_ceil(4));
 
                 b.push_op(OpLd {
@@ -2379,8 +2869,11 @@ impl<'a> ShaderFromNir<'a> {
             nir_intrinsic_barrier => {
                 let modes = intrin.memory_modes();
                 let semantics = intrin.memory_semantics();
-                if (modes & nir_var_mem_global) != 0
+                // Before Volta, L1 is write-through for global memory so
+                // there's nothing to write back on release.
+                if (modes & (nir_var_mem_global | nir_var_mem_ssbo)) != 0
                     && (semantics & NIR_MEMORY_RELEASE) != 0
+                    && self.info.sm >= 70
                 {
                     b.push_op(OpCCtl {
                         op: CCtlOp::WBAll,
@@ -2392,26 +2885,32 @@ impl<'a> ShaderFromNir<'a> {
                 match intrin.execution_scope() {
                     SCOPE_NONE => (),
                     SCOPE_WORKGROUP => {
+                        // For tessellation control, the workgroup is a patch
+                        // and BAR.SYNC waits on the whole patch so outputs
+                        // written by one invocation can be read back from
+                        // the others with ALD.O.
                         assert!(
                             self.nir.info.stage() == MESA_SHADER_COMPUTE
                                 || self.nir.info.stage() == MESA_SHADER_KERNEL
+                                || self.nir.info.stage()
+                                    == MESA_SHADER_TESS_CTRL
                         );
                         self.info.num_barriers = 1;
                         b.push_op(OpBar {});
                     }
                     _ => panic!("Unhandled execution scope"),
                 }
-                if intrin.memory_scope() != SCOPE_NONE {
-                    let mem_scope = match intrin.memory_scope() {
-                        SCOPE_INVOCATION | SCOPE_SUBGROUP => MemScope::CTA,
-                        SCOPE_WORKGROUP | SCOPE_QUEUE_FAMILY | SCOPE_DEVICE => {
-                            MemScope::GPU
-                        }
-                        _ => panic!("Unhandled memory scope"),
-                    };
-                    b.push_op(OpMemBar { scope: mem_scope });
+                // Tessellation control outputs live in the attribute buffer
+                // rather than in memory and BAR.SYNC alone is enough to make
+                // ALD.O see the other invocations' AST.O.
+                if intrin.memory_scope() != SCOPE_NONE
+                    && modes != nir_var_shader_out
+                {
+                    b.push_op(OpMemBar {
+                        scope: mem_scope_from_nir(intrin.memory_scope()),
+                    });
                 }
-                if (modes & nir_var_mem_global) != 0
+                if (modes & (nir_var_mem_global | nir_var_mem_ssbo)) != 0
                     && (semantics & NIR_MEMORY_ACQUIRE) != 0
                 {
                     b.push_op(OpCCtl {
@@ -2422,8 +2921,19 @@ impl<'a> ShaderFromNir<'a> {
                     });
                 }
             }
-            nir_intrinsic_quad_broadcast
-            | nir_intrinsic_read_invocation
+            nir_intrinsic_quad_broadcast => {
+                assert!(srcs[0].bit_size() == 32);
+                assert!(srcs[0].num_components() == 1);
+                let data = self.get_src(&srcs[0]);
+
+                assert!(srcs[1].bit_size() == 32);
+                let idx = self.get_src(&srcs[1]);
+
+                assert!(intrin.def.bit_size() == 32);
+                let dst = b.shfl_quad(ShflOp::Idx, data, idx);
+                self.set_dst(&intrin.def, dst);
+            }
+            nir_intrinsic_read_invocation
             | nir_intrinsic_shuffle
             | nir_intrinsic_shuffle_down
             | nir_intrinsic_shuffle_up
@@ -2444,7 +2954,6 @@ impl<'a> ShaderFromNir<'a> {
                     src: data,
                     lane: idx,
                     c: match intrin.intrinsic {
-                        nir_intrinsic_quad_broadcast => 0x1c_03.into(),
                         nir_intrinsic_shuffle_up => 0.into(),
                         _ => 0x1f.into(),
                     },
@@ -2465,25 +2974,19 @@ impl<'a> ShaderFromNir<'a> {
                 let data = self.get_src(&srcs[0]);
 
                 assert!(intrin.def.bit_size() == 32);
-                let dst = b.alloc_ssa(RegFile::GPR, 1);
-                b.push_op(OpShfl {
-                    dst: dst.into(),
-                    in_bounds: Dst::None,
-                    src: data,
-                    lane: match intrin.intrinsic {
-                        nir_intrinsic_quad_swap_horizontal => 1_u32.into(),
-                        nir_intrinsic_quad_swap_vertical => 2_u32.into(),
-                        nir_intrinsic_quad_swap_diagonal => 3_u32.into(),
-                        op => panic!("Unknown quad intrinsic {}", op),
-                    },
-                    c: 0x1c_03.into(),
-                    op: ShflOp::Bfly,
-                });
+                let lane = match intrin.intrinsic {
+                    nir_intrinsic_quad_swap_horizontal => 1_u32,
+                    nir_intrinsic_quad_swap_vertical => 2_u32,
+                    nir_intrinsic_quad_swap_diagonal => 3_u32,
+                    op => panic!("Unknown quad intrinsic {}", op),
+                };
+                let dst = b.shfl_quad(ShflOp::Bfly, data, lane.into());
                 self.set_dst(&intrin.def, dst);
             }
             nir_intrinsic_shared_atomic => {
                 let bit_size = intrin.def.bit_size();
-                let (addr, offset) = self.get_io_addr_offset(&srcs[0], 24);
+                let (addr, offset) =
+                    self.get_shared_addr_offset(b, &srcs[0], intrin.base());
                 let data = self.get_src(&srcs[1]);
                 let atom_type = self.get_atomic_type(intrin);
                 let atom_op = self.get_atomic_op(intrin);
@@ -2508,9 +3011,9 @@ impl<'a> ShaderFromNir<'a> {
             nir_intrinsic_shared_atomic_swap => {
                 assert!(intrin.atomic_op() == nir_atomic_op_cmpxchg);
                 let bit_size = intrin.def.bit_size();
-                let (addr, offset) = self.get_io_addr_offset(&srcs[0], 24);
-                let cmpr = self.get_src(&srcs[1]);
-                let data = self.get_src(&srcs[2]);
+                let (addr, offset) =
+                    self.get_shared_addr_offset(b, &srcs[0], intrin.base());
+                let (cmpr, data) = self.get_cas_srcs(&srcs[1], &srcs[2]);
                 let atom_type = AtomType::U(bit_size);
 
                 assert!(intrin.def.num_components() == 1);
@@ -2538,11 +3041,14 @@ impl<'a> ShaderFromNir<'a> {
                 let access = MemAccess {
                     mem_type: MemType::from_size(size_B, false),
                     space: MemSpace::Global(MemAddrType::A64),
-                    order: MemOrder::Strong(MemScope::System),
+                    order: self.get_global_mem_order(intrin.access()),
                     eviction_priority: self
                         .get_eviction_priority(intrin.access()),
                 };
-                let (addr, offset) = self.get_io_addr_offset(&srcs[1], 32);
+                let (addr, offset) = self.get_io_addr_offset(
+                    &srcs[1],
+                    access.space.imm_offset_bits(self.info.sm),
+                );
 
                 b.push_op(OpSt {
                     addr: addr,
@@ -2598,8 +3104,8 @@ impl<'a> ShaderFromNir<'a> {
                     order: MemOrder::Strong(MemScope::CTA),
                     eviction_priority: MemEvictionPriority::Normal,
                 };
-                let (addr, offset) = self.get_io_addr_offset(&srcs[1], 24);
-                let offset = offset + intrin.base();
+                let (addr, offset) =
+                    self.get_shared_addr_offset(b, &srcs[1], intrin.base());
 
                 b.push_op(OpSt {
                     addr: addr,
@@ -2745,7 +3251,15 @@ impl<'a> ShaderFromNir<'a> {
             return;
         };
 
+        let color_write_disable =
+            self.fs_key.map_or(0, |key| key.color_write_disable);
         for i in 0..32 {
+            // Components which are never written aren't exported and
+            // opt_dce() then throws away whatever computed them.
+            if color_write_disable & (1 << i) != 0 {
+                self.fs_out_regs[i] = SSAValue::NONE;
+            }
+
             // Assume that colors have to come a vec4 at a time
             if !self.fs_out_regs[i].is_none() {
                 info.writes_color |= 0xf << (i & !3)
@@ -2756,6 +3270,20 @@ impl<'a> ShaderFromNir<'a> {
         let depth_idx = (NAK_FS_OUT_DEPTH / 4) as usize;
         info.writes_depth = !self.fs_out_regs[depth_idx].is_none();
 
+        // A depth layout qualifier promises which way the depth moves
+        let depth_layout =
+            unsafe { self.nir.info.__bindgen_anon_1.fs.depth_layout() };
+        info.writes_depth_less = info.writes_depth
+            && !matches!(
+                depth_layout,
+                FRAG_DEPTH_LAYOUT_GREATER | FRAG_DEPTH_LAYOUT_UNCHANGED
+            );
+        info.writes_depth_greater = info.writes_depth
+            && !matches!(
+                depth_layout,
+                FRAG_DEPTH_LAYOUT_LESS | FRAG_DEPTH_LAYOUT_UNCHANGED
+            );
+
         let mut srcs = Vec::new();
         for i in 0..32 {
             if info.writes_color & (1 << i) != 0 {
@@ -2775,20 +3303,24 @@ impl<'a> ShaderFromNir<'a> {
                 srcs.push(0.into());
             }
             if info.writes_depth {
-                // Saturate depth writes.
-                //
-                // TODO: This seems wrong in light of unrestricted depth but
-                // it's needed to pass CTS tests for now.
+                // Written depth gets clamped to [0, 1] unless the driver
+                // allows an unrestricted depth range.
                 let depth = self.fs_out_regs[depth_idx];
-                let sat_depth = b.alloc_ssa(RegFile::GPR, 1);
-                b.push_op(OpFAdd {
-                    dst: sat_depth.into(),
-                    srcs: [depth.into(), 0.into()],
-                    saturate: true,
-                    rnd_mode: FRndMode::NearestEven,
-                    ftz: false,
-                });
-                srcs.push(sat_depth.into());
+                let unrestricted =
+                    self.fs_key.map_or(false, |key| key.depth_unrestricted);
+                if unrestricted {
+                    srcs.push(depth.into());
+                } else {
+                    let sat_depth = b.alloc_ssa(RegFile::GPR, 1);
+                    b.push_op(OpFAdd {
+                        dst: sat_depth.into(),
+                        srcs: [depth.into(), 0.into()],
+                        saturate: true,
+                        rnd_mode: FRndMode::NearestEven,
+                        ftz: false,
+                    });
+                    srcs.push(sat_depth.into());
+                }
             }
         }
 
@@ -2803,31 +3335,8 @@ impl<'a> ShaderFromNir<'a> {
     ) {
         let mut b = SSAInstrBuilder::new(self.info.sm, ssa_alloc);
 
-        if nb.index == 0 && self.nir.info.shared_size > 0 {
-            // The blob seems to always do a BSYNC before accessing shared
-            // memory.  Perhaps this is to ensure that our allocation is
-            // actually available and not in use by another thread?
-            let label = self.label_alloc.alloc();
-            let bar_clear = b.alloc_ssa(RegFile::Bar, 1);
-
-            b.push_op(OpBClear {
-                dst: bar_clear.into(),
-            });
-
-            let bar = b.alloc_ssa(RegFile::Bar, 1);
-            b.push_op(OpBSSy {
-                bar_out: bar.into(),
-                bar_in: bar_clear.into(),
-                cond: SrcRef::True.into(),
-                target: label,
-            });
-
-            b.push_op(OpBSync {
-                bar: bar.into(),
-                cond: SrcRef::True.into(),
-            });
-
-            b.push_op(OpNop { label: Some(label) });
+        if nb.index == 0 {
+            PrologueBuilder::new(self.nir).emit(&mut b, &mut self.label_alloc);
         }
 
         let mut phi = OpPhiDsts::new();
@@ -2849,6 +3358,15 @@ impl<'a> ShaderFromNir<'a> {
             b.push_op(phi);
         }
 
+        if let Some(ls) = self.loop_sync.last() {
+            if ls.cont && ls.header == nb.index {
+                // Every back-edge is a CONT which lands here and pushes the
+                // token for the next iteration.
+                let target = self.get_block_label(nb);
+                b.push_op(OpPCnt { target: target });
+            }
+        }
+
         for ni in nb.iter_instr_list() {
             match ni.type_ {
                 nir_instr_type_alu => {
@@ -2874,6 +3392,22 @@ impl<'a> ShaderFromNir<'a> {
             }
         }
 
+        // The reconvergence point has to be on the stack before we diverge
+        // and, for loops, ahead of the phi sources for the header.
+        if let Some(ni) = nb.following_if() {
+            if self.if_needs_ssy(ni) {
+                let merge = ni.following_block();
+                self.ssy_merge.insert(merge.index);
+                let target = self.get_block_label(merge);
+                b.push_op(OpSSy { target: target });
+            }
+        } else if let Some(nl) = nb.cf_node.next().and_then(|n| n.as_loop()) {
+            if self.get_loop_sync(nl).brk {
+                let target = self.get_block_label(nl.following_block());
+                b.push_op(OpPBk { target: target });
+            }
+        }
+
         let succ = nb.successors();
         for sb in succ {
             let sb = match sb {
@@ -2930,13 +3464,23 @@ impl<'a> ShaderFromNir<'a> {
                 b.push_op(OpExit {});
             } else {
                 self.cfg.add_edge(nb.index, s0.index);
-                b.push_op(OpBra {
-                    target: self.get_block_label(s0),
-                });
+                let target = self.get_block_label(s0);
+                let ls = self.loop_sync.last();
+                if ls.is_some_and(|ls| ls.brk && ls.exit == s0.index) {
+                    b.push_op(OpBrk { target: target });
+                } else if ls.is_some_and(|ls| ls.cont && ls.header == s0.index)
+                {
+                    b.push_op(OpCont { target: target });
+                } else if self.ssy_merge.contains(&s0.index) {
+                    b.push_op(OpSync { target: target });
+                } else {
+                    b.push_op(OpBra { target: target });
+                }
             }
         }
 
         let mut bb = BasicBlock::new(self.get_block_label(nb));
+        bb.loop_hints = self.loop_hints.remove(&nb.index);
         bb.instrs.append(&mut b.as_vec());
         self.cfg.add_node(nb.index, bb);
     }
@@ -2957,7 +3501,32 @@ impl<'a> ShaderFromNir<'a> {
         phi_map: &mut PhiAllocMap<'b>,
         nl: &nir_loop,
     ) {
+        let mut hints = LoopHints {
+            unroll: match nl.control {
+                nir_loop_control_unroll => LoopUnrollHint::Unroll,
+                nir_loop_control_dont_unroll => LoopUnrollHint::DontUnroll,
+                _ => LoopUnrollHint::None,
+            },
+            ..Default::default()
+        };
+        if let Some(info) = nl.loop_info() {
+            if info.exact_trip_count_known || info.max_trip_count > 0 {
+                hints.max_trip_count = Some(info.max_trip_count);
+                hints.exact_trip_count = info.exact_trip_count_known;
+            }
+            if info.guessed_trip_count > 0 {
+                hints.guessed_trip_count = Some(info.guessed_trip_count);
+            }
+            if info.force_unroll {
+                hints.unroll = LoopUnrollHint::Unroll;
+            }
+        }
+        self.loop_hints.insert(nl.first_block().index, hints);
+
+        let ls = self.get_loop_sync(nl);
+        self.loop_sync.push(ls);
         self.parse_cf_list(ssa_alloc, phi_map, nl.iter_body());
+        self.loop_sync.pop();
     }
 
     fn parse_cf_list<'b>(
@@ -3034,10 +3603,15 @@ impl<'a> ShaderFromNir<'a> {
         Shader {
             info: self.info,
             functions: functions,
+            warnings: Vec::new(),
         }
     }
 }
 
-pub fn nak_shader_from_nir(ns: &nir_shader, sm: u8) -> Shader {
-    ShaderFromNir::new(ns, sm).parse_shader()
+pub fn nak_shader_from_nir(
+    ns: &nir_shader,
+    sm: u8,
+    fs_key: Option<&nak_fs_key>,
+) -> Shader {
+    ShaderFromNir::new(ns, sm, fs_key).parse_shader()
 }
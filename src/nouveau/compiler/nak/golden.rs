@@ -0,0 +1,265 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+//! Golden output tracking for NAK
+//!
+//! If NAK_GOLDEN_DIR is set, every shader we compile is recorded in that
+//! directory along with the final IR and the encoded binary.  Shaders are
+//! identified by a hash of everything handed to nak_compile_shader() which
+//! affects the output, including the NIR.  That input is stored next to the
+//! output so the corpus can be fed back through the compiler without the
+//! application that produced it.  The NAK IR straight out of from_nir is also
+//! stored so the corpus stays readable.  The next time the same shader is
+//! compiled, the output is compared against what was recorded and any
+//! difference is reported on stderr.
+//!
+//! Because the key only depends on what the driver gives us, changes to NAK's
+//! own NIR lowering or to from_nir show up as a diff in the input IR section
+//! rather than as a new shader.
+//!
+//! The intended workflow is to build a corpus with a known-good tree by
+//! running whatever applications or test suites are interesting, then apply a
+//! change and either run them again or run the replay_corpus unit test with
+//! the same NAK_GOLDEN_DIR.  Every shader whose output changed is listed,
+//! together with the first differing lines.  Set NAK_GOLDEN_UPDATE=1 to
+//! overwrite the recorded output instead of comparing against it.
+
+use nak_bindings::*;
+
+use std::env;
+use std::ffi::c_void;
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of differing lines to print for each mismatch
+const MAX_DIFF_LINES: usize = 8;
+
+/// Number of shaders whose output didn't match what was recorded
+static MISMATCHES: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of shaders whose output was recorded rather than checked
+static RECORDED: AtomicUsize = AtomicUsize::new(0);
+
+fn golden_dir() -> Option<PathBuf> {
+    env::var_os("NAK_GOLDEN_DIR").map(PathBuf::from)
+}
+
+pub fn golden_enabled() -> bool {
+    golden_dir().is_some()
+}
+
+/// 64-bit FNV-1a.  Unlike the hashers in std, this is guaranteed to be
+/// stable across Rust versions, which matters for keys written to disk.
+fn fnv1a64(data: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325_u64;
+    for b in data {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn golden_text(input_ir: &str, final_ir: &str, code: &[u32]) -> String {
+    let mut s = String::new();
+    writeln!(s, "# Input IR\n{input_ir}").unwrap();
+    writeln!(s, "# Final IR\n{final_ir}").unwrap();
+    writeln!(s, "# Encoded").unwrap();
+    for dws in code.chunks(8) {
+        for dw in dws {
+            write!(s, " {dw:08x}").unwrap();
+        }
+        writeln!(s).unwrap();
+    }
+    s
+}
+
+fn eprint_diff(expected: &str, actual: &str) {
+    let mut printed = 0;
+    let mut exp_lines = expected.lines();
+    let mut act_lines = actual.lines();
+    for line in 1.. {
+        let (exp, act) = (exp_lines.next(), act_lines.next());
+        if exp.is_none() && act.is_none() {
+            break;
+        }
+        if exp == act {
+            continue;
+        }
+        if printed == MAX_DIFF_LINES {
+            eprintln!("    ...");
+            break;
+        }
+        eprintln!("    {line}: -{}", exp.unwrap_or(""));
+        eprintln!("    {line}: +{}", act.unwrap_or(""));
+        printed += 1;
+    }
+}
+
+unsafe extern "C" fn write_to_vec(
+    data: *mut c_void,
+    bytes: *const c_void,
+    size: usize,
+) {
+    let vec = unsafe { &mut *data.cast::<Vec<u8>>() };
+    vec.extend_from_slice(unsafe {
+        std::slice::from_raw_parts(bytes.cast::<u8>(), size)
+    });
+}
+
+/// Identifies a shader in the corpus
+///
+/// This must be built from the NIR before nak_postprocess_nir() touches it.
+pub struct GoldenInput {
+    data: Vec<u8>,
+    key: u64,
+}
+
+impl GoldenInput {
+    pub fn new(
+        nak: &nak_compiler,
+        nir: &nir_shader,
+        robust2_modes: nir_variable_mode,
+        fs_key: Option<&nak_fs_key>,
+        max_gprs: u8,
+        align_loops: bool,
+    ) -> GoldenInput {
+        let mut data = Vec::new();
+        unsafe {
+            nak_serialize_compile_input(
+                nak,
+                nir,
+                robust2_modes,
+                fs_key.map_or(std::ptr::null(), |k| k as *const _),
+                max_gprs,
+                align_loops,
+                Some(write_to_vec),
+                (&mut data as *mut Vec<u8>).cast(),
+            );
+        }
+
+        let key = fnv1a64(&data);
+        GoldenInput {
+            data: data,
+            key: key,
+        }
+    }
+}
+
+/// Records or checks the output for a single shader.  `input_ir` is the NAK
+/// IR as produced by from_nir.
+pub fn check_golden(
+    sm: u8,
+    input: &GoldenInput,
+    input_ir: &str,
+    final_ir: &str,
+    code: &[u32],
+) {
+    let Some(dir) = golden_dir() else {
+        return;
+    };
+    let dir = dir.join(format!("sm{sm}"));
+    if let Err(err) = fs::create_dir_all(&dir) {
+        eprintln!("Failed to create {}: {err}", dir.display());
+        return;
+    }
+
+    let path = dir.join(format!("{:016x}.nak", input.key));
+    let actual = golden_text(input_ir, final_ir, code);
+
+    let update = env::var("NAK_GOLDEN_UPDATE").map_or(false, |v| v == "1");
+    if !update {
+        if let Ok(expected) = fs::read_to_string(&path) {
+            if expected != actual {
+                MISMATCHES.fetch_add(1, Ordering::Relaxed);
+                eprintln!("NAK golden mismatch: {}", path.display());
+                eprint_diff(&expected, &actual);
+            }
+            return;
+        }
+    }
+
+    RECORDED.fetch_add(1, Ordering::Relaxed);
+    let input_path = path.with_extension("input");
+    if let Err(err) = fs::write(&input_path, &input.data) {
+        eprintln!("Failed to write {}: {err}", input_path.display());
+    }
+    if let Err(err) = fs::write(&path, actual) {
+        eprintln!("Failed to write {}: {err}", path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{nak_compile_shader, nak_shader_bin_destroy};
+
+    /// Feeds every shader in NAK_GOLDEN_DIR back through the compiler and
+    /// checks that the output still matches.  This does nothing unless
+    /// NAK_GOLDEN_DIR is set.
+    #[test]
+    fn replay_corpus() {
+        let Some(dir) = golden_dir() else {
+            return;
+        };
+
+        let mut replayed = 0;
+        for sm_dir in fs::read_dir(&dir).unwrap() {
+            for entry in fs::read_dir(sm_dir.unwrap().path()).unwrap() {
+                let path = entry.unwrap().path();
+                if path.extension().map_or(true, |ext| ext != "input") {
+                    continue;
+                }
+
+                let data = fs::read(&path).unwrap();
+                assert_eq!(
+                    path.file_stem().unwrap().to_str().unwrap(),
+                    format!("{:016x}", fnv1a64(&data)),
+                    "{} has been modified",
+                    path.display()
+                );
+
+                let mut input: nak_compile_input =
+                    unsafe { std::mem::zeroed() };
+                assert!(
+                    unsafe {
+                        nak_deserialize_compile_input(
+                            data.as_ptr().cast(),
+                            data.len(),
+                            &mut input,
+                        )
+                    },
+                    "Failed to read {}",
+                    path.display()
+                );
+
+                let fs_key = if input.has_fs_key {
+                    &input.fs_key as *const _
+                } else {
+                    std::ptr::null()
+                };
+                let bin = nak_compile_shader(
+                    input.nir,
+                    false,
+                    input.nak,
+                    input.robust2_modes,
+                    fs_key,
+                    input.max_gprs,
+                    input.align_loops,
+                );
+                nak_shader_bin_destroy(bin);
+                unsafe { nak_compile_input_finish(&mut input) };
+                replayed += 1;
+            }
+        }
+
+        eprintln!("Replayed {replayed} shader(s) from {}", dir.display());
+        assert_eq!(MISMATCHES.load(Ordering::Relaxed), 0);
+        assert_eq!(
+            RECORDED.load(Ordering::Relaxed),
+            0,
+            "Replaying a shader didn't reproduce its key"
+        );
+    }
+}
@@ -8,7 +8,7 @@ use bitview::BitMutView;
 
 use crate::api::{GetDebugFlags, DEBUG};
 pub use crate::builder::{Builder, InstrBuilder, SSABuilder, SSAInstrBuilder};
-use crate::cfg::CFG;
+use crate::cfg::{CFGBuilder, CFG};
 use crate::sph::{OutputTopology, PixelImap};
 use nak_ir_proc::*;
 use std::cmp::{max, min};
@@ -593,6 +593,13 @@ impl SSAValueAllocator {
         SSAValue::new(file, self.count)
     }
 
+    /// Ensures that alloc() never returns an SSA value with an index less
+    /// than or equal to idx.  This is needed when SSA values are created
+    /// directly such as when parsing IR from text.
+    pub fn reserve(&mut self, idx: u32) {
+        self.count = max(self.count, idx);
+    }
+
     pub fn alloc_vec(&mut self, file: RegFile, comps: u8) -> SSARef {
         assert!(comps >= 1 && comps <= 4);
         let mut vec = [SSAValue::NONE; 4];
@@ -940,7 +947,7 @@ impl fmt::Display for SrcRef {
     }
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
 pub enum SrcMod {
     None,
     FAbs,
@@ -1043,6 +1050,7 @@ pub enum SrcType {
     SSA,
     GPR,
     ALU,
+    F16v2,
     F32,
     F64,
     I32,
@@ -1135,6 +1143,46 @@ impl Src {
         }
     }
 
+    /// If this is an immediate, applies the source modifier to the value
+    /// itself, interpreting it as `src_type`, and drops the modifier.
+    pub fn fold_imm_src_mod(&mut self, src_type: SrcType) {
+        let SrcRef::Imm32(u) = &mut self.src_ref else {
+            return;
+        };
+
+        *u = match src_type {
+            SrcType::F16v2 => match self.src_mod {
+                SrcMod::None => *u,
+                SrcMod::FAbs => *u & !0x80008000,
+                SrcMod::FNeg => *u ^ 0x80008000,
+                SrcMod::FNegAbs => *u | 0x80008000,
+                _ => panic!("Not a float source modifier"),
+            },
+            SrcType::F32 | SrcType::F64 => match self.src_mod {
+                SrcMod::None => *u,
+                SrcMod::FAbs => *u & !(1_u32 << 31),
+                SrcMod::FNeg => *u ^ (1_u32 << 31),
+                SrcMod::FNegAbs => *u | (1_u32 << 31),
+                _ => panic!("Not a float source modifier"),
+            },
+            SrcType::I32 => match self.src_mod {
+                SrcMod::None => *u,
+                SrcMod::INeg => -(*u as i32) as u32,
+                _ => panic!("Not an integer source modifier"),
+            },
+            SrcType::B32 => match self.src_mod {
+                SrcMod::None => *u,
+                SrcMod::BNot => !*u,
+                _ => panic!("Not a bitwise source modifier"),
+            },
+            _ => {
+                assert!(self.src_mod.is_none());
+                *u
+            }
+        };
+        self.src_mod = SrcMod::None;
+    }
+
     pub fn as_imm_not_i20(&self) -> Option<u32> {
         match self.src_ref {
             SrcRef::Imm32(i) => {
@@ -1236,7 +1284,7 @@ impl Src {
                 }
             }
             SrcType::ALU => self.src_mod.is_none() && self.src_ref.is_alu(),
-            SrcType::F32 | SrcType::F64 => {
+            SrcType::F16v2 | SrcType::F32 | SrcType::F64 => {
                 match self.src_mod {
                     SrcMod::None
                     | SrcMod::FAbs
@@ -1517,6 +1565,7 @@ impl fmt::Display for IntCmpOp {
     }
 }
 
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
 pub enum IntCmpType {
     U32,
     I32,
@@ -1779,7 +1828,12 @@ impl fmt::Display for Tld4OffsetMode {
 #[allow(dead_code)]
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub enum TexQuery {
+    /// Width, height, and depth or layer count of the given LOD, followed by
+    /// the number of levels.  textureSize() and textureQueryLevels() both
+    /// use this.
     Dimension,
+    /// Information about the texture header.  The sample count used by
+    /// textureSamples() is in the third component.
     TextureType,
     SamplerPos,
 }
@@ -1830,6 +1884,7 @@ impl fmt::Display for ImageDim {
     }
 }
 
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
 pub enum IntType {
     U8,
     I8,
@@ -1973,6 +2028,23 @@ impl fmt::Display for MemType {
     }
 }
 
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum ImageAccess {
+    /// Bit-exact access of a whole texel with no format conversion
+    Binary(MemType),
+    /// Access converted to or from the image format with a channel mask
+    Formatted(u8),
+}
+
+impl fmt::Display for ImageAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageAccess::Binary(mem_type) => write!(f, ".b{}", mem_type),
+            ImageAccess::Formatted(_) => write!(f, ".p"),
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Clone, Copy, Eq, Hash, PartialEq)]
 pub enum MemOrder {
@@ -2024,6 +2096,28 @@ impl MemSpace {
             MemSpace::Shared => MemAddrType::A32,
         }
     }
+
+    /// Returns the width of the signed immediate offset of a load or store
+    /// to this space.  Global loads and stores have a full 32-bit offset on
+    /// SM70+.  Everything else only has 24 bits.
+    pub fn imm_offset_bits(&self, sm: u8) -> u8 {
+        match self {
+            MemSpace::Global(_) if sm >= 70 => 32,
+            _ => 24,
+        }
+    }
+
+    /// Returns true if the given offset can be encoded directly in the
+    /// immediate offset field of a load or store to this space.
+    pub fn imm_offset_fits(&self, sm: u8, offset: i32) -> bool {
+        let bits = self.imm_offset_bits(sm);
+        if bits >= 32 {
+            true
+        } else {
+            let top = offset >> (bits - 1);
+            top == 0 || top == -1
+        }
+    }
 }
 
 impl fmt::Display for MemSpace {
@@ -2089,7 +2183,6 @@ pub enum AtomType {
 impl AtomType {
     pub fn F(bits: u8) -> AtomType {
         match bits {
-            16 => panic!("16-bit float atomics not yet supported"),
             32 => AtomType::F32,
             64 => AtomType::F64,
             _ => panic!("Invalid float atomic type"),
@@ -2176,6 +2269,7 @@ pub enum InterpLoc {
     Offset,
 }
 
+#[derive(Clone, Copy)]
 pub struct AttrAccess {
     pub addr: u16,
     pub comps: u8,
@@ -2185,7 +2279,7 @@ pub struct AttrAccess {
 }
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpFAdd {
     pub dst: Dst,
 
@@ -2213,7 +2307,7 @@ impl DisplayOp for OpFAdd {
 impl_display_for_op!(OpFAdd);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpFFma {
     pub dst: Dst,
 
@@ -2244,7 +2338,7 @@ impl DisplayOp for OpFFma {
 impl_display_for_op!(OpFFma);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpFMnMx {
     pub dst: Dst,
 
@@ -2270,7 +2364,7 @@ impl DisplayOp for OpFMnMx {
 impl_display_for_op!(OpFMnMx);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpFMul {
     pub dst: Dst,
 
@@ -2301,7 +2395,7 @@ impl DisplayOp for OpFMul {
 impl_display_for_op!(OpFMul);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpFSet {
     pub dst: Dst,
     pub cmp_op: FloatCmpOp,
@@ -2325,7 +2419,7 @@ impl DisplayOp for OpFSet {
 impl_display_for_op!(OpFSet);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpFSetP {
     pub dst: Dst,
 
@@ -2378,7 +2472,7 @@ impl fmt::Display for FSwzAddOp {
 }
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpFSwzAdd {
     pub dst: Dst,
 
@@ -2414,6 +2508,124 @@ impl DisplayOp for OpFSwzAdd {
 }
 impl_display_for_op!(OpFSwzAdd);
 
+#[repr(C)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
+pub struct OpHAdd2 {
+    pub dst: Dst,
+
+    #[src_type(F16v2)]
+    pub srcs: [Src; 2],
+
+    pub saturate: bool,
+    pub ftz: bool,
+    pub f32: bool,
+}
+
+impl DisplayOp for OpHAdd2 {
+    fn fmt_op(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sat = if self.saturate { ".sat" } else { "" };
+        let f32 = if self.f32 { ".f32" } else { "" };
+        write!(f, "hadd2{sat}{f32}")?;
+        if self.ftz {
+            write!(f, ".ftz")?;
+        }
+        write!(f, " {} {}", self.srcs[0], self.srcs[1])
+    }
+}
+impl_display_for_op!(OpHAdd2);
+
+#[repr(C)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
+pub struct OpHFma2 {
+    pub dst: Dst,
+
+    #[src_type(F16v2)]
+    pub srcs: [Src; 3],
+
+    pub saturate: bool,
+    pub ftz: bool,
+    pub dnz: bool,
+    pub f32: bool,
+}
+
+impl DisplayOp for OpHFma2 {
+    fn fmt_op(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sat = if self.saturate { ".sat" } else { "" };
+        let f32 = if self.f32 { ".f32" } else { "" };
+        write!(f, "hfma2{sat}{f32}")?;
+        if self.dnz {
+            write!(f, ".dnz")?;
+        } else if self.ftz {
+            write!(f, ".ftz")?;
+        }
+        write!(f, " {} {} {}", self.srcs[0], self.srcs[1], self.srcs[2])
+    }
+}
+impl_display_for_op!(OpHFma2);
+
+#[repr(C)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
+pub struct OpHMul2 {
+    pub dst: Dst,
+
+    #[src_type(F16v2)]
+    pub srcs: [Src; 2],
+
+    pub saturate: bool,
+    pub ftz: bool,
+    pub dnz: bool,
+}
+
+impl DisplayOp for OpHMul2 {
+    fn fmt_op(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sat = if self.saturate { ".sat" } else { "" };
+        write!(f, "hmul2{sat}")?;
+        if self.dnz {
+            write!(f, ".dnz")?;
+        } else if self.ftz {
+            write!(f, ".ftz")?;
+        }
+        write!(f, " {} {}", self.srcs[0], self.srcs[1])
+    }
+}
+impl_display_for_op!(OpHMul2);
+
+/// Compares each 16-bit half of the two sources and writes 0xffff to the
+/// corresponding half of the destination if the comparison is true, 0
+/// otherwise.
+#[repr(C)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
+pub struct OpHSet2 {
+    pub dst: Dst,
+
+    pub set_op: PredSetOp,
+    pub cmp_op: FloatCmpOp,
+
+    #[src_type(F16v2)]
+    pub srcs: [Src; 2],
+
+    #[src_type(Pred)]
+    pub accum: Src,
+
+    pub ftz: bool,
+}
+
+impl DisplayOp for OpHSet2 {
+    fn fmt_op(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ftz = if self.ftz { ".ftz" } else { "" };
+        write!(f, "hset2{}{ftz}", self.cmp_op)?;
+        if !self.set_op.is_trivial(&self.accum) {
+            write!(f, "{}", self.set_op)?;
+        }
+        write!(f, " {} {}", self.srcs[0], self.srcs[1])?;
+        if !self.set_op.is_trivial(&self.accum) {
+            write!(f, " {}", self.accum)?;
+        }
+        Ok(())
+    }
+}
+impl_display_for_op!(OpHSet2);
+
 #[allow(dead_code)]
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub enum MuFuOp {
@@ -2447,7 +2659,7 @@ impl fmt::Display for MuFuOp {
 }
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpMuFu {
     pub dst: Dst,
     pub op: MuFuOp,
@@ -2464,7 +2676,7 @@ impl DisplayOp for OpMuFu {
 impl_display_for_op!(OpMuFu);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpDAdd {
     pub dst: Dst,
 
@@ -2486,7 +2698,7 @@ impl DisplayOp for OpDAdd {
 impl_display_for_op!(OpDAdd);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpDMul {
     pub dst: Dst,
 
@@ -2508,7 +2720,7 @@ impl DisplayOp for OpDMul {
 impl_display_for_op!(OpDMul);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpDFma {
     pub dst: Dst,
 
@@ -2530,7 +2742,7 @@ impl DisplayOp for OpDFma {
 impl_display_for_op!(OpDFma);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpDMnMx {
     pub dst: Dst,
 
@@ -2549,7 +2761,7 @@ impl DisplayOp for OpDMnMx {
 impl_display_for_op!(OpDMnMx);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpDSetP {
     pub dst: Dst,
 
@@ -2579,7 +2791,7 @@ impl DisplayOp for OpDSetP {
 impl_display_for_op!(OpDSetP);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpBMsk {
     pub dst: Dst,
 
@@ -2601,7 +2813,7 @@ impl DisplayOp for OpBMsk {
 impl_display_for_op!(OpBMsk);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpBRev {
     pub dst: Dst,
 
@@ -2617,7 +2829,7 @@ impl DisplayOp for OpBRev {
 impl_display_for_op!(OpBRev);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpFlo {
     pub dst: Dst,
 
@@ -2640,7 +2852,7 @@ impl DisplayOp for OpFlo {
 impl_display_for_op!(OpFlo);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpIAbs {
     pub dst: Dst,
 
@@ -2656,7 +2868,7 @@ impl DisplayOp for OpIAbs {
 impl_display_for_op!(OpIAbs);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpINeg {
     pub dst: Dst,
 
@@ -2673,7 +2885,7 @@ impl_display_for_op!(OpINeg);
 
 /// Only used on SM50
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpIAdd2 {
     pub dst: Dst,
     pub carry_out: Dst,
@@ -2694,7 +2906,7 @@ impl DisplayOp for OpIAdd2 {
 }
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpIAdd3 {
     pub dst: Dst,
     pub overflow: [Dst; 2],
@@ -2715,7 +2927,7 @@ impl DisplayOp for OpIAdd3 {
 impl_display_for_op!(OpIAdd3);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpIAdd3X {
     pub dst: Dst,
     pub overflow: [Dst; 2],
@@ -2743,7 +2955,7 @@ impl DisplayOp for OpIAdd3X {
 impl_display_for_op!(OpIAdd3X);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpIDp4 {
     pub dst: Dst,
 
@@ -2769,7 +2981,7 @@ impl DisplayOp for OpIDp4 {
 impl_display_for_op!(OpIDp4);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpIMad {
     pub dst: Dst,
 
@@ -2788,7 +3000,7 @@ impl_display_for_op!(OpIMad);
 
 /// Only used on SM50
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpIMul {
     pub dst: Dst,
 
@@ -2817,7 +3029,7 @@ impl DisplayOp for OpIMul {
 }
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpIMad64 {
     pub dst: Dst,
 
@@ -2839,7 +3051,7 @@ impl DisplayOp for OpIMad64 {
 impl_display_for_op!(OpIMad64);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpIMnMx {
     pub dst: Dst,
     pub cmp_type: IntCmpType,
@@ -2863,7 +3075,7 @@ impl DisplayOp for OpIMnMx {
 impl_display_for_op!(OpIMnMx);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpISetP {
     pub dst: Dst,
 
@@ -2904,7 +3116,7 @@ impl DisplayOp for OpISetP {
 impl_display_for_op!(OpISetP);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpLop2 {
     pub dst: Dst,
 
@@ -2921,7 +3133,7 @@ impl DisplayOp for OpLop2 {
 }
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpLop3 {
     pub dst: Dst,
 
@@ -2963,7 +3175,7 @@ impl fmt::Display for ShflOp {
 }
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpShf {
     pub dst: Dst,
 
@@ -3004,7 +3216,7 @@ impl_display_for_op!(OpShf);
 
 /// Only used on SM50
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpShl {
     pub dst: Dst,
 
@@ -3029,7 +3241,7 @@ impl DisplayOp for OpShl {
 
 /// Only used on SM50
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpShr {
     pub dst: Dst,
 
@@ -3057,7 +3269,7 @@ impl DisplayOp for OpShr {
 }
 
 #[repr(C)]
-#[derive(DstsAsSlice)]
+#[derive(Clone, DstsAsSlice)]
 pub struct OpF2F {
     pub dst: Dst,
 
@@ -3113,7 +3325,7 @@ impl DisplayOp for OpF2F {
 impl_display_for_op!(OpF2F);
 
 #[repr(C)]
-#[derive(DstsAsSlice)]
+#[derive(Clone, DstsAsSlice)]
 pub struct OpF2I {
     pub dst: Dst,
 
@@ -3157,7 +3369,7 @@ impl DisplayOp for OpF2I {
 impl_display_for_op!(OpF2I);
 
 #[repr(C)]
-#[derive(DstsAsSlice)]
+#[derive(Clone, DstsAsSlice)]
 pub struct OpI2F {
     pub dst: Dst,
 
@@ -3199,7 +3411,7 @@ impl_display_for_op!(OpI2F);
 
 /// Not used on SM70+
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpI2I {
     pub dst: Dst,
 
@@ -3233,7 +3445,7 @@ impl DisplayOp for OpI2I {
 impl_display_for_op!(OpI2I);
 
 #[repr(C)]
-#[derive(DstsAsSlice)]
+#[derive(Clone, DstsAsSlice)]
 pub struct OpFRnd {
     pub dst: Dst,
 
@@ -3277,7 +3489,7 @@ impl DisplayOp for OpFRnd {
 impl_display_for_op!(OpFRnd);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpMov {
     pub dst: Dst,
 
@@ -3325,7 +3537,7 @@ impl fmt::Display for PrmtMode {
 }
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 /// Permutes `srcs` into `dst` using `selection`.
 pub struct OpPrmt {
     pub dst: Dst,
@@ -3351,7 +3563,7 @@ impl DisplayOp for OpPrmt {
 impl_display_for_op!(OpPrmt);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpSel {
     pub dst: Dst,
 
@@ -3370,9 +3582,15 @@ impl DisplayOp for OpSel {
 impl_display_for_op!(OpSel);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpShfl {
     pub dst: Dst,
+
+    /// Set if the source lane is within the segment described by c.  If it
+    /// isn't, the shuffle leaves dst set to the lane's own src.
+    ///
+    /// This only reflects the lane index computation and says nothing about
+    /// whether or not the source lane is active.
     pub in_bounds: Dst,
 
     #[src_type(SSA)]
@@ -3395,7 +3613,7 @@ impl DisplayOp for OpShfl {
 impl_display_for_op!(OpShfl);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpPLop3 {
     pub dsts: [Dst; 2],
 
@@ -3421,7 +3639,7 @@ impl DisplayOp for OpPLop3 {
 impl_display_for_op!(OpPLop3);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpPSetP {
     pub dsts: [Dst; 2],
 
@@ -3442,7 +3660,7 @@ impl DisplayOp for OpPSetP {
 }
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpPopC {
     pub dst: Dst,
 
@@ -3458,7 +3676,7 @@ impl DisplayOp for OpPopC {
 impl_display_for_op!(OpPopC);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpTex {
     pub dsts: [Dst; 2],
     pub resident: Dst,
@@ -3491,7 +3709,7 @@ impl DisplayOp for OpTex {
 impl_display_for_op!(OpTex);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpTld {
     pub dsts: [Dst; 2],
     pub resident: Dst,
@@ -3524,7 +3742,7 @@ impl DisplayOp for OpTld {
 impl_display_for_op!(OpTld);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpTld4 {
     pub dsts: [Dst; 2],
     pub resident: Dst,
@@ -3551,7 +3769,7 @@ impl DisplayOp for OpTld4 {
 impl_display_for_op!(OpTld4);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpTmml {
     pub dsts: [Dst; 2],
 
@@ -3574,7 +3792,7 @@ impl DisplayOp for OpTmml {
 impl_display_for_op!(OpTmml);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpTxd {
     pub dsts: [Dst; 2],
     pub resident: Dst,
@@ -3598,8 +3816,40 @@ impl DisplayOp for OpTxd {
 }
 impl_display_for_op!(OpTxd);
 
+/// Texture footprint query for NV_shader_texture_footprint
+///
+/// The sources are packed like TXD with the texture handle first in srcs[0]
+/// and the footprint granularity first in srcs[1].
+#[repr(C)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
+pub struct OpFootprint {
+    pub dsts: [Dst; 2],
+
+    #[src_type(SSA)]
+    pub srcs: [Src; 2],
+
+    pub dim: TexDim,
+    pub lod_mode: TexLodMode,
+    pub coarse: bool,
+    pub mask: u8,
+}
+
+impl DisplayOp for OpFootprint {
+    fn fmt_op(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "footprint.b{}", self.dim)?;
+        if self.lod_mode != TexLodMode::Auto {
+            write!(f, ".{}", self.lod_mode)?;
+        }
+        if self.coarse {
+            write!(f, ".coarse")?;
+        }
+        write!(f, " {} {}", self.srcs[0], self.srcs[1])
+    }
+}
+impl_display_for_op!(OpFootprint);
+
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpTxq {
     pub dsts: [Dst; 2],
 
@@ -3618,7 +3868,7 @@ impl DisplayOp for OpTxq {
 impl_display_for_op!(OpTxq);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpSuLd {
     pub dst: Dst,
     pub resident: Dst,
@@ -3651,12 +3901,12 @@ impl DisplayOp for OpSuLd {
 impl_display_for_op!(OpSuLd);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpSuSt {
+    pub image_access: ImageAccess,
     pub image_dim: ImageDim,
     pub mem_order: MemOrder,
     pub mem_eviction_priority: MemEvictionPriority,
-    pub mask: u8,
 
     #[src_type(GPR)]
     pub handle: Src,
@@ -3672,7 +3922,8 @@ impl DisplayOp for OpSuSt {
     fn fmt_op(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "sust.p{}{}{} [{}] {} {}",
+            "sust{}{}{}{} [{}] {} {}",
+            self.image_access,
             self.image_dim,
             self.mem_order,
             self.mem_eviction_priority,
@@ -3685,7 +3936,7 @@ impl DisplayOp for OpSuSt {
 impl_display_for_op!(OpSuSt);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpSuAtom {
     pub dst: Dst,
     pub resident: Dst,
@@ -3727,7 +3978,7 @@ impl DisplayOp for OpSuAtom {
 impl_display_for_op!(OpSuAtom);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpLd {
     pub dst: Dst,
 
@@ -3750,7 +4001,7 @@ impl DisplayOp for OpLd {
 impl_display_for_op!(OpLd);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpLdc {
     pub dst: Dst,
 
@@ -3782,7 +4033,7 @@ impl DisplayOp for OpLdc {
 impl_display_for_op!(OpLdc);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpSt {
     #[src_type(GPR)]
     pub addr: Src,
@@ -3806,7 +4057,7 @@ impl DisplayOp for OpSt {
 impl_display_for_op!(OpSt);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpAtom {
     pub dst: Dst,
 
@@ -3829,6 +4080,16 @@ pub struct OpAtom {
     pub mem_eviction_priority: MemEvictionPriority,
 }
 
+impl OpAtom {
+    /// Returns true if this atomic has a reduction (RED) form which can be
+    /// used when nothing reads the result.  There's no RED for exchanges
+    /// since they'd be plain stores and there's no f16x2 RED at all.
+    pub fn can_be_red(&self) -> bool {
+        !matches!(self.atom_op, AtomOp::Exch | AtomOp::CmpExch)
+            && self.atom_type != AtomType::F16x2
+    }
+}
+
 impl DisplayOp for OpAtom {
     fn fmt_op(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -3856,7 +4117,7 @@ impl DisplayOp for OpAtom {
 impl_display_for_op!(OpAtom);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpAL2P {
     pub dst: Dst,
 
@@ -3885,7 +4146,7 @@ impl DisplayOp for OpAL2P {
 impl_display_for_op!(OpAL2P);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpALd {
     pub dst: Dst,
 
@@ -3924,7 +4185,7 @@ impl DisplayOp for OpALd {
 impl_display_for_op!(OpALd);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpASt {
     #[src_type(GPR)]
     pub vtx: Src,
@@ -3961,7 +4222,7 @@ impl DisplayOp for OpASt {
 impl_display_for_op!(OpASt);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpIpa {
     pub dst: Dst,
     pub addr: u16,
@@ -3996,7 +4257,7 @@ impl DisplayOp for OpIpa {
 impl_display_for_op!(OpIpa);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpLdTram {
     pub dst: Dst,
     pub addr: u16,
@@ -4018,6 +4279,7 @@ impl DisplayOp for OpLdTram {
 impl_display_for_op!(OpLdTram);
 
 #[allow(dead_code)]
+#[derive(Clone, Copy)]
 pub enum CCtlOp {
     PF1,
     PF2,
@@ -4062,7 +4324,7 @@ impl fmt::Display for CCtlOp {
 }
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpCCtl {
     pub op: CCtlOp,
 
@@ -4090,7 +4352,7 @@ impl DisplayOp for OpCCtl {
 impl_display_for_op!(OpCCtl);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpMemBar {
     pub scope: MemScope,
 }
@@ -4103,7 +4365,7 @@ impl DisplayOp for OpMemBar {
 impl_display_for_op!(OpMemBar);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpBClear {
     pub dst: Dst,
 }
@@ -4116,7 +4378,7 @@ impl DisplayOp for OpBClear {
 impl_display_for_op!(OpBClear);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpBMov {
     pub dst: Dst,
     pub src: Src,
@@ -4135,7 +4397,7 @@ impl DisplayOp for OpBMov {
 impl_display_for_op!(OpBMov);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpBreak {
     pub bar_out: Dst,
 
@@ -4154,7 +4416,7 @@ impl DisplayOp for OpBreak {
 impl_display_for_op!(OpBreak);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpBSSy {
     pub bar_out: Dst,
 
@@ -4175,7 +4437,7 @@ impl DisplayOp for OpBSSy {
 impl_display_for_op!(OpBSSy);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpBSync {
     #[src_type(Bar)]
     pub bar: Src,
@@ -4215,8 +4477,96 @@ impl DisplayOp for OpExit {
 }
 impl_display_for_op!(OpExit);
 
+/// Pushes a reconvergence point onto the SM50 control stack.  Used before
+/// divergent branches and popped by the matching SYNC.
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
+pub struct OpSSy {
+    pub target: Label,
+}
+
+impl DisplayOp for OpSSy {
+    fn fmt_op(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ssy {}", self.target)
+    }
+}
+impl_display_for_op!(OpSSy);
+
+/// Waits for the other side of a divergent branch and then jumps to the
+/// target of the matching SSY.
+///
+/// The target isn't encoded.  The hardware takes it from the control stack
+/// so it only exists to tell the CFG where we go.  The same is true of BRK
+/// and CONT.
+#[repr(C)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
+pub struct OpSync {
+    pub target: Label,
+}
+
+impl DisplayOp for OpSync {
+    fn fmt_op(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sync {}", self.target)
+    }
+}
+impl_display_for_op!(OpSync);
+
+/// Pushes a loop break point onto the SM50 control stack
+#[repr(C)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
+pub struct OpPBk {
+    pub target: Label,
+}
+
+impl DisplayOp for OpPBk {
+    fn fmt_op(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pbk {}", self.target)
+    }
+}
+impl_display_for_op!(OpPBk);
+
+#[repr(C)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
+pub struct OpBrk {
+    pub target: Label,
+}
+
+impl DisplayOp for OpBrk {
+    fn fmt_op(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "brk {}", self.target)
+    }
+}
+impl_display_for_op!(OpBrk);
+
+/// Pushes a loop continue point onto the SM50 control stack
+#[repr(C)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
+pub struct OpPCnt {
+    pub target: Label,
+}
+
+impl DisplayOp for OpPCnt {
+    fn fmt_op(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pcnt {}", self.target)
+    }
+}
+impl_display_for_op!(OpPCnt);
+
+#[repr(C)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
+pub struct OpCont {
+    pub target: Label,
+}
+
+impl DisplayOp for OpCont {
+    fn fmt_op(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cont {}", self.target)
+    }
+}
+impl_display_for_op!(OpCont);
+
+#[repr(C)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpWarpSync {
     pub mask: u32,
 }
@@ -4228,8 +4578,15 @@ impl DisplayOp for OpWarpSync {
 }
 impl_display_for_op!(OpWarpSync);
 
+/// A workgroup barrier
+///
+/// This is always hardware barrier 0 waited on by every thread in the
+/// workgroup.  The hardware also has named barriers with an explicit thread
+/// count, which warp-specialized kernels use to synchronize producer and
+/// consumer warps, but neither SPIR-V nor NIR can express those so we don't
+/// model them.
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpBar {}
 
 impl DisplayOp for OpBar {
@@ -4240,7 +4597,7 @@ impl DisplayOp for OpBar {
 impl_display_for_op!(OpBar);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpCS2R {
     pub dst: Dst,
     pub idx: u8,
@@ -4254,7 +4611,7 @@ impl DisplayOp for OpCS2R {
 impl_display_for_op!(OpCS2R);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpIsberd {
     pub dst: Dst,
 
@@ -4270,7 +4627,7 @@ impl DisplayOp for OpIsberd {
 impl_display_for_op!(OpIsberd);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpKill {}
 
 impl DisplayOp for OpKill {
@@ -4281,7 +4638,7 @@ impl DisplayOp for OpKill {
 impl_display_for_op!(OpKill);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpNop {
     pub label: Option<Label>,
 }
@@ -4298,6 +4655,7 @@ impl DisplayOp for OpNop {
 impl_display_for_op!(OpNop);
 
 #[allow(dead_code)]
+#[derive(Clone, Copy)]
 pub enum PixVal {
     MsCount,
     CovMask,
@@ -4307,7 +4665,7 @@ pub enum PixVal {
 }
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpPixLd {
     pub dst: Dst,
     pub val: PixVal,
@@ -4328,7 +4686,7 @@ impl DisplayOp for OpPixLd {
 impl_display_for_op!(OpPixLd);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpS2R {
     pub dst: Dst,
     pub idx: u8,
@@ -4341,6 +4699,7 @@ impl DisplayOp for OpS2R {
 }
 impl_display_for_op!(OpS2R);
 
+#[derive(Clone, Copy)]
 pub enum VoteOp {
     Any,
     All,
@@ -4358,7 +4717,7 @@ impl fmt::Display for VoteOp {
 }
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpVote {
     pub op: VoteOp,
 
@@ -4391,7 +4750,7 @@ impl DisplayOp for OpVote {
 impl_display_for_op!(OpVote);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpUndef {
     pub dst: Dst,
 }
@@ -4403,6 +4762,7 @@ impl DisplayOp for OpUndef {
 }
 impl_display_for_op!(OpUndef);
 
+#[derive(Clone)]
 pub struct VecPair<A, B> {
     a: Vec<A>,
     b: Vec<B>,
@@ -4500,10 +4860,15 @@ impl PhiAllocator {
         self.count = idx + 1;
         idx
     }
+
+    /// Ensures that alloc() never returns the given phi index
+    pub fn reserve(&mut self, idx: u32) {
+        self.count = max(self.count, idx + 1);
+    }
 }
 
 #[repr(C)]
-#[derive(DstsAsSlice)]
+#[derive(Clone, DstsAsSlice)]
 pub struct OpPhiSrcs {
     pub srcs: VecPair<u32, Src>,
 }
@@ -4549,7 +4914,7 @@ impl DisplayOp for OpPhiSrcs {
 impl_display_for_op!(OpPhiSrcs);
 
 #[repr(C)]
-#[derive(SrcsAsSlice)]
+#[derive(Clone, SrcsAsSlice)]
 pub struct OpPhiDsts {
     pub dsts: VecPair<u32, Dst>,
 }
@@ -4591,7 +4956,7 @@ impl DisplayOp for OpPhiDsts {
 impl_display_for_op!(OpPhiDsts);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpCopy {
     pub dst: Dst,
     pub src: Src,
@@ -4605,7 +4970,7 @@ impl DisplayOp for OpCopy {
 impl_display_for_op!(OpCopy);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpSwap {
     pub dsts: [Dst; 2],
     pub srcs: [Src; 2],
@@ -4619,6 +4984,7 @@ impl DisplayOp for OpSwap {
 impl_display_for_op!(OpSwap);
 
 #[repr(C)]
+#[derive(Clone)]
 pub struct OpParCopy {
     pub dsts_srcs: VecPair<Dst, Src>,
     pub tmp: Option<RegRef>,
@@ -4684,7 +5050,7 @@ impl DisplayOp for OpParCopy {
 impl_display_for_op!(OpParCopy);
 
 #[repr(C)]
-#[derive(DstsAsSlice)]
+#[derive(Clone, DstsAsSlice)]
 pub struct OpFSOut {
     pub srcs: Vec<Src>,
 }
@@ -4735,7 +5101,7 @@ impl fmt::Display for OutType {
 }
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpOut {
     pub dst: Dst,
 
@@ -4756,7 +5122,7 @@ impl DisplayOp for OpOut {
 impl_display_for_op!(OpOut);
 
 #[repr(C)]
-#[derive(SrcsAsSlice, DstsAsSlice)]
+#[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpOutFinal {
     #[src_type(SSA)]
     pub handle: Src,
@@ -4769,7 +5135,7 @@ impl DisplayOp for OpOutFinal {
 }
 impl_display_for_op!(OpOutFinal);
 
-#[derive(DisplayOp, DstsAsSlice, SrcsAsSlice, FromVariants)]
+#[derive(Clone, DisplayOp, DstsAsSlice, SrcsAsSlice, FromVariants)]
 pub enum Op {
     FAdd(OpFAdd),
     FFma(OpFFma),
@@ -4779,6 +5145,10 @@ pub enum Op {
     FSet(OpFSet),
     FSetP(OpFSetP),
     FSwzAdd(OpFSwzAdd),
+    HAdd2(OpHAdd2),
+    HFma2(OpHFma2),
+    HMul2(OpHMul2),
+    HSet2(OpHSet2),
     DAdd(OpDAdd),
     DFma(OpDFma),
     DMnMx(OpDMnMx),
@@ -4821,6 +5191,7 @@ pub enum Op {
     Tmml(OpTmml),
     Txd(OpTxd),
     Txq(OpTxq),
+    Footprint(OpFootprint),
     SuLd(OpSuLd),
     SuSt(OpSuSt),
     SuAtom(OpSuAtom),
@@ -4842,6 +5213,12 @@ pub enum Op {
     BSync(OpBSync),
     Bra(OpBra),
     Exit(OpExit),
+    SSy(OpSSy),
+    Sync(OpSync),
+    PBk(OpPBk),
+    Brk(OpBrk),
+    PCnt(OpPCnt),
+    Cont(OpCont),
     WarpSync(OpWarpSync),
     Bar(OpBar),
     CS2R(OpCS2R),
@@ -4978,6 +5355,7 @@ impl fmt::Display for Pred {
 pub const MIN_INSTR_DELAY: u8 = 1;
 pub const MAX_INSTR_DELAY: u8 = 15;
 
+#[derive(Clone)]
 pub struct InstrDeps {
     pub delay: u8,
     pub yld: bool,
@@ -5043,7 +5421,6 @@ impl InstrDeps {
         self.wt_bar_mask |= bar_mask;
     }
 
-    #[allow(dead_code)]
     pub fn add_reuse(&mut self, idx: u8) {
         assert!(idx < 6);
         self.reuse_mask |= 1_u8 << idx;
@@ -5074,6 +5451,7 @@ impl fmt::Display for InstrDeps {
     }
 }
 
+#[derive(Clone)]
 pub struct Instr {
     pub pred: Pred,
     pub op: Op,
@@ -5153,7 +5531,11 @@ impl Instr {
 
     pub fn is_branch(&self) -> bool {
         match self.op {
-            Op::Bra(_) | Op::Exit(_) => true,
+            Op::Bra(_)
+            | Op::Exit(_)
+            | Op::Sync(_)
+            | Op::Brk(_)
+            | Op::Cont(_) => true,
             _ => false,
         }
     }
@@ -5165,6 +5547,15 @@ impl Instr {
         }
     }
 
+    /// Returns true if the instruction runs on the uniform datapath
+    pub fn is_uniform(&self) -> bool {
+        self.dsts().iter().any(|dst| match dst {
+            Dst::None => false,
+            Dst::SSA(ssa) => ssa.file() == RegFile::UGPR,
+            Dst::Reg(reg) => reg.file() == RegFile::UGPR,
+        })
+    }
+
     pub fn uses_global_mem(&self) -> bool {
         match &self.op {
             Op::Atom(op) => op.mem_space != MemSpace::Local,
@@ -5198,6 +5589,12 @@ impl Instr {
             | Op::BSync(_)
             | Op::Bra(_)
             | Op::Exit(_)
+            | Op::SSy(_)
+            | Op::Sync(_)
+            | Op::PBk(_)
+            | Op::Brk(_)
+            | Op::PCnt(_)
+            | Op::Cont(_)
             | Op::WarpSync(_)
             | Op::Bar(_)
             | Op::FSOut(_)
@@ -5209,6 +5606,12 @@ impl Instr {
     }
 
     pub fn has_fixed_latency(&self, _sm: u8) -> bool {
+        // We don't model the uniform datapath latencies so we let the
+        // scoreboard take care of them.
+        if self.is_uniform() {
+            return false;
+        }
+
         match &self.op {
             // Float ALU
             Op::FAdd(_)
@@ -5219,6 +5622,9 @@ impl Instr {
             | Op::FSetP(_)
             | Op::FSwzAdd(_) => true,
 
+            // Half-precision float ALU
+            Op::HAdd2(_) | Op::HFma2(_) | Op::HMul2(_) | Op::HSet2(_) => true,
+
             // Multi-function unit is variable latency
             Op::MuFu(_) => false,
 
@@ -5267,7 +5673,8 @@ impl Instr {
             | Op::Tld4(_)
             | Op::Tmml(_)
             | Op::Txd(_)
-            | Op::Txq(_) => false,
+            | Op::Txq(_)
+            | Op::Footprint(_) => false,
 
             // Surface ops
             Op::SuLd(_) | Op::SuSt(_) | Op::SuAtom(_) => false,
@@ -5288,6 +5695,8 @@ impl Instr {
             // Control-flow ops
             Op::BClear(_) | Op::Break(_) | Op::BSSy(_) | Op::BSync(_) => true,
             Op::Bra(_) | Op::Exit(_) => true,
+            Op::SSy(_) | Op::Sync(_) | Op::PBk(_) | Op::Brk(_) => true,
+            Op::PCnt(_) | Op::Cont(_) => true,
             Op::WarpSync(_) => false,
 
             // BMOV: barriers only when using gprs (and only valid for the gpr),
@@ -5338,7 +5747,7 @@ impl Instr {
                 11
             }
             // Op::DepBar(_) => 4,
-            _ => 1, // TODO: co-issue
+            _ => 1,
         }
     }
 
@@ -5419,9 +5828,38 @@ impl MappedInstrs {
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LoopUnrollHint {
+    #[default]
+    None,
+    Unroll,
+    DontUnroll,
+}
+
+/// What the front-end knew about a loop
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LoopHints {
+    /// The maximum number of iterations, if known
+    pub max_trip_count: Option<u32>,
+    /// True if the loop always runs exactly max_trip_count iterations
+    pub exact_trip_count: bool,
+    /// A guess at the number of iterations based on array indexing
+    pub guessed_trip_count: Option<u32>,
+    pub unroll: LoopUnrollHint,
+}
+
+impl LoopHints {
+    /// Returns our best guess at the number of iterations, if we have one
+    pub fn expected_trip_count(&self) -> Option<u32> {
+        self.max_trip_count.or(self.guessed_trip_count)
+    }
+}
+
 pub struct BasicBlock {
     pub label: Label,
     pub instrs: Vec<Box<Instr>>,
+    /// If this block is a loop header, whatever NIR knew about the loop
+    pub loop_hints: Option<LoopHints>,
 }
 
 impl BasicBlock {
@@ -5429,6 +5867,7 @@ impl BasicBlock {
         BasicBlock {
             label: label,
             instrs: Vec::new(),
+            loop_hints: None,
         }
     }
 
@@ -5557,6 +5996,77 @@ impl Function {
     ) {
         self.map_instrs_priv(&mut map);
     }
+
+    /// Returns the index of the block which block `idx` falls through to, if
+    /// any.
+    ///
+    /// The fall-through edge is always the first successor edge and, since
+    /// the blocks are stored in program order, it's always the next block.
+    pub fn fall_through_succ(&self, idx: usize) -> Option<usize> {
+        if !self.blocks[idx].falls_through() {
+            return None;
+        }
+
+        let succ = *self.blocks.succ_indices(idx).first()?;
+        debug_assert!(succ == idx + 1);
+        Some(succ)
+    }
+
+    /// Returns the index of the block targeted by the branch at the end of
+    /// block `idx`, if any.
+    pub fn branch_succ(&self, idx: usize) -> Option<usize> {
+        let Op::Bra(bra) = &self.blocks[idx].branch()?.op else {
+            return None;
+        };
+
+        let succ = self
+            .blocks
+            .succ_indices(idx)
+            .iter()
+            .copied()
+            .find(|&s| self.blocks[s].label == bra.target);
+        assert!(succ.is_some(), "Branch target is not a successor");
+        succ
+    }
+
+    /// Rebuilds the successor and predecessor edges from the branches at the
+    /// end of each block.
+    ///
+    /// Passes which change branch targets or remove branches must call this
+    /// so that later passes can trust the CFG edges rather than assuming
+    /// anything about block order.  Blocks which become unreachable are
+    /// removed.
+    pub fn rebuild_cfg(&mut self) {
+        let mut builder = CFGBuilder::new();
+
+        for i in 0..self.blocks.len() {
+            let block = &self.blocks[i];
+            // The fall-through edge must be first
+            if block.falls_through() {
+                let next_block = &self.blocks[i + 1];
+                builder.add_edge(block.label, next_block.label);
+            }
+            if let Some(control_flow) = block.branch() {
+                match &control_flow.op {
+                    Op::Bra(bra) => {
+                        builder.add_edge(block.label, bra.target);
+                    }
+                    Op::Sync(OpSync { target })
+                    | Op::Brk(OpBrk { target })
+                    | Op::Cont(OpCont { target }) => {
+                        builder.add_edge(block.label, *target);
+                    }
+                    Op::Exit(_) => (),
+                    _ => panic!("Unhandled branch instruction"),
+                };
+            }
+        }
+
+        for block in self.blocks.drain() {
+            builder.add_node(block.label, block);
+        }
+        self.blocks = builder.as_cfg();
+    }
 }
 
 impl fmt::Display for Function {
@@ -5677,6 +6187,23 @@ pub struct SysValInfo {
     pub c: u16,
 }
 
+/// Transform feedback layout for the driver
+///
+/// Each buffer captures the vertices of a single stream.  With a geometry
+/// shader emitting to several streams, the driver points each buffer at the
+/// stream it captures and the hardware only writes the vertices emitted to
+/// that stream.
+#[derive(Debug)]
+pub struct XfbInfo {
+    /// Stride of each buffer in bytes, or 0 if the buffer isn't written
+    pub stride: [u32; 4],
+    /// The stream each buffer captures
+    pub stream: [u8; 4],
+    /// For each buffer, the attribute index of each dword written per
+    /// vertex, or 0xff if the dword is skipped
+    pub attr_index: [Vec<u8>; 4],
+}
+
 #[derive(Debug)]
 pub struct VtgIoInfo {
     pub sysvals_in: SysValInfo,
@@ -5687,6 +6214,7 @@ pub struct VtgIoInfo {
     pub attr_out: [u32; 4],
     pub store_req_start: u8,
     pub store_req_end: u8,
+    pub xfb: Option<Box<XfbInfo>>,
 }
 
 impl VtgIoInfo {
@@ -5755,6 +6283,10 @@ pub struct FragmentIoInfo {
     pub writes_color: u32,
     pub writes_sample_mask: bool,
     pub writes_depth: bool,
+    /// The depth written may be less than the interpolated depth
+    pub writes_depth_less: bool,
+    /// The depth written may be greater than the interpolated depth
+    pub writes_depth_greater: bool,
     pub does_interlock: bool,
 }
 
@@ -5796,6 +6328,11 @@ pub enum ShaderIoInfo {
 pub struct ShaderInfo {
     pub sm: u8,
     pub num_gprs: u8,
+    /// Maximum number of GPRs the register allocator may use before it has
+    /// to spill.  See Shader::set_max_gprs().
+    pub max_gprs: u32,
+    /// Whether loop headers get aligned.  See Shader::loop_header_align().
+    pub align_loops: bool,
     pub num_barriers: u8,
     pub slm_size: u32,
     pub uses_global_mem: bool,
@@ -5805,12 +6342,53 @@ pub struct ShaderInfo {
     pub io: ShaderIoInfo,
 }
 
+impl ShaderInfo {
+    /// Returns the number of GPRs the hardware needs to allocate per thread
+    pub fn num_hw_gprs(&self) -> u8 {
+        if self.sm >= 70 {
+            // Volta+ needs two extra GPRs.  See RegFile::num_regs().
+            max(4, self.num_gprs + 2)
+        } else {
+            max(4, self.num_gprs)
+        }
+    }
+
+    /// Returns the theoretical maximum number of warps which can be resident
+    /// on an SM at one time, taking only register usage into account.
+    pub fn max_warps_per_sm(&self, warps_per_sm: u32) -> u32 {
+        // Each SM has 64K registers which get allocated to warps in units of
+        // 256 registers, or 8 per thread.
+        let regs_per_warp =
+            (u32::from(self.num_hw_gprs()) * 32).next_multiple_of(256);
+        min(warps_per_sm, 65536 / regs_per_warp)
+    }
+}
+
 pub struct Shader {
     pub info: ShaderInfo,
     pub functions: Vec<Function>,
+
+    /// Warnings about things which may hurt performance or correctness, to
+    /// be reported back to the driver along with the compiled shader.
+    pub warnings: Vec<String>,
 }
 
 impl Shader {
+    pub fn warn(&mut self, msg: String) {
+        self.warnings.push(msg);
+    }
+
+    /// Limits the number of GPRs available to the register allocator.  This
+    /// can be used to target a given occupancy at the cost of spilling.  The
+    /// limit is clamped to what the hardware supports and to the minimum the
+    /// register allocator needs to always succeed.
+    pub fn set_max_gprs(&mut self, max_gprs: u32) {
+        // We need at least 16 registers to satisfy RA constraints for
+        // texture ops and another 2 for parallel copy lowering
+        let max_gprs = max(max_gprs, 18);
+        self.info.max_gprs = min(max_gprs, RegFile::GPR.num_regs(self.info.sm));
+    }
+
     pub fn for_each_instr(&self, f: &mut impl FnMut(&Instr)) {
         for func in &self.functions {
             for b in &func.blocks {
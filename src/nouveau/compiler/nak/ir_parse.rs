@@ -0,0 +1,948 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+//! A parser for the textual form of NAK IR
+//!
+//! This parses the output of the Display impl for Function back into a
+//! Function so that hand-written IR can be fed to individual passes, the
+//! register allocator, or the encoder without going through NIR.  Only IR in
+//! SSA form is supported and only the instructions handled by parse_op() can
+//! be parsed.  Labels are renumbered in the order in which they first appear
+//! and instruction dependency comments are ignored.
+
+use crate::cfg::CFGBuilder;
+use crate::ir::*;
+
+use std::collections::{HashMap, HashSet};
+
+type ParseResult<T> = Result<T, String>;
+
+fn parse_u32(s: &str) -> ParseResult<u32> {
+    let res = if let Some(hex) = s.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    };
+    res.map_err(|_| format!("Invalid integer \"{}\"", s))
+}
+
+fn parse_reg_file(s: &str) -> ParseResult<(RegFile, &str)> {
+    // The two-letter prefixes have to come first
+    for (prefix, file) in [
+        ("ur", RegFile::UGPR),
+        ("up", RegFile::UPred),
+        ("r", RegFile::GPR),
+        ("p", RegFile::Pred),
+        ("c", RegFile::Carry),
+        ("b", RegFile::Bar),
+        ("m", RegFile::Mem),
+    ] {
+        if let Some(rest) = s.strip_prefix(prefix) {
+            return Ok((file, rest));
+        }
+    }
+    Err(format!("Unknown register file in \"{}\"", s))
+}
+
+fn parse_reg_ref(s: &str) -> ParseResult<RegRef> {
+    let (file, idx) = parse_reg_file(s)?;
+    if let Some((start, end)) = idx.split_once("..") {
+        let start = parse_u32(start)?;
+        let end = parse_u32(end)?;
+        if end <= start || end - start > 8 {
+            return Err(format!("Invalid register range \"{}\"", s));
+        }
+        Ok(RegRef::new(file, start, (end - start) as u8))
+    } else {
+        Ok(RegRef::new(file, parse_u32(idx)?, 1))
+    }
+}
+
+fn parse_cbuf_ref(s: &str) -> ParseResult<CBufRef> {
+    let err = || format!("Invalid constant buffer reference \"{}\"", s);
+    let rest = s.strip_prefix("c[").ok_or_else(err)?;
+    let (idx, rest) = rest.split_once("][").ok_or_else(err)?;
+    let offset = rest.strip_suffix(']').ok_or_else(err)?;
+    Ok(CBufRef {
+        buf: CBuf::Binding(u8::try_from(parse_u32(idx)?).map_err(|_| err())?),
+        offset: u16::try_from(parse_u32(offset)?).map_err(|_| err())?,
+    })
+}
+
+/// Splits a line into whitespace-separated tokens.  Vector SSA references
+/// such as {%r1 %r2} are kept together as a single token and commas are
+/// treated as whitespace.
+fn tokenize(line: &str) -> ParseResult<Vec<&str>> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut in_vec = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '{' => {
+                if in_vec {
+                    return Err(format!("Nested braces in \"{}\"", line));
+                }
+                in_vec = true;
+                start.get_or_insert(i);
+            }
+            '}' => {
+                if !in_vec {
+                    return Err(format!("Unmatched '}}' in \"{}\"", line));
+                }
+                in_vec = false;
+            }
+            ',' | ' ' | '\t' if !in_vec => {
+                if let Some(s) = start.take() {
+                    tokens.push(&line[s..i]);
+                }
+            }
+            _ => {
+                start.get_or_insert(i);
+            }
+        }
+    }
+    if in_vec {
+        return Err(format!("Unmatched '{{' in \"{}\"", line));
+    }
+    if let Some(s) = start {
+        tokens.push(&line[s..]);
+    }
+    Ok(tokens)
+}
+
+fn parse_phi_id(s: &str) -> ParseResult<u32> {
+    let id = s
+        .strip_prefix('φ')
+        .ok_or_else(|| format!("Invalid phi \"{}\"", s))?;
+    parse_u32(id)
+}
+
+fn dst_or_none(dsts: &[Dst], i: usize) -> Dst {
+    dsts.get(i).copied().unwrap_or(Dst::None)
+}
+
+struct FloatMods {
+    saturate: bool,
+    rnd_mode: FRndMode,
+    ftz: bool,
+    dnz: bool,
+}
+
+fn parse_float_mods(mods: &[&str]) -> ParseResult<FloatMods> {
+    let mut fm = FloatMods {
+        saturate: false,
+        rnd_mode: FRndMode::NearestEven,
+        ftz: false,
+        dnz: false,
+    };
+    for m in mods {
+        match *m {
+            "sat" => fm.saturate = true,
+            "ftz" => fm.ftz = true,
+            "dnz" => fm.dnz = true,
+            "re" => fm.rnd_mode = FRndMode::NearestEven,
+            "rm" => fm.rnd_mode = FRndMode::NegInf,
+            "rp" => fm.rnd_mode = FRndMode::PosInf,
+            "rz" => fm.rnd_mode = FRndMode::Zero,
+            _ => return Err(format!("Unknown float modifier \".{}\"", m)),
+        }
+    }
+    Ok(fm)
+}
+
+fn parse_int_cmp_op(s: &str) -> ParseResult<IntCmpOp> {
+    match s {
+        "eq" => Ok(IntCmpOp::Eq),
+        "ne" => Ok(IntCmpOp::Ne),
+        "lt" => Ok(IntCmpOp::Lt),
+        "le" => Ok(IntCmpOp::Le),
+        "gt" => Ok(IntCmpOp::Gt),
+        "ge" => Ok(IntCmpOp::Ge),
+        _ => Err(format!("Unknown comparison \".{}\"", s)),
+    }
+}
+
+fn parse_int_cmp_type(s: &str) -> ParseResult<IntCmpType> {
+    match s {
+        "u32" => Ok(IntCmpType::U32),
+        "i32" => Ok(IntCmpType::I32),
+        _ => Err(format!("Unknown comparison type \".{}\"", s)),
+    }
+}
+
+/// Parses the modifiers printed by MemAccess, such as .global.a64.weak.b32
+fn parse_mem_access(mods: &[&str]) -> ParseResult<MemAccess> {
+    let err = || format!("Invalid memory access \".{}\"", mods.join("."));
+    let mut mods = mods.iter().copied().peekable();
+
+    let space = match mods.next() {
+        Some("global") => match mods.next() {
+            Some("a32") => MemSpace::Global(MemAddrType::A32),
+            Some("a64") => MemSpace::Global(MemAddrType::A64),
+            _ => return Err(err()),
+        },
+        Some("local") => MemSpace::Local,
+        Some("shared") => MemSpace::Shared,
+        _ => return Err(err()),
+    };
+
+    let order = match mods.next() {
+        Some("constant") => MemOrder::Constant,
+        Some("weak") => MemOrder::Weak,
+        Some("strong") => MemOrder::Strong(match mods.next() {
+            Some("cta") => MemScope::CTA,
+            Some("gpu") => MemScope::GPU,
+            Some("sys") => MemScope::System,
+            _ => return Err(err()),
+        }),
+        _ => return Err(err()),
+    };
+
+    let eviction_priority = match mods.peek() {
+        Some(&"ef") => MemEvictionPriority::First,
+        Some(&"el") => MemEvictionPriority::Last,
+        Some(&"lu") => MemEvictionPriority::Unchanged,
+        _ => MemEvictionPriority::Normal,
+    };
+    if eviction_priority != MemEvictionPriority::Normal {
+        mods.next();
+    }
+
+    let mem_type = match mods.next() {
+        Some("u8") => MemType::U8,
+        Some("i8") => MemType::I8,
+        Some("u16") => MemType::U16,
+        Some("i16") => MemType::I16,
+        Some("b32") => MemType::B32,
+        Some("b64") => MemType::B64,
+        Some("b128") => MemType::B128,
+        _ => return Err(err()),
+    };
+
+    if mods.next().is_some() {
+        return Err(err());
+    }
+
+    Ok(MemAccess {
+        mem_type: mem_type,
+        space: space,
+        order: order,
+        eviction_priority: eviction_priority,
+    })
+}
+
+struct Parser {
+    ssa_alloc: SSAValueAllocator,
+    phi_alloc: PhiAllocator,
+    label_alloc: LabelAllocator,
+    labels: HashMap<String, Label>,
+    ssa_values: HashSet<SSAValue>,
+}
+
+impl Parser {
+    fn new() -> Parser {
+        Parser {
+            ssa_alloc: SSAValueAllocator::new(),
+            phi_alloc: PhiAllocator::new(),
+            label_alloc: LabelAllocator::new(),
+            labels: HashMap::new(),
+            ssa_values: HashSet::new(),
+        }
+    }
+
+    fn parse_label(&mut self, s: &str) -> ParseResult<Label> {
+        if !s.starts_with('L') {
+            return Err(format!("Invalid label \"{}\"", s));
+        }
+        if let Some(label) = self.labels.get(s) {
+            return Ok(*label);
+        }
+        let label = self.label_alloc.alloc();
+        self.labels.insert(s.to_string(), label);
+        Ok(label)
+    }
+
+    /// Parses an SSA value without the leading %
+    fn parse_ssa_value(&mut self, s: &str) -> ParseResult<SSAValue> {
+        let (file, idx) = parse_reg_file(s)?;
+        let idx = parse_u32(idx)?;
+        if idx == 0 || idx >= (1 << 29) - 2 {
+            return Err(format!("Invalid SSA value index in \"{}\"", s));
+        }
+        self.ssa_alloc.reserve(idx);
+        let ssa = SSAValue::new(file, idx);
+        self.ssa_values.insert(ssa);
+        Ok(ssa)
+    }
+
+    fn parse_ssa_ref(&mut self, s: &str) -> ParseResult<SSARef> {
+        let vec = s.strip_prefix('{').and_then(|s| s.strip_suffix('}'));
+        let comps: Vec<&str> = match vec {
+            Some(vec) => vec.split_whitespace().collect(),
+            None => vec![s],
+        };
+
+        let mut ssa = Vec::new();
+        for c in comps {
+            let c = c
+                .strip_prefix('%')
+                .ok_or_else(|| format!("Invalid SSA value \"{}\"", c))?;
+            ssa.push(self.parse_ssa_value(c)?);
+        }
+
+        SSARef::try_from(ssa)
+            .map_err(|_| format!("Invalid SSA reference \"{}\"", s))
+    }
+
+    fn parse_dst(&mut self, s: &str) -> ParseResult<Dst> {
+        if s == "null" {
+            Ok(Dst::None)
+        } else if s.starts_with('%') || s.starts_with('{') {
+            Ok(self.parse_ssa_ref(s)?.into())
+        } else {
+            Ok(parse_reg_ref(s)?.into())
+        }
+    }
+
+    fn parse_src_ref(&mut self, s: &str) -> ParseResult<SrcRef> {
+        match s {
+            "rZ" => Ok(SrcRef::Zero),
+            "pT" => Ok(SrcRef::True),
+            "pF" => Ok(SrcRef::False),
+            _ => {
+                if s.starts_with(|c: char| c.is_ascii_digit()) {
+                    Ok(SrcRef::Imm32(parse_u32(s)?))
+                } else if s.starts_with("c[") {
+                    Ok(parse_cbuf_ref(s)?.into())
+                } else if s.starts_with('%') || s.starts_with('{') {
+                    Ok(self.parse_ssa_ref(s)?.into())
+                } else {
+                    Ok(parse_reg_ref(s)?.into())
+                }
+            }
+        }
+    }
+
+    fn parse_src(&mut self, s: &str, src_type: SrcType) -> ParseResult<Src> {
+        let is_float =
+            matches!(src_type, SrcType::F16v2 | SrcType::F32 | SrcType::F64);
+
+        let (src_mod, s) = if let Some(s) =
+            s.strip_prefix("-|").and_then(|s| s.strip_suffix('|'))
+        {
+            (SrcMod::FNegAbs, s)
+        } else if let Some(s) =
+            s.strip_prefix('|').and_then(|s| s.strip_suffix('|'))
+        {
+            (SrcMod::FAbs, s)
+        } else if let Some(s) = s.strip_prefix('-') {
+            if is_float {
+                (SrcMod::FNeg, s)
+            } else {
+                (SrcMod::INeg, s)
+            }
+        } else if let Some(s) = s.strip_prefix('!') {
+            (SrcMod::BNot, s)
+        } else {
+            (SrcMod::None, s)
+        };
+
+        Ok(Src {
+            src_ref: self.parse_src_ref(s)?,
+            src_mod: src_mod,
+        })
+    }
+
+    fn parse_srcs<const N: usize>(
+        &mut self,
+        op: &str,
+        srcs: &[&str],
+        src_type: SrcType,
+    ) -> ParseResult<[Src; N]> {
+        if srcs.len() != N {
+            return Err(format!(
+                "{} takes {} sources but got {}",
+                op,
+                N,
+                srcs.len()
+            ));
+        }
+
+        let mut res = [Src::new_zero(); N];
+        for i in 0..N {
+            res[i] = self.parse_src(srcs[i], src_type)?;
+        }
+        Ok(res)
+    }
+
+    fn parse_pred(&mut self, s: &str) -> ParseResult<Pred> {
+        let (pred_inv, s) = match s.strip_prefix('!') {
+            Some(s) => (true, s),
+            None => (false, s),
+        };
+        // Predicate SSA values are printed without the %, the same as
+        // predicate registers.  It's an SSA value if we've already seen it
+        // as one and a register otherwise.
+        let pred_ref = if s == "pT" {
+            PredRef::None
+        } else {
+            let (file, idx) = parse_reg_file(s)?;
+            let idx = parse_u32(idx)?;
+            let is_ssa = idx > 0
+                && idx < (1 << 29) - 2
+                && self.ssa_values.contains(&SSAValue::new(file, idx));
+            if is_ssa {
+                self.parse_ssa_value(s)?.into()
+            } else {
+                parse_reg_ref(s)?.into()
+            }
+        };
+        Ok(Pred {
+            pred_ref: pred_ref,
+            pred_inv: pred_inv,
+        })
+    }
+
+    fn parse_op(
+        &mut self,
+        name: &str,
+        dsts: &[Dst],
+        srcs: &[&str],
+    ) -> ParseResult<Op> {
+        // mov prints a non-trivial quad lane mask as mov[0x3]
+        let (name, quad_lanes) = match name.split_once('[') {
+            Some((name, lanes)) => {
+                let lanes = lanes
+                    .strip_suffix(']')
+                    .ok_or_else(|| format!("Invalid opcode \"{}\"", name))?;
+                (name, Some(parse_u32(lanes)?))
+            }
+            None => (name, None),
+        };
+
+        let mut parts = name.split('.');
+        let base = parts.next().unwrap();
+        let mods: Vec<&str> = parts.collect();
+
+        if quad_lanes.is_some() && base != "mov" {
+            return Err(format!("Unexpected [] on \"{}\"", base));
+        }
+        if !mods.is_empty()
+            && !matches!(base, "fadd" | "fmul" | "ffma" | "isetp" | "ld")
+        {
+            return Err(format!("Unknown modifiers on \"{}\"", name));
+        }
+
+        let op: Op = match base {
+            "copy" => {
+                let [src] = self.parse_srcs(base, srcs, SrcType::GPR)?;
+                OpCopy {
+                    dst: dst_or_none(dsts, 0),
+                    src: src,
+                }
+                .into()
+            }
+            "mov" => {
+                let [src] = self.parse_srcs(base, srcs, SrcType::ALU)?;
+                OpMov {
+                    dst: dst_or_none(dsts, 0),
+                    src: src,
+                    quad_lanes: quad_lanes.unwrap_or(0xf) as u8,
+                }
+                .into()
+            }
+            "undef" => {
+                // The destination is printed both before the = and as the
+                // only operand.
+                OpUndef {
+                    dst: dst_or_none(dsts, 0),
+                }
+                .into()
+            }
+            "iadd3" => OpIAdd3 {
+                dst: dst_or_none(dsts, 0),
+                overflow: [dst_or_none(dsts, 1), dst_or_none(dsts, 2)],
+                srcs: self.parse_srcs(base, srcs, SrcType::I32)?,
+            }
+            .into(),
+            "fadd" => {
+                let fm = parse_float_mods(&mods)?;
+                OpFAdd {
+                    dst: dst_or_none(dsts, 0),
+                    srcs: self.parse_srcs(base, srcs, SrcType::F32)?,
+                    saturate: fm.saturate,
+                    rnd_mode: fm.rnd_mode,
+                    ftz: fm.ftz,
+                }
+                .into()
+            }
+            "fmul" => {
+                let fm = parse_float_mods(&mods)?;
+                OpFMul {
+                    dst: dst_or_none(dsts, 0),
+                    srcs: self.parse_srcs(base, srcs, SrcType::F32)?,
+                    saturate: fm.saturate,
+                    rnd_mode: fm.rnd_mode,
+                    ftz: fm.ftz,
+                    dnz: fm.dnz,
+                }
+                .into()
+            }
+            "ffma" => {
+                let fm = parse_float_mods(&mods)?;
+                OpFFma {
+                    dst: dst_or_none(dsts, 0),
+                    srcs: self.parse_srcs(base, srcs, SrcType::F32)?,
+                    saturate: fm.saturate,
+                    rnd_mode: fm.rnd_mode,
+                    ftz: fm.ftz,
+                    dnz: fm.dnz,
+                }
+                .into()
+            }
+            "isetp" => {
+                // We don't handle accumulation or 64-bit comparisons
+                if mods.len() != 2 {
+                    return Err(format!("Unsupported isetp \"{}\"", name));
+                }
+                OpISetP {
+                    dst: dst_or_none(dsts, 0),
+                    set_op: PredSetOp::And,
+                    cmp_op: parse_int_cmp_op(mods[0])?,
+                    cmp_type: parse_int_cmp_type(mods[1])?,
+                    ex: false,
+                    srcs: self.parse_srcs(base, srcs, SrcType::ALU)?,
+                    accum: true.into(),
+                    low_cmp: true.into(),
+                }
+                .into()
+            }
+            "sel" => {
+                if srcs.len() != 3 {
+                    return Err("sel takes 3 sources".to_string());
+                }
+                OpSel {
+                    dst: dst_or_none(dsts, 0),
+                    cond: self.parse_src(srcs[0], SrcType::Pred)?,
+                    srcs: self.parse_srcs(base, &srcs[1..], SrcType::ALU)?,
+                }
+                .into()
+            }
+            "s2r" => {
+                let idx = match srcs {
+                    [sr] => sr
+                        .strip_prefix("sr[")
+                        .and_then(|s| s.strip_suffix(']'))
+                        .ok_or_else(|| format!("Invalid sysval \"{}\"", sr))?,
+                    _ => return Err("s2r takes 1 source".to_string()),
+                };
+                OpS2R {
+                    dst: dst_or_none(dsts, 0),
+                    idx: u8::try_from(parse_u32(idx)?)
+                        .map_err(|_| format!("Invalid sysval \"{}\"", idx))?,
+                }
+                .into()
+            }
+            "ld" => {
+                // The address is printed as [addr] or [addr+offset]
+                let addr = match srcs {
+                    [addr] => addr
+                        .strip_prefix('[')
+                        .and_then(|s| s.strip_suffix(']'))
+                        .ok_or_else(|| format!("Invalid address {}", addr))?,
+                    _ => return Err("ld takes 1 address".to_string()),
+                };
+                let (addr, offset) = match addr.split_once('+') {
+                    Some((addr, offset)) => (addr, parse_u32(offset)?),
+                    None => (addr, 0),
+                };
+                OpLd {
+                    dst: dst_or_none(dsts, 0),
+                    addr: self.parse_src(addr, SrcType::GPR)?,
+                    offset: offset as i32,
+                    access: parse_mem_access(&mods)?,
+                }
+                .into()
+            }
+            "bra" => match srcs {
+                [target] => OpBra {
+                    target: self.parse_label(target)?,
+                }
+                .into(),
+                _ => return Err("bra takes 1 label".to_string()),
+            },
+            "exit" => OpExit {}.into(),
+            "kill" => OpKill {}.into(),
+            "ssy" => match srcs {
+                [target] => OpSSy {
+                    target: self.parse_label(target)?,
+                }
+                .into(),
+                _ => return Err("ssy takes 1 label".to_string()),
+            },
+            "sync" => match srcs {
+                [target] => OpSync {
+                    target: self.parse_label(target)?,
+                }
+                .into(),
+                _ => return Err("sync takes 1 label".to_string()),
+            },
+            "pbk" => match srcs {
+                [target] => OpPBk {
+                    target: self.parse_label(target)?,
+                }
+                .into(),
+                _ => return Err("pbk takes 1 label".to_string()),
+            },
+            "brk" => match srcs {
+                [target] => OpBrk {
+                    target: self.parse_label(target)?,
+                }
+                .into(),
+                _ => return Err("brk takes 1 label".to_string()),
+            },
+            "pcnt" => match srcs {
+                [target] => OpPCnt {
+                    target: self.parse_label(target)?,
+                }
+                .into(),
+                _ => return Err("pcnt takes 1 label".to_string()),
+            },
+            "cont" => match srcs {
+                [target] => OpCont {
+                    target: self.parse_label(target)?,
+                }
+                .into(),
+                _ => return Err("cont takes 1 label".to_string()),
+            },
+            "nop" => match srcs {
+                [] => OpNop { label: None }.into(),
+                [label] => OpNop {
+                    label: Some(self.parse_label(label)?),
+                }
+                .into(),
+                _ => return Err("nop takes at most 1 label".to_string()),
+            },
+            _ => return Err(format!("Unknown opcode \"{}\"", base)),
+        };
+        Ok(op)
+    }
+
+    fn parse_phi_dsts(&mut self, args: &[&str]) -> ParseResult<Op> {
+        let mut phi = OpPhiDsts::new();
+        for arg in args.chunks(3) {
+            let [dst, "=", id] = arg else {
+                return Err(format!("Invalid phi_dst {:?}", args));
+            };
+            let id = parse_phi_id(id)?;
+            self.phi_alloc.reserve(id);
+            phi.dsts.push(id, self.parse_dst(dst)?);
+        }
+        Ok(phi.into())
+    }
+
+    fn parse_phi_srcs(&mut self, args: &[&str]) -> ParseResult<Op> {
+        let mut phi = OpPhiSrcs::new();
+        for arg in args.chunks(3) {
+            let [id, "=", src] = arg else {
+                return Err(format!("Invalid phi_src {:?}", args));
+            };
+            let id = parse_phi_id(id)?;
+            self.phi_alloc.reserve(id);
+            phi.srcs.push(id, self.parse_src(src, SrcType::GPR)?);
+        }
+        Ok(phi.into())
+    }
+
+    fn parse_instr(&mut self, line: &str) -> ParseResult<Box<Instr>> {
+        let tokens = tokenize(line)?;
+        let (pred, tokens) = match tokens.split_first() {
+            Some((p, rest)) if p.starts_with('@') => {
+                (self.parse_pred(&p[1..])?, rest)
+            }
+            _ => (PredRef::None.into(), &tokens[..]),
+        };
+
+        let op = match tokens {
+            [] => return Err("Missing opcode".to_string()),
+            ["phi_dst", args @ ..] => self.parse_phi_dsts(args)?,
+            ["phi_src", args @ ..] => self.parse_phi_srcs(args)?,
+            _ => {
+                let (dsts, op) = match tokens.iter().position(|t| *t == "=") {
+                    Some(eq) => (&tokens[..eq], &tokens[(eq + 1)..]),
+                    None => (&tokens[..0], tokens),
+                };
+                let Some((name, srcs)) = op.split_first() else {
+                    return Err("Missing opcode".to_string());
+                };
+
+                let mut parsed_dsts = Vec::new();
+                for dst in dsts {
+                    parsed_dsts.push(self.parse_dst(dst)?);
+                }
+                self.parse_op(name, &parsed_dsts, srcs)?
+            }
+        };
+
+        let mut instr = Instr::new_boxed(op);
+        instr.pred = pred;
+        Ok(instr)
+    }
+
+    fn parse_function(mut self, text: &str) -> ParseResult<Function> {
+        let mut cfg = CFGBuilder::new();
+        let mut block: Option<(usize, BasicBlock)> = None;
+        let mut num_blocks = 0;
+
+        for (line_num, line) in text.lines().enumerate() {
+            let err = |e: String| format!("Line {}: {}", line_num + 1, e);
+
+            // Strip off instruction dependency comments
+            let line = match line.find("//") {
+                Some(i) => &line[..i],
+                None => line,
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(header) = line.strip_prefix("block ") {
+                if block.is_some() {
+                    return Err(err("Unterminated block".to_string()));
+                }
+
+                // The predecessors are implied by the successors so we only
+                // care about the index and the label.
+                let header = header
+                    .strip_suffix('{')
+                    .ok_or_else(|| err("Invalid block header".to_string()))?;
+                let tokens = tokenize(header).map_err(err)?;
+                let [idx, label, ..] = tokens[..] else {
+                    return Err(err("Invalid block header".to_string()));
+                };
+                let idx = parse_u32(idx).map_err(err)? as usize;
+                if idx != num_blocks {
+                    return Err(err("Blocks are out of order".to_string()));
+                }
+                let label = self.parse_label(label).map_err(err)?;
+                block = Some((idx, BasicBlock::new(label)));
+                num_blocks += 1;
+            } else if let Some(footer) = line.strip_prefix('}') {
+                let Some((idx, b)) = block.take() else {
+                    return Err(err("Unexpected '}'".to_string()));
+                };
+
+                let succs = footer
+                    .trim()
+                    .strip_prefix("->")
+                    .map(|s| s.trim())
+                    .and_then(|s| s.strip_prefix('['))
+                    .and_then(|s| s.strip_suffix(']'))
+                    .ok_or_else(|| err("Invalid block footer".to_string()))?;
+                for s in succs.split(',') {
+                    let s = s.trim();
+                    if !s.is_empty() {
+                        let s = parse_u32(s).map_err(err)? as usize;
+                        cfg.add_edge(idx, s);
+                    }
+                }
+                cfg.add_node(idx, b);
+            } else {
+                let Some((_, b)) = &mut block else {
+                    return Err(err("Instruction outside a block".to_string()));
+                };
+                b.instrs.push(self.parse_instr(line).map_err(err)?);
+            }
+        }
+
+        if block.is_some() {
+            return Err("Unterminated block at end of input".to_string());
+        }
+        if num_blocks == 0 {
+            return Err("No blocks".to_string());
+        }
+
+        Ok(Function {
+            ssa_alloc: self.ssa_alloc,
+            phi_alloc: self.phi_alloc,
+            blocks: cfg.as_cfg(),
+        })
+    }
+}
+
+/// Parses a function in the format produced by its Display impl
+pub fn parse_function(text: &str) -> Result<Function, String> {
+    Parser::new().parse_function(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses text and checks that printing it and parsing it again is
+    /// stable
+    fn parse_round_trip(text: &str) -> Function {
+        let f = parse_function(text).unwrap();
+        let printed = f.to_string();
+        let reparsed = parse_function(&printed).unwrap();
+        assert_eq!(printed, reparsed.to_string());
+        f
+    }
+
+    #[test]
+    fn round_trip_alu() {
+        let f = parse_round_trip(
+            "block 0 L0 [] -> {
+                %r1 = s2r sr[0x21]
+                %r2 = copy c[0x1][0x10]
+                %r3 = fadd.sat -%r1 |%r2|
+                %r4 = fmul.rz.ftz %r3 0x3f800000
+                %r5 = ffma.dnz -|%r4| %r3 %r1
+                %r6 = iadd3 -%r1 0x10 rZ
+                %p7 = isetp.lt.u32 %r6 %r2
+                %r8 = sel !%p7 %r5 %r6
+                %r9 = mov[0x3] %r8
+                %r10 = undef %r10
+                exit
+            } -> []",
+        );
+
+        let instrs = &f.blocks[0].instrs;
+        assert_eq!(instrs.len(), 11);
+        match &instrs[3].op {
+            Op::FMul(op) => {
+                assert!(op.ftz && !op.dnz && !op.saturate);
+                assert!(op.rnd_mode == FRndMode::Zero);
+                assert!(op.srcs[1].src_ref == SrcRef::Imm32(0x3f800000));
+            }
+            _ => panic!("Expected fmul"),
+        }
+        match &instrs[4].op {
+            Op::FFma(op) => {
+                assert!(op.dnz && !op.ftz);
+                assert!(op.srcs[0].src_mod == SrcMod::FNegAbs);
+            }
+            _ => panic!("Expected ffma"),
+        }
+        match &instrs[5].op {
+            Op::IAdd3(op) => {
+                assert!(op.srcs[0].src_mod == SrcMod::INeg);
+                assert!(op.overflow.iter().all(|d| d.is_none()));
+            }
+            _ => panic!("Expected iadd3"),
+        }
+        match &instrs[8].op {
+            Op::Mov(op) => assert_eq!(op.quad_lanes, 0x3),
+            _ => panic!("Expected mov"),
+        }
+    }
+
+    #[test]
+    fn round_trip_ld() {
+        let f = parse_round_trip(
+            "block 0 L0 [] -> {
+                %r1 = copy c[0x1][0x0]
+                %r2 = ld.global.a32.weak.b32 [%r1+0x10]
+                %r3 = ld.global.a64.strong.gpu.ef.b64 [{%r1 %r2}]
+                %r4 = ld.shared.constant.u16 [%r1]
+                exit
+            } -> []",
+        );
+
+        match &f.blocks[0].instrs[2].op {
+            Op::Ld(op) => {
+                assert_eq!(op.offset, 0);
+                assert!(op.access.mem_type == MemType::B64);
+                assert!(op.access.space == MemSpace::Global(MemAddrType::A64));
+                assert!(op.access.order == MemOrder::Strong(MemScope::GPU));
+                assert!(
+                    op.access.eviction_priority == MemEvictionPriority::First
+                );
+            }
+            _ => panic!("Expected ld"),
+        }
+    }
+
+    #[test]
+    fn round_trip_control_flow() {
+        let f = parse_round_trip(
+            "block 0 L5 [] -> {
+                %r1 = s2r sr[0x21]
+                %p2 = isetp.lt.u32 %r1 0x10
+                phi_src φ0 = %r1
+                @!p2 bra L7
+            } -> [1, 2]
+            block 1 L6 [0] -> {
+                %r3 = iadd3 %r1 0x1 rZ
+                phi_src φ0 = %r3
+            } -> [2]
+            block 2 L7 [0, 1] -> {
+                phi_dst %r4 = φ0
+                exit
+            } -> []",
+        );
+
+        assert_eq!(f.blocks.len(), 3);
+        assert_eq!(f.blocks.succ_indices(1), &[2]);
+        assert_eq!(f.blocks.pred_indices(2).len(), 2);
+
+        // Labels are renumbered in the order they first appear
+        assert_eq!(f.blocks[0].label.to_string(), "L0");
+        let bra = f.blocks[0].instrs.last().unwrap();
+        assert!(bra.pred.pred_inv);
+        match &bra.op {
+            Op::Bra(op) => assert!(op.target == f.blocks[2].label),
+            _ => panic!("Expected bra"),
+        }
+    }
+
+    #[test]
+    fn round_trip_registers() {
+        let f = parse_round_trip(
+            "block 0 L0 [] -> {
+                r0..2 = copy {%r1 %r2}
+                r4 = iadd3 r0 r1 rZ
+                exit
+            } -> []",
+        );
+
+        match &f.blocks[0].instrs[0].op {
+            Op::Copy(op) => match op.dst {
+                Dst::Reg(reg) => assert_eq!(reg.comps(), 2),
+                _ => panic!("Expected a register destination"),
+            },
+            _ => panic!("Expected copy"),
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = parse_function(
+            "block 0 L0 [] -> {
+                %r1 = frob %r2
+            } -> []",
+        )
+        .err()
+        .unwrap();
+        assert!(err.starts_with("Line 2:"), "{err}");
+        assert!(err.contains("Unknown opcode"), "{err}");
+
+        let err = parse_function(
+            "block 0 L0 [] -> {
+                exit",
+        )
+        .err()
+        .unwrap();
+        assert!(err.contains("Unterminated block"), "{err}");
+
+        let err = parse_function(
+            "block 1 L0 [] -> {
+                exit
+            } -> []",
+        )
+        .err()
+        .unwrap();
+        assert!(err.contains("out of order"), "{err}");
+    }
+}
@@ -46,6 +46,7 @@ fn copy_alu_src(b: &mut impl SSABuilder, src: &mut Src, src_type: SrcType) {
     let val = match src_type {
         SrcType::GPR
         | SrcType::ALU
+        | SrcType::F16v2
         | SrcType::F32
         | SrcType::I32
         | SrcType::B32 => b.alloc_ssa(RegFile::GPR, 1),
@@ -363,6 +364,7 @@ fn legalize_sm50_instr(
                         assert!(src_is_reg(src));
                     }
                     SrcType::ALU
+                    | SrcType::F16v2
                     | SrcType::F32
                     | SrcType::F64
                     | SrcType::I32
@@ -424,6 +426,30 @@ fn legalize_sm70_instr(
             copy_alu_src_if_not_reg(b, src0, SrcType::F32);
         }
         Op::MuFu(_) => (), // Nothing to do
+        Op::HAdd2(op) => {
+            let [ref mut src0, ref mut src1] = op.srcs;
+            swap_srcs_if_not_reg(src0, src1);
+            copy_alu_src_if_not_reg(b, src0, SrcType::F16v2);
+        }
+        Op::HFma2(op) => {
+            let [ref mut src0, ref mut src1, ref mut src2] = op.srcs;
+            swap_srcs_if_not_reg(src0, src1);
+            copy_alu_src_if_not_reg(b, src0, SrcType::F16v2);
+            copy_alu_src_if_both_not_reg(b, src1, src2, SrcType::F16v2);
+        }
+        Op::HMul2(op) => {
+            let [ref mut src0, ref mut src1] = op.srcs;
+            swap_srcs_if_not_reg(src0, src1);
+            copy_alu_src_if_not_reg(b, src0, SrcType::F16v2);
+        }
+        Op::HSet2(op) => {
+            let [ref mut src0, ref mut src1] = op.srcs;
+            if !src_is_reg(src0) && src_is_reg(src1) {
+                std::mem::swap(src0, src1);
+                op.cmp_op = op.cmp_op.flip();
+            }
+            copy_alu_src_if_not_reg(b, src0, SrcType::F16v2);
+        }
         Op::DAdd(op) => {
             let [ref mut src0, ref mut src1] = op.srcs;
             swap_srcs_if_not_reg(src0, src1);
@@ -647,6 +673,7 @@ fn legalize_sm70_instr(
                         assert!(src_is_reg(src));
                     }
                     SrcType::ALU
+                    | SrcType::F16v2
                     | SrcType::F32
                     | SrcType::F64
                     | SrcType::I32
@@ -663,12 +690,42 @@ fn legalize_sm70_instr(
     }
 }
 
+fn legalize_mem_offset(b: &mut impl SSABuilder, instr: &mut Instr) {
+    let (space, addr, offset) = match &mut instr.op {
+        Op::Ld(op) => (op.access.space, &mut op.addr, &mut op.offset),
+        Op::St(op) => (op.access.space, &mut op.addr, &mut op.offset),
+        Op::Atom(op) => (op.mem_space, &mut op.addr, &mut op.addr_offset),
+        _ => return,
+    };
+
+    if space.imm_offset_fits(b.sm(), *offset) {
+        return;
+    }
+
+    // The offset doesn't fit in the instruction so add it into the address.
+    // We don't legalize the instructions we emit here so the offset needs to
+    // go through a register first.
+    assert!(space.addr_type() == MemAddrType::A32);
+    let offset_gpr = b.copy((*offset as u32).into());
+    let new_addr = b.iadd(*addr, offset_gpr.into());
+    *addr = new_addr.into();
+    *offset = 0;
+}
+
 fn legalize_instr(
     b: &mut impl SSABuilder,
     bl: &impl BlockLiveness,
     ip: usize,
     instr: &mut Instr,
 ) {
+    legalize_mem_offset(b, instr);
+
+    // opt_uniform_regs() only creates uniform instructions with sources the
+    // uniform encodings can take.
+    if instr.is_uniform() {
+        return;
+    }
+
     if b.sm() >= 70 {
         legalize_sm70_instr(b, bl, ip, instr);
     } else if b.sm() >= 50 {
@@ -679,32 +736,7 @@ fn legalize_instr(
 
     let src_types = instr.src_types();
     for (i, src) in instr.srcs_mut().iter_mut().enumerate() {
-        if let SrcRef::Imm32(u) = &mut src.src_ref {
-            *u = match src_types[i] {
-                SrcType::F32 | SrcType::F64 => match src.src_mod {
-                    SrcMod::None => *u,
-                    SrcMod::FAbs => *u & !(1_u32 << 31),
-                    SrcMod::FNeg => *u ^ !(1_u32 << 31),
-                    SrcMod::FNegAbs => *u | !(1_u32 << 31),
-                    _ => panic!("Not a float source modifier"),
-                },
-                SrcType::I32 => match src.src_mod {
-                    SrcMod::None => *u,
-                    SrcMod::INeg => -(*u as i32) as u32,
-                    _ => panic!("Not an integer source modifier"),
-                },
-                SrcType::B32 => match src.src_mod {
-                    SrcMod::None => *u,
-                    SrcMod::BNot => !*u,
-                    _ => panic!("Not a bitwise source modifier"),
-                },
-                _ => {
-                    assert!(src.src_mod.is_none());
-                    *u
-                }
-            };
-            src.src_mod = SrcMod::None;
-        }
+        src.fold_imm_src_mod(src_types[i]);
     }
 
     let mut vec_src_map: HashMap<SSARef, SSARef> = HashMap::new();
@@ -745,6 +777,34 @@ fn legalize_instr(
     }
 }
 
+/// Liveness which claims every value is live everywhere.  This makes
+/// legalization take the conservative path wherever it depends on liveness.
+struct AllLive {}
+
+impl BlockLiveness for AllLive {
+    fn is_live_after_ip(&self, _val: &SSAValue, _ip: usize) -> bool {
+        true
+    }
+
+    fn is_live_in(&self, _val: &SSAValue) -> bool {
+        true
+    }
+
+    fn is_live_out(&self, _val: &SSAValue) -> bool {
+        true
+    }
+}
+
+/// Returns the number of instructions legalization would add in order to
+/// make `instr` encodable, without modifying `instr`.
+pub fn legalize_instr_cost(sm: u8, instr: &Instr) -> usize {
+    let mut alloc = SSAValueAllocator::new();
+    let mut b = SSAInstrBuilder::new(sm, &mut alloc);
+    let mut instr = instr.clone();
+    legalize_instr(&mut b, &AllLive {}, 0, &mut instr);
+    b.as_vec().len()
+}
+
 impl Shader {
     pub fn legalize(&mut self) {
         let sm = self.info.sm;
@@ -766,3 +826,95 @@ impl Shader {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shared_ld(alloc: &mut SSAValueAllocator, offset: i32) -> Instr {
+        Instr::new(OpLd {
+            dst: alloc.alloc(RegFile::GPR).into(),
+            addr: alloc.alloc(RegFile::GPR).into(),
+            offset: offset,
+            access: MemAccess {
+                mem_type: MemType::B32,
+                space: MemSpace::Shared,
+                order: MemOrder::Strong(MemScope::CTA),
+                eviction_priority: MemEvictionPriority::Normal,
+            },
+        })
+    }
+
+    #[test]
+    fn imm_offset_fits() {
+        let global = MemSpace::Global(MemAddrType::A64);
+        for (space, sm) in [
+            (MemSpace::Shared, 70),
+            (MemSpace::Local, 70),
+            (MemSpace::Shared, 50),
+            (global, 50),
+        ] {
+            assert_eq!(space.imm_offset_bits(sm), 24);
+            assert!(space.imm_offset_fits(sm, 0));
+            assert!(space.imm_offset_fits(sm, 0x7fffff));
+            assert!(space.imm_offset_fits(sm, -0x800000));
+            assert!(!space.imm_offset_fits(sm, 0x800000));
+            assert!(!space.imm_offset_fits(sm, -0x800001));
+        }
+        assert_eq!(global.imm_offset_bits(70), 32);
+        assert!(global.imm_offset_fits(70, i32::MAX));
+        assert!(global.imm_offset_fits(70, i32::MIN));
+    }
+
+    #[test]
+    fn mem_offset_in_range() {
+        let mut alloc = SSAValueAllocator::new();
+        let mut instr = shared_ld(&mut alloc, 0x7ffffc);
+        let mut b = SSAInstrBuilder::new(70, &mut alloc);
+        legalize_mem_offset(&mut b, &mut instr);
+
+        assert!(b.as_vec().is_empty());
+        let Op::Ld(op) = &instr.op else {
+            panic!("Expected ld")
+        };
+        assert_eq!(op.offset, 0x7ffffc);
+    }
+
+    #[test]
+    fn mem_offset_out_of_range() {
+        for sm in [50, 70] {
+            let mut alloc = SSAValueAllocator::new();
+            let mut instr = shared_ld(&mut alloc, 0x800000);
+            let Op::Ld(op) = &instr.op else {
+                panic!("Expected ld")
+            };
+            let old_addr = op.addr;
+
+            let mut b = SSAInstrBuilder::new(sm, &mut alloc);
+            legalize_mem_offset(&mut b, &mut instr);
+            let added = b.as_vec();
+
+            // The offset goes through a GPR and gets added into the address
+            let Op::Ld(op) = &instr.op else {
+                panic!("Expected ld")
+            };
+            assert_eq!(op.offset, 0);
+            assert_eq!(added.len(), 2);
+            let Op::Copy(copy) = &added[0].op else {
+                panic!("Expected copy")
+            };
+            assert!(copy.src.src_ref == SrcRef::Imm32(0x800000));
+
+            let (dst, srcs) = match &added[1].op {
+                Op::IAdd3(add) => (add.dst, &add.srcs[1..]),
+                Op::IAdd2(add) => (add.dst, &add.srcs[..]),
+                _ => panic!("Expected an integer add"),
+            };
+            assert!(srcs[0] == old_addr);
+            let offset_gpr = *copy.dst.as_ssa().unwrap();
+            assert!(srcs[1].src_ref == offset_gpr.into());
+            let new_addr = *dst.as_ssa().unwrap();
+            assert!(op.addr.src_ref == new_addr.into());
+        }
+    }
+}
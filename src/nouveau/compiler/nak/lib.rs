@@ -3,26 +3,45 @@
 
 mod api;
 mod assign_regs;
+mod assign_reuse;
 mod bitset;
 mod builder;
 mod calc_instr_deps;
 mod cfg;
+mod decode_sm50;
+mod divergence;
+mod encode;
 mod encode_sm50;
 mod encode_sm70;
 mod from_nir;
+mod golden;
 mod ir;
+#[cfg(test)]
+mod ir_parse;
 mod legalize;
 mod liveness;
 mod lower_copy_swap;
 mod lower_par_copies;
+mod mem_alias;
 mod nir;
+#[cfg(test)]
+mod nvdisasm;
 mod opt_bar_prop;
+mod opt_canonicalize;
+mod opt_const_fold;
 mod opt_copy_prop;
+mod opt_cse;
 mod opt_dce;
+mod opt_fold_srcs;
+mod opt_instr_sched;
 mod opt_jump_thread;
+mod opt_loop_prefetch;
 mod opt_lop;
 mod opt_out;
+mod opt_uniform_regs;
+mod prologue;
 mod repair_ssa;
 mod sph;
 mod spill_values;
 mod to_cssa;
+mod validate;
@@ -36,7 +36,7 @@ impl LowerCopySwap {
                     panic!("Cannot copy to GPR");
                 }
                 SrcRef::Reg(src_reg) => match src_reg.file() {
-                    RegFile::GPR => {
+                    RegFile::GPR | RegFile::UGPR => {
                         b.push_op(OpMov {
                             dst: copy.dst,
                             src: copy.src,
@@ -70,6 +70,37 @@ impl LowerCopySwap {
                 },
                 SrcRef::SSA(_) => panic!("Should be run after RA"),
             },
+            RegFile::UGPR => match copy.src.src_ref {
+                SrcRef::Zero | SrcRef::Imm32(_) => {
+                    b.push_op(OpMov {
+                        dst: copy.dst,
+                        src: copy.src,
+                        quad_lanes: 0xf,
+                    });
+                }
+                SrcRef::CBuf(_) => {
+                    b.push_op(OpLdc {
+                        dst: copy.dst,
+                        cb: copy.src,
+                        offset: Src::new_zero(),
+                        mem_type: MemType::B32,
+                    });
+                }
+                SrcRef::Reg(src_reg) => match src_reg.file() {
+                    RegFile::UGPR => {
+                        b.push_op(OpMov {
+                            dst: copy.dst,
+                            src: copy.src,
+                            quad_lanes: 0xf,
+                        });
+                    }
+                    _ => panic!("Cannot copy to UGPR"),
+                },
+                SrcRef::True | SrcRef::False => {
+                    panic!("Cannot copy to UGPR");
+                }
+                SrcRef::SSA(_) => panic!("Should be run after RA"),
+            },
             RegFile::Pred => match copy.src.src_ref {
                 SrcRef::Zero | SrcRef::Imm32(_) | SrcRef::CBuf(_) => {
                     panic!("Cannot copy to Pred");
@@ -0,0 +1,191 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+//! A very simple memory alias analysis
+//!
+//! This is entirely local and only looks at a single pair of instructions at
+//! a time.  It doesn't chase address computations at all.  Two accesses are
+//! known not to alias if they are in different memory spaces, if either one
+//! is a load from memory which is known to be constant for the duration of
+//! the shader, or if they use the same SSA base address with disjoint static
+//! offset ranges.
+
+use crate::ir::*;
+
+use std::ops::Range;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum AliasSpace {
+    /// Global memory and anything else which may be backed by it.  Surfaces
+    /// live in global memory and may be bound as SSBOs at the same time.
+    Global,
+    Local,
+    Shared,
+    /// Constant buffers are never written from a shader.
+    CBuf,
+}
+
+struct MemRef {
+    space: AliasSpace,
+    base: Option<SSARef>,
+    range: Option<Range<i64>>,
+    writes: bool,
+    can_reorder: bool,
+}
+
+fn mem_type_size_B(mem_type: MemType) -> i64 {
+    match mem_type {
+        MemType::U8 | MemType::I8 => 1,
+        MemType::U16 | MemType::I16 => 2,
+        MemType::B32 => 4,
+        MemType::B64 => 8,
+        MemType::B128 => 16,
+    }
+}
+
+fn atom_type_size_B(atom_type: AtomType) -> i64 {
+    match atom_type {
+        AtomType::F16x2 | AtomType::U32 | AtomType::I32 | AtomType::F32 => 4,
+        AtomType::U64 | AtomType::I64 | AtomType::F64 => 8,
+    }
+}
+
+fn alias_space(space: MemSpace) -> AliasSpace {
+    match space {
+        MemSpace::Global(_) => AliasSpace::Global,
+        MemSpace::Local => AliasSpace::Local,
+        MemSpace::Shared => AliasSpace::Shared,
+    }
+}
+
+fn static_range(offset: i32, size_B: i64) -> Range<i64> {
+    let start = i64::from(offset);
+    start..(start + size_B)
+}
+
+impl MemRef {
+    fn for_instr(instr: &Instr) -> Option<MemRef> {
+        match &instr.op {
+            Op::Ld(op) => Some(MemRef {
+                space: alias_space(op.access.space),
+                base: op.addr.as_ssa().copied(),
+                range: Some(static_range(
+                    op.offset,
+                    mem_type_size_B(op.access.mem_type),
+                )),
+                writes: false,
+                can_reorder: op.access.order == MemOrder::Constant,
+            }),
+            Op::St(op) => Some(MemRef {
+                space: alias_space(op.access.space),
+                base: op.addr.as_ssa().copied(),
+                range: Some(static_range(
+                    op.offset,
+                    mem_type_size_B(op.access.mem_type),
+                )),
+                writes: true,
+                can_reorder: false,
+            }),
+            Op::Atom(op) => Some(MemRef {
+                space: alias_space(op.mem_space),
+                base: op.addr.as_ssa().copied(),
+                range: Some(static_range(
+                    op.addr_offset,
+                    atom_type_size_B(op.atom_type),
+                )),
+                writes: true,
+                can_reorder: false,
+            }),
+            Op::Ldc(_) => Some(MemRef {
+                space: AliasSpace::CBuf,
+                base: None,
+                range: None,
+                writes: false,
+                can_reorder: true,
+            }),
+            Op::SuLd(op) => Some(MemRef {
+                space: AliasSpace::Global,
+                base: None,
+                range: None,
+                writes: false,
+                can_reorder: op.mem_order == MemOrder::Constant,
+            }),
+            Op::SuSt(_) | Op::SuAtom(_) => Some(MemRef {
+                space: AliasSpace::Global,
+                base: None,
+                range: None,
+                writes: true,
+                can_reorder: false,
+            }),
+            Op::Tex(_)
+            | Op::Tld(_)
+            | Op::Tld4(_)
+            | Op::Tmml(_)
+            | Op::Txd(_)
+            | Op::Txq(_)
+            | Op::Footprint(_) => Some(MemRef {
+                space: AliasSpace::Global,
+                base: None,
+                range: None,
+                writes: false,
+                can_reorder: false,
+            }),
+            _ => None,
+        }
+    }
+
+    fn may_alias(&self, other: &MemRef) -> bool {
+        // Two reads never conflict
+        if !self.writes && !other.writes {
+            return false;
+        }
+
+        if self.space != other.space {
+            return false;
+        }
+
+        // Memory which is constant for the life of the shader can't be
+        // written so nothing which reads it can conflict with anything.
+        if self.can_reorder || other.can_reorder {
+            return false;
+        }
+
+        if let (Some(a_base), Some(b_base)) = (&self.base, &other.base) {
+            if a_base == b_base {
+                if let (Some(a), Some(b)) = (&self.range, &other.range) {
+                    return a.start < b.end && b.start < a.end;
+                }
+            }
+        }
+
+        true
+    }
+}
+
+/// Returns true if the instruction orders memory accesses around it such that
+/// no memory access may be moved across it.
+pub fn is_mem_barrier(instr: &Instr) -> bool {
+    matches!(
+        instr.op,
+        Op::MemBar(_) | Op::Bar(_) | Op::CCtl(_) | Op::BSync(_)
+    )
+}
+
+/// Returns true if the instruction reads or writes memory
+pub fn accesses_mem(instr: &Instr) -> bool {
+    MemRef::for_instr(instr).is_some()
+}
+
+/// Returns true if the two instructions may access the same memory and at
+/// least one of them writes it.  Swapping the order of two instructions for
+/// which this returns false does not change the result of the program.
+pub fn has_mem_dep(a: &Instr, b: &Instr) -> bool {
+    if is_mem_barrier(a) || is_mem_barrier(b) {
+        return accesses_mem(a) || accesses_mem(b);
+    }
+
+    match (MemRef::for_instr(a), MemRef::for_instr(b)) {
+        (Some(a), Some(b)) => a.may_alias(&b),
+        _ => false,
+    }
+}
@@ -255,6 +255,7 @@ pub trait NirIntrinsicInstr {
     fn cluster_size(&self) -> u32;
     fn image_dim(&self) -> glsl_sampler_dim;
     fn image_array(&self) -> bool;
+    fn format(&self) -> pipe_format;
     fn access(&self) -> gl_access_qualifier;
     fn align(&self) -> u32;
     fn align_mul(&self) -> u32;
@@ -324,6 +325,10 @@ impl NirIntrinsicInstr for nir_intrinsic_instr {
         self.get_const_index(NIR_INTRINSIC_IMAGE_ARRAY) != 0
     }
 
+    fn format(&self) -> pipe_format {
+        self.get_const_index(NIR_INTRINSIC_FORMAT) as pipe_format
+    }
+
     fn access(&self) -> gl_access_qualifier {
         self.get_const_index(NIR_INTRINSIC_ACCESS) as gl_access_qualifier
     }
@@ -525,6 +530,7 @@ pub trait NirIf {
     fn first_else_block(&self) -> &nir_block;
     fn iter_then_list(&self) -> ExecListIter<nir_cf_node>;
     fn iter_else_list(&self) -> ExecListIter<nir_cf_node>;
+    fn following_block(&self) -> &nir_block;
 }
 
 impl NirIf for nir_if {
@@ -540,22 +546,38 @@ impl NirIf for nir_if {
     fn iter_else_list(&self) -> ExecListIter<nir_cf_node> {
         ExecListIter::new(&self.else_list, offset_of!(nir_cf_node, node))
     }
+    fn following_block(&self) -> &nir_block {
+        self.cf_node.next().unwrap().as_block().unwrap()
+    }
 }
 
 pub trait NirLoop {
     fn iter_body(&self) -> ExecListIter<nir_cf_node>;
+    fn first_block(&self) -> &nir_block;
+    fn following_block(&self) -> &nir_block;
+    fn loop_info(&self) -> Option<&nir_loop_info>;
 }
 
 impl NirLoop for nir_loop {
     fn iter_body(&self) -> ExecListIter<nir_cf_node> {
         ExecListIter::new(&self.body, offset_of!(nir_cf_node, node))
     }
+    fn first_block(&self) -> &nir_block {
+        self.iter_body().next().unwrap().as_block().unwrap()
+    }
+    fn following_block(&self) -> &nir_block {
+        self.cf_node.next().unwrap().as_block().unwrap()
+    }
+    fn loop_info(&self) -> Option<&nir_loop_info> {
+        unsafe { self.info.as_ref() }
+    }
 }
 
 pub trait NirCfNode {
     fn as_block(&self) -> Option<&nir_block>;
     fn as_if(&self) -> Option<&nir_if>;
     fn as_loop(&self) -> Option<&nir_loop>;
+    fn next(&self) -> Option<&nir_cf_node>;
 }
 
 impl NirCfNode for nir_cf_node {
@@ -582,6 +604,16 @@ impl NirCfNode for nir_cf_node {
             None
         }
     }
+
+    fn next(&self) -> Option<&nir_cf_node> {
+        let next = unsafe { &*self.node.next };
+        if next.next.is_null() {
+            None
+        } else {
+            let p = next as *const exec_node;
+            Some(unsafe { &*(p as *const nir_cf_node) })
+        }
+    }
 }
 
 pub trait NirFunctionImpl {
@@ -0,0 +1,211 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+//! Differential testing of the encoder against nvdisasm
+//!
+//! Shaders which we can disassemble ourselves are also run through NVIDIA's
+//! nvdisasm, if it can be found, and the two listings are compared
+//! instruction by instruction.  Any instruction where the mnemonics or the
+//! registers differ is a mismatch.  This only runs as part of the unit tests
+//! and is skipped if there is no nvdisasm.
+//!
+//! By default, nvdisasm is looked up in PATH.  Set NAK_NVDISASM to use a
+//! different binary.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A single disassembled instruction, reduced to what both disassemblers
+/// agree on how to print
+struct DisasmInstr {
+    text: String,
+    mnemonic: String,
+    regs: Vec<String>,
+}
+
+/// Strips the predicate, any dual-issue braces and the trailing `;` and
+/// whatever comes after it from a line of disassembly.
+fn parse_instr(text: &str) -> Option<DisasmInstr> {
+    let text = text.split(';').next().unwrap();
+    let text = text.trim_matches(|c: char| c.is_whitespace() || c == '{');
+    let text = text.trim_end_matches('}').trim();
+
+    let mut tokens = text
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|t| !t.is_empty())
+        .peekable();
+    if tokens.peek()?.starts_with('@') {
+        tokens.next();
+    }
+
+    // Modifiers are printed very differently so we only compare the base
+    // opcode name.
+    let mnemonic = tokens.next()?.split('.').next().unwrap().to_lowercase();
+
+    // nvdisasm drops RZ in a bunch of places so leave it out entirely.  The
+    // order of operands isn't consistent either (stores put the address
+    // first, for instance) so compare them as a sorted list.
+    let mut regs = Vec::new();
+    for t in tokens {
+        let t = t.trim_matches(|c: char| !c.is_ascii_alphanumeric());
+        let t = t
+            .split(|c| c == '+' || c == '-' || c == '.')
+            .next()
+            .unwrap();
+        let t = t.to_lowercase();
+        if t.len() > 1
+            && t.starts_with('r')
+            && t[1..].chars().all(|c| c.is_ascii_digit())
+        {
+            regs.push(t);
+        }
+    }
+    regs.sort();
+
+    Some(DisasmInstr {
+        text: text.to_string(),
+        mnemonic: mnemonic,
+        regs: regs,
+    })
+}
+
+/// Parses a disassembly listing where each instruction is prefixed with its
+/// address as `/*0008*/`.  This is what both nvdisasm and our own
+/// disassemblers produce.
+fn parse_listing(listing: &str) -> HashMap<usize, DisasmInstr> {
+    let mut instrs = HashMap::new();
+    for line in listing.lines() {
+        let line = line.trim_start();
+        let Some(line) = line.strip_prefix("/*") else {
+            continue;
+        };
+        let Some((addr, rest)) = line.split_once("*/") else {
+            continue;
+        };
+        let Ok(addr) = usize::from_str_radix(addr.trim(), 16) else {
+            continue;
+        };
+        if let Some(instr) = parse_instr(rest) {
+            instrs.insert(addr, instr);
+        }
+    }
+    instrs
+}
+
+fn run_nvdisasm(sm: u8, code: &[u32]) -> Option<String> {
+    static TMP_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let nvdisasm =
+        env::var("NAK_NVDISASM").unwrap_or_else(|_| "nvdisasm".to_string());
+
+    let path = env::temp_dir().join(format!(
+        "nak-{}-{}.bin",
+        std::process::id(),
+        TMP_COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let bytes: Vec<u8> = code.iter().flat_map(|dw| dw.to_le_bytes()).collect();
+    fs::write(&path, bytes).ok()?;
+
+    let output = Command::new(&nvdisasm)
+        .arg("--binary")
+        .arg(format!("SM{sm}"))
+        .arg(&path)
+        .output();
+    let _ = fs::remove_file(&path);
+
+    match output {
+        Ok(output) => {
+            assert!(
+                output.status.success(),
+                "nvdisasm failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            Some(String::from_utf8_lossy(&output.stdout).into_owned())
+        }
+        Err(err) => {
+            eprintln!("Skipping nvdisasm comparison, {nvdisasm}: {err}");
+            None
+        }
+    }
+}
+
+/// Compares `ours` against `theirs`, both disassembly listings of the same
+/// code, and returns every mismatch, grouped by our mnemonic.
+fn find_mismatches(ours: &str, theirs: &str) -> BTreeMap<String, Vec<String>> {
+    let ours = parse_listing(ours);
+    let theirs = parse_listing(theirs);
+
+    let mut addrs: Vec<_> = ours.keys().copied().collect();
+    addrs.sort();
+
+    let mut mismatches: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for addr in addrs {
+        let our = &ours[&addr];
+        let msg = match theirs.get(&addr) {
+            None => format!("/*{addr:04x}*/ {} vs nothing", our.text),
+            Some(their) => {
+                // Our disassembler prints unknown operand encodings as raw
+                // bits so we can't check the registers in that case.
+                let raw = our.text.contains("/*");
+                if our.mnemonic == their.mnemonic
+                    && (raw || our.regs == their.regs)
+                {
+                    continue;
+                }
+                format!("/*{addr:04x}*/ {} vs {}", our.text, their.text)
+            }
+        };
+        mismatches
+            .entry(our.mnemonic.clone())
+            .or_default()
+            .push(msg);
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode_sm50::disassemble_sm50;
+    use crate::encode_sm50::encode_sm50_function;
+    use crate::ir_parse::parse_function;
+
+    #[test]
+    fn mismatches() {
+        let ours = "/*0008*/ fadd r0, r1, r2;
+                    /*0010*/ iadd r3, r0, r4;
+                    /*0018*/ mov r5, r6;";
+        let theirs = "/*0008*/ FADD R0, R1, R2 ;
+                      /*0010*/ IADD R3, R0, R5 ;
+                      /*0018*/ MOV R5, R6 ;";
+        let mismatches = find_mismatches(ours, theirs);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches["iadd"].len(), 1);
+    }
+
+    #[test]
+    fn sm50_matches_nvdisasm() {
+        let f = parse_function(
+            "block 0 L0 [] -> {
+                r0 = fadd r1 r2
+                r3 = fadd.ftz r0 0x3f800000
+                r4 = ffma r0 c[0x1][0x10] r3
+                r5 = s2r sr[0x21]
+                p0 = isetp.lt.u32 r4 r1
+                r6 = sel p0 r1 0x10
+                exit
+            } -> []",
+        )
+        .unwrap();
+        let code = encode_sm50_function(&f, 50);
+        let Some(theirs) = run_nvdisasm(50, &code) else {
+            return;
+        };
+
+        let mismatches = find_mismatches(&disassemble_sm50(&code), &theirs);
+        assert!(mismatches.is_empty(), "{mismatches:#?}");
+    }
+}
@@ -0,0 +1,272 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+//! Puts instructions into a canonical form so that later passes only have to
+//! match one spelling of any given computation.
+//!
+//! The canonical form is:
+//!
+//!  - Sources of commutative ops are sorted with SSA values first, in order
+//!    of SSA index, followed by constants.  Comparisons are flipped as
+//!    needed so the same holds for ISETP and FSETP.
+//!
+//!  - FMUL carries at most one negation and it lives on the first source.
+//!
+//!  - LOP3 has no source modifiers, sources which the LUT ignores are zero,
+//!    and the LUT is permuted to match the sorted sources.
+//!
+//! This is purely a naming convention.  None of it is required by the
+//! hardware and legalize is still responsible for putting sources where
+//! the encoding wants them.
+
+use crate::ir::*;
+
+use std::cmp::Ordering;
+
+/// Sort key for a source of a commutative op
+fn src_rank(src: &Src) -> (u8, u32) {
+    match &src.src_ref {
+        SrcRef::SSA(ssa) => (0, ssa[0].idx()),
+        SrcRef::Reg(reg) => (1, reg.base_idx()),
+        SrcRef::Zero | SrcRef::True | SrcRef::False => (2, 0),
+        SrcRef::Imm32(imm) => (3, *imm),
+        SrcRef::CBuf(cb) => (4, cb.offset.into()),
+    }
+}
+
+fn src_cmp(a: &Src, b: &Src) -> Ordering {
+    src_rank(a).cmp(&src_rank(b))
+}
+
+/// Sorts a pair of sources and returns true if they were swapped
+fn sort_src_pair(srcs: &mut [Src; 2]) -> bool {
+    if src_cmp(&srcs[0], &srcs[1]) == Ordering::Greater {
+        srcs.swap(0, 1);
+        true
+    } else {
+        false
+    }
+}
+
+fn canonicalize_fmul(op: &mut OpFMul) {
+    sort_src_pair(&mut op.srcs);
+
+    if op.srcs.iter().all(|s| s.src_ref.as_ssa().is_some()) {
+        let neg = op.srcs[0].src_mod.has_fneg() ^ op.srcs[1].src_mod.has_fneg();
+        for src in &mut op.srcs {
+            if src.src_mod.has_fneg() {
+                src.src_mod = src.src_mod.fneg();
+            }
+        }
+        if neg {
+            op.srcs[0].src_mod = op.srcs[0].src_mod.fneg();
+        }
+    }
+}
+
+fn canonicalize_lop3(op: &mut OpLop3) {
+    for (i, src) in op.srcs.iter_mut().enumerate() {
+        if matches!(src.src_mod, SrcMod::BNot) {
+            op.op.invert_src(i);
+            src.src_mod = SrcMod::None;
+        }
+    }
+
+    for (i, src) in op.srcs.iter_mut().enumerate() {
+        if !op.op.src_used(i) {
+            *src = Src::new_zero();
+        }
+    }
+
+    // Three sources so a bubble sort is as good as anything
+    for _ in 0..2 {
+        for i in 0..2 {
+            if src_cmp(&op.srcs[i], &op.srcs[i + 1]) == Ordering::Greater {
+                op.srcs.swap(i, i + 1);
+                let old = op.op;
+                op.op = LogicOp3::new_lut(&|x, y, z| {
+                    let mut v = [x, y, z];
+                    v.swap(i, i + 1);
+                    old.eval(v[0], v[1], v[2])
+                });
+            }
+        }
+    }
+}
+
+fn canonicalize_instr(instr: &mut Instr) {
+    match &mut instr.op {
+        Op::FAdd(op) => {
+            sort_src_pair(&mut op.srcs);
+        }
+        Op::FMul(op) => canonicalize_fmul(op),
+        Op::FSetP(op) => {
+            if sort_src_pair(&mut op.srcs) {
+                op.cmp_op = op.cmp_op.flip();
+            }
+        }
+        Op::IAdd3(op) => {
+            op.srcs.sort_by(src_cmp);
+        }
+        Op::IMnMx(op) => {
+            sort_src_pair(&mut op.srcs);
+        }
+        // The extended form consumes a comparison of the low halves which
+        // was done with the sources in their original order.
+        Op::ISetP(op) if !op.ex => {
+            if sort_src_pair(&mut op.srcs) {
+                op.cmp_op = op.cmp_op.flip();
+            }
+        }
+        Op::Lop3(op) => canonicalize_lop3(op),
+        _ => (),
+    }
+}
+
+impl Shader {
+    /// Rewrites instructions into the canonical form described above
+    pub fn opt_canonicalize(&mut self) {
+        for f in &mut self.functions {
+            for b in &mut f.blocks {
+                for instr in &mut b.instrs {
+                    canonicalize_instr(instr);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir_parse::parse_function;
+
+    /// Parses a single block, canonicalizes it, and returns its instructions
+    fn canonicalize(text: &str) -> Vec<Box<Instr>> {
+        let mut f = parse_function(text).unwrap();
+        let instrs = &mut f.blocks[0].instrs;
+        for instr in instrs.iter_mut() {
+            canonicalize_instr(instr);
+        }
+        std::mem::take(instrs)
+    }
+
+    fn dst_ssa(instr: &Instr) -> SSAValue {
+        instr.dsts()[0].as_ssa().unwrap()[0]
+    }
+
+    fn src_ssa(src: &Src) -> SSAValue {
+        src.src_ref.as_ssa().unwrap()[0]
+    }
+
+    #[test]
+    fn iadd3_sorts_srcs() {
+        let instrs = canonicalize(
+            "block 0 L0 [] -> {
+                %r1 = s2r sr[0x21]
+                %r2 = s2r sr[0x22]
+                %r3 = iadd3 0x10 %r2 %r1
+                exit
+            } -> []",
+        );
+        let Op::IAdd3(op) = &instrs[2].op else {
+            panic!("Expected iadd3")
+        };
+        assert!(src_ssa(&op.srcs[0]) == dst_ssa(&instrs[0]));
+        assert!(src_ssa(&op.srcs[1]) == dst_ssa(&instrs[1]));
+        assert!(op.srcs[2].src_ref == SrcRef::Imm32(0x10));
+    }
+
+    #[test]
+    fn isetp_flips_cmp() {
+        let instrs = canonicalize(
+            "block 0 L0 [] -> {
+                %r1 = s2r sr[0x21]
+                %r2 = s2r sr[0x22]
+                %p3 = isetp.lt.u32 %r2 %r1
+                %p4 = isetp.lt.u32 %r1 0x10
+                exit
+            } -> []",
+        );
+        let Op::ISetP(op) = &instrs[2].op else {
+            panic!("Expected isetp")
+        };
+        assert!(src_ssa(&op.srcs[0]) == dst_ssa(&instrs[0]));
+        assert!(op.cmp_op == IntCmpOp::Gt);
+
+        // Already canonical so nothing changes
+        let Op::ISetP(op) = &instrs[3].op else {
+            panic!("Expected isetp")
+        };
+        assert!(src_ssa(&op.srcs[0]) == dst_ssa(&instrs[0]));
+        assert!(op.cmp_op == IntCmpOp::Lt);
+    }
+
+    #[test]
+    fn fmul_moves_fneg_to_src0() {
+        let instrs = canonicalize(
+            "block 0 L0 [] -> {
+                %r1 = s2r sr[0x21]
+                %r2 = s2r sr[0x22]
+                %r3 = fmul %r1 -%r2
+                %r4 = fmul -%r2 -%r1
+                exit
+            } -> []",
+        );
+        let Op::FMul(op) = &instrs[2].op else {
+            panic!("Expected fmul")
+        };
+        assert!(op.srcs[0].src_mod == SrcMod::FNeg);
+        assert!(op.srcs[1].src_mod == SrcMod::None);
+
+        // The negations cancel
+        let Op::FMul(op) = &instrs[3].op else {
+            panic!("Expected fmul")
+        };
+        assert!(src_ssa(&op.srcs[0]) == dst_ssa(&instrs[0]));
+        assert!(op.srcs.iter().all(|s| s.src_mod == SrcMod::None));
+    }
+
+    #[test]
+    fn lop3_canonical_lut() {
+        let mut alloc = SSAValueAllocator::new();
+        let a = alloc.alloc(RegFile::GPR);
+        let b = alloc.alloc(RegFile::GPR);
+        let c = alloc.alloc(RegFile::GPR);
+
+        let val = |src: &Src| {
+            let v = match src.src_ref {
+                SrcRef::Zero => 0_u32,
+                SrcRef::SSA(ssa) if ssa[0] == a => 0xff00ff00,
+                SrcRef::SSA(ssa) if ssa[0] == b => 0xf0f0f0f0,
+                SrcRef::SSA(ssa) if ssa[0] == c => 0xcccccccc,
+                _ => panic!("Unexpected source"),
+            };
+            if src.src_mod.is_bnot() {
+                !v
+            } else {
+                v
+            }
+        };
+        let eval = |op: &OpLop3| {
+            op.op
+                .eval(val(&op.srcs[0]), val(&op.srcs[1]), val(&op.srcs[2]))
+        };
+
+        // c & !(!a), with b ignored by the LUT
+        let mut op = OpLop3 {
+            dst: alloc.alloc(RegFile::GPR).into(),
+            srcs: [c.into(), Src::from(a).bnot(), b.into()],
+            op: LogicOp3::new_lut(&|x, y, _| x & !y),
+        };
+        let expected = eval(&op);
+        canonicalize_lop3(&mut op);
+
+        assert_eq!(eval(&op), expected);
+        assert!(src_ssa(&op.srcs[0]) == a);
+        assert!(src_ssa(&op.srcs[1]) == c);
+        assert!(op.srcs[2].is_zero());
+        assert!(op.srcs.iter().all(|s| s.src_mod == SrcMod::None));
+        assert!(op.op == LogicOp3::new_lut(&|x, y, _| x & y));
+    }
+}
@@ -0,0 +1,326 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+use crate::ir::*;
+
+use std::cmp::min;
+use std::collections::HashMap;
+
+fn src_as_int_imm(src: &Src) -> Option<u32> {
+    let u = match src.src_ref {
+        SrcRef::Zero => 0,
+        SrcRef::Imm32(u) => u,
+        _ => return None,
+    };
+
+    match src.src_mod {
+        SrcMod::None => Some(u),
+        SrcMod::INeg => Some(u.wrapping_neg()),
+        SrcMod::BNot => Some(!u),
+        _ => None,
+    }
+}
+
+fn src_as_f32_imm(src: &Src, ftz: bool) -> Option<f32> {
+    let u = match src.src_ref {
+        SrcRef::Zero => 0,
+        SrcRef::Imm32(u) => u,
+        _ => return None,
+    };
+
+    let u = match src.src_mod {
+        SrcMod::None => u,
+        SrcMod::FAbs => u & 0x7fffffff,
+        SrcMod::FNeg => u ^ 0x80000000,
+        SrcMod::FNegAbs => u | 0x80000000,
+        _ => return None,
+    };
+
+    // We don't know exactly what bits the hardware produces for NaNs and we
+    // don't want to bother with denorm flushing.
+    let f = f32::from_bits(u);
+    if f.is_nan() || (ftz && f.is_subnormal()) {
+        None
+    } else {
+        Some(f)
+    }
+}
+
+fn f32_result(f: f32, ftz: bool) -> Option<Src> {
+    if f.is_nan() || (ftz && f.is_subnormal()) {
+        None
+    } else {
+        Some(f.to_bits().into())
+    }
+}
+
+fn src_is_imm(src: &Src, imm: u32) -> bool {
+    src.src_mod.is_none() && src.as_u32() == Some(imm)
+}
+
+/// Returns `src` if it can be used directly as the source of an OpCopy
+fn copy_src(src: &Src) -> Option<Src> {
+    if src.src_mod.is_none() {
+        Some(*src)
+    } else {
+        None
+    }
+}
+
+fn shift_amount(shift: u32, wrap: bool) -> u32 {
+    if wrap {
+        shift & 0x1f
+    } else {
+        min(shift, 32)
+    }
+}
+
+fn fold_int_cmp(op: IntCmpOp, cmp_type: &IntCmpType, x: u32, y: u32) -> bool {
+    let ord = match cmp_type {
+        IntCmpType::U32 => x.cmp(&y),
+        IntCmpType::I32 => (x as i32).cmp(&(y as i32)),
+    };
+    match op {
+        IntCmpOp::Eq => ord.is_eq(),
+        IntCmpOp::Ne => ord.is_ne(),
+        IntCmpOp::Lt => ord.is_lt(),
+        IntCmpOp::Le => ord.is_le(),
+        IntCmpOp::Gt => ord.is_gt(),
+        IntCmpOp::Ge => ord.is_ge(),
+    }
+}
+
+struct ConstFoldPass {
+    /// Every SSA value we know to be a constant
+    consts: HashMap<SSAValue, SrcRef>,
+}
+
+impl ConstFoldPass {
+    fn new() -> ConstFoldPass {
+        ConstFoldPass {
+            consts: HashMap::new(),
+        }
+    }
+
+    /// Replaces `src` with a constant if we know its value
+    fn resolve(&self, src: &Src) -> Src {
+        if let SrcRef::SSA(vec) = src.src_ref {
+            if vec.comps() == 1 {
+                if let Some(c) = self.consts.get(&vec[0]) {
+                    return Src {
+                        src_ref: *c,
+                        src_mod: src.src_mod,
+                    };
+                }
+            }
+        }
+        *src
+    }
+
+    fn add_const(&mut self, dst: &Dst, src: &Src) {
+        let Dst::SSA(vec) = dst else {
+            return;
+        };
+        if vec.comps() != 1 || !src.src_mod.is_none() {
+            return;
+        }
+
+        match src.src_ref {
+            SrcRef::Zero | SrcRef::Imm32(_) | SrcRef::True | SrcRef::False => {
+                self.consts.insert(vec[0], src.src_ref);
+            }
+            _ => (),
+        }
+    }
+
+    /// Returns the source of a copy which can replace the instruction, if
+    /// any.  This is either a constant or one of the instruction's sources.
+    fn fold_op(&self, op: &Op) -> Option<Src> {
+        match op {
+            Op::FAdd(op) => {
+                if op.saturate || op.rnd_mode != FRndMode::NearestEven {
+                    return None;
+                }
+                let x = src_as_f32_imm(&self.resolve(&op.srcs[0]), op.ftz)?;
+                let y = src_as_f32_imm(&self.resolve(&op.srcs[1]), op.ftz)?;
+                f32_result(x + y, op.ftz)
+            }
+            Op::FMul(op) => {
+                if op.saturate || op.dnz || op.rnd_mode != FRndMode::NearestEven
+                {
+                    return None;
+                }
+                let srcs =
+                    [self.resolve(&op.srcs[0]), self.resolve(&op.srcs[1])];
+
+                // x * 1.0 is x as long as we don't have to flush denorms
+                if !op.ftz {
+                    for i in 0..2 {
+                        if src_is_imm(&srcs[i], 0x3f800000) {
+                            return copy_src(&srcs[1 - i]);
+                        }
+                    }
+                }
+
+                let x = src_as_f32_imm(&srcs[0], op.ftz)?;
+                let y = src_as_f32_imm(&srcs[1], op.ftz)?;
+                f32_result(x * y, op.ftz)
+            }
+            Op::IAdd2(op) => {
+                if !op.carry_out.is_none() || !op.carry_in.is_zero() {
+                    return None;
+                }
+                let x = src_as_int_imm(&self.resolve(&op.srcs[0]))?;
+                let y = src_as_int_imm(&self.resolve(&op.srcs[1]))?;
+                Some(x.wrapping_add(y).into())
+            }
+            Op::IAdd3(op) => {
+                if !op.overflow[0].is_none() || !op.overflow[1].is_none() {
+                    return None;
+                }
+                let srcs = op.srcs.map(|src| self.resolve(&src));
+
+                // x + 0 + 0 is x
+                let non_zero: Vec<usize> =
+                    (0..3).filter(|&i| !srcs[i].is_zero()).collect();
+                match non_zero[..] {
+                    [] => return Some(Src::new_zero()),
+                    [i] => {
+                        if let Some(src) = copy_src(&srcs[i]) {
+                            return Some(src);
+                        }
+                    }
+                    _ => (),
+                }
+
+                let mut sum = 0_u32;
+                for src in &srcs {
+                    sum = sum.wrapping_add(src_as_int_imm(src)?);
+                }
+                Some(sum.into())
+            }
+            Op::IMad(op) => {
+                let x = src_as_int_imm(&self.resolve(&op.srcs[0]))?;
+                let y = src_as_int_imm(&self.resolve(&op.srcs[1]))?;
+                let z = src_as_int_imm(&self.resolve(&op.srcs[2]))?;
+                Some(x.wrapping_mul(y).wrapping_add(z).into())
+            }
+            Op::INeg(op) => {
+                let x = src_as_int_imm(&self.resolve(&op.src))?;
+                Some(x.wrapping_neg().into())
+            }
+            Op::Lop3(op) => {
+                let mut vals = [0_u32; 3];
+                for i in 0..3 {
+                    if op.op.src_used(i) {
+                        vals[i] = src_as_int_imm(&self.resolve(&op.srcs[i]))?;
+                    }
+                }
+                Some(op.op.eval(vals[0], vals[1], vals[2]).into())
+            }
+            Op::Shf(op) => {
+                if !matches!(op.data_type, IntType::I32 | IntType::U32) {
+                    return None;
+                }
+                let low = src_as_int_imm(&self.resolve(&op.low))?;
+                let high = src_as_int_imm(&self.resolve(&op.high))?;
+                let shift = src_as_int_imm(&self.resolve(&op.shift))?;
+                let shift = shift_amount(shift, op.wrap);
+
+                // We only fold the two forms SSABuilder uses for 32-bit
+                // shifts, not general funnel shifts.
+                if !op.right && !op.dst_high && high == 0 {
+                    Some(((u64::from(low) << shift) as u32).into())
+                } else if op.right && op.dst_high && low == 0 {
+                    let res = if matches!(op.data_type, IntType::I32) {
+                        (i64::from(high as i32) >> shift) as u32
+                    } else {
+                        (u64::from(high) >> shift) as u32
+                    };
+                    Some(res.into())
+                } else {
+                    None
+                }
+            }
+            Op::Shl(op) => {
+                let x = src_as_int_imm(&self.resolve(&op.src))?;
+                let shift = src_as_int_imm(&self.resolve(&op.shift))?;
+                let shift = shift_amount(shift, op.wrap);
+                Some(((u64::from(x) << shift) as u32).into())
+            }
+            Op::Shr(op) => {
+                let x = src_as_int_imm(&self.resolve(&op.src))?;
+                let shift = src_as_int_imm(&self.resolve(&op.shift))?;
+                let shift = shift_amount(shift, op.wrap);
+                let res = if op.signed {
+                    (i64::from(x as i32) >> shift) as u32
+                } else {
+                    (u64::from(x) >> shift) as u32
+                };
+                Some(res.into())
+            }
+            Op::ISetP(op) => {
+                if op.ex {
+                    return None;
+                }
+                let x = src_as_int_imm(&self.resolve(&op.srcs[0]))?;
+                let y = src_as_int_imm(&self.resolve(&op.srcs[1]))?;
+                let accum = self.resolve(&op.accum).as_bool()?;
+                let cmp = fold_int_cmp(op.cmp_op, &op.cmp_type, x, y);
+                let res = match op.set_op {
+                    PredSetOp::And => cmp && accum,
+                    PredSetOp::Or => cmp || accum,
+                    PredSetOp::Xor => cmp ^ accum,
+                };
+                Some(res.into())
+            }
+            Op::Sel(op) => {
+                let srcs =
+                    [self.resolve(&op.srcs[0]), self.resolve(&op.srcs[1])];
+                if srcs[0] == srcs[1] {
+                    return copy_src(&srcs[0]);
+                }
+                let cond = self.resolve(&op.cond).as_bool()?;
+                copy_src(&srcs[if cond { 0 } else { 1 }])
+            }
+            _ => None,
+        }
+    }
+
+    fn run(&mut self, f: &mut Function) {
+        for b in &mut f.blocks {
+            for instr in &mut b.instrs {
+                if !instr.pred.is_true() {
+                    continue;
+                }
+
+                if let Op::Copy(copy) = &instr.op {
+                    let src = self.resolve(&copy.src);
+                    self.add_const(&copy.dst, &src);
+                    continue;
+                }
+
+                let Some(src) = self.fold_op(&instr.op) else {
+                    continue;
+                };
+
+                let dst = instr.dsts()[0];
+                self.add_const(&dst, &src);
+                *instr = Instr::new_boxed(OpCopy { dst: dst, src: src });
+            }
+        }
+    }
+}
+
+impl Shader {
+    /// Folds instructions with constant sources into copies of the result
+    /// and simplifies a few trivial identities.
+    ///
+    /// This leaves copies behind which opt_copy_prop() propagates and
+    /// opt_dce() cleans up.
+    pub fn opt_const_fold(&mut self) {
+        for f in &mut self.functions {
+            ConstFoldPass::new().run(f);
+        }
+    }
+}
@@ -184,6 +184,13 @@ impl CopyPropPass {
                 return;
             }
 
+            // Immediates and constant buffer references are left to
+            // opt_fold_srcs() which knows whether the instruction can encode
+            // them or if we're better off with the copy.
+            if matches!(entry.src.src_ref, SrcRef::Imm32(_) | SrcRef::CBuf(_)) {
+                return;
+            }
+
             src.src_ref = entry.src.src_ref;
             src.src_mod = entry.src.src_mod.modify(src.src_mod);
         }
@@ -289,6 +296,7 @@ impl CopyPropPass {
                 self.prop_to_gpr_src(src);
             }
             SrcType::ALU
+            | SrcType::F16v2
             | SrcType::F32
             | SrcType::I32
             | SrcType::B32
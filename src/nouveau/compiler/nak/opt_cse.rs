@@ -0,0 +1,235 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+use crate::ir::*;
+
+use std::collections::{HashMap, HashSet};
+
+/// Everything about an op other than its sources and destinations which
+/// affects the value it computes
+#[derive(Eq, Hash, PartialEq)]
+enum OpKey {
+    FAdd {
+        saturate: bool,
+        rnd_mode: FRndMode,
+        ftz: bool,
+    },
+    FFma {
+        saturate: bool,
+        rnd_mode: FRndMode,
+        ftz: bool,
+        dnz: bool,
+    },
+    FMul {
+        saturate: bool,
+        rnd_mode: FRndMode,
+        ftz: bool,
+        dnz: bool,
+    },
+    IAdd2,
+    IAdd3,
+    ISetP {
+        set_op: PredSetOp,
+        cmp_op: IntCmpOp,
+        cmp_type: IntCmpType,
+        ex: bool,
+    },
+    Ldc(MemType),
+    Lop3(LogicOp3),
+    Prmt(PrmtMode),
+    Sel,
+    Shf {
+        right: bool,
+        wrap: bool,
+        data_type: IntType,
+        dst_high: bool,
+    },
+    Shl {
+        wrap: bool,
+    },
+    Shr {
+        wrap: bool,
+        signed: bool,
+    },
+}
+
+impl OpKey {
+    fn new(op: &Op) -> Option<OpKey> {
+        Some(match op {
+            Op::FAdd(op) => OpKey::FAdd {
+                saturate: op.saturate,
+                rnd_mode: op.rnd_mode,
+                ftz: op.ftz,
+            },
+            Op::FFma(op) => OpKey::FFma {
+                saturate: op.saturate,
+                rnd_mode: op.rnd_mode,
+                ftz: op.ftz,
+                dnz: op.dnz,
+            },
+            Op::FMul(op) => OpKey::FMul {
+                saturate: op.saturate,
+                rnd_mode: op.rnd_mode,
+                ftz: op.ftz,
+                dnz: op.dnz,
+            },
+            Op::IAdd2(_) => OpKey::IAdd2,
+            Op::IAdd3(_) => OpKey::IAdd3,
+            Op::ISetP(op) => OpKey::ISetP {
+                set_op: op.set_op,
+                cmp_op: op.cmp_op,
+                cmp_type: op.cmp_type,
+                ex: op.ex,
+            },
+            Op::Ldc(op) => OpKey::Ldc(op.mem_type),
+            Op::Lop3(op) => OpKey::Lop3(op.op),
+            Op::Prmt(op) => OpKey::Prmt(op.mode),
+            Op::Sel(_) => OpKey::Sel,
+            Op::Shf(op) => OpKey::Shf {
+                right: op.right,
+                wrap: op.wrap,
+                data_type: op.data_type,
+                dst_high: op.dst_high,
+            },
+            Op::Shl(op) => OpKey::Shl { wrap: op.wrap },
+            Op::Shr(op) => OpKey::Shr {
+                wrap: op.wrap,
+                signed: op.signed,
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// Identifies the value computed by an instruction
+///
+/// Besides the op and its sources, this has the shape of the destinations so
+/// we never replace a value with one from another file.
+#[derive(Eq, Hash, PartialEq)]
+struct InstrKey {
+    op: OpKey,
+    srcs: Vec<(SrcRef, SrcMod)>,
+    dsts: Vec<Option<(RegFile, u8)>>,
+}
+
+/// Returns a key which identifies the value computed by `instr` or None if
+/// the instruction isn't safe to de-duplicate.
+fn instr_key(instr: &Instr) -> Option<InstrKey> {
+    if !instr.pred.is_true() {
+        return None;
+    }
+
+    let op = OpKey::new(&instr.op)?;
+
+    let srcs = instr
+        .srcs()
+        .iter()
+        .map(|s| (s.src_ref, s.src_mod))
+        .collect();
+
+    let mut dsts = Vec::new();
+    for dst in instr.dsts() {
+        match dst {
+            Dst::None => dsts.push(None),
+            Dst::SSA(ssa) => dsts.push(Some((ssa.file(), ssa.comps()))),
+            _ => return None,
+        }
+    }
+
+    Some(InstrKey { op, srcs, dsts })
+}
+
+struct CSEPass {
+    /// Map from a value to the equivalent value which replaces it
+    replace: HashMap<SSAValue, SSAValue>,
+    /// Instructions seen so far by key along with their block and
+    /// destinations
+    seen: HashMap<InstrKey, Vec<(usize, Vec<Dst>)>>,
+}
+
+impl CSEPass {
+    fn new() -> CSEPass {
+        CSEPass {
+            replace: HashMap::new(),
+            seen: HashMap::new(),
+        }
+    }
+
+    fn rewrite_uses(&self, instr: &mut Instr) {
+        instr.for_each_ssa_use_mut(|ssa| {
+            if let Some(new_ssa) = self.replace.get(&*ssa) {
+                *ssa = *new_ssa;
+            }
+        });
+    }
+
+    fn run(&mut self, f: &mut Function) {
+        let mut any_replaced = false;
+
+        // Blocks are in reverse post-order so every block is visited after
+        // all of its dominators.  Because we rewrite uses as we go, keys of
+        // later instructions already take earlier replacements into account.
+        for b_idx in 0..f.blocks.len() {
+            let mut instrs = std::mem::take(&mut f.blocks[b_idx].instrs);
+            let mut dead = HashSet::new();
+            for (i, instr) in instrs.iter_mut().enumerate() {
+                self.rewrite_uses(instr);
+
+                let Some(key) = instr_key(instr) else {
+                    continue;
+                };
+
+                let dominating = self.seen.get(&key).and_then(|candidates| {
+                    candidates.iter().find(|(def_b_idx, _)| {
+                        f.blocks.dominates(*def_b_idx, b_idx)
+                    })
+                });
+
+                if let Some((_, dsts)) = dominating {
+                    for (old, new) in instr.dsts().iter().zip(dsts.iter()) {
+                        for (o, n) in old.iter_ssa().zip(new.iter_ssa()) {
+                            self.replace.insert(*o, *n);
+                        }
+                    }
+                    dead.insert(i);
+                    any_replaced = true;
+                } else {
+                    self.seen
+                        .entry(key)
+                        .or_default()
+                        .push((b_idx, instr.dsts().to_vec()));
+                }
+            }
+
+            if !dead.is_empty() {
+                let mut i = 0;
+                instrs.retain(|_| {
+                    let keep = !dead.contains(&i);
+                    i += 1;
+                    keep
+                });
+            }
+            f.blocks[b_idx].instrs = instrs;
+        }
+
+        // Phi sources live at the ends of predecessors, which may come later
+        // in the block order than the value they use.
+        if any_replaced {
+            for b in &mut f.blocks {
+                for instr in &mut b.instrs {
+                    self.rewrite_uses(instr);
+                }
+            }
+        }
+    }
+}
+
+impl Shader {
+    /// Replaces pure instructions which compute the same thing as a
+    /// dominating instruction with the earlier result
+    pub fn opt_cse(&mut self) {
+        for f in &mut self.functions {
+            CSEPass::new().run(f);
+        }
+    }
+}
@@ -3,27 +3,39 @@
 
 use crate::ir::*;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+
+/// Where an SSA value comes from
+enum SSADef {
+    /// An ordinary instruction, by block and instruction index
+    Instr(usize, usize),
+    /// The destination of a phi
+    Phi(u32),
+    /// The destination of a parallel copy with the given source
+    Copy(Src),
+}
 
 struct DeadCodePass {
     any_dead: bool,
-    new_live: bool,
     live_ssa: HashSet<SSAValue>,
     live_phi: HashSet<u32>,
+    worklist: Vec<SSAValue>,
 }
 
 impl DeadCodePass {
     pub fn new() -> DeadCodePass {
         DeadCodePass {
             any_dead: false,
-            new_live: false,
             live_ssa: HashSet::new(),
             live_phi: HashSet::new(),
+            worklist: Vec::new(),
         }
     }
 
     fn mark_ssa_live(&mut self, ssa: &SSAValue) {
-        self.new_live |= self.live_ssa.insert(*ssa);
+        if self.live_ssa.insert(*ssa) {
+            self.worklist.push(*ssa);
+        }
     }
 
     fn mark_src_live(&mut self, src: &Src) {
@@ -34,8 +46,14 @@ impl DeadCodePass {
         }
     }
 
-    fn mark_phi_live(&mut self, id: u32) {
-        self.new_live |= self.live_phi.insert(id);
+    fn mark_instr_srcs_live(&mut self, instr: &Instr) {
+        if let PredRef::SSA(ssa) = &instr.pred.pred_ref {
+            self.mark_ssa_live(ssa);
+        }
+
+        for src in instr.srcs() {
+            self.mark_src_live(src);
+        }
     }
 
     fn is_dst_live(&self, dst: &Dst) -> bool {
@@ -53,6 +71,18 @@ impl DeadCodePass {
         }
     }
 
+    /// Returns true if instr is an atomic whose result nobody reads.  We
+    /// keep the atomic but drop its destination so the encoder can use the
+    /// cheaper reduction form.
+    fn is_atom_dst_dead(&self, instr: &Instr) -> bool {
+        match &instr.op {
+            Op::Atom(atom) => {
+                !atom.dst.is_none() && !self.is_dst_live(&atom.dst)
+            }
+            _ => false,
+        }
+    }
+
     fn is_phi_live(&self, id: u32) -> bool {
         self.live_phi.get(&id).is_some()
     }
@@ -75,49 +105,110 @@ impl DeadCodePass {
         false
     }
 
-    fn mark_instr(&mut self, instr: &Instr) {
-        match &instr.op {
-            Op::PhiSrcs(phi) => {
-                assert!(instr.pred.is_true());
-                for (id, src) in phi.srcs.iter() {
-                    if self.is_phi_live(*id) {
-                        self.mark_src_live(src);
-                    } else {
-                        self.any_dead = true;
+    /// Records where every SSA value is defined and marks the sources of
+    /// every instruction which has to stay regardless of its destinations.
+    fn find_defs(
+        &mut self,
+        f: &Function,
+    ) -> (HashMap<SSAValue, SSADef>, HashMap<u32, Vec<Src>>) {
+        let mut defs = HashMap::new();
+        let mut phi_srcs: HashMap<u32, Vec<Src>> = HashMap::new();
+
+        for (bi, b) in f.blocks.iter().enumerate() {
+            for (ii, instr) in b.instrs.iter().enumerate() {
+                match &instr.op {
+                    Op::PhiSrcs(phi) => {
+                        assert!(instr.pred.is_true());
+                        for (id, src) in phi.srcs.iter() {
+                            phi_srcs.entry(*id).or_default().push(*src);
+                        }
+                    }
+                    Op::PhiDsts(phi) => {
+                        assert!(instr.pred.is_true());
+                        for (id, dst) in phi.dsts.iter() {
+                            for ssa in dst.iter_ssa() {
+                                defs.insert(*ssa, SSADef::Phi(*id));
+                            }
+                        }
+                    }
+                    Op::ParCopy(pcopy) => {
+                        assert!(instr.pred.is_true());
+                        for (dst, src) in pcopy.dsts_srcs.iter() {
+                            for ssa in dst.iter_ssa() {
+                                defs.insert(*ssa, SSADef::Copy(*src));
+                            }
+                        }
+                    }
+                    _ => {
+                        if !instr.pred.is_false() && !instr.can_eliminate() {
+                            self.mark_instr_srcs_live(instr);
+                        }
+
+                        for dst in instr.dsts() {
+                            for ssa in dst.iter_ssa() {
+                                defs.insert(*ssa, SSADef::Instr(bi, ii));
+                            }
+                        }
                     }
                 }
             }
-            Op::PhiDsts(phi) => {
-                assert!(instr.pred.is_true());
-                for (id, dst) in phi.dsts.iter() {
-                    if self.is_dst_live(dst) {
-                        self.mark_phi_live(*id);
-                    } else {
-                        self.any_dead = true;
+        }
+
+        (defs, phi_srcs)
+    }
+
+    /// Walks back from the live values to everything they depend on
+    fn mark_live(&mut self, f: &Function) {
+        let (defs, phi_srcs) = self.find_defs(f);
+
+        while let Some(ssa) = self.worklist.pop() {
+            match defs.get(&ssa) {
+                Some(SSADef::Instr(bi, ii)) => {
+                    let instr = &f.blocks[*bi].instrs[*ii];
+                    if !instr.pred.is_false() {
+                        self.mark_instr_srcs_live(instr);
                     }
                 }
-            }
-            Op::ParCopy(pcopy) => {
-                assert!(instr.pred.is_true());
-                for (dst, src) in pcopy.dsts_srcs.iter() {
-                    if self.is_dst_live(dst) {
-                        self.mark_src_live(src);
-                    } else {
-                        self.any_dead = true;
+                Some(SSADef::Phi(id)) => {
+                    if self.live_phi.insert(*id) {
+                        if let Some(srcs) = phi_srcs.get(id) {
+                            for src in srcs {
+                                self.mark_src_live(src);
+                            }
+                        }
                     }
                 }
+                Some(SSADef::Copy(src)) => {
+                    self.mark_src_live(src);
+                }
+                // Values without a definition are undefined
+                None => (),
             }
-            _ => {
-                if self.is_instr_live(instr) {
-                    if let PredRef::SSA(ssa) = &instr.pred.pred_ref {
-                        self.mark_ssa_live(ssa);
-                    }
+        }
+    }
 
-                    for src in instr.srcs() {
-                        self.mark_src_live(src);
+    fn find_any_dead(&mut self, f: &Function) {
+        for b in &f.blocks {
+            for instr in &b.instrs {
+                let any_dead = match &instr.op {
+                    Op::PhiSrcs(phi) => {
+                        phi.srcs.iter().any(|(id, _)| !self.is_phi_live(*id))
+                    }
+                    Op::PhiDsts(phi) => {
+                        phi.dsts.iter().any(|(_, dst)| !self.is_dst_live(dst))
+                    }
+                    Op::ParCopy(pcopy) => pcopy
+                        .dsts_srcs
+                        .iter()
+                        .any(|(dst, _)| !self.is_dst_live(dst)),
+                    _ => {
+                        !self.is_instr_live(instr)
+                            || self.is_atom_dst_dead(instr)
                     }
-                } else {
+                };
+                if any_dead {
                     self.any_dead = true;
+                    return;
                 }
             }
         }
@@ -137,7 +228,14 @@ impl DeadCodePass {
                 pcopy.dsts_srcs.retain(|dst, _| self.is_dst_live(dst));
                 !pcopy.dsts_srcs.is_empty()
             }
-            _ => self.is_instr_live(&instr),
+            _ => {
+                if self.is_atom_dst_dead(&instr) {
+                    if let Op::Atom(atom) = &mut instr.op {
+                        atom.dst = Dst::None;
+                    }
+                }
+                self.is_instr_live(&instr)
+            }
         };
 
         if is_live {
@@ -148,20 +246,8 @@ impl DeadCodePass {
     }
 
     pub fn run(&mut self, f: &mut Function) {
-        loop {
-            self.new_live = false;
-            self.any_dead = false;
-
-            for b in f.blocks.iter().rev() {
-                for instr in b.instrs.iter().rev() {
-                    self.mark_instr(instr);
-                }
-            }
-
-            if !self.new_live {
-                break;
-            }
-        }
+        self.mark_live(f);
+        self.find_any_dead(f);
 
         if self.any_dead {
             f.map_instrs(|instr, _| self.map_instr(instr));
@@ -0,0 +1,182 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+use crate::ir::*;
+use crate::legalize::legalize_instr_cost;
+
+use std::collections::HashMap;
+
+struct FoldSrcsPass {
+    sm: u8,
+    /// SSA values which are copies of an immediate or a constant buffer or
+    /// which are loaded from a constant buffer at a constant offset
+    consts: HashMap<SSAValue, SrcRef>,
+}
+
+impl FoldSrcsPass {
+    fn new(sm: u8) -> FoldSrcsPass {
+        FoldSrcsPass {
+            sm: sm,
+            consts: HashMap::new(),
+        }
+    }
+
+    /// If the offset of an OpLdc is a known immediate, moves it into the
+    /// cbuf reference so the load becomes a plain cbuf read.
+    fn fold_ldc_offset(&self, ldc: &mut OpLdc) {
+        let SrcRef::SSA(ssa) = ldc.offset.src_ref else {
+            return;
+        };
+
+        if ssa.comps() != 1 || !ldc.offset.src_mod.is_none() {
+            return;
+        }
+
+        let Some(SrcRef::Imm32(imm)) = self.consts.get(&ssa[0]) else {
+            return;
+        };
+
+        let SrcRef::CBuf(cb) = &mut ldc.cb.src_ref else {
+            return;
+        };
+
+        let offset = u16::try_from(*imm)
+            .ok()
+            .and_then(|imm| cb.offset.checked_add(imm));
+        if let Some(offset) = offset {
+            cb.offset = offset;
+            ldc.offset = 0.into();
+        }
+    }
+
+    /// Records each component of an OpLdc with no register offset as the
+    /// cbuf value it loads.  Any ALU instruction which can read that cbuf
+    /// slot directly no longer needs the load.
+    fn add_ldc(&mut self, ldc: &OpLdc) {
+        if !ldc.offset.is_zero() {
+            return;
+        }
+
+        match ldc.mem_type {
+            MemType::B32 | MemType::B64 | MemType::B128 => (),
+            _ => return,
+        }
+
+        let SrcRef::CBuf(cb) = ldc.cb.src_ref else {
+            return;
+        };
+
+        let Dst::SSA(dst) = ldc.dst else {
+            return;
+        };
+
+        for (i, ssa) in dst.iter().enumerate() {
+            let i = u16::try_from(i).unwrap();
+            self.consts.insert(*ssa, SrcRef::CBuf(cb.offset(i * 4)));
+        }
+    }
+
+    fn add_const_def(&mut self, instr: &mut Instr) {
+        if !instr.pred.is_true() {
+            return;
+        }
+
+        let copy = match &mut instr.op {
+            Op::Copy(copy) => copy,
+            Op::Ldc(ldc) => {
+                self.fold_ldc_offset(ldc);
+                self.add_ldc(ldc);
+                return;
+            }
+            _ => return,
+        };
+
+        let Dst::SSA(dst) = copy.dst else {
+            return;
+        };
+
+        if dst.comps() != 1 || !copy.src.src_mod.is_none() {
+            return;
+        }
+
+        match copy.src.src_ref {
+            SrcRef::Imm32(_) | SrcRef::CBuf(_) => {
+                self.consts.insert(dst[0], copy.src.src_ref);
+            }
+            _ => (),
+        }
+    }
+
+    fn fold_srcs(&self, instr: &mut Instr) {
+        let src_types = instr.src_types();
+        let mut cost = None;
+
+        for i in 0..instr.srcs().len() {
+            match src_types[i] {
+                SrcType::ALU
+                | SrcType::F16v2
+                | SrcType::F32
+                | SrcType::I32
+                | SrcType::B32 => (),
+                _ => continue,
+            }
+
+            let SrcRef::SSA(ssa) = instr.srcs()[i].src_ref else {
+                continue;
+            };
+
+            if ssa.comps() != 1 {
+                continue;
+            }
+
+            let Some(c) = self.consts.get(&ssa[0]) else {
+                continue;
+            };
+
+            let old_cost = *cost
+                .get_or_insert_with(|| legalize_instr_cost(self.sm, instr));
+
+            // Only fold if the encoding can take the immediate or cbuf
+            // directly.  Otherwise, legalize() would just insert another
+            // copy and we're better off sharing the one we have.
+            //
+            // Source modifiers are applied to immediates right away.  Both
+            // because the i20 and f20 checks in legalize() expect them to
+            // be gone and because negating an immediate can change whether
+            // or not it fits.
+            let old_src = instr.srcs()[i];
+            let src = &mut instr.srcs_mut()[i];
+            src.src_ref = *c;
+            src.fold_imm_src_mod(src_types[i]);
+
+            let new_cost = legalize_instr_cost(self.sm, instr);
+            if new_cost <= old_cost {
+                cost = Some(new_cost);
+            } else {
+                instr.srcs_mut()[i] = old_src;
+            }
+        }
+    }
+
+    fn run(&mut self, f: &mut Function) {
+        for b in &mut f.blocks {
+            for instr in &mut b.instrs {
+                self.add_const_def(instr);
+                self.fold_srcs(instr);
+            }
+        }
+    }
+}
+
+impl Shader {
+    /// Folds copies of immediates and constant buffer values, as well as
+    /// constant-offset OpLdc, into the sources of the instructions which use
+    /// them wherever the hardware can encode them directly.
+    ///
+    /// The copies and loads themselves are left for opt_dce() to clean up.
+    pub fn opt_fold_srcs(&mut self) {
+        for f in &mut self.functions {
+            FoldSrcsPass::new(self.info.sm).run(f);
+        }
+    }
+}
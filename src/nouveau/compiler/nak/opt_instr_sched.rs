@@ -0,0 +1,331 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+//! A simple pre-RA list scheduler
+//!
+//! Each block is split into regions at instructions which have to stay where
+//! they are, such as phis, control-flow, and barriers.  Within a region, we
+//! build a dependency graph from SSA values and memory dependencies and
+//! schedule top-down, preferring instructions whose sources are available and
+//! which are the furthest from the end of the region by latency.  This lets
+//! ALU work fill in behind texture and memory loads instead of leaving them
+//! in NIR order.
+
+use crate::ir::*;
+use crate::mem_alias::{accesses_mem, has_mem_dep};
+
+use std::cmp::{max, Reverse};
+use std::collections::HashMap;
+
+/// The maximum distance we're willing to move an instruction from where it
+/// started.  Every instruction we move above one of its uses or below one of
+/// its sources extends a live range so this is our (very crude) register
+/// pressure limit.
+const MAX_SCHED_DIST: usize = 16;
+
+// Rough guesses for the latencies the scoreboard takes care of.  They only
+// need to be in the right ballpark relative to each other.
+const TEX_LATENCY: u32 = 300;
+const GLOBAL_MEM_LATENCY: u32 = 200;
+const SHARED_MEM_LATENCY: u32 = 30;
+const CBUF_LATENCY: u32 = 20;
+const VAR_ALU_LATENCY: u32 = 20;
+
+fn mem_space_latency(space: MemSpace) -> u32 {
+    match space {
+        MemSpace::Global(_) | MemSpace::Local => GLOBAL_MEM_LATENCY,
+        MemSpace::Shared => SHARED_MEM_LATENCY,
+    }
+}
+
+fn dst_latency(instr: &Instr, sm: u8, dst_idx: usize) -> u32 {
+    if instr.has_fixed_latency(sm) {
+        return instr.get_dst_latency(sm, dst_idx);
+    }
+
+    match &instr.op {
+        Op::Tex(_)
+        | Op::Tld(_)
+        | Op::Tld4(_)
+        | Op::Tmml(_)
+        | Op::Txd(_)
+        | Op::Txq(_)
+        | Op::Footprint(_)
+        | Op::SuLd(_)
+        | Op::SuAtom(_) => TEX_LATENCY,
+        Op::Ld(op) => mem_space_latency(op.access.space),
+        Op::Atom(op) => mem_space_latency(op.mem_space),
+        Op::Ldc(_) => CBUF_LATENCY,
+        _ => VAR_ALU_LATENCY,
+    }
+}
+
+fn is_sched_barrier(instr: &Instr) -> bool {
+    if instr.is_branch() {
+        return true;
+    }
+
+    match &instr.op {
+        // Phis have to stay at the top and bottom of the block
+        Op::PhiDsts(_) | Op::PhiSrcs(_) => return true,
+        // These depend on exactly which threads are active and where they
+        // are relative to the branches around them.
+        Op::BClear(_) | Op::BMov(_) | Op::Break(_) | Op::BSSy(_) => {
+            return true;
+        }
+        _ => (),
+    }
+
+    // Anything else with side effects which isn't a memory access we know
+    // how to order stays put.  This covers control-flow, barriers, and
+    // shader outputs.
+    if !instr.can_eliminate() && !accesses_mem(instr) {
+        return true;
+    }
+
+    // We only track dependencies through SSA values
+    instr.dsts().iter().any(|dst| matches!(dst, Dst::Reg(_)))
+        || instr
+            .srcs()
+            .iter()
+            .any(|src| matches!(src.src_ref, SrcRef::Reg(_)))
+}
+
+#[derive(Default)]
+struct SchedNode {
+    num_preds: usize,
+    succs: Vec<(usize, u32)>,
+    /// Length of the longest latency path from here to the end of the region
+    height: u32,
+    /// The earliest cycle at which all of our sources are available
+    ready_cycle: u32,
+}
+
+fn sched_region(instrs: Vec<Box<Instr>>, sm: u8) -> Vec<Box<Instr>> {
+    if instrs.len() <= 1 {
+        return instrs;
+    }
+
+    let mut nodes: Vec<SchedNode> = Vec::new();
+    nodes.resize_with(instrs.len(), Default::default);
+
+    let mut defs: HashMap<SSAValue, (usize, u32)> = HashMap::new();
+    let mut mem_ips: Vec<usize> = Vec::new();
+    for (ip, instr) in instrs.iter().enumerate() {
+        let mut deps = Vec::new();
+        instr.for_each_ssa_use(|ssa| {
+            if let Some(dep) = defs.get(ssa) {
+                deps.push(*dep);
+            }
+        });
+
+        if accesses_mem(instr) {
+            for &m in &mem_ips {
+                if has_mem_dep(&instrs[m], instr) {
+                    deps.push((m, 1));
+                }
+            }
+            mem_ips.push(ip);
+        }
+
+        for (dep, latency) in deps {
+            nodes[dep].succs.push((ip, latency));
+            nodes[ip].num_preds += 1;
+        }
+
+        for (i, dst) in instr.dsts().iter().enumerate() {
+            let latency = dst_latency(instr, sm, i);
+            for ssa in dst.iter_ssa() {
+                defs.insert(*ssa, (ip, latency));
+            }
+        }
+    }
+
+    for ip in (0..nodes.len()).rev() {
+        let mut height = 0;
+        for &(s, latency) in &nodes[ip].succs {
+            height = max(height, latency + nodes[s].height);
+        }
+        nodes[ip].height = height;
+    }
+
+    let mut ready: Vec<usize> = (0..nodes.len())
+        .filter(|&ip| nodes[ip].num_preds == 0)
+        .collect();
+    let mut scheduled = vec![false; nodes.len()];
+    let mut order = Vec::with_capacity(nodes.len());
+    let mut oldest = 0;
+    let mut cycle = 0_u32;
+    while order.len() < nodes.len() {
+        while scheduled[oldest] {
+            oldest += 1;
+        }
+
+        // Everything before the oldest unscheduled instruction has been
+        // scheduled, including all of its dependencies, so it's always
+        // ready and always inside the window.
+        let pick = if order.len() >= oldest + MAX_SCHED_DIST {
+            oldest
+        } else {
+            let window_end = order.len() + MAX_SCHED_DIST;
+            ready
+                .iter()
+                .copied()
+                .filter(|&ip| ip < window_end)
+                .max_by_key(|&ip| {
+                    let n = &nodes[ip];
+                    (n.ready_cycle <= cycle, n.height, Reverse(ip))
+                })
+                .unwrap()
+        };
+
+        ready.retain(|&ip| ip != pick);
+        scheduled[pick] = true;
+        order.push(pick);
+
+        let issue_cycle = max(cycle, nodes[pick].ready_cycle);
+        cycle = issue_cycle + 1;
+
+        for (s, latency) in std::mem::take(&mut nodes[pick].succs) {
+            let n = &mut nodes[s];
+            n.ready_cycle = max(n.ready_cycle, issue_cycle + latency);
+            n.num_preds -= 1;
+            if n.num_preds == 0 {
+                ready.push(s);
+            }
+        }
+    }
+
+    let mut instrs: Vec<Option<Box<Instr>>> =
+        instrs.into_iter().map(Some).collect();
+    order
+        .into_iter()
+        .map(|ip| instrs[ip].take().unwrap())
+        .collect()
+}
+
+fn sched_block(b: &mut BasicBlock, sm: u8) {
+    let mut instrs = Vec::with_capacity(b.instrs.len());
+    let mut region = Vec::new();
+    for instr in b.instrs.drain(..) {
+        if is_sched_barrier(&instr) {
+            instrs.extend(sched_region(std::mem::take(&mut region), sm));
+            instrs.push(instr);
+        } else {
+            region.push(instr);
+        }
+    }
+    instrs.extend(sched_region(region, sm));
+    b.instrs = instrs;
+}
+
+impl Shader {
+    /// Reorders instructions within each block to hide latency
+    pub fn opt_instr_sched(&mut self) {
+        let sm = self.info.sm;
+        for f in &mut self.functions {
+            for b in &mut f.blocks {
+                sched_block(b, sm);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir_parse::parse_function;
+
+    /// Parses a single block, schedules it, and returns its instructions
+    fn sched(text: &str) -> Vec<Box<Instr>> {
+        let mut f = parse_function(text).unwrap();
+        sched_block(&mut f.blocks[0], 70);
+        std::mem::take(&mut f.blocks[0].instrs)
+    }
+
+    fn dst_ssa(instr: &Instr) -> SSAValue {
+        instr.dsts()[0].as_ssa().unwrap()[0]
+    }
+
+    fn find_def(instrs: &[Box<Instr>], ssa: SSAValue) -> usize {
+        instrs
+            .iter()
+            .position(|i| {
+                i.dsts()
+                    .first()
+                    .and_then(|d| d.as_ssa())
+                    .is_some_and(|d| d[0] == ssa)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn moves_long_latency_up() {
+        let f = parse_function(
+            "block 0 L0 [] -> {
+                %r1 = fadd c[0x1][0x0] c[0x1][0x4]
+                %r2 = fadd %r1 %r1
+                %r3 = s2r sr[0x21]
+                %r4 = fadd %r3 %r2
+                exit
+            } -> []",
+        )
+        .unwrap();
+        let s2r = dst_ssa(&f.blocks[0].instrs[2]);
+
+        let instrs = sched(&f.to_string());
+        assert_eq!(find_def(&instrs, s2r), 0);
+        assert!(matches!(instrs[4].op, Op::Exit(_)));
+    }
+
+    #[test]
+    fn regions_split_at_barriers() {
+        let f = parse_function(
+            "block 0 L0 [] -> {
+                %r1 = fadd c[0x1][0x0] c[0x1][0x4]
+                %r2 = fadd %r1 %r1
+                kill
+                %r3 = s2r sr[0x21]
+                %r4 = fadd %r3 %r2
+                exit
+            } -> []",
+        )
+        .unwrap();
+        let before: Vec<String> =
+            f.blocks[0].instrs.iter().map(|i| i.to_string()).collect();
+
+        // The s2r would like to go first but it can't cross the kill
+        let instrs = sched(&f.to_string());
+        let after: Vec<String> = instrs.iter().map(|i| i.to_string()).collect();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn oldest_is_forced_at_max_dist() {
+        // An unused fadd has no height so it loses to every s2r whose
+        // result is waited on.  There are more of those than fit in the
+        // window, so the fadd has to get forced out once it falls
+        // MAX_SCHED_DIST behind.
+        let num_s2r = MAX_SCHED_DIST + 4;
+        let mut text = String::from("block 0 L0 [] -> {\n");
+        text.push_str("%r1 = fadd c[0x1][0x0] c[0x1][0x4]\n");
+        for i in 0..num_s2r {
+            text.push_str(&format!("%r{} = s2r sr[0x21]\n", i + 2));
+        }
+        for i in 0..num_s2r {
+            let s = i + 2;
+            let d = s + num_s2r;
+            text.push_str(&format!("%r{d} = fadd %r{s} %r{s}\n"));
+        }
+        text.push_str("exit\n} -> []");
+
+        let f = parse_function(&text).unwrap();
+        let fadd = dst_ssa(&f.blocks[0].instrs[0]);
+
+        let instrs = sched(&text);
+        assert_eq!(find_def(&instrs, fadd), MAX_SCHED_DIST);
+        for instr in &instrs[..MAX_SCHED_DIST] {
+            assert!(matches!(instr.op, Op::S2R(_)));
+        }
+    }
+}
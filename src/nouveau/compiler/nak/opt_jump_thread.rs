@@ -1,7 +1,6 @@
 // Copyright © 2023 Mel Henning
 // SPDX-License-Identifier: MIT
 
-use crate::cfg::CFGBuilder;
 use crate::ir::*;
 use std::collections::HashMap;
 
@@ -57,7 +56,10 @@ fn jump_thread(func: &mut Function) -> bool {
         let block_label = func.blocks[i].label;
         match &func.blocks[i].instrs[..] {
             [instr] => {
-                if instr.is_branch() && instr.pred.is_true() {
+                // SYNC, BRK, and CONT jump wherever the control stack says
+                // so we can only forward BRA and EXIT.
+                let forwardable = matches!(instr.op, Op::Bra(_) | Op::Exit(_));
+                if forwardable && instr.pred.is_true() {
                     // Upholds invariant 2 because we updated the branch above
                     replacements.insert(block_label, clone_branch(&instr.op));
                 }
@@ -66,7 +68,8 @@ fn jump_thread(func: &mut Function) -> bool {
                 // Empty block - falls through
                 // Our successor might be trivial, so we need to
                 // apply the rewrite map to uphold invariant 2
-                let target_label = func.blocks[i + 1].label;
+                let next = func.fall_through_succ(i).unwrap();
+                let target_label = func.blocks[next].label;
                 let replacement = replacements
                     .get(&target_label)
                     .map(clone_branch)
@@ -82,54 +85,18 @@ fn jump_thread(func: &mut Function) -> bool {
     }
 
     if progress {
-        // We don't update the CFG above, so rewrite it if we made progress
-        rewrite_cfg(func);
+        // We don't update the CFG above, so rewrite it if we made progress.
+        // This also takes care of removing dead blocks for us.
+        func.rebuild_cfg();
     }
 
     return progress;
 }
 
-fn rewrite_cfg(func: &mut Function) {
-    // CFGBuilder takes care of removing dead blocks for us
-    // We use the basic block's label to identify it
-    let mut builder = CFGBuilder::new();
-
-    for i in 0..func.blocks.len() {
-        let block = &func.blocks[i];
-        // Note: fall-though must be first edge
-        if block.falls_through() {
-            let next_block = &func.blocks[i + 1];
-            builder.add_edge(block.label, next_block.label);
-        }
-        if let Some(control_flow) = block.branch() {
-            match &control_flow.op {
-                Op::Bra(bra) => {
-                    builder.add_edge(block.label, bra.target);
-                }
-                Op::Exit(_) => (),
-                _ => unreachable!(),
-            };
-        }
-    }
-
-    for block in func.blocks.drain() {
-        builder.add_node(block.label, block);
-    }
-    let _ = std::mem::replace(&mut func.blocks, builder.as_cfg());
-}
-
 /// Replace jumps to the following block with fall-through
 fn opt_fall_through(func: &mut Function) {
     for i in 0..func.blocks.len() - 1 {
-        let remove_last_instr = match func.blocks[i].branch() {
-            Some(b) => match b.op {
-                Op::Bra(OpBra { target }) => target == func.blocks[i + 1].label,
-                _ => false,
-            },
-            None => false,
-        };
-
-        if remove_last_instr {
+        if func.branch_succ(i) == Some(i + 1) {
             func.blocks[i].instrs.pop();
         }
     }
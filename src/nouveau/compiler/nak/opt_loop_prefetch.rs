@@ -0,0 +1,684 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+use crate::ir::*;
+
+use std::collections::{HashMap, HashSet};
+
+// The maximum number of 32-bit components we're willing to carry around the
+// back-edge of a single loop.  Every pipelined load keeps its destination
+// live across the whole loop so this is our register pressure limit.
+const MAX_PIPELINED_COMPS: u8 = 8;
+
+fn loop_blocks_mem_reorder(instr: &Instr) -> bool {
+    instr.writes_global_mem()
+        || matches!(instr.op, Op::MemBar(_) | Op::Bar(_) | Op::CCtl(_))
+}
+
+fn phi_srcs_map(b: &BasicBlock) -> HashMap<u32, Src> {
+    let mut srcs = HashMap::new();
+    if let Some(phi) = b.phi_srcs() {
+        for (id, src) in phi.srcs.iter() {
+            srcs.insert(*id, *src);
+        }
+    }
+    srcs
+}
+
+fn push_before_phi_srcs(b: &mut BasicBlock, instr: Box<Instr>) {
+    let mut ip = b.instrs.len();
+    while ip > 0 {
+        let prev = &b.instrs[ip - 1];
+        if !prev.is_branch() && !matches!(prev.op, Op::PhiSrcs(_)) {
+            break;
+        }
+        ip -= 1;
+    }
+    b.instrs.insert(ip, instr);
+}
+
+fn add_phi_src(b: &mut BasicBlock, id: u32, src: Src) {
+    if let Some(phi) = b.phi_srcs_mut() {
+        phi.srcs.push(id, src);
+        return;
+    }
+
+    let mut phi = OpPhiSrcs::new();
+    phi.srcs.push(id, src);
+
+    let ip = if b.branch().is_some() {
+        b.instrs.len() - 1
+    } else {
+        b.instrs.len()
+    };
+    b.instrs.insert(ip, Instr::new_boxed(phi));
+}
+
+fn add_phi_dst(b: &mut BasicBlock, id: u32, dst: Dst) {
+    if let Some(phi) = b.phi_dsts_mut() {
+        phi.dsts.push(id, dst);
+        return;
+    }
+
+    let mut phi = OpPhiDsts::new();
+    phi.dsts.push(id, dst);
+    b.instrs.insert(0, Instr::new_boxed(phi));
+}
+
+struct LoopInfo {
+    header: usize,
+    preheader: usize,
+    latch: usize,
+}
+
+impl LoopInfo {
+    fn for_header(f: &Function, header: usize) -> Option<LoopInfo> {
+        let mut preheader = None;
+        let mut latch = None;
+        for &p in f.blocks.pred_indices(header) {
+            if p < header {
+                if preheader.replace(p).is_some() {
+                    return None;
+                }
+            } else if latch.replace(p).is_some() {
+                return None;
+            }
+        }
+        let (Some(preheader), Some(latch)) = (preheader, latch) else {
+            return None;
+        };
+
+        // Both the pre-header and the latch have to unconditionally jump to
+        // the header.  Otherwise, the loads we add to them may execute when
+        // the header doesn't and we'd be loading speculatively.
+        if f.blocks.succ_indices(preheader) != [header]
+            || f.blocks.succ_indices(latch) != [header]
+        {
+            return None;
+        }
+
+        // We only handle innermost loops
+        for i in (header + 1)..=latch {
+            if f.blocks.is_loop_header(i) {
+                return None;
+            }
+        }
+
+        Some(LoopInfo {
+            header: header,
+            preheader: preheader,
+            latch: latch,
+        })
+    }
+}
+
+/// Integer ALU ops which are cheap enough to re-compute a load address with
+fn is_addr_alu(instr: &Instr) -> bool {
+    matches!(
+        instr.op,
+        Op::IAdd2(_)
+            | Op::IAdd3(_)
+            | Op::IAdd3X(_)
+            | Op::IMad(_)
+            | Op::Lop2(_)
+            | Op::Lop3(_)
+            | Op::Shf(_)
+            | Op::Shl(_)
+            | Op::Mov(_)
+            | Op::Copy(_)
+            | Op::Prmt(_)
+    )
+}
+
+/// Rewrites load addresses in terms of the values flowing into the header
+/// from one of its predecessors.  Any address arithmetic done in the header
+/// is re-computed from those values and the instructions to do so are
+/// collected in instrs, in order, to be placed in the predecessor.
+struct AddrRemap<'a> {
+    phi_ids: &'a HashMap<SSAValue, u32>,
+    addr_alu: &'a HashMap<SSAValue, &'a Instr>,
+    loop_defs: &'a HashSet<SSAValue>,
+    phi_srcs: HashMap<u32, Src>,
+    map: HashMap<SSAValue, SSAValue>,
+    instrs: Vec<Box<Instr>>,
+}
+
+impl<'a> AddrRemap<'a> {
+    fn remap_ssa(
+        &mut self,
+        ssa_alloc: &mut SSAValueAllocator,
+        ssa: &SSAValue,
+    ) -> Option<SSAValue> {
+        if let Some(new) = self.map.get(ssa) {
+            return Some(*new);
+        }
+
+        let new = if let Some(id) = self.phi_ids.get(ssa) {
+            let src = self.phi_srcs.get(id)?;
+            if !src.src_mod.is_none() {
+                return None;
+            }
+            match &src.src_ref {
+                SrcRef::SSA(src_ssa) => {
+                    if src_ssa.comps() != 1 {
+                        return None;
+                    }
+                    src_ssa[0]
+                }
+                // Constants have to go in a register so the load can use
+                // them as an address.
+                SrcRef::Zero | SrcRef::Imm32(_) | SrcRef::CBuf(_) => {
+                    let dst = ssa_alloc.alloc(ssa.file());
+                    self.instrs.push(Instr::new_boxed(OpCopy {
+                        dst: dst.into(),
+                        src: *src,
+                    }));
+                    dst
+                }
+                _ => return None,
+            }
+        } else if let Some(instr) = self.addr_alu.get(ssa) {
+            let mut instr = Box::new(Instr::clone(instr));
+
+            let mut uses = Vec::new();
+            instr.for_each_ssa_use(|ssa| uses.push(*ssa));
+            for u in uses {
+                self.remap_ssa(ssa_alloc, &u)?;
+            }
+
+            let map = &mut self.map;
+            instr.for_each_ssa_use_mut(|ssa| *ssa = map[ssa]);
+            instr.for_each_ssa_def_mut(|ssa| {
+                let new = ssa_alloc.alloc(ssa.file());
+                map.insert(*ssa, new);
+                *ssa = new;
+            });
+            self.instrs.push(instr);
+
+            return Some(self.map[ssa]);
+        } else if self.loop_defs.contains(ssa) {
+            return None;
+        } else {
+            *ssa
+        };
+
+        self.map.insert(*ssa, new);
+        Some(new)
+    }
+
+    fn checkpoint(&self) -> (HashMap<SSAValue, SSAValue>, usize) {
+        (self.map.clone(), self.instrs.len())
+    }
+
+    fn rollback(&mut self, cp: (HashMap<SSAValue, SSAValue>, usize)) {
+        let (map, num_instrs) = cp;
+        self.map = map;
+        self.instrs.truncate(num_instrs);
+    }
+
+    /// Returns None if the address depends on anything computed inside the
+    /// loop other than the header phis and address arithmetic on them.  In
+    /// that case, nothing is added to instrs.
+    fn remap_addr(
+        &mut self,
+        ssa_alloc: &mut SSAValueAllocator,
+        addr: &SSARef,
+    ) -> Option<SSARef> {
+        let cp = self.checkpoint();
+        let mut new_addr = Vec::new();
+        for ssa in addr.iter() {
+            let Some(new) = self.remap_ssa(ssa_alloc, ssa) else {
+                self.rollback(cp);
+                return None;
+            };
+            new_addr.push(new);
+        }
+        SSARef::try_from(new_addr).ok()
+    }
+}
+
+fn prefetch_loop_loads(f: &mut Function, l: &LoopInfo) {
+    let mut loop_defs = HashSet::new();
+    let mut can_reorder_mem = true;
+    for i in l.header..=l.latch {
+        for instr in &f.blocks[i].instrs {
+            instr.for_each_ssa_def(|ssa| {
+                loop_defs.insert(*ssa);
+            });
+            can_reorder_mem &= !loop_blocks_mem_reorder(instr);
+        }
+    }
+
+    let mut phi_ids = HashMap::new();
+    if let Some(phi) = f.blocks[l.header].phi_dsts() {
+        for (id, dst) in phi.dsts.iter() {
+            if let Dst::SSA(ssa) = dst {
+                debug_assert!(ssa.comps() == 1);
+                phi_ids.insert(ssa[0], *id);
+            }
+        }
+    }
+
+    let mut addr_alu = HashMap::new();
+    for instr in &f.blocks[l.header].instrs {
+        if instr.pred.is_true() && is_addr_alu(instr) {
+            instr.for_each_ssa_def(|ssa| {
+                addr_alu.insert(*ssa, instr.as_ref());
+            });
+        }
+    }
+
+    let mut pre_remap = AddrRemap {
+        phi_ids: &phi_ids,
+        addr_alu: &addr_alu,
+        loop_defs: &loop_defs,
+        phi_srcs: phi_srcs_map(&f.blocks[l.preheader]),
+        map: HashMap::new(),
+        instrs: Vec::new(),
+    };
+    let mut latch_remap = AddrRemap {
+        phi_ids: &phi_ids,
+        addr_alu: &addr_alu,
+        loop_defs: &loop_defs,
+        phi_srcs: phi_srcs_map(&f.blocks[l.latch]),
+        map: HashMap::new(),
+        instrs: Vec::new(),
+    };
+
+    let ssa_alloc = &mut f.ssa_alloc;
+    let mut pipelined = Vec::new();
+    let mut comps = 0_u8;
+    for (ip, instr) in f.blocks[l.header].instrs.iter().enumerate() {
+        if !instr.pred.is_true() {
+            continue;
+        }
+
+        let Op::Ld(ld) = &instr.op else {
+            continue;
+        };
+
+        if !matches!(ld.access.space, MemSpace::Global(_)) {
+            continue;
+        }
+
+        if !can_reorder_mem && !matches!(ld.access.order, MemOrder::Constant) {
+            continue;
+        }
+
+        let Some(dst) = ld.dst.as_ssa() else {
+            continue;
+        };
+
+        if comps + dst.comps() > MAX_PIPELINED_COMPS {
+            break;
+        }
+
+        let Some(addr) = ld.addr.as_ssa() else {
+            continue;
+        };
+
+        let pre_cp = pre_remap.checkpoint();
+        let Some(pre_addr) = pre_remap.remap_addr(ssa_alloc, addr) else {
+            continue;
+        };
+
+        let Some(latch_addr) = latch_remap.remap_addr(ssa_alloc, addr) else {
+            pre_remap.rollback(pre_cp);
+            continue;
+        };
+
+        comps += dst.comps();
+        pipelined.push((ip, pre_addr, latch_addr));
+    }
+
+    let pre_instrs = pre_remap.instrs;
+    let latch_instrs = latch_remap.instrs;
+
+    // The re-computed addresses have to come before any of the loads
+    for instr in pre_instrs {
+        push_before_phi_srcs(&mut f.blocks[l.preheader], instr);
+    }
+    for instr in latch_instrs {
+        push_before_phi_srcs(&mut f.blocks[l.latch], instr);
+    }
+
+    // Walk backwards so the instruction indices stay valid as we remove
+    let mut loads = Vec::new();
+    for (ip, pre_addr, latch_addr) in pipelined.into_iter().rev() {
+        let instr = f.blocks[l.header].instrs.remove(ip);
+        let Op::Ld(ld) = instr.op else {
+            panic!("Not a load");
+        };
+        loads.push((ld, pre_addr, latch_addr));
+    }
+
+    for (ld, pre_addr, latch_addr) in loads {
+        let dst = *ld.dst.as_ssa().unwrap();
+
+        let pre_dst = f.ssa_alloc.alloc_vec(RegFile::GPR, dst.comps());
+        push_before_phi_srcs(
+            &mut f.blocks[l.preheader],
+            Instr::new_boxed(OpLd {
+                dst: pre_dst.into(),
+                addr: pre_addr.into(),
+                offset: ld.offset,
+                access: ld.access.clone(),
+            }),
+        );
+
+        let latch_dst = f.ssa_alloc.alloc_vec(RegFile::GPR, dst.comps());
+        push_before_phi_srcs(
+            &mut f.blocks[l.latch],
+            Instr::new_boxed(OpLd {
+                dst: latch_dst.into(),
+                addr: latch_addr.into(),
+                offset: ld.offset,
+                access: ld.access,
+            }),
+        );
+
+        for c in 0..usize::from(dst.comps()) {
+            let id = f.phi_alloc.alloc();
+            add_phi_src(&mut f.blocks[l.preheader], id, pre_dst[c].into());
+            add_phi_src(&mut f.blocks[l.latch], id, latch_dst[c].into());
+            add_phi_dst(&mut f.blocks[l.header], id, dst[c].into());
+        }
+    }
+}
+
+impl Function {
+    fn opt_loop_prefetch(&mut self) {
+        if !self.blocks.has_loop() {
+            return;
+        }
+
+        for h in 0..self.blocks.len() {
+            if !self.blocks.is_loop_header(h) {
+                continue;
+            }
+
+            // If the loop never goes around a second time, the prefetch in
+            // the latch is wasted.
+            let max_trips = self.blocks[h]
+                .loop_hints
+                .and_then(|hints| hints.max_trip_count);
+            if max_trips.map_or(false, |n| n <= 1) {
+                continue;
+            }
+
+            if let Some(l) = LoopInfo::for_header(self, h) {
+                prefetch_loop_loads(self, &l);
+            }
+        }
+    }
+}
+
+impl Shader {
+    /// A very simple form of software pipelining for compute shaders.
+    ///
+    /// Global loads in the header of an innermost loop whose addresses only
+    /// depend on the loop phis, either directly or through integer address
+    /// arithmetic in the header, are rotated around the back-edge: the load
+    /// for the first iteration is issued in the pre-header and the load for
+    /// each following iteration is issued in the latch of the previous one.
+    /// Any address arithmetic is re-computed in the pre-header and latch.
+    /// The loaded values are carried into the header as phis and register
+    /// allocation takes care of rotating the register sets.  The latch loads
+    /// can then be scheduled early by opt_instr_sched so they overlap with
+    /// the current iteration's math.
+    pub fn opt_loop_prefetch(&mut self) {
+        if !matches!(self.info.stage, ShaderStageInfo::Compute(_)) {
+            return;
+        }
+
+        for f in &mut self.functions {
+            f.opt_loop_prefetch();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir_parse::parse_function;
+
+    fn count_lds(b: &BasicBlock) -> usize {
+        b.instrs
+            .iter()
+            .filter(|i| matches!(i.op, Op::Ld(_)))
+            .count()
+    }
+
+    fn find_ld(b: &BasicBlock) -> &OpLd {
+        b.instrs
+            .iter()
+            .find_map(|i| match &i.op {
+                Op::Ld(ld) => Some(ld),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    fn find_def<'a>(b: &'a BasicBlock, ssa: &SSAValue) -> &'a Instr {
+        b.instrs
+            .iter()
+            .find(|i| i.dsts().iter().any(|d| d.iter_ssa().any(|d| d == ssa)))
+            .unwrap()
+    }
+
+    fn prefetch(text: &str) -> Function {
+        let mut f = parse_function(text).unwrap();
+        f.opt_loop_prefetch();
+        f
+    }
+
+    /// A loop which loads from base + i and increments i by 4 in the latch
+    const SIMPLE_LOOP: &str = "block 0 L0 [] -> {
+        %r1 = copy c[0x1][0x0]
+        phi_src φ0 = rZ
+    } -> [1]
+    block 1 L1 [0, 2] -> {
+        phi_dst %r2 = φ0
+        %r3 = iadd3 %r1 %r2 rZ
+        %r4 = ld.global.a32.weak.b32 [%r3+0x10]
+        %p5 = isetp.ge.u32 %r2 0x40
+        @p5 bra L3
+    } -> [2, 3]
+    block 2 L2 [1] -> {
+        %r6 = fadd %r4 %r4
+        %r7 = iadd3 %r2 0x4 rZ
+        phi_src φ0 = %r7
+        bra L1
+    } -> [1]
+    block 3 L3 [1] -> {
+        exit
+    } -> []";
+
+    #[test]
+    fn prefetch_simple_loop() {
+        let f = prefetch(SIMPLE_LOOP);
+
+        assert_eq!(count_lds(&f.blocks[0]), 1);
+        assert_eq!(count_lds(&f.blocks[1]), 0);
+        assert_eq!(count_lds(&f.blocks[2]), 1);
+
+        // The header now gets the loaded value through a phi
+        let phi = f.blocks[1].phi_dsts().unwrap();
+        assert_eq!(phi.dsts.iter().count(), 2);
+
+        // In the pre-header, i is zero and the iadd3 is re-computed from a
+        // copy of it.
+        let ld = find_ld(&f.blocks[0]);
+        assert_eq!(ld.offset, 0x10);
+        let addr = ld.addr.as_ssa().unwrap();
+        let Op::IAdd3(add) = &find_def(&f.blocks[0], &addr[0]).op else {
+            panic!("Expected iadd3");
+        };
+        let i = add.srcs[1].as_ssa().unwrap();
+        let Op::Copy(copy) = &find_def(&f.blocks[0], &i[0]).op else {
+            panic!("Expected copy");
+        };
+        assert!(copy.src.src_ref == SrcRef::Zero);
+
+        // In the latch, it's re-computed from the next value of i
+        let ld = find_ld(&f.blocks[2]);
+        let addr = ld.addr.as_ssa().unwrap();
+        let Op::IAdd3(add) = &find_def(&f.blocks[2], &addr[0]).op else {
+            panic!("Expected iadd3");
+        };
+        let i = add.srcs[1].as_ssa().unwrap();
+        assert!(matches!(find_def(&f.blocks[2], &i[0]).op, Op::IAdd3(_)));
+
+        // Everything goes before the phi sources and the branch
+        let latch = &f.blocks[2].instrs;
+        assert!(matches!(latch[latch.len() - 2].op, Op::PhiSrcs(_)));
+    }
+
+    #[test]
+    fn no_prefetch_single_trip() {
+        let mut f = parse_function(SIMPLE_LOOP).unwrap();
+        f.blocks[1].loop_hints = Some(LoopHints {
+            max_trip_count: Some(1),
+            exact_trip_count: false,
+            guessed_trip_count: None,
+            unroll: LoopUnrollHint::None,
+        });
+        f.opt_loop_prefetch();
+
+        assert_eq!(count_lds(&f.blocks[0]), 0);
+        assert_eq!(count_lds(&f.blocks[1]), 1);
+        assert_eq!(count_lds(&f.blocks[2]), 0);
+    }
+
+    #[test]
+    fn no_prefetch_loop_dependent_addr() {
+        // The second load's address comes from the first one, which we
+        // can't re-compute outside of the loop.
+        let f = prefetch(
+            "block 0 L0 [] -> {
+                %r1 = copy c[0x1][0x0]
+                phi_src φ0 = %r1
+            } -> [1]
+            block 1 L1 [0, 2] -> {
+                phi_dst %r2 = φ0
+                %r3 = ld.global.a32.weak.b32 [%r2]
+                %r4 = ld.global.a32.weak.b32 [%r3]
+                %p5 = isetp.ge.u32 %r4 0x40
+                @p5 bra L3
+            } -> [2, 3]
+            block 2 L2 [1] -> {
+                phi_src φ0 = %r4
+                bra L1
+            } -> [1]
+            block 3 L3 [1] -> {
+                exit
+            } -> []",
+        );
+
+        // Only the first load gets pipelined
+        assert_eq!(count_lds(&f.blocks[0]), 1);
+        assert_eq!(count_lds(&f.blocks[1]), 1);
+        assert_eq!(count_lds(&f.blocks[2]), 1);
+    }
+
+    #[test]
+    fn no_prefetch_multiple_latches() {
+        let f = prefetch(
+            "block 0 L0 [] -> {
+                %r1 = copy c[0x1][0x0]
+                phi_src φ0 = %r1
+            } -> [1]
+            block 1 L1 [0, 3, 4] -> {
+                phi_dst %r2 = φ0
+                %r3 = ld.global.a32.weak.b32 [%r2]
+                %p4 = isetp.ge.u32 %r3 0x40
+                @p4 bra L5
+            } -> [2, 5]
+            block 2 L2 [1] -> {
+                %p5 = isetp.ge.u32 %r3 0x20
+                @p5 bra L4
+            } -> [3, 4]
+            block 3 L3 [2] -> {
+                phi_src φ0 = %r3
+                bra L1
+            } -> [1]
+            block 4 L4 [2] -> {
+                phi_src φ0 = %r2
+                bra L1
+            } -> [1]
+            block 5 L5 [1] -> {
+                exit
+            } -> []",
+        );
+
+        assert_eq!(count_lds(&f.blocks[0]), 0);
+        assert_eq!(count_lds(&f.blocks[1]), 1);
+    }
+
+    #[test]
+    fn no_prefetch_conditional_preheader() {
+        // A load in the pre-header would run even when the loop doesn't
+        let f = prefetch(
+            "block 0 L0 [] -> {
+                %r1 = copy c[0x1][0x0]
+                %p2 = isetp.eq.u32 %r1 rZ
+                phi_src φ0 = %r1
+                @p2 bra L3
+            } -> [1, 3]
+            block 1 L1 [0, 2] -> {
+                phi_dst %r3 = φ0
+                %r4 = ld.global.a32.weak.b32 [%r3]
+                %p5 = isetp.ge.u32 %r4 0x40
+                @p5 bra L3
+            } -> [2, 3]
+            block 2 L2 [1] -> {
+                phi_src φ0 = %r4
+                bra L1
+            } -> [1]
+            block 3 L3 [0, 1] -> {
+                exit
+            } -> []",
+        );
+
+        assert_eq!(count_lds(&f.blocks[0]), 0);
+        assert_eq!(count_lds(&f.blocks[1]), 1);
+        assert_eq!(count_lds(&f.blocks[2]), 0);
+    }
+
+    #[test]
+    fn no_prefetch_outer_loop() {
+        // Block 1 heads a loop which contains the loop headed by block 2
+        let f = prefetch(
+            "block 0 L0 [] -> {
+                %r1 = copy c[0x1][0x0]
+                phi_src φ0 = %r1
+            } -> [1]
+            block 1 L1 [0, 5] -> {
+                phi_dst %r2 = φ0
+                %r3 = ld.global.a32.weak.b32 [%r2]
+            } -> [2]
+            block 2 L2 [1, 3] -> {
+                %p4 = isetp.ge.u32 %r3 0x40
+                @p4 bra L4
+            } -> [3, 4]
+            block 3 L3 [2] -> {
+                bra L2
+            } -> [2]
+            block 4 L4 [2] -> {
+                %p5 = isetp.ge.u32 %r3 0x20
+                @p5 bra L6
+            } -> [5, 6]
+            block 5 L5 [4] -> {
+                phi_src φ0 = %r3
+                bra L1
+            } -> [1]
+            block 6 L6 [4] -> {
+                exit
+            } -> []",
+        );
+
+        assert_eq!(count_lds(&f.blocks[0]), 0);
+        assert_eq!(count_lds(&f.blocks[1]), 1);
+        assert_eq!(count_lds(&f.blocks[5]), 0);
+    }
+}
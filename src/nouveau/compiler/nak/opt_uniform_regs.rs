@@ -0,0 +1,245 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+use crate::builder::*;
+use crate::divergence::find_warp_uniform_values;
+use crate::ir::*;
+
+use std::collections::{HashMap, HashSet};
+
+/// Returns which source slot of the uniform encoding the `i`th entry of
+/// `instr.srcs()` lands in.  Only the middle slot can hold an immediate or a
+/// constant buffer.
+fn uniform_src_slot(op: &Op, i: usize) -> usize {
+    match op {
+        // srcs() is [low, high, shift] but the encoding is [low, shift, high]
+        Op::Shf(_) => [0, 2, 1][i],
+        // srcs() is [cb, offset] but the encoding is [offset, cb]
+        Op::Ldc(_) => [1, 0][i],
+        _ => i,
+    }
+}
+
+/// Returns true if the uniform datapath can encode `src` in `slot` without
+/// first copying it into a UGPR
+fn uniform_src_is_legal(op: &Op, slot: usize, src: &Src) -> bool {
+    match src.src_ref {
+        SrcRef::Zero | SrcRef::SSA(_) => true,
+        SrcRef::Imm32(_) => slot == 1 && !matches!(op, Op::Ldc(_)),
+        SrcRef::CBuf(_) => slot == 1 && matches!(op, Op::Ldc(_)),
+        _ => false,
+    }
+}
+
+fn ldc_is_uniform_candidate(op: &OpLdc) -> bool {
+    if op.mem_type != MemType::B32 {
+        return false;
+    }
+    match &op.cb.src_ref {
+        SrcRef::CBuf(cb) => matches!(cb.buf, CBuf::Binding(_)),
+        _ => false,
+    }
+}
+
+/// Returns true if the instruction is one of the few we know how to encode
+/// on the uniform datapath.  S2R and LDC are the roots of uniform
+/// computations and the rest is integer math on top of them.  IMad is what
+/// descriptor and buffer address math (base + index * stride) turns into.
+fn instr_is_uniform_candidate(instr: &Instr) -> bool {
+    if !instr.pred.is_true() {
+        return false;
+    }
+
+    let ok = match &instr.op {
+        Op::IAdd3(op) => op.overflow.iter().all(|d| d.is_none()),
+        Op::IMad(_) | Op::Lop3(_) | Op::Shf(_) | Op::S2R(_) => true,
+        Op::Ldc(op) => ldc_is_uniform_candidate(op),
+        _ => false,
+    };
+    if !ok {
+        return false;
+    }
+
+    let dsts = instr.dsts();
+    let Dst::SSA(dst) = &dsts[0] else {
+        return false;
+    };
+    if dst.comps() != 1 || dst.file() != RegFile::GPR {
+        return false;
+    }
+
+    instr.srcs().iter().all(|src| {
+        src.src_mod.is_none()
+            && match src.src_ref {
+                SrcRef::Zero | SrcRef::Imm32(_) | SrcRef::CBuf(_) => true,
+                SrcRef::SSA(ssa) => ssa.comps() == 1,
+                _ => false,
+            }
+    })
+}
+
+fn instr_is_uniform_root(instr: &Instr) -> bool {
+    matches!(instr.op, Op::S2R(_) | Op::Ldc(_))
+}
+
+struct UniformRegsPass {
+    sm: u8,
+    /// Values which get moved into UGPRs
+    convert: HashSet<SSAValue>,
+    /// Map from each converted GPR value to its UGPR replacement
+    umap: HashMap<SSAValue, SSAValue>,
+}
+
+impl UniformRegsPass {
+    fn new(sm: u8) -> UniformRegsPass {
+        UniformRegsPass {
+            sm: sm,
+            convert: HashSet::new(),
+            umap: HashMap::new(),
+        }
+    }
+
+    /// Finds the values to convert and returns the number of UGPRs needed,
+    /// including temporaries for sources the uniform encodings can't take.
+    fn find_convert(&mut self, f: &Function) -> usize {
+        let uniform = find_warp_uniform_values(f);
+
+        // A value computed inside a loop may differ between the iterations
+        // in which each lane leaves the loop.  A GPR keeps each lane's value
+        // but a UGPR only holds the last one written so we stay out of loops
+        // entirely.
+        let mut cands: HashMap<SSAValue, &Instr> = HashMap::new();
+        for (b_idx, b) in f.blocks.iter().enumerate() {
+            if f.blocks.loop_header_index(b_idx).is_some() {
+                continue;
+            }
+
+            for instr in &b.instrs {
+                if !instr_is_uniform_candidate(instr) {
+                    continue;
+                }
+
+                let dst = instr.dsts()[0].as_ssa().unwrap()[0];
+                if !uniform.contains(&dst) {
+                    continue;
+                }
+
+                let mut srcs_ok = true;
+                instr.for_each_ssa_use(|ssa| {
+                    srcs_ok &= cands.contains_key(ssa);
+                });
+                if srcs_ok {
+                    cands.insert(dst, instr);
+                }
+            }
+        }
+
+        // Moving S2R or LDC alone doesn't save anything so we only convert
+        // math which takes at least one uniform value along with everything
+        // it depends on.
+        let mut worklist = Vec::new();
+        for (ssa, instr) in &cands {
+            if instr_is_uniform_root(instr) {
+                continue;
+            }
+            if instr.srcs().iter().any(|src| src.as_ssa().is_some()) {
+                worklist.push(*ssa);
+            }
+        }
+
+        let mut num_ugprs = 0;
+        while let Some(ssa) = worklist.pop() {
+            if !self.convert.insert(ssa) {
+                continue;
+            }
+            num_ugprs += 1;
+
+            let instr = cands[&ssa];
+            for (i, src) in instr.srcs().iter().enumerate() {
+                let slot = uniform_src_slot(&instr.op, i);
+                if !uniform_src_is_legal(&instr.op, slot, src) {
+                    num_ugprs += 1;
+                }
+            }
+            instr.for_each_ssa_use(|src_ssa| {
+                worklist.push(*src_ssa);
+            });
+        }
+
+        num_ugprs
+    }
+
+    fn convert_instr(
+        &mut self,
+        mut instr: Box<Instr>,
+        ssa_alloc: &mut SSAValueAllocator,
+    ) -> MappedInstrs {
+        let dst = match &instr.dsts()[0] {
+            Dst::SSA(ssa) if self.convert.contains(&ssa[0]) => ssa[0],
+            _ => return MappedInstrs::One(instr),
+        };
+
+        let mut b = SSAInstrBuilder::new(self.sm, ssa_alloc);
+
+        let legal: Vec<bool> = instr
+            .srcs()
+            .iter()
+            .enumerate()
+            .map(|(i, src)| {
+                let slot = uniform_src_slot(&instr.op, i);
+                uniform_src_is_legal(&instr.op, slot, src)
+            })
+            .collect();
+
+        for (i, src) in instr.srcs_mut().iter_mut().enumerate() {
+            if let SrcRef::SSA(ssa) = &mut src.src_ref {
+                // Blocks are in reverse post-order so the definition has
+                // already been converted.
+                ssa[0] = self.umap[&ssa[0]];
+            } else if !legal[i] {
+                let tmp = b.alloc_ssa(RegFile::UGPR, 1);
+                b.copy_to(tmp.into(), *src);
+                *src = tmp.into();
+            }
+        }
+
+        let udst = b.alloc_ssa(RegFile::UGPR, 1);
+        self.umap.insert(dst, udst[0]);
+        instr.dsts_mut()[0] = udst.into();
+
+        b.push_instr(instr);
+        b.copy_to(dst.into(), udst.into());
+        b.as_mapped_instrs()
+    }
+
+    fn run(&mut self, f: &mut Function) {
+        let num_ugprs = self.find_convert(f);
+        let max_ugprs: usize =
+            RegFile::UGPR.num_regs(self.sm).try_into().unwrap();
+        if num_ugprs == 0 || num_ugprs > max_ugprs {
+            return;
+        }
+
+        f.map_instrs(|instr, ssa_alloc| self.convert_instr(instr, ssa_alloc));
+    }
+}
+
+impl Shader {
+    /// Moves warp-uniform integer math into uniform registers
+    ///
+    /// This runs the computation once per warp on the uniform datapath and
+    /// leaves a copy back to a GPR behind for every value.  Copies which
+    /// only feed other uniform instructions are removed by opt_dce().
+    ///
+    /// NVIDIA hardware has no shader preamble so this is the closest we get
+    /// to hoisting uniform work out of the per-thread instruction stream.
+    pub fn opt_uniform_regs(&mut self) {
+        if self.info.sm < 75 {
+            return;
+        }
+
+        for f in &mut self.functions {
+            UniformRegsPass::new(self.info.sm).run(f);
+        }
+    }
+}
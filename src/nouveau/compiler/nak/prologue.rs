@@ -0,0 +1,83 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+//! Code which runs once at the top of a shader, ahead of anything translated
+//! from NIR.
+
+use crate::ir::*;
+use crate::nir::*;
+
+use nak_bindings::*;
+
+/// Returns true if a shader needs to BSYNC before it touches shared memory.
+/// BSSY and BSYNC are SM70+ convergence barrier ops which have no SM50
+/// encoding.  SM50 has nothing equivalent which doesn't go through the CRS
+/// stack so it simply goes without.
+fn needs_shared_mem_sync(sm: u8, shared_size: u32) -> bool {
+    sm >= 70 && shared_size > 0
+}
+
+pub struct PrologueBuilder<'a> {
+    nir: &'a nir_shader,
+}
+
+impl<'a> PrologueBuilder<'a> {
+    pub fn new(nir: &'a nir_shader) -> Self {
+        Self { nir: nir }
+    }
+
+    /// Emits the prologue for this shader into the start of the first block
+    pub fn emit(
+        &self,
+        b: &mut impl SSABuilder,
+        label_alloc: &mut LabelAllocator,
+    ) {
+        if needs_shared_mem_sync(b.sm(), self.nir.info.shared_size) {
+            self.emit_shared_mem_sync(b, label_alloc);
+        }
+    }
+
+    fn emit_shared_mem_sync(
+        &self,
+        b: &mut impl SSABuilder,
+        label_alloc: &mut LabelAllocator,
+    ) {
+        // The blob seems to always do a BSYNC before accessing shared
+        // memory.  Perhaps this is to ensure that our allocation is
+        // actually available and not in use by another thread?
+        let label = label_alloc.alloc();
+        let bar_clear = b.alloc_ssa(RegFile::Bar, 1);
+
+        b.push_op(OpBClear {
+            dst: bar_clear.into(),
+        });
+
+        let bar = b.alloc_ssa(RegFile::Bar, 1);
+        b.push_op(OpBSSy {
+            bar_out: bar.into(),
+            bar_in: bar_clear.into(),
+            cond: SrcRef::True.into(),
+            target: label,
+        });
+
+        b.push_op(OpBSync {
+            bar: bar.into(),
+            cond: SrcRef::True.into(),
+        });
+
+        b.push_op(OpNop { label: Some(label) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_mem_sync_only_on_sm70() {
+        assert!(!needs_shared_mem_sync(50, 0x100));
+        assert!(!needs_shared_mem_sync(70, 0));
+        assert!(needs_shared_mem_sync(70, 0x100));
+        assert!(needs_shared_mem_sync(75, 4));
+    }
+}
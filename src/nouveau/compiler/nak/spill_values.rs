@@ -231,22 +231,93 @@ impl PartialOrd for SSANextUse {
     }
 }
 
+/// Returns the value of a copy which is cheap enough to re-do at every fill
+/// rather than spilling and filling the result.
+fn remat_src(instr: &Instr) -> Option<Src> {
+    if !instr.pred.is_true() {
+        return None;
+    }
+
+    let src = match &instr.op {
+        Op::Copy(op) => op.src,
+        Op::Mov(op) if op.quad_lanes == 0xf => op.src,
+        _ => return None,
+    };
+
+    if !src.src_mod.is_none() {
+        return None;
+    }
+
+    match &src.src_ref {
+        SrcRef::Imm32(_) => Some(src),
+        SrcRef::CBuf(cb) => match cb.buf {
+            CBuf::Binding(_) => Some(src),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Finds all the values in @file which can be rematerialized
+///
+/// Values which pass through a phi or parallel copy are left out because
+/// those get rewritten to use the spill value directly.
+fn find_remat_values(func: &Function, file: RegFile) -> HashMap<SSAValue, Src> {
+    let mut remat = HashMap::new();
+    let mut in_copies = HashSet::new();
+    for b in &func.blocks {
+        for instr in &b.instrs {
+            match &instr.op {
+                Op::PhiDsts(_) | Op::PhiSrcs(_) | Op::ParCopy(_) => {
+                    instr.for_each_ssa_def(|ssa| {
+                        in_copies.insert(*ssa);
+                    });
+                    instr.for_each_ssa_use(|ssa| {
+                        in_copies.insert(*ssa);
+                    });
+                }
+                _ => {
+                    let Some(src) = remat_src(instr) else {
+                        continue;
+                    };
+                    let Dst::SSA(vec) = instr.dsts()[0] else {
+                        continue;
+                    };
+                    if vec.comps() == 1 && vec[0].file() == file {
+                        remat.insert(vec[0], src);
+                    }
+                }
+            }
+        }
+    }
+
+    remat.retain(|ssa, _| !in_copies.contains(ssa));
+    remat
+}
+
 struct SpillCache<'a, S: Spill> {
     alloc: &'a mut SSAValueAllocator,
     spill: S,
     val_spill: HashMap<SSAValue, SSAValue>,
+    remat: HashMap<SSAValue, Src>,
 }
 
 impl<'a, S: Spill> SpillCache<'a, S> {
-    fn new(alloc: &'a mut SSAValueAllocator, spill: S) -> SpillCache<'a, S> {
+    fn new(
+        alloc: &'a mut SSAValueAllocator,
+        spill: S,
+        remat: HashMap<SSAValue, Src>,
+    ) -> SpillCache<'a, S> {
         SpillCache {
             alloc: alloc,
             spill: spill,
             val_spill: HashMap::new(),
+            remat: remat,
         }
     }
 
     fn get_spill(&mut self, ssa: SSAValue) -> SSAValue {
+        debug_assert!(!self.remat.contains_key(&ssa));
         *self.val_spill.entry(ssa).or_insert_with(|| {
             self.alloc.alloc(self.spill.spill_file(ssa.file()))
         })
@@ -257,11 +328,24 @@ impl<'a, S: Spill> SpillCache<'a, S> {
         self.spill.spill(dst, src)
     }
 
-    fn spill(&mut self, ssa: SSAValue) -> Box<Instr> {
-        self.spill_src(ssa, ssa.into())
+    /// Spills @ssa, if needed.  Values which can be rematerialized don't need
+    /// to be stored anywhere.
+    fn spill(&mut self, ssa: SSAValue) -> Option<Box<Instr>> {
+        if self.remat.contains_key(&ssa) {
+            None
+        } else {
+            Some(self.spill_src(ssa, ssa.into()))
+        }
     }
 
     fn fill_dst(&mut self, dst: Dst, ssa: SSAValue) -> Box<Instr> {
+        if let Some(src) = self.remat.get(&ssa) {
+            return Instr::new_boxed(OpCopy {
+                dst: dst,
+                src: *src,
+            });
+        }
+
         let src = self.get_spill(ssa);
         self.spill.fill(dst, src)
     }
@@ -356,6 +440,15 @@ fn spill_values<S: Spill>(
 ) {
     let files = RegFileSet::from_iter([file]);
     let live = NextUseLiveness::for_function(func, &files);
+
+    // Only GPRs are worth rematerializing.  Predicates and barriers are
+    // spilled to GPRs, which is already cheap.
+    let remat = if file == RegFile::GPR {
+        find_remat_values(func, file)
+    } else {
+        HashMap::new()
+    };
+
     let blocks = &mut func.blocks;
 
     // Record the set of SSA values used within each loop
@@ -407,7 +500,7 @@ fn spill_values<S: Spill>(
         }
     }
 
-    let mut spill = SpillCache::new(&mut func.ssa_alloc, spill);
+    let mut spill = SpillCache::new(&mut func.ssa_alloc, spill, remat);
     let mut spilled_phis = BitSet::new();
 
     let mut ssa_state_in: Vec<SSAState> = Vec::new();
@@ -666,7 +759,7 @@ fn spill_values<S: Spill>(
                             let src_ssa = &src.src_ref.as_ssa().unwrap()[0];
                             if spills.contains(dst_ssa) {
                                 if b.s.insert(*src_ssa) {
-                                    instrs.push(spill.spill(*src_ssa));
+                                    instrs.extend(spill.spill(*src_ssa));
                                 }
                                 b.s.insert(*dst_ssa);
                                 *src = spill.get_spill(*src_ssa).into();
@@ -710,7 +803,7 @@ fn spill_values<S: Spill>(
                         for ssa in spills {
                             debug_assert!(ssa.file() == file);
                             b.w.remove(&ssa);
-                            instrs.push(spill.spill(ssa));
+                            instrs.extend(spill.spill(ssa));
                             b.s.insert(ssa);
                         }
                     }
@@ -814,7 +907,7 @@ fn spill_values<S: Spill>(
 
         let mut instrs = Vec::new();
         for ssa in spills {
-            instrs.push(spill.spill(ssa));
+            instrs.extend(spill.spill(ssa));
         }
         for ssa in fills {
             instrs.push(spill.fill(ssa));
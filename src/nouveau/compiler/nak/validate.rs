@@ -0,0 +1,326 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+//! An IR validator
+//!
+//! This checks a handful of invariants which every pass is expected to
+//! maintain.  It's run between passes when NAK_DEBUG=validate is set or in
+//! debug builds so that a broken pass is caught right after it runs rather
+//! than exploding somewhere down the line in RA or the encoder.
+
+use crate::ir::*;
+
+use std::collections::{HashMap, HashSet};
+
+fn src_mod_supports_type(src_mod: SrcMod, src_type: SrcType) -> bool {
+    match src_mod {
+        SrcMod::None => true,
+        SrcMod::FAbs | SrcMod::FNeg | SrcMod::FNegAbs => {
+            matches!(src_type, SrcType::F16v2 | SrcType::F32 | SrcType::F64)
+        }
+        SrcMod::INeg => matches!(src_type, SrcType::I32),
+        SrcMod::BNot => matches!(src_type, SrcType::B32 | SrcType::Pred),
+    }
+}
+
+fn validate_ssa_ref(vec: &SSARef, instr: &Instr) {
+    let file = vec[0].file();
+    for ssa in vec.iter() {
+        assert!(
+            ssa.file() == file,
+            "Mixed register files in {} in {}",
+            vec,
+            instr
+        );
+    }
+}
+
+fn validate_instr_srcs_dsts(instr: &Instr) {
+    let src_types = instr.src_types();
+    for (i, src) in instr.srcs().iter().enumerate() {
+        let src_type = src_types[i];
+        assert!(
+            src_mod_supports_type(src.src_mod, src_type),
+            "Invalid source modifier on {} in {}",
+            src,
+            instr
+        );
+
+        if src_type == SrcType::Pred {
+            assert!(
+                src.is_predicate(),
+                "Predicate source {} is not a predicate in {}",
+                src,
+                instr
+            );
+        } else if src.is_predicate() && src_type != SrcType::GPR {
+            // Untyped sources default to GPR and things like copies and phis
+            // may legitimately move predicates around.
+            panic!("Unexpected predicate source {} in {}", src, instr);
+        }
+
+        if let SrcRef::SSA(vec) = &src.src_ref {
+            validate_ssa_ref(vec, instr);
+        }
+    }
+
+    for dst in instr.dsts() {
+        if let Dst::SSA(vec) = dst {
+            validate_ssa_ref(vec, instr);
+        }
+    }
+}
+
+impl Function {
+    fn validate_cfg(&self) {
+        for i in 0..self.blocks.len() {
+            for &s in self.blocks.succ_indices(i) {
+                assert!(
+                    self.blocks.pred_indices(s).contains(&i),
+                    "Block {} is a successor of block {} but not vice versa",
+                    s,
+                    i
+                );
+            }
+            for &p in self.blocks.pred_indices(i) {
+                assert!(
+                    self.blocks.succ_indices(p).contains(&i),
+                    "Block {} is a predecessor of block {} but not vice versa",
+                    p,
+                    i
+                );
+            }
+
+            // These assert if the edges don't match the branches
+            if self.blocks[i].falls_through() {
+                assert!(
+                    self.fall_through_succ(i) == Some(i + 1),
+                    "Block {} falls through but not to the next block",
+                    i
+                );
+            }
+            self.branch_succ(i);
+
+            let b = &self.blocks[i];
+            for (ip, instr) in b.instrs.iter().enumerate() {
+                // An exit has no successor so it may come early in a block.
+                // from_nir puts one right after the kill for terminate and
+                // for a uniform discard_if, and the rest of the block only
+                // runs in warps which didn't exit.
+                if instr.is_branch() && !matches!(instr.op, Op::Exit(_)) {
+                    assert!(
+                        ip == b.instrs.len() - 1,
+                        "Branch is not at the end of block {}",
+                        i
+                    );
+                }
+                if matches!(instr.op, Op::PhiDsts(_)) {
+                    assert!(ip == 0, "Phis are not at the top of block {}", i);
+                }
+            }
+        }
+    }
+
+    fn validate_phis(&self) {
+        for i in 0..self.blocks.len() {
+            let Some(phi) = self.blocks[i].phi_dsts() else {
+                continue;
+            };
+
+            for &p in self.blocks.pred_indices(i) {
+                let mut srcs = HashSet::new();
+                if let Some(phi_srcs) = self.blocks[p].phi_srcs() {
+                    for (id, _) in phi_srcs.srcs.iter() {
+                        srcs.insert(*id);
+                    }
+                }
+
+                for (id, _) in phi.dsts.iter() {
+                    assert!(
+                        srcs.contains(id),
+                        "Phi {} in block {} has no source from block {}",
+                        id,
+                        i,
+                        p
+                    );
+                }
+            }
+        }
+    }
+
+    fn validate_ssa(&self) {
+        // Maps each SSA value to the block and instruction defining it
+        let mut defs: HashMap<SSAValue, (usize, usize)> = HashMap::new();
+        for (bi, b) in self.blocks.iter().enumerate() {
+            for (ip, instr) in b.instrs.iter().enumerate() {
+                instr.for_each_ssa_def(|ssa| {
+                    let old = defs.insert(*ssa, (bi, ip));
+                    assert!(old.is_none(), "{} is defined twice", ssa);
+                });
+            }
+        }
+
+        for (bi, b) in self.blocks.iter().enumerate() {
+            for (ip, instr) in b.instrs.iter().enumerate() {
+                instr.for_each_ssa_use(|ssa| {
+                    let Some(&(def_bi, def_ip)) = defs.get(ssa) else {
+                        panic!("{} is used but never defined", ssa);
+                    };
+
+                    // Phi sources are really uses at the end of the block so
+                    // the def only needs to dominate the block.
+                    let dominated = if def_bi == bi {
+                        def_ip < ip || matches!(instr.op, Op::PhiSrcs(_))
+                    } else {
+                        self.blocks.dominates(def_bi, bi)
+                    };
+                    assert!(
+                        dominated,
+                        "Use of {} in block {} is not dominated by its def",
+                        ssa, bi
+                    );
+                });
+            }
+        }
+    }
+
+    /// Checks that every reconvergence point has a matching push.
+    ///
+    /// Before Volta, a SYNC, BRK, or CONT pops the control stack entry pushed
+    /// by the matching SSY, PBK, or PCNT with the same target so the push has
+    /// to dominate the pop.  On Volta+, the label after a BSYNC is the target
+    /// of its BSSY so the BSSY has to dominate the BSYNC.  Getting either of
+    /// these wrong doesn't crash the compiler but it hangs the GPU.
+    fn validate_reconvergence(&self) {
+        // Maps (pop, target) to the blocks with a matching push
+        let mut pushes: HashMap<(&str, Label), Vec<usize>> = HashMap::new();
+        let mut bssy_blocks: HashMap<Label, usize> = HashMap::new();
+        for (bi, b) in self.blocks.iter().enumerate() {
+            for instr in &b.instrs {
+                match &instr.op {
+                    Op::SSy(op) => {
+                        pushes.entry(("sync", op.target)).or_default().push(bi)
+                    }
+                    Op::PBk(op) => {
+                        pushes.entry(("brk", op.target)).or_default().push(bi)
+                    }
+                    Op::PCnt(op) => {
+                        pushes.entry(("cont", op.target)).or_default().push(bi)
+                    }
+                    Op::BSSy(op) => {
+                        bssy_blocks.insert(op.target, bi);
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        for (bi, b) in self.blocks.iter().enumerate() {
+            let mut seen_bsync = false;
+            for instr in &b.instrs {
+                let pop = match &instr.op {
+                    Op::Sync(op) => Some(("sync", op.target)),
+                    Op::Brk(op) => Some(("brk", op.target)),
+                    Op::Cont(op) => Some(("cont", op.target)),
+                    Op::BSync(_) => {
+                        seen_bsync = true;
+                        None
+                    }
+                    Op::Nop(OpNop { label: Some(label) }) => {
+                        if let Some(&bssy_bi) = bssy_blocks.get(label) {
+                            assert!(
+                                seen_bsync,
+                                "BSSY target {} in block {} has no BSYNC",
+                                label, bi
+                            );
+                            assert!(
+                                self.blocks.dominates(bssy_bi, bi),
+                                "BSSY in block {} does not dominate its \
+                                 target {} in block {}",
+                                bssy_bi,
+                                label,
+                                bi
+                            );
+                        }
+                        None
+                    }
+                    _ => None,
+                };
+
+                let Some(pop) = pop else {
+                    continue;
+                };
+                let dominated = pushes.get(&pop).map_or(false, |push_blocks| {
+                    push_blocks.iter().any(|&p| self.blocks.dominates(p, bi))
+                });
+                assert!(
+                    dominated,
+                    "{} in block {} has no dominating push for {}",
+                    instr, bi, pop.1
+                );
+            }
+        }
+    }
+
+    fn validate(&self) {
+        self.validate_cfg();
+        self.validate_phis();
+        self.validate_ssa();
+        self.validate_reconvergence();
+        for b in &self.blocks {
+            for instr in &b.instrs {
+                validate_instr_srcs_dsts(instr);
+            }
+        }
+    }
+}
+
+impl Shader {
+    /// Checks that the shader is well-formed and panics if it isn't.
+    pub fn validate(&self) {
+        for f in &self.functions {
+            f.validate();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir_parse::parse_function;
+
+    #[test]
+    fn exit_in_the_middle_of_a_block() {
+        let f = parse_function(
+            "block 0 L0 [] -> {
+                %r1 = s2r sr[0x21]
+                %p2 = isetp.lt.u32 %r1 0x10
+                @p2 kill
+                @p2 exit
+                %r3 = iadd3 %r1 0x1 rZ
+                kill
+                exit
+                %r4 = iadd3 %r3 0x1 rZ
+                exit
+            } -> []",
+        )
+        .unwrap();
+        f.validate();
+    }
+
+    #[test]
+    #[should_panic(expected = "Branch is not at the end of block 0")]
+    fn bra_in_the_middle_of_a_block() {
+        let f = parse_function(
+            "block 0 L0 [] -> {
+                %r1 = s2r sr[0x21]
+                bra L1
+                %r2 = iadd3 %r1 0x1 rZ
+            } -> [1]
+            block 1 L1 [0] -> {
+                exit
+            } -> []",
+        )
+        .unwrap();
+        f.validate();
+    }
+}
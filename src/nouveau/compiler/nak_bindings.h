@@ -4,3 +4,4 @@
  */
 
 #include "nak_private.h"
+#include "nir_xfb_info.h"
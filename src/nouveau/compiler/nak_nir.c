@@ -5,8 +5,11 @@
 
 #include "nak_private.h"
 #include "nir_builder.h"
+#include "nir_builtin_builder.h"
+#include "nir_serialize.h"
 #include "nir_xfb_info.h"
 
+#include "util/blob.h"
 #include "util/u_math.h"
 
 #define OPT(nir, pass, ...) ({                           \
@@ -147,6 +150,8 @@ nak_optimize_nir(nir_shader *nir, const struct nak_compiler *nak)
 static unsigned
 lower_bit_size_cb(const nir_instr *instr, void *_data)
 {
+   const struct nak_compiler *nak = _data;
+
    switch (instr->type) {
    case nir_instr_type_alu: {
       nir_alu_instr *alu = nir_instr_as_alu(instr);
@@ -173,7 +178,24 @@ lower_bit_size_cb(const nir_instr *instr, void *_data)
       if (bit_size >= 32)
          return 0;
 
-      /* TODO: Some hardware has native 16-bit support */
+      /* Volta+ has packed f16x2 HADD2, HMUL2, HFMA2, and HSET2 */
+      if (bit_size == 16 && nak->sm >= 70) {
+         switch (alu->op) {
+         case nir_op_fabs:
+         case nir_op_fadd:
+         case nir_op_fneg:
+         case nir_op_ffma:
+         case nir_op_fmul:
+         case nir_op_feq:
+         case nir_op_fge:
+         case nir_op_flt:
+         case nir_op_fneu:
+            return 0;
+         default:
+            break;
+         }
+      }
+
       if (bit_size & (8 | 16))
          return 32;
 
@@ -287,6 +309,54 @@ nak_nir_lower_subgroup_id(nir_shader *nir)
                                      NULL);
 }
 
+/* SM50 TEX has no LOD clamp so, on Maxwell and Pascal, we compute the LOD in
+ * the shader, apply the bias and the clamp, and sample with an explicit LOD
+ * instead.  This is what nir_lower_tex does for lower_txb_shadow_clamp, only
+ * for every op with an implicit LOD.
+ *
+ * This gives up anisotropic filtering and adds a TMML to every lookup with a
+ * clamp.  NVK advertises shaderResourceMinLod so color lookups have to be
+ * lowered too, or they would reach the encoder with a LOD mode it can't
+ * encode.
+ */
+static bool
+lower_sm50_min_lod_instr(nir_builder *b, nir_instr *instr,
+                         UNUSED void *_data)
+{
+   if (instr->type != nir_instr_type_tex)
+      return false;
+
+   nir_tex_instr *tex = nir_instr_as_tex(instr);
+   if (tex->op != nir_texop_tex && tex->op != nir_texop_txb)
+      return false;
+
+   if (nir_tex_instr_src_index(tex, nir_tex_src_min_lod) < 0)
+      return false;
+
+   nir_def *lod = nir_get_texture_lod(b, tex);
+
+   nir_def *bias = nir_steal_tex_src(tex, nir_tex_src_bias);
+   if (bias != NULL)
+      lod = nir_fadd(b, lod, bias);
+
+   nir_def *min_lod = nir_steal_tex_src(tex, nir_tex_src_min_lod);
+   lod = nir_fmax(b, lod, min_lod);
+
+   nir_tex_instr_add_src(tex, nir_tex_src_lod, lod);
+   tex->op = nir_texop_txl;
+
+   return true;
+}
+
+bool
+nak_nir_lower_sm50_min_lod(nir_shader *nir)
+{
+   return nir_shader_instructions_pass(nir, lower_sm50_min_lod_instr,
+                                       nir_metadata_block_index |
+                                       nir_metadata_dominance,
+                                       NULL);
+}
+
 void
 nak_preprocess_nir(nir_shader *nir, const struct nak_compiler *nak)
 {
@@ -303,6 +373,8 @@ nak_preprocess_nir(nir_shader *nir, const struct nak_compiler *nak)
       /* TODO: More lowering */
    };
    OPT(nir, nir_lower_tex, &tex_options);
+   if (nak->sm < 70)
+      OPT(nir, nak_nir_lower_sm50_min_lod);
    OPT(nir, nir_normalize_cubemap_coords);
 
    nir_lower_image_options image_options = {
@@ -321,6 +393,22 @@ nak_preprocess_nir(nir_shader *nir, const struct nak_compiler *nak)
    OPT(nir, nir_lower_load_const_to_scalar);
    OPT(nir, nir_lower_var_copies);
    OPT(nir, nir_lower_system_values);
+
+   /* Compute system values come from a few different places:
+    *
+    *  - subgroup_id and num_subgroups are computed from the workgroup and
+    *    subgroup sizes by nak_nir_lower_subgroup_id().
+    *
+    *  - local_invocation_index is computed from local_invocation_id because
+    *    we set lower_cs_local_index_to_id.  SR_COMBINED_TID packs the
+    *    invocation ID into bitfields and isn't a linear index.
+    *
+    *  - num_workgroups is up to the driver.  NVK loads it from its root
+    *    descriptor table so that indirect dispatches work.
+    *
+    * Everything else is read from a system register by
+    * nak_nir_lower_system_values().
+    */
    OPT(nir, nak_nir_lower_subgroup_id);
    OPT(nir, nir_lower_compute_system_values, NULL);
 }
@@ -338,6 +426,70 @@ type_size_vec4_bytes(const struct glsl_type *type, bool bindless)
    return glsl_count_vec4_slots(type, false, bindless) * 16;
 }
 
+/* Attributes are always 32 bits per component in hardware and interpolation
+ * is always done at 32 bits so we convert 16-bit varyings at the edges.
+ */
+static bool
+lower_16bit_io_intrin(nir_builder *b, nir_intrinsic_instr *intrin,
+                      UNUSED void *_data)
+{
+   switch (intrin->intrinsic) {
+   case nir_intrinsic_load_input:
+   case nir_intrinsic_load_per_vertex_input:
+   case nir_intrinsic_load_interpolated_input:
+   case nir_intrinsic_load_output:
+   case nir_intrinsic_load_per_vertex_output: {
+      if (intrin->def.bit_size != 16)
+         return false;
+
+      const nir_alu_type dest_type = nir_intrinsic_dest_type(intrin);
+      const nir_alu_type dest_type32 =
+         nir_alu_type_get_base_type(dest_type) | 32;
+
+      intrin->def.bit_size = 32;
+      nir_intrinsic_set_dest_type(intrin, dest_type32);
+
+      b->cursor = nir_after_instr(&intrin->instr);
+      nir_def *res = nir_type_convert(b, &intrin->def, dest_type32,
+                                      dest_type, nir_rounding_mode_undef);
+      nir_def_rewrite_uses_after(&intrin->def, res, res->parent_instr);
+
+      return true;
+   }
+
+   case nir_intrinsic_store_output:
+   case nir_intrinsic_store_per_vertex_output: {
+      nir_def *data = intrin->src[0].ssa;
+      if (data->bit_size != 16)
+         return false;
+
+      const nir_alu_type src_type = nir_intrinsic_src_type(intrin);
+      const nir_alu_type src_type32 =
+         nir_alu_type_get_base_type(src_type) | 32;
+
+      b->cursor = nir_before_instr(&intrin->instr);
+      nir_def *data32 = nir_type_convert(b, data, src_type, src_type32,
+                                         nir_rounding_mode_undef);
+      nir_src_rewrite(&intrin->src[0], data32);
+      nir_intrinsic_set_src_type(intrin, src_type32);
+
+      return true;
+   }
+
+   default:
+      return false;
+   }
+}
+
+static bool
+nak_nir_lower_16bit_io(nir_shader *nir)
+{
+   return nir_shader_intrinsics_pass(nir, lower_16bit_io_intrin,
+                                     nir_metadata_block_index |
+                                     nir_metadata_dominance,
+                                     NULL);
+}
+
 static bool
 nak_nir_lower_vs_inputs(nir_shader *nir)
 {
@@ -350,11 +502,12 @@ nak_nir_lower_vs_inputs(nir_shader *nir)
 
    progress |= OPT(nir, nir_lower_io, nir_var_shader_in, type_size_vec4_bytes,
                         nir_lower_io_lower_64bit_to_32);
+   progress |= OPT(nir, nak_nir_lower_16bit_io);
 
    return progress;
 }
 
-static uint16_t
+uint16_t
 nak_varying_attr_addr(gl_varying_slot slot)
 {
    if (slot >= VARYING_SLOT_PATCH0) {
@@ -396,13 +549,7 @@ static uint8_t
 nak_sysval_sysval_idx(gl_system_value sysval)
 {
    switch (sysval) {
-   case SYSTEM_VALUE_SUBGROUP_INVOCATION:    return NAK_SV_LANE_ID;
    case SYSTEM_VALUE_VERTICES_IN:            return NAK_SV_VERTEX_COUNT;
-   case SYSTEM_VALUE_INVOCATION_ID:          return NAK_SV_INVOCATION_ID;
-   case SYSTEM_VALUE_HELPER_INVOCATION:      return NAK_SV_THREAD_KILL;
-   case SYSTEM_VALUE_LOCAL_INVOCATION_INDEX: return NAK_SV_COMBINED_TID;
-   case SYSTEM_VALUE_LOCAL_INVOCATION_ID:    return NAK_SV_TID;
-   case SYSTEM_VALUE_WORKGROUP_ID:           return NAK_SV_CTAID;
    case SYSTEM_VALUE_SUBGROUP_EQ_MASK:       return NAK_SV_LANEMASK_EQ;
    case SYSTEM_VALUE_SUBGROUP_LT_MASK:       return NAK_SV_LANEMASK_LT;
    case SYSTEM_VALUE_SUBGROUP_LE_MASK:       return NAK_SV_LANEMASK_LE;
@@ -412,6 +559,37 @@ nak_sysval_sysval_idx(gl_system_value sysval)
    }
 }
 
+/* System values which are read straight out of system registers, one
+ * register per component.  Compute shaders get everything else from NIR:
+ * subgroup_id and num_subgroups from nak_nir_lower_subgroup_id(),
+ * local_invocation_index from local_invocation_id, and num_workgroups from
+ * the driver.
+ */
+static const struct {
+   nir_intrinsic_op intrin;
+   uint8_t idx;
+} nak_sysval_regs[] = {
+   { nir_intrinsic_load_subgroup_invocation,    NAK_SV_LANE_ID },
+   { nir_intrinsic_load_helper_invocation,      NAK_SV_THREAD_KILL },
+   { nir_intrinsic_load_invocation_id,          NAK_SV_INVOCATION_ID },
+   { nir_intrinsic_load_local_invocation_index, NAK_SV_COMBINED_TID },
+   { nir_intrinsic_load_local_invocation_id,    NAK_SV_TID },
+   { nir_intrinsic_load_workgroup_id,           NAK_SV_CTAID },
+   { nir_intrinsic_load_workgroup_id_zero_base, NAK_SV_CTAID },
+};
+
+static bool
+nak_sysval_reg_for_intrinsic(nir_intrinsic_op intrin, uint8_t *idx)
+{
+   for (unsigned i = 0; i < ARRAY_SIZE(nak_sysval_regs); i++) {
+      if (nak_sysval_regs[i].intrin == intrin) {
+         *idx = nak_sysval_regs[i].idx;
+         return true;
+      }
+   }
+   return false;
+}
+
 static bool
 nak_nir_lower_system_value_intrin(nir_builder *b, nir_intrinsic_instr *intrin,
                                   void *data)
@@ -431,6 +609,18 @@ nak_nir_lower_system_value_intrin(nir_builder *b, nir_intrinsic_instr *intrin,
    }
 
    case nir_intrinsic_load_primitive_id: {
+      if (b->shader->info.stage == MESA_SHADER_FRAGMENT) {
+         /* The rasterizer provides the primitive ID to fragment shaders
+          * whether or not there is a geometry shader.  It's a flat input
+          * so it gets read with a constant IPA and reading it is enough to
+          * set the SPH bit.
+          */
+         val = nir_load_input(b, 1, 32, nir_imm_int(b, 0),
+                              .base = NAK_ATTR_PRIMITIVE_ID,
+                              .dest_type = nir_type_int32);
+         break;
+      }
+
       assert(b->shader->info.stage == MESA_SHADER_TESS_CTRL ||
              b->shader->info.stage == MESA_SHADER_TESS_EVAL ||
              b->shader->info.stage == MESA_SHADER_GEOMETRY);
@@ -481,28 +671,6 @@ nak_nir_lower_system_value_intrin(nir_builder *b, nir_intrinsic_instr *intrin,
       break;
    }
 
-   case nir_intrinsic_load_subgroup_invocation:
-   case nir_intrinsic_load_helper_invocation:
-   case nir_intrinsic_load_invocation_id:
-   case nir_intrinsic_load_local_invocation_index:
-   case nir_intrinsic_load_local_invocation_id:
-   case nir_intrinsic_load_workgroup_id:
-   case nir_intrinsic_load_workgroup_id_zero_base: {
-      const gl_system_value sysval =
-         intrin->intrinsic == nir_intrinsic_load_workgroup_id_zero_base ?
-         SYSTEM_VALUE_WORKGROUP_ID :
-         nir_system_value_from_intrinsic(intrin->intrinsic);
-      const uint32_t idx = nak_sysval_sysval_idx(sysval);
-      nir_def *comps[3];
-      assert(intrin->def.num_components <= 3);
-      for (unsigned c = 0; c < intrin->def.num_components; c++) {
-         comps[c] = nir_load_sysval_nv(b, 32, .base = idx + c,
-                                       .access = ACCESS_CAN_REORDER);
-      }
-      val = nir_vec(b, comps, intrin->def.num_components);
-      break;
-   }
-
    case nir_intrinsic_is_helper_invocation: {
       /* Unlike load_helper_invocation, this one isn't re-orderable */
       val = nir_load_sysval_nv(b, 32, .base = NAK_SV_THREAD_KILL);
@@ -533,8 +701,20 @@ nak_nir_lower_system_value_intrin(nir_builder *b, nir_intrinsic_instr *intrin,
       val = nir_ubitfield_extract_imm(b, val, 20, 9);
       break;
 
-   default:
-      return false;
+   default: {
+      uint8_t idx;
+      if (!nak_sysval_reg_for_intrinsic(intrin->intrinsic, &idx))
+         return false;
+
+      nir_def *comps[3];
+      assert(intrin->def.num_components <= 3);
+      for (unsigned c = 0; c < intrin->def.num_components; c++) {
+         comps[c] = nir_load_sysval_nv(b, 32, .base = idx + c,
+                                       .access = ACCESS_CAN_REORDER);
+      }
+      val = nir_vec(b, comps, intrin->def.num_components);
+      break;
+   }
    }
 
    if (intrin->def.bit_size == 1)
@@ -545,7 +725,7 @@ nak_nir_lower_system_value_intrin(nir_builder *b, nir_intrinsic_instr *intrin,
    return true;
 }
 
-static bool
+bool
 nak_nir_lower_system_values(nir_shader *nir, const struct nak_compiler *nak)
 {
    return nir_shader_intrinsics_pass(nir, nak_nir_lower_system_value_intrin,
@@ -566,45 +746,11 @@ nak_nir_lower_varyings(nir_shader *nir, nir_variable_mode modes)
 
    OPT(nir, nir_lower_io, modes, type_size_vec4_bytes,
        nir_lower_io_lower_64bit_to_32);
+   OPT(nir, nak_nir_lower_16bit_io);
 
    return progress;
 }
 
-struct nak_xfb_info
-nak_xfb_from_nir(const struct nir_xfb_info *nir_xfb)
-{
-   if (nir_xfb == NULL)
-      return (struct nak_xfb_info) { };
-
-   struct nak_xfb_info nak_xfb = { };
-
-   u_foreach_bit(b, nir_xfb->buffers_written) {
-      nak_xfb.stride[b] = nir_xfb->buffers[b].stride;
-      nak_xfb.stream[b] = nir_xfb->buffer_to_stream[b];
-   }
-   memset(nak_xfb.attr_index, 0xff, sizeof(nak_xfb.attr_index)); /* = skip */
-
-   for (unsigned o = 0; o < nir_xfb->output_count; o++) {
-      const nir_xfb_output_info *out = &nir_xfb->outputs[o];
-      const uint8_t b = out->buffer;
-      assert(nir_xfb->buffers_written & BITFIELD_BIT(b));
-
-      const uint16_t attr_addr = nak_varying_attr_addr(out->location);
-      assert(attr_addr % 4 == 0);
-      const uint16_t attr_idx = attr_addr / 4;
-
-      assert(out->offset % 4 == 0);
-      uint8_t out_idx = out->offset / 4;
-
-      u_foreach_bit(c, out->component_mask)
-         nak_xfb.attr_index[b][out_idx++] = attr_idx + c;
-
-      nak_xfb.attr_count[b] = MAX2(nak_xfb.attr_count[b], out_idx);
-   }
-
-   return nak_xfb;
-}
-
 static nir_def *
 load_frag_w(nir_builder *b, enum nak_interp_loc interp_loc, nir_def *offset)
 {
@@ -685,6 +831,9 @@ static nir_def *
 load_sample_pos_at(nir_builder *b, nir_def *sample_id,
                    const struct nak_fs_key *fs_key)
 {
+   /* The driver has to give us the sample locations table */
+   assert(fs_key != NULL);
+
    nir_def *loc = nir_load_ubo(b, 1, 64,
                                nir_imm_int(b, fs_key->sample_locations_cb),
                                nir_imm_int(b, fs_key->sample_locations_offset),
@@ -733,6 +882,16 @@ struct lower_fs_input_ctx {
    const struct nak_fs_key *fs_key;
 };
 
+/* Returns true if the shader runs once per sample, either because it asks
+ * for it or because the pipeline forces sample shading.
+ */
+static bool
+fs_runs_per_sample(nir_builder *b, const struct lower_fs_input_ctx *ctx)
+{
+   return b->shader->info.fs.uses_sample_shading ||
+          (ctx->fs_key && ctx->fs_key->force_sample_shading);
+}
+
 static bool
 lower_fs_input_intrin(nir_builder *b, nir_intrinsic_instr *intrin, void *data)
 {
@@ -751,9 +910,12 @@ lower_fs_input_intrin(nir_builder *b, nir_intrinsic_instr *intrin, void *data)
    case nir_intrinsic_load_point_coord: {
       b->cursor = nir_before_instr(&intrin->instr);
 
+      /* When running per-sample, the centroid is the sample location and
+       * the coordinate has to reflect the sample, not the pixel center.
+       */
       const enum nak_interp_loc interp_loc =
-         b->shader->info.fs.uses_sample_shading ? NAK_INTERP_LOC_CENTROID
-                                                : NAK_INTERP_LOC_DEFAULT;
+         fs_runs_per_sample(b, ctx) ? NAK_INTERP_LOC_CENTROID
+                                    : NAK_INTERP_LOC_DEFAULT;
       const uint32_t addr =
          intrin->intrinsic == nir_intrinsic_load_point_coord ?
          nak_sysval_attr_addr(SYSTEM_VALUE_POINT_COORD) :
@@ -903,8 +1065,7 @@ lower_fs_input_intrin(nir_builder *b, nir_intrinsic_instr *intrin, void *data)
    }
 
    case nir_intrinsic_load_sample_mask_in: {
-      if (!b->shader->info.fs.uses_sample_shading &&
-          !(ctx->fs_key && ctx->fs_key->force_sample_shading))
+      if (!fs_runs_per_sample(b, ctx))
          return false;
 
       b->cursor = nir_after_instr(&intrin->instr);
@@ -930,6 +1091,38 @@ lower_fs_input_intrin(nir_builder *b, nir_intrinsic_instr *intrin, void *data)
       return true;
    }
 
+   case nir_intrinsic_load_sample_pos_from_id: {
+      b->cursor = nir_before_instr(&intrin->instr);
+
+      nir_def *sample_id = intrin->src[0].ssa;
+      nir_def *sample_pos = load_sample_pos_at(b, sample_id, ctx->fs_key);
+
+      nir_def_rewrite_uses(&intrin->def, sample_pos);
+      nir_instr_remove(&intrin->instr);
+
+      return true;
+   }
+
+   case nir_intrinsic_load_sample_pos_or_center: {
+      b->cursor = nir_before_instr(&intrin->instr);
+
+      /* This must not turn on per-sample shading by itself so we only look
+       * at the sample locations table if it's already on.
+       */
+      nir_def *sample_pos;
+      if (fs_runs_per_sample(b, ctx)) {
+         nir_def *sample_id = nir_load_sample_id(b);
+         sample_pos = load_sample_pos_at(b, sample_id, ctx->fs_key);
+      } else {
+         sample_pos = nir_imm_vec2(b, 0.5, 0.5);
+      }
+
+      nir_def_rewrite_uses(&intrin->def, sample_pos);
+      nir_instr_remove(&intrin->instr);
+
+      return true;
+   }
+
    case nir_intrinsic_load_input_vertex: {
       b->cursor = nir_before_instr(&intrin->instr);
 
@@ -1004,6 +1197,13 @@ nak_nir_lower_fs_outputs(nir_shader *nir)
          var->data.driver_location = NAK_FS_OUT_DEPTH;
          break;
       case FRAG_RESULT_STENCIL:
+         /* The SPH output map only has bits for the color targets, the
+          * sample mask, and depth, and FSOUT takes the sample mask and
+          * depth in consecutive registers right after the color targets
+          * with no slot in between.  As far as we know, the hardware has
+          * no way for a fragment shader to write stencil so NVK doesn't
+          * advertise the extension.
+          */
          unreachable("EXT_shader_stencil_export not supported");
          break;
       case FRAG_RESULT_COLOR:
@@ -1027,6 +1227,11 @@ nak_nir_lower_fs_outputs(nir_shader *nir)
 
    NIR_PASS_V(nir, nir_lower_io, nir_var_shader_out, fs_out_size, 0);
 
+   /* Color outputs are 32 bits per component just like varyings and the
+    * conversion to the render target format happens in the ROP.
+    */
+   NIR_PASS_V(nir, nak_nir_lower_16bit_io);
+
    return true;
 }
 
@@ -1120,6 +1325,71 @@ nir_shader_has_local_variables(const nir_shader *nir)
    return false;
 }
 
+static nir_cf_node *
+top_level_cf_node(nir_block *block)
+{
+   nir_cf_node *node = &block->cf_node;
+   while (node->parent->type != nir_cf_node_function)
+      node = node->parent;
+   return node;
+}
+
+/* Demote is a bare KIL which leaves the lane running as a helper invocation
+ * so the warp keeps going until the end of the shader even once every lane
+ * in it has been demoted.  At that point, nothing can need the helpers
+ * anymore so we may as well throw the whole warp away.  This is a big win
+ * for alpha-tested shaders where entire warps are often discarded.
+ *
+ * The vote has to happen in top-level control flow so that every live lane
+ * takes part in it.  Otherwise, we might throw away helpers which a lane
+ * that didn't take the demote still needs.
+ */
+static bool
+nak_nir_exit_demoted_warps(nir_shader *nir)
+{
+   assert(nir->info.stage == MESA_SHADER_FRAGMENT);
+   nir_function_impl *impl = nir_shader_get_entrypoint(nir);
+   nir_builder b = nir_builder_create(impl);
+
+   bool progress = false;
+   nir_cf_node *last_top = NULL;
+   nir_foreach_block_safe(block, impl) {
+      nir_foreach_instr_safe(instr, block) {
+         if (instr->type != nir_instr_type_intrinsic)
+            continue;
+
+         nir_intrinsic_instr *intrin = nir_instr_as_intrinsic(instr);
+         if (intrin->intrinsic != nir_intrinsic_demote &&
+             intrin->intrinsic != nir_intrinsic_demote_if)
+            continue;
+
+         /* Consecutive demotes under the same CF node share one check */
+         nir_cf_node *top = top_level_cf_node(block);
+         if (top->type == nir_cf_node_block)
+            b.cursor = nir_after_instr(instr);
+         else if (top != last_top)
+            b.cursor = nir_after_cf_node_and_phis(top);
+         else
+            continue;
+         last_top = top;
+
+         nir_def *all_helpers =
+            nir_vote_all(&b, 1, nir_is_helper_invocation(&b, 1));
+         nir_terminate_if(&b, all_helpers);
+         progress = true;
+      }
+   }
+
+   if (progress) {
+      nir_metadata_preserve(impl, nir_metadata_block_index |
+                                  nir_metadata_dominance);
+   } else {
+      nir_metadata_preserve(impl, nir_metadata_all);
+   }
+
+   return progress;
+}
+
 void
 nak_postprocess_nir(nir_shader *nir,
                     const struct nak_compiler *nak,
@@ -1130,6 +1400,31 @@ nak_postprocess_nir(nir_shader *nir,
 
    nak_optimize_nir(nir, nak);
 
+   if (nir->info.stage == MESA_SHADER_FRAGMENT)
+      OPT(nir, nak_nir_exit_demoted_warps);
+
+   /* This has to happen before nir_lower_subgroups lowers the shuffles it
+    * creates.
+    */
+   OPT(nir, nak_nir_lower_shared_broadcast);
+
+   /* Atomics to a warp-uniform address become a subgroup reduction plus a
+    * single atomic from one lane.  This needs LCSSA and divergence
+    * information and emits reduce, exclusive_scan, elect, and
+    * read_first_invocation so it has to run before those are lowered below.
+    */
+   OPT(nir, nir_convert_to_lcssa, true, true);
+   nir_divergence_analysis(nir);
+   OPT(nir, nir_opt_uniform_atomics);
+
+   /* The hardware only gives us VOTE, FLO, and SHFL so all of the
+    * ballot-relative intrinsics get built on top of those.  With a 32-bit
+    * scalar ballot, nir_lower_subgroups turns elect and first_invocation
+    * into a find_lsb of ballot(true), read_first_invocation into a shuffle
+    * from that lane, and inverse_ballot, ballot_bitfield_extract, and the
+    * ballot_bit_count_* family into plain ALU on the ballot and the
+    * subgroup_*_mask sysvals.
+    */
    const nir_lower_subgroups_options subgroups_options = {
       .subgroup_size = 32,
       .ballot_bit_size = 32,
@@ -1154,6 +1449,13 @@ nak_postprocess_nir(nir_shader *nir,
 
    OPT(nir, nir_opt_shrink_vectors);
 
+   /* This also handles shared memory and scratch, which the vectorizer
+    * files under nir_var_shader_temp, so adjacent LDS/STS and LDL/STL get
+    * merged into 64 and 128-bit accesses.  Anything which ends up with a
+    * size or alignment the hardware can't do is split back up by
+    * nak_mem_access_size_align() below.  This has to happen here rather
+    * than on OpLd/OpSt because NAK IR doesn't know the base alignment.
+    */
    nir_load_store_vectorize_options vectorize_opts = {};
    vectorize_opts.modes = nir_var_mem_global |
                           nir_var_mem_ssbo |
@@ -1226,6 +1528,8 @@ nak_postprocess_nir(nir_shader *nir,
    OPT(nir, nir_lower_doubles, NULL, nak->nir_options.lower_doubles_options);
    OPT(nir, nir_lower_int64);
 
+   OPT(nir, nak_nir_opt_if_ladders);
+
    nak_optimize_nir(nir, nak);
 
    do {
@@ -1259,6 +1563,10 @@ nak_postprocess_nir(nir_shader *nir,
       if (func->impl) {
          nir_index_blocks(func->impl);
          nir_index_ssa_defs(func->impl);
+
+         /* NAK copies trip counts and unroll hints onto its loops */
+         nir_metadata_require(func->impl, nir_metadata_loop_analysis,
+                              nir_var_all, false);
       }
    }
 
@@ -1313,3 +1621,85 @@ nak_get_io_addr_offset(nir_def *addr, uint8_t imm_bits)
       .base = addr_s,
    };
 }
+
+void
+nak_serialize_compile_input(const struct nak_compiler *nak,
+                            const nir_shader *nir,
+                            nir_variable_mode robust2_modes,
+                            const struct nak_fs_key *fs_key,
+                            uint8_t max_gprs, bool align_loops,
+                            nak_write_func write_func, void *data)
+{
+   struct blob blob;
+   blob_init(&blob);
+
+   blob_write_uint8(&blob, nak->sm);
+   blob_write_uint8(&blob, nak->warps_per_sm);
+   blob_write_uint32(&blob, robust2_modes);
+   blob_write_uint8(&blob, max_gprs);
+   blob_write_uint8(&blob, align_loops);
+   blob_write_uint8(&blob, fs_key != NULL);
+   if (fs_key != NULL) {
+      blob_write_uint8(&blob, fs_key->zs_self_dep);
+      blob_write_uint8(&blob, fs_key->force_sample_shading);
+      blob_write_uint8(&blob, fs_key->depth_unrestricted);
+      blob_write_uint8(&blob, fs_key->sample_locations_cb);
+      blob_write_uint32(&blob, fs_key->sample_locations_offset);
+      blob_write_uint32(&blob, fs_key->color_write_disable);
+   }
+   nir_serialize(&blob, nir, true /* strip */);
+
+   write_func(data, blob.data, blob.size);
+
+   blob_finish(&blob);
+}
+
+bool
+nak_deserialize_compile_input(const void *data, size_t size,
+                              struct nak_compile_input *input)
+{
+   struct blob_reader blob;
+   blob_reader_init(&blob, data, size);
+
+   memset(input, 0, sizeof(*input));
+
+   /* Initializers are evaluated in no particular order */
+   const uint8_t sm = blob_read_uint8(&blob);
+   const uint8_t warps_per_sm = blob_read_uint8(&blob);
+   const struct nv_device_info dev = {
+      .sm = sm,
+      .max_warps_per_mp = warps_per_sm,
+   };
+   input->robust2_modes = blob_read_uint32(&blob);
+   input->max_gprs = blob_read_uint8(&blob);
+   input->align_loops = blob_read_uint8(&blob);
+   input->has_fs_key = blob_read_uint8(&blob);
+   if (input->has_fs_key) {
+      input->fs_key.zs_self_dep = blob_read_uint8(&blob);
+      input->fs_key.force_sample_shading = blob_read_uint8(&blob);
+      input->fs_key.depth_unrestricted = blob_read_uint8(&blob);
+      input->fs_key.sample_locations_cb = blob_read_uint8(&blob);
+      input->fs_key.sample_locations_offset = blob_read_uint32(&blob);
+      input->fs_key.color_write_disable = blob_read_uint32(&blob);
+   }
+   if (blob.overrun)
+      return false;
+
+   input->nak = nak_compiler_create(&dev);
+   input->nir = nir_deserialize(NULL, nak_nir_options(input->nak), &blob);
+   if (blob.overrun || blob.current != blob.end) {
+      nak_compile_input_finish(input);
+      return false;
+   }
+
+   return true;
+}
+
+void
+nak_compile_input_finish(struct nak_compile_input *input)
+{
+   ralloc_free(input->nir);
+   if (input->nak != NULL)
+      nak_compiler_destroy(input->nak);
+   memset(input, 0, sizeof(*input));
+}
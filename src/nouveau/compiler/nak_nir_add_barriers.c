@@ -47,6 +47,31 @@ add_bar_cf_node(nir_cf_node *node, struct add_barriers_state *state)
    state->progress = true;
 }
 
+/* If the block is the only thing on one side of an if, then the threads
+ * which break are exactly the ones for which the if condition (or its
+ * inverse) is true.  In that case, we can break out of the barriers
+ * conditionally before the if instead of in the divergent block.
+ */
+static nir_def *
+break_cond_before_if(nir_block *block, nir_builder *b)
+{
+   if (block->cf_node.parent->type != nir_cf_node_if)
+      return NULL;
+
+   nir_if *nif = nir_cf_node_as_if(block->cf_node.parent);
+   if (nir_if_first_then_block(nif) == block &&
+       nir_if_last_then_block(nif) == block) {
+      b->cursor = nir_before_cf_node(&nif->cf_node);
+      return nif->condition.ssa;
+   } else if (nir_if_first_else_block(nif) == block &&
+              nir_if_last_else_block(nif) == block) {
+      b->cursor = nir_before_cf_node(&nif->cf_node);
+      return nir_inot(b, nif->condition.ssa);
+   } else {
+      return NULL;
+   }
+}
+
 static void
 break_loop_bars(nir_block *block, struct add_barriers_state *state)
 {
@@ -62,7 +87,11 @@ break_loop_bars(nir_block *block, struct add_barriers_state *state)
           jump->type == nir_jump_continue);
 
    nir_builder *b = &state->builder;
-   b->cursor = nir_before_instr(&jump->instr);
+   nir_def *cond = break_cond_before_if(block, b);
+   if (cond == NULL) {
+      b->cursor = nir_before_instr(&jump->instr);
+      cond = nir_imm_true(b);
+   }
 
    const unsigned num_bars =
       util_dynarray_num_elements(&state->barriers, struct barrier);
@@ -77,7 +106,7 @@ break_loop_bars(nir_block *block, struct add_barriers_state *state)
          util_dynarray_element(&state->barriers, struct barrier, idx);
       if (bar->node == p) {
          nir_def *bar_val = nir_load_reg(b, bar->bar_reg);
-         bar_val = nir_bar_break_nv(b, bar_val);
+         bar_val = nir_bar_break_nv(b, bar_val, cond);
          nir_store_reg(b, bar_val, bar->bar_reg);
          idx--;
       }
@@ -6,20 +6,27 @@
 #include "nak_private.h"
 #include "nir_builder.h"
 
+/* Returns the mask of all invocations in our cluster, active or not */
 static nir_def *
-cluster_mask(nir_builder *b, unsigned cluster_size)
+cluster_bits(nir_builder *b, unsigned cluster_size)
 {
-   nir_def *mask = nir_ballot(b, 1, 32, nir_imm_true(b));
+   if (cluster_size >= 32)
+      return nir_imm_int(b, -1);
 
-   if (cluster_size < 32) {
-      nir_def *idx = nir_load_subgroup_invocation(b);
-      nir_def *cluster = nir_iand_imm(b, idx, ~(uint64_t)(cluster_size - 1));
+   nir_def *idx = nir_load_subgroup_invocation(b);
+   nir_def *cluster = nir_iand_imm(b, idx, ~(uint64_t)(cluster_size - 1));
 
-      nir_def *cluster_mask = nir_imm_int(b, BITFIELD_MASK(cluster_size));
-      cluster_mask = nir_ishl(b, cluster_mask, cluster);
+   nir_def *cluster_mask = nir_imm_int(b, BITFIELD_MASK(cluster_size));
+   return nir_ishl(b, cluster_mask, cluster);
+}
 
-      mask = nir_iand(b, mask, cluster_mask);
-   }
+static nir_def *
+cluster_mask(nir_builder *b, unsigned cluster_size)
+{
+   nir_def *mask = nir_ballot(b, 1, 32, nir_imm_true(b));
+
+   if (cluster_size < 32)
+      mask = nir_iand(b, mask, cluster_bits(b, cluster_size));
 
    return mask;
 }
@@ -231,8 +238,12 @@ nak_nir_lower_scan_reduce_intrin(nir_builder *b,
        */
       nir_def *mask = cluster_mask(b, cluster_size);
 
+      /* The butterfly in build_scan_full() never leaves the cluster so we
+       * can use it whenever our whole cluster is active, even if other
+       * clusters in the subgroup aren't.
+       */
       nir_def *full, *partial;
-      nir_push_if(b, nir_ieq_imm(b, mask, -1));
+      nir_push_if(b, nir_ieq(b, mask, cluster_bits(b, cluster_size)));
       {
          full = build_scan_full(b, intrin->intrinsic, red_op,
                                 intrin->src[0].ssa, cluster_size);
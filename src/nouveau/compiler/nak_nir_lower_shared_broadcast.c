@@ -0,0 +1,231 @@
+/*
+ * Copyright © 2024 Collabora, Ltd.
+ * SPDX-License-Identifier: MIT
+ */
+
+#include "nak_private.h"
+#include "nir_builder.h"
+
+#include "util/u_dynarray.h"
+
+/*
+ * A common idiom in compute shaders is to broadcast a value from one
+ * invocation to the rest of the workgroup through shared memory:
+ *
+ *    if (gl_LocalInvocationIndex == 0)
+ *       s = value;
+ *    barrier();
+ *    ... = s;
+ *
+ * If the whole workgroup fits in a single subgroup, this is just a shuffle
+ * from that lane.  We only handle the simple case where that store is the
+ * only thing which ever writes shared memory, it sits directly in a
+ * top-level if, and every load of shared memory reads back exactly what it
+ * wrote from top-level control flow after the if.  Since all shared memory
+ * traffic goes away in that case, so do any barriers which only order
+ * shared memory.
+ */
+
+/* Returns true if def is the invocation's index in the workgroup, which is
+ * also its index in the subgroup since there's only the one.
+ */
+static bool
+def_is_invocation_index(nir_def *def)
+{
+   if (def->parent_instr->type != nir_instr_type_intrinsic)
+      return false;
+
+   nir_intrinsic_instr *intrin = nir_instr_as_intrinsic(def->parent_instr);
+   return intrin->intrinsic == nir_intrinsic_load_local_invocation_index ||
+          intrin->intrinsic == nir_intrinsic_load_subgroup_invocation;
+}
+
+/* Matches (invocation_index == lane) in either order */
+static bool
+cond_is_lane_eq(nir_def *cond, uint32_t *lane)
+{
+   if (cond->parent_instr->type != nir_instr_type_alu)
+      return false;
+
+   nir_alu_instr *alu = nir_instr_as_alu(cond->parent_instr);
+   if (alu->op != nir_op_ieq)
+      return false;
+
+   for (unsigned i = 0; i < 2; i++) {
+      nir_scalar idx = nir_get_scalar(alu->src[i].src.ssa,
+                                      alu->src[i].swizzle[0]);
+      nir_scalar imm = nir_get_scalar(alu->src[1 - i].src.ssa,
+                                      alu->src[1 - i].swizzle[0]);
+      if (def_is_invocation_index(idx.def) && nir_scalar_is_const(imm)) {
+         *lane = nir_scalar_as_uint(imm);
+         return true;
+      }
+   }
+
+   return false;
+}
+
+static bool
+shared_addr(nir_intrinsic_instr *intrin, unsigned addr_src, uint32_t *addr)
+{
+   if (!nir_src_is_const(intrin->src[addr_src]))
+      return false;
+
+   *addr = nir_intrinsic_base(intrin) +
+           nir_src_as_uint(intrin->src[addr_src]);
+   return true;
+}
+
+static bool
+block_is_top_level(nir_block *block)
+{
+   return block->cf_node.parent->type == nir_cf_node_function;
+}
+
+/* Looks for the broadcast idiom.  On success, returns the store and fills
+ * out the loads which read it back, the barriers which only order shared
+ * memory, and the lane doing the store.
+ */
+static nir_intrinsic_instr *
+match_shared_broadcast(nir_function_impl *impl,
+                       struct util_dynarray *loads,
+                       struct util_dynarray *barriers,
+                       uint32_t *lane)
+{
+   nir_intrinsic_instr *store = NULL;
+
+   nir_foreach_block(block, impl) {
+      nir_foreach_instr(instr, block) {
+         if (instr->type != nir_instr_type_intrinsic)
+            continue;
+
+         nir_intrinsic_instr *intrin = nir_instr_as_intrinsic(instr);
+         switch (intrin->intrinsic) {
+         case nir_intrinsic_load_shared:
+            util_dynarray_append(loads, nir_intrinsic_instr *, intrin);
+            break;
+
+         case nir_intrinsic_store_shared:
+            if (store != NULL)
+               return NULL;
+            store = intrin;
+            break;
+
+         case nir_intrinsic_shared_atomic:
+         case nir_intrinsic_shared_atomic_swap:
+            return NULL;
+
+         case nir_intrinsic_barrier:
+            if (nir_intrinsic_memory_modes(intrin) == nir_var_mem_shared)
+               util_dynarray_append(barriers, nir_intrinsic_instr *, intrin);
+            break;
+
+         default:
+            break;
+         }
+      }
+   }
+
+   if (store == NULL)
+      return NULL;
+
+   nir_def *value = store->src[0].ssa;
+   const nir_component_mask_t full_mask =
+      nir_component_mask(value->num_components);
+   uint32_t store_addr;
+   if (!shared_addr(store, 1, &store_addr) || value->bit_size > 32 ||
+       nir_intrinsic_write_mask(store) != full_mask)
+      return NULL;
+
+   /* The store has to be the only thing in the then side of a top-level if */
+   nir_cf_node *parent = store->instr.block->cf_node.parent;
+   if (parent->type != nir_cf_node_if ||
+       parent->parent->type != nir_cf_node_function)
+      return NULL;
+
+   nir_if *nif = nir_cf_node_as_if(parent);
+   if (store->instr.block != nir_if_first_then_block(nif) ||
+       store->instr.block != nir_if_last_then_block(nif))
+      return NULL;
+
+   if (!cond_is_lane_eq(nif->condition.ssa, lane) || *lane >= 32)
+      return NULL;
+
+   /* The value has to be available to every lane at the loads so it can't
+    * be computed inside the if.  Anything defined outside of it is in or
+    * before the top-level block preceding the if and dominates everything
+    * after it.
+    */
+   if (value->parent_instr->block == store->instr.block)
+      return NULL;
+
+   nir_block *after_if = nir_cf_node_cf_tree_next(&nif->cf_node);
+   util_dynarray_foreach(loads, nir_intrinsic_instr *, load_p) {
+      nir_intrinsic_instr *load = *load_p;
+      uint32_t load_addr;
+      if (!shared_addr(load, 0, &load_addr) || load_addr != store_addr ||
+          load->def.num_components != value->num_components ||
+          load->def.bit_size != value->bit_size ||
+          !block_is_top_level(load->instr.block) ||
+          load->instr.block->index < after_if->index)
+         return NULL;
+   }
+
+   return store;
+}
+
+static bool
+lower_shared_broadcast_impl(nir_function_impl *impl)
+{
+   struct util_dynarray loads, barriers;
+   util_dynarray_init(&loads, NULL);
+   util_dynarray_init(&barriers, NULL);
+
+   uint32_t lane;
+   nir_intrinsic_instr *store =
+      match_shared_broadcast(impl, &loads, &barriers, &lane);
+
+   if (store != NULL) {
+      nir_def *value = store->src[0].ssa;
+      nir_builder b = nir_builder_create(impl);
+
+      util_dynarray_foreach(&loads, nir_intrinsic_instr *, load_p) {
+         nir_intrinsic_instr *load = *load_p;
+         b.cursor = nir_instr_remove(&load->instr);
+         nir_def *bcast =
+            nir_read_invocation(&b, value, nir_imm_int(&b, lane));
+         nir_def_rewrite_uses(&load->def, bcast);
+      }
+
+      nir_instr_remove(&store->instr);
+
+      util_dynarray_foreach(&barriers, nir_intrinsic_instr *, barrier_p)
+         nir_instr_remove(&(*barrier_p)->instr);
+
+      nir_metadata_preserve(impl, nir_metadata_block_index |
+                                  nir_metadata_dominance);
+   } else {
+      nir_metadata_preserve(impl, nir_metadata_all);
+   }
+
+   util_dynarray_fini(&loads);
+   util_dynarray_fini(&barriers);
+
+   return store != NULL;
+}
+
+bool
+nak_nir_lower_shared_broadcast(nir_shader *nir)
+{
+   if (nir->info.stage != MESA_SHADER_COMPUTE &&
+       nir->info.stage != MESA_SHADER_KERNEL)
+      return false;
+
+   if (!nak_nir_workgroup_has_one_subgroup(nir))
+      return false;
+
+   nir_function_impl *impl = nir_shader_get_entrypoint(nir);
+   nir_metadata_require(impl, nir_metadata_block_index);
+
+   return lower_shared_broadcast_impl(impl);
+}
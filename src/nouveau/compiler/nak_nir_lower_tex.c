@@ -24,9 +24,14 @@ lower_tex(nir_builder *b, nir_tex_instr *tex, const struct nak_compiler *nak)
 {
    b->cursor = nir_before_instr(&tex->instr);
 
+   /* SM50 texture ops have no resident predicate so there's no way to get
+    * at the residency code.
+    */
+   assert(!tex->is_sparse || nak->sm >= 70);
+
    nir_def *tex_h = NULL, *samp_h = NULL, *coord = NULL, *ms_idx = NULL;
    nir_def *offset = NULL, *lod = NULL, *bias = NULL, *min_lod = NULL;
-   nir_def *ddx = NULL, *ddy = NULL, *z_cmpr = NULL;
+   nir_def *ddx = NULL, *ddy = NULL, *z_cmpr = NULL, *granularity = NULL;
    for (unsigned i = 0; i < tex->num_srcs; i++) {
       switch (tex->src[i].src_type) {
       case nir_tex_src_texture_handle: tex_h =     tex->src[i].src.ssa; break;
@@ -40,12 +45,22 @@ lower_tex(nir_builder *b, nir_tex_instr *tex, const struct nak_compiler *nak)
       case nir_tex_src_min_lod:        min_lod =   tex->src[i].src.ssa; break;
       case nir_tex_src_ddx:            ddx =       tex->src[i].src.ssa; break;
       case nir_tex_src_ddy:            ddy =       tex->src[i].src.ssa; break;
+      case nir_tex_src_footprint_granularity_nv:
+         granularity = tex->src[i].src.ssa;
+         break;
       default:
          unreachable("Unsupported texture source");
       }
    }
 
-   /* Combine sampler and texture into one if needed */
+   /* Combine sampler and texture into one if needed
+    *
+    * Every texture op builds its own copy of this but there's no need for
+    * anything clever to share them.  nak_postprocess_nir() runs
+    * nak_optimize_nir() after us and nir_opt_cse will merge identical
+    * handles, along with the descriptor loads feeding them, across the
+    * whole function.
+    */
    if (samp_h != NULL && samp_h != tex_h) {
       tex_h = nir_ior(b, nir_iand_imm(b, tex_h,  0x000fffff),
                          nir_iand_imm(b, samp_h, 0xfff00000));
@@ -129,6 +144,11 @@ lower_tex(nir_builder *b, nir_tex_instr *tex, const struct nak_compiler *nak)
       offset = nir_format_pack_uint(b, offset, bits, 4);
       offset_mode = NAK_NIR_OFFSET_MODE_AOFFI;
    } else if (nir_tex_instr_has_explicit_tg4_offsets(tex)) {
+      /* This is textureGatherOffsets().  The APIs require the four offsets
+       * to be constant so they're always in tg4_offsets and we can pack
+       * them at compile time.  Only TLD4 has a per-pixel offset mode.
+       */
+      assert(tex->op == nir_texop_tg4);
       uint64_t off_u64 = 0;
       for (uint8_t i = 0; i < 8; ++i) {
          uint64_t off = (uint8_t)tex->tg4_offsets[i / 2][i % 2];
@@ -149,7 +169,31 @@ lower_tex(nir_builder *b, nir_tex_instr *tex, const struct nak_compiler *nak)
 } while(0)
 
    if (nak->sm >= 50) {
-      if (tex->op == nir_texop_txd) {
+      if (tex->op == nir_texop_sample_footprint_nv) {
+         /* Footprint queries take the handle first like TXD with the
+          * granularity leading the second vector.
+          */
+         assert(nak->sm >= 75);
+         assert(granularity != NULL);
+         assert(offset == NULL && z_cmpr == NULL);
+
+         /* TODO: Gradient footprints */
+         assert(ddx == NULL && ddy == NULL);
+
+         PUSH(src0, tex_h);
+
+         for (uint32_t i = 0; i < coord_components; i++)
+            PUSH(src0, nir_channel(b, coord, i));
+
+         if (arr_idx != NULL)
+            PUSH(src0, arr_idx);
+
+         PUSH(src1, nir_u2u32(b, granularity));
+         if (lod != NULL)
+            PUSH(src1, lod);
+         if (min_lod != NULL)
+            PUSH(src1, min_lod);
+      } else if (tex->op == nir_texop_txd) {
          PUSH(src0, tex_h);
 
          for (uint32_t i = 0; i < coord_components; i++)
@@ -492,6 +536,34 @@ lower_image_txq(nir_builder *b, nir_intrinsic_instr *intrin,
    return true;
 }
 
+/* The residency code returned by a sparse texture op is 0 if every texel
+ * touched was resident and non-zero otherwise.  That way, combining two codes
+ * is just an OR and we don't need the original predicate back.
+ */
+static bool
+lower_sparse_residency(nir_builder *b, nir_intrinsic_instr *intrin)
+{
+   b->cursor = nir_instr_remove(&intrin->instr);
+
+   nir_def *res;
+   switch (intrin->intrinsic) {
+   case nir_intrinsic_is_sparse_texels_resident:
+      res = nir_ieq_imm(b, intrin->src[0].ssa, 0);
+      break;
+
+   case nir_intrinsic_sparse_residency_code_and:
+      res = nir_ior(b, intrin->src[0].ssa, intrin->src[1].ssa);
+      break;
+
+   default:
+      unreachable("Invalid sparse residency intrinsic");
+   }
+
+   nir_def_rewrite_uses(&intrin->def, res);
+
+   return true;
+}
+
 static bool
 lower_tex_instr(nir_builder *b, nir_instr *instr, void *_data)
 {
@@ -509,6 +581,7 @@ lower_tex_instr(nir_builder *b, nir_instr *instr, void *_data)
       case nir_texop_txf_ms:
       case nir_texop_tg4:
       case nir_texop_lod:
+      case nir_texop_sample_footprint_nv:
          return lower_tex(b, tex, nak);
       case nir_texop_txs:
       case nir_texop_query_levels:
@@ -528,6 +601,9 @@ lower_tex_instr(nir_builder *b, nir_instr *instr, void *_data)
       case nir_intrinsic_bindless_image_size:
       case nir_intrinsic_bindless_image_samples:
          return lower_image_txq(b, intrin, nak);
+      case nir_intrinsic_is_sparse_texels_resident:
+      case nir_intrinsic_sparse_residency_code_and:
+         return lower_sparse_residency(b, intrin);
       default:
          return false;
       }
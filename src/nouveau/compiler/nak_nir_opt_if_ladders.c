@@ -0,0 +1,389 @@
+/*
+ * Copyright © 2024 Collabora, Ltd.
+ * SPDX-License-Identifier: MIT
+ */
+
+#include "nak_private.h"
+#include "nir_builder.h"
+#include "nir_control_flow.h"
+
+#include "util/ralloc.h"
+
+/*
+ * Switches on things like material IDs tend to come out of SPIR-V as a
+ * ladder of ifs comparing the same value against one constant after
+ * another:
+ *
+ *    if (x == 3) { A } else { if (x == 4) { B } else { if (x == 5) { C }
+ *    else { if (x == 6) { D } else { E } } } }
+ *
+ * A warp which hits every case has to walk all the way down the ladder and
+ * back out again, reconverging once per level.  When the constants form a
+ * dense range, we can instead do a single range check for the default case
+ * and then a binary search on x - min inside it:
+ *
+ *    i = x - 3;
+ *    if (i < 4) {
+ *       if (i < 2) { if (i < 1) { A } else { B } }
+ *       else { if (i < 3) { C } else { D } }
+ *    } else { E }
+ *
+ * which needs only log2(n) + 1 levels.  The comparisons are mutually
+ * exclusive so the order in which we test them doesn't matter.
+ *
+ * NAK has no indirect branch so this is the best we can do for now.
+ */
+
+#define MIN_LADDER_CASES 4
+
+struct ladder_case {
+   nir_if *nif;
+   int64_t value;
+
+   /* Value each phi at the bottom of the ladder takes in this case */
+   nir_def **phi_vals;
+
+   nir_cf_list body;
+};
+
+/* Matches (x == imm) in either order */
+static bool
+cond_is_ieq_imm(nir_def *cond, nir_scalar *x, int64_t *imm)
+{
+   if (cond->parent_instr->type != nir_instr_type_alu)
+      return false;
+
+   nir_alu_instr *alu = nir_instr_as_alu(cond->parent_instr);
+   if (alu->op != nir_op_ieq)
+      return false;
+
+   for (unsigned i = 0; i < 2; i++) {
+      nir_scalar s = nir_get_scalar(alu->src[i].src.ssa,
+                                    alu->src[i].swizzle[0]);
+      nir_scalar c = nir_get_scalar(alu->src[1 - i].src.ssa,
+                                    alu->src[1 - i].swizzle[0]);
+      if (!nir_scalar_is_const(s) && nir_scalar_is_const(c)) {
+         *x = s;
+         *imm = nir_scalar_as_int(c);
+         return true;
+      }
+   }
+
+   return false;
+}
+
+static nir_block *
+block_after_if(nir_if *nif)
+{
+   return nir_cf_node_as_block(nir_cf_node_next(&nif->cf_node));
+}
+
+/* If the else side of nif is nothing but another if, returns that if.  We
+ * allow pure instructions ahead of it, since that's where the comparison for
+ * the next rung lives, and phis after it.
+ */
+static nir_if *
+else_ladder_if(nir_if *nif)
+{
+   nir_cf_node *first = &nir_if_first_else_block(nif)->cf_node;
+   nir_cf_node *inner = nir_cf_node_next(first);
+   if (inner == NULL || inner->type != nir_cf_node_if)
+      return NULL;
+
+   nir_cf_node *last = nir_cf_node_next(inner);
+   if (nir_cf_node_next(last) != NULL)
+      return NULL;
+
+   nir_foreach_instr(instr, nir_cf_node_as_block(first)) {
+      if (instr->type != nir_instr_type_alu &&
+          instr->type != nir_instr_type_load_const)
+         return NULL;
+   }
+
+   nir_foreach_instr(instr, nir_cf_node_as_block(last)) {
+      if (instr->type != nir_instr_type_phi)
+         return NULL;
+   }
+
+   return nir_cf_node_as_if(inner);
+}
+
+static bool
+cf_list_has_jump(struct exec_list *list)
+{
+   foreach_list_typed(nir_cf_node, node, node, list) {
+      nir_foreach_block_in_cf_node(block, node) {
+         if (nir_block_ends_in_jump(block))
+            return true;
+      }
+   }
+
+   return false;
+}
+
+/* Phis which merge one rung into the one above it get deleted along with
+ * the ladder so they can't have any other users.
+ */
+static bool
+inner_phis_only_feed_ladder(struct ladder_case *cases, unsigned num_cases)
+{
+   for (unsigned k = 1; k < num_cases; k++) {
+      nir_block *outer_merge = block_after_if(cases[k - 1].nif);
+      nir_foreach_phi(phi, block_after_if(cases[k].nif)) {
+         nir_foreach_use_including_if(src, &phi->def) {
+            if (nir_src_is_if(src))
+               return false;
+
+            nir_instr *use = nir_src_parent_instr(src);
+            if (use->type != nir_instr_type_phi || use->block != outer_merge)
+               return false;
+         }
+      }
+   }
+
+   return true;
+}
+
+/* Walks a phi at the bottom of the ladder back up through the rungs to find
+ * the value it takes for each case and for the default.
+ */
+static nir_def *
+resolve_phi(nir_phi_instr *phi, struct ladder_case *cases,
+            unsigned num_cases, unsigned phi_idx)
+{
+   for (unsigned k = 0; k < num_cases; k++) {
+      nir_if *nif = cases[k].nif;
+      nir_block *then_block = nir_if_last_then_block(nif);
+      nir_block *else_block = nir_if_last_else_block(nif);
+
+      cases[k].phi_vals[phi_idx] =
+         nir_phi_get_src_from_block(phi, then_block)->src.ssa;
+      nir_def *else_val = nir_phi_get_src_from_block(phi, else_block)->src.ssa;
+
+      if (k + 1 == num_cases)
+         return else_val;
+
+      nir_instr *parent = else_val->parent_instr;
+      if (parent->type == nir_instr_type_phi &&
+          parent->block == block_after_if(cases[k + 1].nif)) {
+         phi = nir_instr_as_phi(parent);
+      } else {
+         /* Every remaining rung passes through the same value, which has
+          * to dominate the rest of the ladder.
+          */
+         for (unsigned j = k + 1; j < num_cases; j++)
+            cases[j].phi_vals[phi_idx] = else_val;
+         return else_val;
+      }
+   }
+
+   unreachable("Ladders have at least one rung");
+}
+
+static int
+compare_cases(const void *_a, const void *_b)
+{
+   const struct ladder_case *a = *(const struct ladder_case **)_a;
+   const struct ladder_case *b = *(const struct ladder_case **)_b;
+   return (a->value > b->value) - (a->value < b->value);
+}
+
+static nir_def *
+ult_imm(nir_builder *b, nir_def *x, uint64_t imm)
+{
+   return nir_ult(b, x, nir_imm_intN_t(b, imm, x->bit_size));
+}
+
+/* Emits a binary search over sorted[lo..hi) where sorted[i] is taken when
+ * idx == i and writes the merged phi values to vals.
+ */
+static void
+build_search(nir_builder *b, nir_def *idx, struct ladder_case **sorted,
+             unsigned lo, unsigned hi, unsigned num_phis, nir_def **vals,
+             void *mem_ctx)
+{
+   if (hi - lo == 1) {
+      b->cursor = nir_cf_reinsert(&sorted[lo]->body, b->cursor);
+      for (unsigned i = 0; i < num_phis; i++)
+         vals[i] = sorted[lo]->phi_vals[i];
+      return;
+   }
+
+   const unsigned mid = lo + (hi - lo) / 2;
+   nir_def **lo_vals = ralloc_array(mem_ctx, nir_def *, num_phis);
+   nir_def **hi_vals = ralloc_array(mem_ctx, nir_def *, num_phis);
+
+   nir_if *nif = nir_push_if(b, ult_imm(b, idx, mid));
+   {
+      build_search(b, idx, sorted, lo, mid, num_phis, lo_vals, mem_ctx);
+   }
+   nir_push_else(b, nif);
+   {
+      build_search(b, idx, sorted, mid, hi, num_phis, hi_vals, mem_ctx);
+   }
+   nir_pop_if(b, nif);
+
+   for (unsigned i = 0; i < num_phis; i++)
+      vals[i] = nir_if_phi(b, lo_vals[i], hi_vals[i]);
+}
+
+static bool
+try_lower_if_ladder(nir_if *top, void *mem_ctx)
+{
+   nir_scalar x;
+   int64_t value;
+   if (!cond_is_ieq_imm(top->condition.ssa, &x, &value) ||
+       x.def->bit_size > 32)
+      return false;
+
+   unsigned num_cases = 1;
+   for (nir_if *nif = top; (nif = else_ladder_if(nif)) != NULL;) {
+      nir_scalar s;
+      if (!cond_is_ieq_imm(nif->condition.ssa, &s, &value) ||
+          !nir_scalar_equal(s, x))
+         break;
+      num_cases++;
+   }
+
+   if (num_cases < MIN_LADDER_CASES)
+      return false;
+
+   struct ladder_case *cases =
+      rzalloc_array(mem_ctx, struct ladder_case, num_cases);
+
+   nir_if *nif = top;
+   for (unsigned k = 0; k < num_cases; k++) {
+      ASSERTED nir_scalar s;
+      ASSERTED bool is_ieq =
+         cond_is_ieq_imm(nif->condition.ssa, &s, &cases[k].value);
+      assert(is_ieq);
+
+      if (cf_list_has_jump(&nif->then_list))
+         return false;
+
+      cases[k].nif = nif;
+      if (k + 1 < num_cases)
+         nif = else_ladder_if(nif);
+   }
+   nir_if *bottom = nif;
+
+   if (cf_list_has_jump(&bottom->else_list))
+      return false;
+
+   /* Sort pointers rather than the cases themselves because a nir_cf_list
+    * can't be moved once something has been extracted into it.
+    */
+   struct ladder_case **sorted =
+      ralloc_array(mem_ctx, struct ladder_case *, num_cases);
+   for (unsigned k = 0; k < num_cases; k++)
+      sorted[k] = &cases[k];
+   qsort(sorted, num_cases, sizeof(*sorted), compare_cases);
+
+   for (unsigned k = 1; k < num_cases; k++) {
+      if (sorted[k]->value != sorted[k - 1]->value + 1)
+         return false;
+   }
+
+   if (!inner_phis_only_feed_ladder(cases, num_cases))
+      return false;
+
+   nir_block *merge = block_after_if(top);
+   unsigned num_phis = 0;
+   nir_foreach_phi(phi, merge)
+      num_phis++;
+
+   for (unsigned k = 0; k < num_cases; k++)
+      cases[k].phi_vals = ralloc_array(mem_ctx, nir_def *, num_phis);
+
+   nir_def **default_vals = ralloc_array(mem_ctx, nir_def *, num_phis);
+   unsigned phi_idx = 0;
+   nir_foreach_phi(phi, merge) {
+      default_vals[phi_idx] = resolve_phi(phi, cases, num_cases, phi_idx);
+      phi_idx++;
+   }
+
+   /* Everything ahead of each inner rung is a pure computation, usually
+    * just its comparison, so it can all go in front of the ladder.
+    */
+   const nir_cursor before_top = nir_before_cf_node(&top->cf_node);
+   for (unsigned k = 0; k + 1 < num_cases; k++) {
+      nir_block *first = nir_if_first_else_block(cases[k].nif);
+      nir_foreach_instr_safe(instr, first)
+         nir_instr_move(before_top, instr);
+   }
+
+   for (unsigned k = 0; k < num_cases; k++)
+      nir_cf_list_extract(&cases[k].body, &cases[k].nif->then_list);
+
+   nir_cf_list default_body;
+   nir_cf_list_extract(&default_body, &bottom->else_list);
+
+   nir_builder b = nir_builder_at(before_top);
+
+   nir_def *x_def = nir_channel(&b, x.def, x.comp);
+   nir_def *idx = nir_iadd_imm(&b, x_def, -sorted[0]->value);
+
+   nir_def **case_vals = ralloc_array(mem_ctx, nir_def *, num_phis);
+   nir_if *range = nir_push_if(&b, ult_imm(&b, idx, num_cases));
+   {
+      build_search(&b, idx, sorted, 0, num_cases, num_phis, case_vals,
+                   mem_ctx);
+   }
+   nir_push_else(&b, range);
+   {
+      b.cursor = nir_cf_reinsert(&default_body, b.cursor);
+   }
+   nir_pop_if(&b, range);
+
+   phi_idx = 0;
+   nir_foreach_phi_safe(phi, merge) {
+      nir_def *val = nir_if_phi(&b, case_vals[phi_idx], default_vals[phi_idx]);
+      nir_def_rewrite_uses(&phi->def, val);
+      nir_instr_remove(&phi->instr);
+      phi_idx++;
+   }
+
+   /* All that's left of the old ladder is empty rungs and the phis which
+    * used to tie them together.
+    */
+   nir_cf_node_remove(&top->cf_node);
+
+   return true;
+}
+
+static bool
+lower_one_if_ladder(nir_function_impl *impl, void *mem_ctx)
+{
+   nir_foreach_block(block, impl) {
+      nir_if *nif = nir_block_get_following_if(block);
+      if (nif != NULL && try_lower_if_ladder(nif, mem_ctx))
+         return true;
+   }
+
+   return false;
+}
+
+bool
+nak_nir_opt_if_ladders(nir_shader *nir)
+{
+   bool progress = false;
+
+   nir_foreach_function_impl(impl, nir) {
+      void *mem_ctx = ralloc_context(NULL);
+
+      bool impl_progress = false;
+      while (lower_one_if_ladder(impl, mem_ctx))
+         impl_progress = true;
+
+      ralloc_free(mem_ctx);
+
+      if (impl_progress) {
+         nir_metadata_preserve(impl, nir_metadata_none);
+         progress = true;
+      } else {
+         nir_metadata_preserve(impl, nir_metadata_all);
+      }
+   }
+
+   return progress;
+}
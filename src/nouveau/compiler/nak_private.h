@@ -16,6 +16,42 @@ extern "C" {
 
 bool nak_should_print_nir(void);
 
+typedef void (*nak_write_func)(void *data, const void *bytes, size_t size);
+
+/** Serializes everything passed to nak_compile_shader() which affects the
+ * output, along with the bits of the compiler it depends on
+ *
+ * The NIR is stripped of names.  The result is handed to write_func in one
+ * go.
+ */
+void nak_serialize_compile_input(const struct nak_compiler *nak,
+                                 const nir_shader *nir,
+                                 nir_variable_mode robust2_modes,
+                                 const struct nak_fs_key *fs_key,
+                                 uint8_t max_gprs, bool align_loops,
+                                 nak_write_func write_func, void *data);
+
+/** Everything needed to replay a nak_compile_shader() call */
+struct nak_compile_input {
+   struct nak_compiler *nak;
+   nir_shader *nir;
+   nir_variable_mode robust2_modes;
+   bool has_fs_key;
+   struct nak_fs_key fs_key;
+   uint8_t max_gprs;
+   bool align_loops;
+};
+
+/** Reads back what nak_serialize_compile_input() wrote
+ *
+ * On success, input owns a new compiler and shader which must be freed with
+ * nak_compile_input_finish().
+ */
+bool nak_deserialize_compile_input(const void *data, size_t size,
+                                   struct nak_compile_input *input);
+
+void nak_compile_input_finish(struct nak_compile_input *input);
+
 struct nak_compiler {
    uint8_t sm;
    uint8_t warps_per_sm;
@@ -113,8 +149,8 @@ enum ENUM_PACKED nak_sv {
 
 bool nak_nir_workgroup_has_one_subgroup(const nir_shader *nir);
 
-struct nak_xfb_info
-nak_xfb_from_nir(const struct nir_xfb_info *nir_xfb);
+/** Returns the attribute address of a varying slot */
+uint16_t nak_varying_attr_addr(gl_varying_slot slot);
 
 struct nak_io_addr_offset {
    nir_scalar base;
@@ -147,9 +183,14 @@ struct nak_nir_tex_flags {
 };
 
 bool nak_nir_lower_scan_reduce(nir_shader *shader);
+bool nak_nir_lower_shared_broadcast(nir_shader *nir);
 bool nak_nir_lower_tex(nir_shader *nir, const struct nak_compiler *nak);
+bool nak_nir_lower_sm50_min_lod(nir_shader *nir);
+bool nak_nir_lower_system_values(nir_shader *nir,
+                                 const struct nak_compiler *nak);
 bool nak_nir_lower_gs_intrinsics(nir_shader *shader);
 bool nak_nir_lower_algebraic_late(nir_shader *nir, const struct nak_compiler *nak);
+bool nak_nir_opt_if_ladders(nir_shader *nir);
 
 struct nak_nir_attr_io_flags {
    bool output : 1;
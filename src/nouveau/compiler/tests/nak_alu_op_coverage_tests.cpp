@@ -0,0 +1,249 @@
+/*
+ * Copyright © 2024 Collabora, Ltd.
+ * SPDX-License-Identifier: MIT
+ */
+
+#include "nak_private.h"
+#include "nir_builder.h"
+
+#include <gtest/gtest.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+/* Every NIR ALU op gets its own test case which builds a tiny compute shader
+ * around that op and runs it through the whole compiler.  Ops which NAK
+ * handles are expected to compile.  Anything else is skipped rather than
+ * failed if it doesn't, so the skipped cases are a checklist of the ops which
+ * still panic somewhere.
+ */
+
+/* Ops which parse_alu() translates directly at 32 bits on every SM.  Keep
+ * this in sync with from_nir.rs.
+ */
+static const nir_op expected_ops[] = {
+   nir_op_mov,
+   nir_op_bcsel,
+   nir_op_bit_count,
+   nir_op_bitfield_reverse,
+   nir_op_f2i32,
+   nir_op_f2u32,
+   nir_op_fabs,
+   nir_op_fadd,
+   nir_op_fceil,
+   nir_op_fcos,
+   nir_op_feq,
+   nir_op_fexp2,
+   nir_op_ffloor,
+   nir_op_ffma,
+   nir_op_fge,
+   nir_op_find_lsb,
+   nir_op_flog2,
+   nir_op_flt,
+   nir_op_fmax,
+   nir_op_fmin,
+   nir_op_fmul,
+   nir_op_fneg,
+   nir_op_fneu,
+   nir_op_frcp,
+   nir_op_fround_even,
+   nir_op_frsq,
+   nir_op_fsat,
+   nir_op_fsin,
+   nir_op_fsqrt,
+   nir_op_ftrunc,
+   nir_op_i2f32,
+   nir_op_iabs,
+   nir_op_iadd,
+   nir_op_iand,
+   nir_op_ieq,
+   nir_op_ifind_msb,
+   nir_op_ige,
+   nir_op_ilt,
+   nir_op_imax,
+   nir_op_imin,
+   nir_op_imul,
+   nir_op_ine,
+   nir_op_ineg,
+   nir_op_inot,
+   nir_op_ior,
+   nir_op_ishl,
+   nir_op_ishr,
+   nir_op_ixor,
+   nir_op_u2f32,
+   nir_op_ufind_msb,
+   nir_op_uge,
+   nir_op_ult,
+   nir_op_umax,
+   nir_op_umin,
+   nir_op_ushr,
+};
+
+static bool
+is_expected_op(nir_op op)
+{
+   for (unsigned i = 0; i < ARRAY_SIZE(expected_ops); i++) {
+      if (expected_ops[i] == op)
+         return true;
+   }
+   return false;
+}
+
+static unsigned
+alu_type_bit_size(nir_alu_type type)
+{
+   const unsigned bit_size = nir_alu_type_get_type_size(type);
+   return bit_size == 0 ? 32 : bit_size;
+}
+
+/* Loads a value from a UBO so that nothing can be constant folded */
+static nir_def *
+load_src(nir_builder *b, nir_alu_type type, unsigned num_components,
+         unsigned offset)
+{
+   const unsigned bit_size = alu_type_bit_size(type);
+   const unsigned load_bit_size = MAX2(bit_size, 32);
+
+   nir_def *val = nir_load_ubo(b, num_components, load_bit_size,
+                               nir_imm_int(b, 0), nir_imm_int(b, offset),
+                               (gl_access_qualifier)0, load_bit_size / 8, 0,
+                               0, ~0);
+   if (bit_size == 1)
+      return nir_ine_imm(b, val, 0);
+   else if (bit_size < 32)
+      return nir_u2uN(b, val, bit_size);
+   else
+      return val;
+}
+
+static void
+store_dst(nir_builder *b, nir_def *val)
+{
+   nir_def *addr = nir_load_ubo(b, 1, 64, nir_imm_int(b, 0),
+                                nir_imm_int(b, 0x100),
+                                (gl_access_qualifier)0, 8, 0, 0, ~0);
+
+   if (val->bit_size == 1)
+      val = nir_b2i32(b, val);
+   else if (val->bit_size < 32)
+      val = nir_u2u32(b, val);
+
+   for (unsigned c = 0; c < val->num_components; c++) {
+      nir_def *comp_addr = nir_iadd_imm(b, addr, c * (val->bit_size / 8));
+      nir_store_global(b, comp_addr, val->bit_size / 8,
+                       nir_channel(b, val, c), 0x1);
+   }
+}
+
+static bool
+can_build_op(nir_op op)
+{
+   const nir_op_info *info = &nir_op_infos[op];
+
+   if (info->num_inputs == 0 || info->output_size > 4)
+      return false;
+
+   for (unsigned i = 0; i < info->num_inputs; i++) {
+      if (info->input_sizes[i] > 4)
+         return false;
+   }
+
+   return true;
+}
+
+/* Builds and compiles a shader which uses op.  This may well crash so it is
+ * only ever called in a child process.
+ */
+static void
+compile_op(nir_op op, uint8_t sm)
+{
+   struct nv_device_info dev = {};
+   dev.sm = sm;
+   dev.max_warps_per_mp = 64;
+   struct nak_compiler *nak = nak_compiler_create(&dev);
+
+   glsl_type_singleton_init_or_ref();
+
+   nir_builder _b =
+      nir_builder_init_simple_shader(MESA_SHADER_COMPUTE,
+                                     nak_nir_options(nak), "alu_op_%s",
+                                     nir_op_infos[op].name);
+   nir_builder *b = &_b;
+   b->shader->info.workgroup_size[0] = 32;
+   b->shader->info.workgroup_size[1] = 1;
+   b->shader->info.workgroup_size[2] = 1;
+
+   const nir_op_info *info = &nir_op_infos[op];
+   nir_def *srcs[NIR_MAX_VEC_COMPONENTS];
+   for (unsigned i = 0; i < info->num_inputs; i++) {
+      const unsigned num_components = MAX2(info->input_sizes[i], 1);
+      srcs[i] = load_src(b, info->input_types[i], num_components, i * 32);
+   }
+   store_dst(b, nir_build_alu_src_arr(b, op, srcs));
+
+   nir_validate_shader(b->shader, "after building the shader");
+
+   nak_preprocess_nir(b->shader, nak);
+   struct nak_shader_bin *bin =
+      nak_compile_shader(b->shader, false, nak, 0, NULL, 0, false);
+
+   nak_shader_bin_destroy(bin);
+   ralloc_free(b->shader);
+   glsl_type_singleton_decref();
+   nak_compiler_destroy(nak);
+}
+
+/* Returns true if op compiles without crashing the compiler */
+static bool
+op_compiles(nir_op op, uint8_t sm)
+{
+   fflush(stdout);
+   fflush(stderr);
+
+   pid_t pid = fork();
+   if (pid == 0) {
+      compile_op(op, sm);
+      _exit(0);
+   }
+   if (pid < 0)
+      return false;
+
+   int status;
+   if (waitpid(pid, &status, 0) != pid)
+      return false;
+
+   return WIFEXITED(status) && WEXITSTATUS(status) == 0;
+}
+
+class nak_alu_op_test
+   : public ::testing::TestWithParam<std::tuple<unsigned, uint8_t>> {
+};
+
+TEST_P(nak_alu_op_test, compiles)
+{
+   const nir_op op = (nir_op)std::get<0>(GetParam());
+   const uint8_t sm = std::get<1>(GetParam());
+
+   if (!can_build_op(op))
+      GTEST_SKIP() << "Can't build a shader around this op";
+
+   const bool compiles = op_compiles(op, sm);
+   if (is_expected_op(op))
+      EXPECT_TRUE(compiles);
+   else if (!compiles)
+      GTEST_SKIP() << nir_op_infos[op].name << " is not supported on SM"
+                   << unsigned(sm);
+}
+
+static std::string
+alu_op_test_name(
+   const ::testing::TestParamInfo<std::tuple<unsigned, uint8_t>> &info)
+{
+   return std::string(nir_op_infos[std::get<0>(info.param)].name) + "_sm" +
+          std::to_string(std::get<1>(info.param));
+}
+
+INSTANTIATE_TEST_SUITE_P(
+   all_ops, nak_alu_op_test,
+   ::testing::Combine(::testing::Range(0u, (unsigned)nir_num_opcodes),
+                      ::testing::Values((uint8_t)50, (uint8_t)75)),
+   alu_op_test_name);
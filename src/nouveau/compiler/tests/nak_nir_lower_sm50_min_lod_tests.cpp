@@ -0,0 +1,99 @@
+/*
+ * Copyright © 2024 Collabora, Ltd.
+ * SPDX-License-Identifier: MIT
+ */
+
+#include "nak_private.h"
+#include "tests/nir_test.h"
+
+class nak_nir_lower_sm50_min_lod_test : public nir_test {
+protected:
+   nak_nir_lower_sm50_min_lod_test()
+      : nir_test("nak_nir_lower_sm50_min_lod_test")
+   {
+      b->shader->info.stage = MESA_SHADER_FRAGMENT;
+
+      const glsl_type *sampler_type =
+         glsl_sampler_type(GLSL_SAMPLER_DIM_2D, false, false,
+                           GLSL_TYPE_FLOAT);
+      nir_variable *var =
+         nir_variable_create(b->shader, nir_var_uniform, sampler_type, "tex");
+      tex = nir_build_deref_var(b, var);
+      coord = nir_imm_vec2(b, 0.5, 0.5);
+   }
+
+   nir_tex_instr *build_tex(nir_texop op, bool is_shadow,
+                            nir_def *bias, nir_def *min_lod);
+
+   nir_deref_instr *tex;
+   nir_def *coord;
+};
+
+nir_tex_instr *
+nak_nir_lower_sm50_min_lod_test::build_tex(nir_texop op, bool is_shadow,
+                                           nir_def *bias, nir_def *min_lod)
+{
+   nir_tex_src srcs[4];
+   unsigned num_srcs = 0;
+   srcs[num_srcs++] = nir_tex_src_for_ssa(nir_tex_src_coord, coord);
+   if (is_shadow) {
+      srcs[num_srcs++] = nir_tex_src_for_ssa(nir_tex_src_comparator,
+                                             nir_imm_float(b, 0.5));
+   }
+   if (bias != NULL)
+      srcs[num_srcs++] = nir_tex_src_for_ssa(nir_tex_src_bias, bias);
+   if (min_lod != NULL)
+      srcs[num_srcs++] = nir_tex_src_for_ssa(nir_tex_src_min_lod, min_lod);
+
+   nir_def *def = nir_build_tex_deref_instr(b, op, tex, tex,
+                                            num_srcs, srcs);
+   return nir_instr_as_tex(def->parent_instr);
+}
+
+TEST_F(nak_nir_lower_sm50_min_lod_test, color_tex)
+{
+   nir_tex_instr *instr = build_tex(nir_texop_tex, false, NULL,
+                                    nir_imm_float(b, 2.0));
+
+   ASSERT_TRUE(nak_nir_lower_sm50_min_lod(b->shader));
+   nir_validate_shader(b->shader, "after nak_nir_lower_sm50_min_lod");
+
+   EXPECT_EQ(instr->op, nir_texop_txl);
+   EXPECT_GE(nir_tex_instr_src_index(instr, nir_tex_src_lod), 0);
+   EXPECT_LT(nir_tex_instr_src_index(instr, nir_tex_src_min_lod), 0);
+}
+
+TEST_F(nak_nir_lower_sm50_min_lod_test, color_txb)
+{
+   nir_tex_instr *instr = build_tex(nir_texop_txb, false,
+                                    nir_imm_float(b, 1.0),
+                                    nir_imm_float(b, 2.0));
+
+   ASSERT_TRUE(nak_nir_lower_sm50_min_lod(b->shader));
+   nir_validate_shader(b->shader, "after nak_nir_lower_sm50_min_lod");
+
+   EXPECT_EQ(instr->op, nir_texop_txl);
+   EXPECT_GE(nir_tex_instr_src_index(instr, nir_tex_src_lod), 0);
+   EXPECT_LT(nir_tex_instr_src_index(instr, nir_tex_src_bias), 0);
+   EXPECT_LT(nir_tex_instr_src_index(instr, nir_tex_src_min_lod), 0);
+}
+
+TEST_F(nak_nir_lower_sm50_min_lod_test, shadow_tex)
+{
+   nir_tex_instr *instr = build_tex(nir_texop_tex, true, NULL,
+                                    nir_imm_float(b, 2.0));
+
+   ASSERT_TRUE(nak_nir_lower_sm50_min_lod(b->shader));
+   nir_validate_shader(b->shader, "after nak_nir_lower_sm50_min_lod");
+
+   EXPECT_EQ(instr->op, nir_texop_txl);
+   EXPECT_LT(nir_tex_instr_src_index(instr, nir_tex_src_min_lod), 0);
+}
+
+TEST_F(nak_nir_lower_sm50_min_lod_test, no_clamp)
+{
+   nir_tex_instr *instr = build_tex(nir_texop_tex, false, NULL, NULL);
+
+   EXPECT_FALSE(nak_nir_lower_sm50_min_lod(b->shader));
+   EXPECT_EQ(instr->op, nir_texop_tex);
+}
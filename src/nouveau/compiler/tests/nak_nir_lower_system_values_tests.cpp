@@ -0,0 +1,97 @@
+/*
+ * Copyright © 2024 Collabora, Ltd.
+ * SPDX-License-Identifier: MIT
+ */
+
+#include "nak_private.h"
+#include "tests/nir_test.h"
+
+#include <vector>
+
+class nak_nir_lower_system_values_test : public nir_test {
+protected:
+   nak_nir_lower_system_values_test()
+      : nir_test("nak_nir_lower_system_values_test")
+   {
+      nak.sm = 75;
+      nak.warps_per_sm = 32;
+   }
+
+   std::vector<unsigned> lower_and_get_sysvals(nir_def *def);
+
+   struct nak_compiler nak = {};
+};
+
+/* Runs the pass and returns the system register read for each component of
+ * whatever replaced def
+ */
+std::vector<unsigned>
+nak_nir_lower_system_values_test::lower_and_get_sysvals(nir_def *def)
+{
+   nir_def *addr = nir_imm_int64(b, 0);
+   nir_store_global(b, addr, 4, def, 0x7);
+   nir_intrinsic_instr *store = nir_instr_as_intrinsic(
+      nir_builder_last_instr(b));
+
+   EXPECT_TRUE(nak_nir_lower_system_values(b->shader, &nak));
+   nir_validate_shader(b->shader, "after nak_nir_lower_system_values");
+
+   std::vector<unsigned> sysvals;
+   nir_def *val = store->src[0].ssa;
+   for (unsigned c = 0; c < val->num_components; c++) {
+      nir_scalar s = nir_scalar_chase_movs(nir_get_scalar(val, c));
+      nir_instr *instr = s.def->parent_instr;
+      if (instr->type != nir_instr_type_intrinsic)
+         continue;
+
+      nir_intrinsic_instr *intrin = nir_instr_as_intrinsic(instr);
+      if (intrin->intrinsic == nir_intrinsic_load_sysval_nv)
+         sysvals.push_back(nir_intrinsic_base(intrin));
+   }
+   return sysvals;
+}
+
+TEST_F(nak_nir_lower_system_values_test, subgroup_invocation)
+{
+   auto sysvals = lower_and_get_sysvals(nir_load_subgroup_invocation(b));
+   EXPECT_EQ(sysvals, std::vector<unsigned>({ NAK_SV_LANE_ID }));
+}
+
+TEST_F(nak_nir_lower_system_values_test, local_invocation_id)
+{
+   auto sysvals = lower_and_get_sysvals(nir_load_local_invocation_id(b));
+   EXPECT_EQ(sysvals, std::vector<unsigned>({
+      NAK_SV_TID_X, NAK_SV_TID_Y, NAK_SV_TID_Z,
+   }));
+}
+
+TEST_F(nak_nir_lower_system_values_test, workgroup_id)
+{
+   auto sysvals = lower_and_get_sysvals(nir_load_workgroup_id(b));
+   EXPECT_EQ(sysvals, std::vector<unsigned>({
+      NAK_SV_CTAID_X, NAK_SV_CTAID_Y, NAK_SV_CTAID_Z,
+   }));
+}
+
+TEST_F(nak_nir_lower_system_values_test, workgroup_id_zero_base)
+{
+   auto sysvals = lower_and_get_sysvals(nir_load_workgroup_id_zero_base(b));
+   EXPECT_EQ(sysvals, std::vector<unsigned>({
+      NAK_SV_CTAID_X, NAK_SV_CTAID_Y, NAK_SV_CTAID_Z,
+   }));
+}
+
+TEST_F(nak_nir_lower_system_values_test, subgroup_id_not_a_register)
+{
+   /* Computed from other system values by nak_nir_lower_subgroup_id() */
+   nir_store_global(b, nir_imm_int64(b, 0), 4, nir_load_subgroup_id(b), 0x1);
+   EXPECT_FALSE(nak_nir_lower_system_values(b->shader, &nak));
+}
+
+TEST_F(nak_nir_lower_system_values_test, num_workgroups_not_a_register)
+{
+   /* Provided by the driver */
+   nir_store_global(b, nir_imm_int64(b, 0), 4, nir_load_num_workgroups(b),
+                    0x7);
+   EXPECT_FALSE(nak_nir_lower_system_values(b->shader, &nak));
+}
@@ -0,0 +1,142 @@
+/*
+ * Copyright © 2024 Collabora, Ltd.
+ * SPDX-License-Identifier: MIT
+ */
+
+#include "nak_private.h"
+#include "nir_builder.h"
+
+#include <gtest/gtest.h>
+
+class nak_tcs_test : public ::testing::TestWithParam<uint8_t> {
+protected:
+   nak_tcs_test();
+   ~nak_tcs_test();
+
+   nir_variable *create_output(const char *name, gl_varying_slot slot,
+                               bool patch);
+   struct nak_shader_bin *compile();
+
+   struct nak_compiler *nak;
+   nir_builder _b;
+   nir_builder *b;
+   struct nak_shader_bin *bin = NULL;
+};
+
+static const unsigned VERTICES_OUT = 3;
+
+nak_tcs_test::nak_tcs_test()
+{
+   struct nv_device_info dev = {};
+   dev.sm = GetParam();
+   dev.max_warps_per_mp = 64;
+   nak = nak_compiler_create(&dev);
+
+   glsl_type_singleton_init_or_ref();
+
+   _b = nir_builder_init_simple_shader(MESA_SHADER_TESS_CTRL,
+                                       nak_nir_options(nak), "tcs_test");
+   b = &_b;
+   b->shader->info.tess.tcs_vertices_out = VERTICES_OUT;
+}
+
+nak_tcs_test::~nak_tcs_test()
+{
+   if (bin != NULL)
+      nak_shader_bin_destroy(bin);
+   ralloc_free(b->shader);
+   glsl_type_singleton_decref();
+   nak_compiler_destroy(nak);
+}
+
+nir_variable *
+nak_tcs_test::create_output(const char *name, gl_varying_slot slot,
+                            bool patch)
+{
+   const struct glsl_type *type = glsl_vec4_type();
+   if (!patch)
+      type = glsl_array_type(type, VERTICES_OUT, 0);
+
+   nir_variable *var =
+      nir_variable_create(b->shader, nir_var_shader_out, type, name);
+   var->data.location = slot;
+   var->data.patch = patch;
+   return var;
+}
+
+struct nak_shader_bin *
+nak_tcs_test::compile()
+{
+   nir_validate_shader(b->shader, "after building the shader");
+
+   nak_preprocess_nir(b->shader, nak);
+   bin = nak_compile_shader(b->shader, true, nak, 0, NULL, 0, false);
+   return bin;
+}
+
+static unsigned
+hdr_per_patch_attribute_count(const struct nak_shader_bin *bin)
+{
+   return bin->info.hdr[1] >> 24;
+}
+
+static unsigned
+hdr_threads_per_patch(const struct nak_shader_bin *bin)
+{
+   return bin->info.hdr[2] >> 24;
+}
+
+/* Every invocation writes its own vertex and then reads back the one written
+ * by the next invocation in the patch.
+ */
+TEST_P(nak_tcs_test, output_loopback)
+{
+   nir_variable *out = create_output("out", VARYING_SLOT_VAR0, false);
+   nir_variable *patch = create_output("patch", VARYING_SLOT_PATCH2, true);
+
+   nir_def *id = nir_load_invocation_id(b);
+   nir_def *val = nir_load_ubo(b, 4, 32, nir_imm_int(b, 0), nir_imm_int(b, 0),
+                               (gl_access_qualifier)0, 16, 0, 0, ~0);
+   nir_deref_instr *out_deref = nir_build_deref_var(b, out);
+   nir_store_deref(b, nir_build_deref_array(b, out_deref, id), val, 0xf);
+
+   nir_barrier(b, SCOPE_WORKGROUP, SCOPE_WORKGROUP, NIR_MEMORY_ACQ_REL,
+               nir_var_shader_out);
+
+   nir_def *next = nir_umod_imm(b, nir_iadd_imm(b, id, 1), VERTICES_OUT);
+   nir_def *other =
+      nir_load_deref(b, nir_build_deref_array(b, out_deref, next));
+   nir_store_deref(b, nir_build_deref_var(b, patch), other, 0xf);
+
+   compile();
+
+   EXPECT_NE(strstr(bin->asm_str, "bar.sync"), nullptr);
+   EXPECT_NE(strstr(bin->asm_str, "ald.o"), nullptr);
+   EXPECT_EQ(strstr(bin->asm_str, "membar"), nullptr);
+
+   /* The tess levels plus PATCH0 through PATCH2 */
+   EXPECT_EQ(hdr_per_patch_attribute_count(bin), 8 + 3 * 4);
+   EXPECT_EQ(hdr_threads_per_patch(bin), VERTICES_OUT);
+}
+
+TEST_P(nak_tcs_test, no_patch_outputs)
+{
+   nir_variable *out = create_output("out", VARYING_SLOT_VAR0, false);
+
+   nir_def *id = nir_load_invocation_id(b);
+   nir_deref_instr *out_deref =
+      nir_build_deref_array(b, nir_build_deref_var(b, out), id);
+   nir_store_deref(b, out_deref, nir_imm_vec4(b, 0, 0, 0, 1), 0xf);
+
+   compile();
+
+   /* Just the tess levels */
+   EXPECT_EQ(hdr_per_patch_attribute_count(bin), 6);
+   EXPECT_EQ(hdr_threads_per_patch(bin), VERTICES_OUT);
+}
+
+INSTANTIATE_TEST_SUITE_P(
+   sm, nak_tcs_test, ::testing::Values((uint8_t)50, (uint8_t)75),
+   [](const ::testing::TestParamInfo<uint8_t> &info) {
+      return "sm" + std::to_string(info.param);
+   });
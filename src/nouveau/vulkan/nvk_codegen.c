@@ -683,6 +683,8 @@ nvk_fs_gen_header(struct nvk_shader *fs, const struct nak_fs_key *key,
    if (info->prop.fp.writesDepth) {
       fs->info.hdr[19] |= 0x2;
       fs->info.fs.writes_depth = true;
+      fs->info.fs.writes_depth_less = true;
+      fs->info.fs.writes_depth_greater = true;
    }
 
    for (i = 0; i < info->numInputs; ++i) {
@@ -24,6 +24,34 @@
 #include "nvk_clb197.h"
 #include "nvk_clc397.h"
 
+static bool
+blend_factor_reads_src_alpha(VkBlendFactor factor)
+{
+   return factor == VK_BLEND_FACTOR_SRC_ALPHA ||
+          factor == VK_BLEND_FACTOR_ONE_MINUS_SRC_ALPHA ||
+          factor == VK_BLEND_FACTOR_SRC_ALPHA_SATURATE;
+}
+
+/* Returns true if blending may read the shader's alpha output for this
+ * attachment even when the alpha channel isn't written.
+ */
+static bool
+blend_may_read_src_alpha(const struct vk_graphics_pipeline_state *state,
+                         const struct vk_color_blend_attachment_state *att)
+{
+   if (BITSET_TEST(state->dynamic, MESA_VK_DYNAMIC_CB_BLEND_ENABLES) ||
+       BITSET_TEST(state->dynamic, MESA_VK_DYNAMIC_CB_BLEND_EQUATIONS))
+      return true;
+
+   if (!att->blend_enable)
+      return false;
+
+   return blend_factor_reads_src_alpha(att->src_color_blend_factor) ||
+          blend_factor_reads_src_alpha(att->dst_color_blend_factor) ||
+          blend_factor_reads_src_alpha(att->src_alpha_blend_factor) ||
+          blend_factor_reads_src_alpha(att->dst_alpha_blend_factor);
+}
+
 static void
 nvk_populate_fs_key(struct nak_fs_key *key,
                     const struct vk_multisample_state *ms,
@@ -38,6 +66,30 @@ nvk_populate_fs_key(struct nak_fs_key *key,
        VK_PIPELINE_CREATE_DEPTH_STENCIL_ATTACHMENT_FEEDBACK_LOOP_BIT_EXT)
       key->zs_self_dep = true;
 
+   /* Only bake static write masks into the shader.  Attachments past
+    * attachment_count are left alone because that's where the second source
+    * of dual-source blending goes.
+    */
+   const struct vk_color_blend_state *cb = state->cb;
+   if (cb != NULL &&
+       !BITSET_TEST(state->dynamic, MESA_VK_DYNAMIC_CB_WRITE_MASKS) &&
+       !BITSET_TEST(state->dynamic, MESA_VK_DYNAMIC_CB_COLOR_WRITE_ENABLES)) {
+      for (uint32_t a = 0; a < cb->attachment_count; a++) {
+         uint32_t write_mask = cb->attachments[a].write_mask;
+         if (!(cb->color_write_enables & BITFIELD_BIT(a)))
+            write_mask = 0;
+         else if (blend_may_read_src_alpha(state, &cb->attachments[a]))
+            write_mask |= BITFIELD_BIT(3);
+         key->color_write_disable |= (~write_mask & 0xf) << (4 * a);
+      }
+
+      /* Alpha-to-coverage reads alpha from the first render target */
+      if (ms == NULL || ms->alpha_to_coverage_enable ||
+          BITSET_TEST(state->dynamic,
+                      MESA_VK_DYNAMIC_MS_ALPHA_TO_COVERAGE_ENABLE))
+         key->color_write_disable &= ~BITFIELD_BIT(3);
+   }
+
    if (ms == NULL || ms->rasterization_samples <= 1)
       return;
 
@@ -366,8 +418,8 @@ nvk_graphics_pipeline_create(struct nvk_device *dev,
          }
 
          P_IMMD(p, NV9097, SET_ZCULL_BOUNDS, {
-            .z_min_unbounded_enable = shader->info.fs.writes_depth,
-            .z_max_unbounded_enable = shader->info.fs.writes_depth,
+            .z_min_unbounded_enable = shader->info.fs.writes_depth_less,
+            .z_max_unbounded_enable = shader->info.fs.writes_depth_greater,
          });
 
          /* If we're using the incoming sample mask and doing sample shading,
@@ -385,6 +385,14 @@ record_cbuf_uses_instr(UNUSED nir_builder *b, nir_instr *instr, void *_ctx)
    }
 }
 
+/* Picks which UBOs and descriptor sets get bound as cbufs for this shader.
+ *
+ * Anything in the map is read with load_ubo, which NAK turns into LDC or a
+ * cbuf operand folded directly into the instruction that uses it, and
+ * everything else falls back to load_global_constant.  This has to happen
+ * here rather than as a NAK pass: only the driver knows which addresses
+ * correspond to which bindings, and NAK never sees the addresses themselves.
+ */
 static void
 build_cbuf_map(nir_shader *nir, struct lower_descriptors_ctx *ctx)
 {
@@ -899,6 +907,10 @@ try_lower_intrin(nir_builder *b, nir_intrinsic_instr *intrin,
       return lower_sysval_to_root_table(b, intrin, draw.draw_id, ctx);
 
    case nir_intrinsic_load_view_index:
+      /* Multiview is implemented by replaying each draw once per view and
+       * the draw loop writes the view index into the root table before
+       * each one.  See nvk_mme_emit_view_index().
+       */
       return lower_sysval_to_root_table(b, intrin, draw.view_index, ctx);
 
    case nir_intrinsic_image_deref_load:
@@ -268,6 +268,7 @@ nvk_get_device_features(const struct nv_device_info *info,
       .storageBuffer16BitAccess = true,
       .uniformAndStorageBuffer16BitAccess = true,
       .storagePushConstant16 = true,
+      .storageInputOutput16 = nvk_use_nak(info),
       .multiview = true,
       .multiviewGeometryShader = true,
       .multiviewTessellationShader = true,
@@ -188,6 +188,24 @@ nvk_GetPipelineExecutableStatisticsKHR(
       stat->value.u64 = shader->code_size;
    }
 
+   if (shader->nak != NULL) {
+      vk_outarray_append_typed(VkPipelineExecutableStatisticKHR, &out, stat) {
+         WRITE_STR(stat->name, "Instruction Count");
+         WRITE_STR(stat->description,
+                   "Number of instructions in the compiled shader");
+         stat->format = VK_PIPELINE_EXECUTABLE_STATISTIC_FORMAT_UINT64_KHR;
+         stat->value.u64 = shader->nak->stats.instr_count;
+      }
+
+      vk_outarray_append_typed(VkPipelineExecutableStatisticKHR, &out, stat) {
+         WRITE_STR(stat->name, "Static Cycle Count");
+         WRITE_STR(stat->description,
+                   "Sum of the static delays of all instructions");
+         stat->format = VK_PIPELINE_EXECUTABLE_STATISTIC_FORMAT_UINT64_KHR;
+         stat->value.u64 = shader->nak->stats.static_cycle_count;
+      }
+   }
+
    vk_outarray_append_typed(VkPipelineExecutableStatisticKHR, &out, stat) {
       WRITE_STR(stat->name, "Number of GPRs");
       WRITE_STR(stat->description, "Number of GPRs used by this pipeline");
@@ -195,6 +213,18 @@ nvk_GetPipelineExecutableStatisticsKHR(
       stat->value.u64 = shader->info.num_gprs;
    }
 
+   /* Only filled out by NAK */
+   if (shader->info.max_warps_per_sm > 0) {
+      vk_outarray_append_typed(VkPipelineExecutableStatisticKHR, &out, stat) {
+         WRITE_STR(stat->name, "Max Warps per SM");
+         WRITE_STR(stat->description,
+                   "Theoretical maximum number of warps per SM given the "
+                   "number of GPRs used");
+         stat->format = VK_PIPELINE_EXECUTABLE_STATISTIC_FORMAT_UINT64_KHR;
+         stat->value.u64 = shader->info.max_warps_per_sm;
+      }
+   }
+
    vk_outarray_append_typed(VkPipelineExecutableStatisticKHR, &out, stat) {
       WRITE_STR(stat->name, "SLM Size");
       WRITE_STR(stat->description,
@@ -243,12 +273,15 @@ nvk_GetPipelineExecutableInternalRepresentationsKHR(
    struct nvk_shader *shader =
       shader_for_exe_idx(pipeline, pExecutableInfo->executableIndex);
 
-   if (shader->nak != NULL && shader->nak->asm_str != NULL) {
-      vk_outarray_append_typed(VkPipelineExecutableInternalRepresentationKHR, &out, ir) {
-         WRITE_STR(ir->name, "NAK assembly");
-         WRITE_STR(ir->description, "NAK assembly");
-         if (!write_ir_text(ir, shader->nak->asm_str))
-            incomplete_text = true;
+   if (shader->nak != NULL) {
+      for (uint32_t r = 0; r < shader->nak->ir_count; r++) {
+         const struct nak_shader_ir *nak_ir = &shader->nak->irs[r];
+         vk_outarray_append_typed(VkPipelineExecutableInternalRepresentationKHR, &out, ir) {
+            WRITE_STR(ir->name, "%s", nak_ir->name);
+            WRITE_STR(ir->description, "%s", nak_ir->description);
+            if (!write_ir_text(ir, nak_ir->text))
+               incomplete_text = true;
+         }
       }
    }
 
@@ -81,6 +81,7 @@ uint64_t
 nvk_physical_device_compiler_flags(const struct nvk_physical_device *pdev)
 {
    bool no_cbufs = pdev->debug_flags & NVK_DEBUG_NO_CBUF;
+   bool align_loops = pdev->debug_flags & NVK_DEBUG_ALIGN_LOOPS;
    uint64_t prog_debug = nvk_cg_get_prog_debug();
    uint64_t prog_optimize = nvk_cg_get_prog_optimize();
    uint64_t nak_stages = nvk_nak_stages(&pdev->info);
@@ -94,6 +95,7 @@ nvk_physical_device_compiler_flags(const struct nvk_physical_device *pdev)
    return prog_debug
       | (prog_optimize << 8)
       | ((uint64_t)no_cbufs << 12)
+      | ((uint64_t)align_loops << 13)
       | (nak_stages << 16)
       | (nak_flags << 48);
 }
@@ -427,6 +429,27 @@ nvk_shader_dump(struct nvk_shader *shader)
 }
 #endif
 
+/* Every invocation of a compute workgroup has to be resident on the same SM
+ * at once so large workgroups limit how many GPRs each invocation can use.
+ * GPRs come out of a 64K register file, allocated per warp, eight per thread
+ * at a time.
+ */
+static uint8_t
+nvk_nak_max_gprs(const nir_shader *nir)
+{
+   if (nir->info.stage != MESA_SHADER_COMPUTE ||
+       nir->info.workgroup_size_variable)
+      return 0;
+
+   const unsigned threads = nir->info.workgroup_size[0] *
+                            nir->info.workgroup_size[1] *
+                            nir->info.workgroup_size[2];
+   const unsigned warps = DIV_ROUND_UP(threads, 32);
+   const unsigned gprs = ROUND_DOWN_TO(65536 / (warps * 32), 8);
+
+   return MIN2(gprs, UINT8_MAX);
+}
+
 static VkResult
 nvk_compile_nir_with_nak(struct nvk_physical_device *pdev,
                          nir_shader *nir,
@@ -444,7 +467,17 @@ nvk_compile_nir_with_nak(struct nvk_physical_device *pdev,
    if (rs->storage_buffers == VK_PIPELINE_ROBUSTNESS_BUFFER_BEHAVIOR_ROBUST_BUFFER_ACCESS_2_EXT)
       robust2_modes |= nir_var_mem_ssbo;
 
-   shader->nak = nak_compile_shader(nir, dump_asm, pdev->nak, robust2_modes, fs_key);
+   const bool align_loops = nir->info.stage == MESA_SHADER_COMPUTE &&
+                            pdev->info.sm >= 70 &&
+                            (pdev->debug_flags & NVK_DEBUG_ALIGN_LOOPS);
+
+   shader->nak = nak_compile_shader(nir, dump_asm, pdev->nak, robust2_modes,
+                                    fs_key, nvk_nak_max_gprs(nir),
+                                    align_loops);
+   if (shader->nak->warnings_str != NULL) {
+      vk_perf(VK_LOG_NO_OBJS(pdev->vk.instance), "NAK: %s",
+              shader->nak->warnings_str);
+   }
    shader->info = shader->nak->info;
    shader->code_ptr = shader->nak->code;
    shader->code_size = shader->nak->code_size;
@@ -133,6 +133,7 @@ nouveau_ws_device_set_dbg_flags(struct nouveau_ws_device *dev)
       { "zero_memory", NVK_DEBUG_ZERO_MEMORY },
       { "vm", NVK_DEBUG_VM },
       { "no_cbuf", NVK_DEBUG_NO_CBUF },
+      { "align_loops", NVK_DEBUG_ALIGN_LOOPS },
       { NULL, 0 },
    };
 
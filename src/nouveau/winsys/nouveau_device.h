@@ -40,6 +40,12 @@ enum nvk_debug {
     * Root descriptors still end up in a cbuf
     */
    NVK_DEBUG_NO_CBUF = 1ull << 5,
+
+   /* Align loop headers in compute shaders to instruction cache lines
+    *
+    * Only NAK on Volta+ supports this.
+    */
+   NVK_DEBUG_ALIGN_LOOPS = 1ull << 6,
 };
 
 struct nouveau_ws_device {